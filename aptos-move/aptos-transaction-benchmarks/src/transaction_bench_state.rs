@@ -236,11 +236,9 @@ where
         maybe_block_gas_limit: Option<u64>,
     ) -> (Vec<TransactionOutput>, usize) {
         let block_size = transactions.num_txns();
+        let sharded_block_executor = self.sharded_block_executor.as_ref().unwrap();
         let timer = Instant::now();
-        let output = self
-            .sharded_block_executor
-            .as_ref()
-            .unwrap()
+        let output = sharded_block_executor
             .execute_block(
                 self.state_view.clone(),
                 transactions,
@@ -249,6 +247,9 @@ where
             )
             .expect("VM should not fail to start");
         let exec_time = timer.elapsed().as_millis();
+        if let Some(breakdown) = sharded_block_executor.last_block_breakdown() {
+            print!("{}", breakdown);
+        }
 
         (output, block_size * 1000 / exec_time as usize)
     }
@@ -2515,12 +2515,14 @@ impl VMExecutor for AptosVM {
         );
 
         let count = transactions.num_txns();
-        let ret = sharded_block_executor.execute_block(
-            state_view,
-            transactions,
-            AptosVM::get_concurrency_level(),
-            onchain_config,
-        );
+        let ret = sharded_block_executor
+            .execute_block(
+                state_view,
+                transactions,
+                AptosVM::get_concurrency_level(),
+                onchain_config,
+            )
+            .map_err(VMStatus::from);
         if ret.is_ok() {
             // Record the histogram count for transactions per block.
             BLOCK_TRANSACTION_COUNT.observe(count as f64);
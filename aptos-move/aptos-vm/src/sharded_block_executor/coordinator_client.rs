@@ -1,13 +1,37 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::sharded_block_executor::ExecutorShardCommand;
-use aptos_types::{state_store::StateView, transaction::TransactionOutput};
-use move_core_types::vm_status::VMStatus;
+use crate::sharded_block_executor::{
+    execution_stats::ShardExecutionStats, executor_client::ShardedExecutionError,
+    ExecutorShardCommand,
+};
+use aptos_types::{
+    block_executor::partitioner::RoundId, state_store::StateView, transaction::TransactionOutput,
+};
 
 // Interface to communicate from the executor shards to the block executor coordinator.
 pub trait CoordinatorClient<S: StateView + Sync + Send + 'static>: Send + Sync {
     fn receive_execute_command(&self) -> ExecutorShardCommand<S>;
 
-    fn send_execution_result(&self, result: Result<Vec<Vec<TransactionOutput>>, VMStatus>);
+    // The span the execution of the last command received is traced under, if the coordinator
+    // traces it.
+    fn command_span(&self) -> tracing::Span {
+        tracing::Span::none()
+    }
+
+    // Whether the coordinator aborted the block being executed, which the shard checks before each
+    // round. Blocks are never aborted by default.
+    fn is_block_aborted(&self) -> bool {
+        false
+    }
+
+    // Sends the output of a round as soon as it is executed, in the round order.
+    fn send_round_output(&self, round: RoundId, output: Vec<TransactionOutput>);
+
+    // Ends the execution of a block, once all the rounds are executed or on the first failure.
+    fn send_execution_result(
+        &self,
+        result: Result<(), ShardedExecutionError>,
+        stats: ShardExecutionStats,
+    );
 }
@@ -3,8 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use aptos_metrics_core::{
-    exponential_buckets, register_histogram, register_histogram_vec, register_int_gauge, Histogram,
-    HistogramVec, IntGauge,
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_gauge,
+    register_int_gauge_vec, Histogram, HistogramVec, IntGauge, IntGaugeVec,
 };
 use once_cell::sync::Lazy;
 
@@ -16,6 +16,23 @@ pub static NUM_EXECUTOR_SHARDS: Lazy<IntGauge> = Lazy::new(|| {
     .unwrap()
 });
 
+pub static SHARDED_EXECUTOR_IN_FLIGHT_BLOCKS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sharded_executor_in_flight_blocks",
+        "Number of blocks the sharded block executor started executing and did not output yet"
+    )
+    .unwrap()
+});
+
+pub static SHARDED_EXECUTOR_CONCURRENCY_LEVEL: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sharded_executor_concurrency_level",
+        "Concurrency level a shard executed the last block with",
+        &["shard_id"]
+    )
+    .unwrap()
+});
+
 pub static SHARDED_BLOCK_EXECUTION_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!(
         "sharded_block_execution_seconds",
@@ -59,6 +76,26 @@ pub static SHARDED_BLOCK_EXECUTOR_TXN_COUNT: Lazy<HistogramVec> = Lazy::new(|| {
     .unwrap()
 });
 
+pub static SHARDED_EXECUTOR_CROSS_SHARD_WAIT_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sharded_executor_cross_shard_wait_seconds",
+        "Time the txns of a sub block spent waiting for values from other shards in seconds, summed over the txns",
+        &["shard_id", "round_id"],
+        exponential_buckets(/*start=*/ 1e-3, /*factor=*/ 2.0, /*count=*/ 20).unwrap(),
+    )
+    .unwrap()
+});
+
+pub static SHARDED_EXECUTOR_DEPENDENCY_WAIT_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sharded_executor_dependency_wait_seconds",
+        "Time the txns of a sub block spent waiting for a value from another shard in seconds, summed over the txns, per value waited for",
+        &["shard_id"],
+        exponential_buckets(/*start=*/ 1e-5, /*factor=*/ 2.0, /*count=*/ 24).unwrap(),
+    )
+    .unwrap()
+});
+
 pub static SHARDED_EXECUTOR_SERVICE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
     register_histogram_vec!(
         // metric name
@@ -66,7 +103,8 @@ pub static SHARDED_EXECUTOR_SERVICE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
         // metric description
         "Time spent in seconds on executing a block on a shard including: \
          1. execute_block: fetching state values and cross-shard communications; \
-         2. result_tx: TX of results to coordinator.",
+         2. result_tx: TX of results to coordinator; \
+         3. receive_to_start: from receiving the command to starting the execution.",
         &["shard_id", "name"],
         exponential_buckets(/*start=*/ 1e-3, /*factor=*/ 2.0, /*count=*/ 20).unwrap(),
     )
@@ -17,6 +17,7 @@ use aptos_types::{
     transaction::analyzed_transaction::AnalyzedTransaction,
     write_set::TransactionWrite,
 };
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
@@ -115,19 +116,38 @@ impl CrossShardCommitSender {
             if let Some(dependent_shard_ids) = edges.get(state_key) {
                 for (dependent_shard_id, round_id) in dependent_shard_ids.iter() {
                     trace!("Sending remote update for success for shard id {:?} and txn_idx: {:?}, state_key: {:?}, dependent shard id: {:?}", self.shard_id, txn_idx, state_key, dependent_shard_id);
-                    let message = RemoteTxnWriteMsg(RemoteTxnWrite::new(
-                        state_key.clone(),
-                        Some(write_op.clone()),
-                    ));
-                    if *round_id == GLOBAL_ROUND_ID {
-                        self.cross_shard_client.send_global_msg(message);
-                    } else {
-                        self.cross_shard_client.send_cross_shard_msg(
-                            *dependent_shard_id,
-                            *round_id,
-                            message,
-                        );
-                    }
+                    self.send_to_dependent(
+                        *dependent_shard_id,
+                        *round_id,
+                        RemoteTxnWrite::new(state_key.clone(), Some(write_op.clone())),
+                    );
+                }
+            }
+        }
+    }
+
+    fn send_to_dependent(&self, shard_id: ShardId, round: RoundId, write: RemoteTxnWrite) {
+        let message = RemoteTxnWriteMsg(write);
+        if round == GLOBAL_ROUND_ID {
+            self.cross_shard_client.send_global_msg(message);
+        } else {
+            self.cross_shard_client
+                .send_cross_shard_msg(shard_id, round, message);
+        }
+    }
+
+    /// Send no value for every write the txns of the other shards wait on from the sub-block, which
+    /// is not executed or failed, so that the other shards do not wait forever. A value already
+    /// sent may be overwritten, which does not matter as the block fails.
+    pub fn release_dependents(&self) {
+        for edges in self.dependent_edges.values() {
+            for (state_key, dependent_shard_ids) in edges.iter() {
+                for (dependent_shard_id, round_id) in dependent_shard_ids.iter() {
+                    self.send_to_dependent(
+                        *dependent_shard_id,
+                        *round_id,
+                        RemoteTxnWrite::new(state_key.clone(), None),
+                    );
                 }
             }
         }
@@ -158,3 +178,39 @@ pub trait CrossShardClient: Send + Sync {
 
     fn receive_cross_shard_msg(&self, current_round: RoundId) -> CrossShardMsg;
 }
+
+/// A cross-shard client replaying the writes a shard received from the other shards for a round,
+/// to execute the sub-block of the round again without the other shards. What the sub-block
+/// writes is not sent anywhere.
+pub struct ReplayCrossShardClient {
+    message_tx: Sender<CrossShardMsg>,
+    message_rx: Receiver<CrossShardMsg>,
+}
+
+impl ReplayCrossShardClient {
+    pub fn new(writes: Vec<RemoteTxnWrite>) -> Self {
+        let (message_tx, message_rx) = unbounded();
+        for write in writes {
+            message_tx.send(RemoteTxnWriteMsg(write)).unwrap();
+        }
+        Self {
+            message_tx,
+            message_rx,
+        }
+    }
+}
+
+impl CrossShardClient for ReplayCrossShardClient {
+    fn send_global_msg(&self, _msg: CrossShardMsg) {}
+
+    fn send_cross_shard_msg(&self, _shard_id: ShardId, _round: RoundId, msg: CrossShardMsg) {
+        // Only the stop message the shard sends itself once the sub-block is executed is kept.
+        if matches!(msg, CrossShardMsg::StopMsg) {
+            self.message_tx.send(msg).unwrap();
+        }
+    }
+
+    fn receive_cross_shard_msg(&self, _current_round: RoundId) -> CrossShardMsg {
+        self.message_rx.recv().unwrap()
+    }
+}
@@ -1,48 +1,94 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
-use crate::sharded_block_executor::remote_state_value::RemoteStateValue;
+use crate::sharded_block_executor::{
+    execution_stats::DependencyWait, remote_state_value::RemoteStateValue,
+};
 use anyhow::Result;
 use aptos_logger::trace;
 use aptos_types::{
-    block_executor::partitioner::TransactionWithDependencies,
+    block_executor::partitioner::{ShardId, TransactionWithDependencies},
     state_store::{
         errors::StateviewError, state_key::StateKey, state_storage_usage::StateStorageUsage,
         state_value::StateValue, StateView, TStateView,
     },
     transaction::analyzed_transaction::AnalyzedTransaction,
 };
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// A value written by another shard, with the time the readers spent waiting for it.
+#[derive(Clone)]
+struct CrossShardValue {
+    value: RemoteStateValue,
+    source_shard: ShardId,
+    wait_nanos: Arc<AtomicU64>,
+}
 
 /// A state view for reading cross shard state values. It is backed by a state view
 /// and a hashmap of cross shard state keys. When a cross shard state value is not
 /// available in the hashmap, it will be fetched from the underlying base view.
 #[derive(Clone)]
 pub struct CrossShardStateView<'a, S> {
-    cross_shard_data: HashMap<StateKey, RemoteStateValue>,
+    cross_shard_data: HashMap<StateKey, CrossShardValue>,
     base_view: &'a S,
+    // Total time spent in `get_state_value()` waiting for cross shard values.
+    cross_shard_wait_nanos: Arc<AtomicU64>,
 }
 
 impl<'a, S: StateView + Sync + Send> CrossShardStateView<'a, S> {
-    pub fn new(cross_shard_keys: HashSet<StateKey>, base_view: &'a S) -> Self {
+    /// `cross_shard_keys` are the keys to receive from other shards, with the shard each of them
+    /// comes from.
+    pub fn new(cross_shard_keys: HashMap<StateKey, ShardId>, base_view: &'a S) -> Self {
         let mut cross_shard_data = HashMap::new();
         trace!(
             "Initializing cross shard state view with {} keys",
             cross_shard_keys.len(),
         );
-        for key in cross_shard_keys {
-            cross_shard_data.insert(key, RemoteStateValue::waiting());
+        for (key, source_shard) in cross_shard_keys {
+            cross_shard_data.insert(key, CrossShardValue {
+                value: RemoteStateValue::waiting(),
+                source_shard,
+                wait_nanos: Arc::new(AtomicU64::new(0)),
+            });
         }
         Self {
             cross_shard_data,
             base_view,
+            cross_shard_wait_nanos: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// The time spent waiting for cross shard values so far, summed over the readers.
+    pub fn cross_shard_wait_time(&self) -> Duration {
+        Duration::from_nanos(self.cross_shard_wait_nanos.load(Ordering::Relaxed))
+    }
+
+    /// The time spent waiting for each cross shard value so far, for the values waited for.
+    pub fn dependency_waits(&self) -> Vec<DependencyWait> {
+        self.cross_shard_data
+            .iter()
+            .filter_map(|(state_key, data)| {
+                let wait_nanos = data.wait_nanos.load(Ordering::Relaxed);
+                (wait_nanos > 0).then(|| DependencyWait {
+                    source_shard: data.source_shard,
+                    state_key: state_key.clone(),
+                    wait_time: Duration::from_nanos(wait_nanos),
+                })
+            })
+            .collect()
+    }
+
     #[cfg(test)]
     fn waiting_count(&self) -> usize {
         self.cross_shard_data
             .values()
-            .filter(|v| !v.is_ready())
+            .filter(|data| !data.value.is_ready())
             .count()
     }
 
@@ -50,6 +96,7 @@ impl<'a, S: StateView + Sync + Send> CrossShardStateView<'a, S> {
         self.cross_shard_data
             .get(state_key)
             .unwrap()
+            .value
             .set_value(state_value);
         // uncomment the following line to debug waiting count
         // trace!("waiting count for shard id {} is {}", self.shard_id, self.waiting_count());
@@ -59,11 +106,16 @@ impl<'a, S: StateView + Sync + Send> CrossShardStateView<'a, S> {
         base_view: &'a S,
         transactions: &[TransactionWithDependencies<AnalyzedTransaction>],
     ) -> CrossShardStateView<'a, S> {
-        let mut cross_shard_state_key = HashSet::new();
+        let mut cross_shard_state_key = HashMap::new();
         for txn in transactions {
-            for (_, storage_locations) in txn.cross_shard_dependencies.required_edges_iter() {
+            for (source_txn, storage_locations) in
+                txn.cross_shard_dependencies.required_edges_iter()
+            {
                 for storage_location in storage_locations {
-                    cross_shard_state_key.insert(storage_location.clone().into_state_key());
+                    cross_shard_state_key.insert(
+                        storage_location.clone().into_state_key(),
+                        source_txn.shard_id,
+                    );
                 }
             }
         }
@@ -75,8 +127,17 @@ impl<'a, S: StateView + Sync + Send> TStateView for CrossShardStateView<'a, S> {
     type Key = StateKey;
 
     fn get_state_value(&self, state_key: &StateKey) -> Result<Option<StateValue>, StateviewError> {
-        if let Some(value) = self.cross_shard_data.get(state_key) {
-            return Ok(value.get_value());
+        if let Some(data) = self.cross_shard_data.get(state_key) {
+            if data.value.is_ready() {
+                return Ok(data.value.get_value());
+            }
+            let started_at = Instant::now();
+            let value = data.value.get_value();
+            let wait_nanos = started_at.elapsed().as_nanos() as u64;
+            self.cross_shard_wait_nanos
+                .fetch_add(wait_nanos, Ordering::Relaxed);
+            data.wait_nanos.fetch_add(wait_nanos, Ordering::Relaxed);
+            return Ok(value);
         }
         self.base_view.get_state_value(state_key)
     }
@@ -94,12 +155,7 @@ mod tests {
         TStateView,
     };
     use once_cell::sync::Lazy;
-    use std::{
-        collections::{HashMap, HashSet},
-        sync::Arc,
-        thread,
-        time::Duration,
-    };
+    use std::{collections::HashMap, sync::Arc, thread, time::Duration};
 
     pub static EMPTY_VIEW: Lazy<Arc<InMemoryStateView>> =
         Lazy::new(|| Arc::new(InMemoryStateView::new(HashMap::new())));
@@ -111,8 +167,8 @@ mod tests {
         let state_value_clone = state_value.clone();
         let state_key_clone = state_key.clone();
 
-        let mut state_keys = HashSet::new();
-        state_keys.insert(state_key.clone());
+        let mut state_keys = HashMap::new();
+        state_keys.insert(state_key.clone(), 1);
 
         let cross_shard_state_view = Arc::new(CrossShardStateView::new(state_keys, &EMPTY_VIEW));
         let cross_shard_state_view_clone = cross_shard_state_view.clone();
@@ -129,5 +185,14 @@ mod tests {
         assert_eq!(cross_shard_state_view.waiting_count(), 0);
 
         wait_thread.join().unwrap();
+        assert!(cross_shard_state_view.cross_shard_wait_time() > Duration::ZERO);
+        let dependency_waits = cross_shard_state_view.dependency_waits();
+        assert_eq!(dependency_waits.len(), 1);
+        assert_eq!(dependency_waits[0].source_shard, 1);
+        assert_eq!(dependency_waits[0].state_key, state_key);
+        assert_eq!(
+            dependency_waits[0].wait_time,
+            cross_shard_state_view.cross_shard_wait_time()
+        );
     }
 }
@@ -0,0 +1,193 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use aptos_types::{
+    block_executor::partitioner::{RoundId, ShardId},
+    state_store::state_key::StateKey,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, time::Duration};
+
+/// The number of dependencies a shard reports the wait time of for a block, the longest ones.
+pub const MAX_DEPENDENCY_WAITS_REPORTED: usize = 10;
+
+/// How the execution of a sub-block went on a shard.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RoundExecutionStats {
+    pub num_txns: usize,
+    /// The number of workers the block executor ran the sub-block with, capped by the threads of
+    /// the shard.
+    pub concurrency_level: usize,
+    pub execution_time: Duration,
+    /// The time the txns spent blocked on values from other shards. It is summed over the txns,
+    /// so it can exceed `execution_time`.
+    pub cross_shard_wait_time: Duration,
+}
+
+/// The time the txns of a shard spent waiting for the value of a key written by another shard.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DependencyWait {
+    /// The shard writing the value.
+    pub source_shard: ShardId,
+    pub state_key: StateKey,
+    /// Summed over the txns, like `RoundExecutionStats::cross_shard_wait_time`.
+    pub wait_time: Duration,
+}
+
+/// How the execution of a block went on a shard, sent back to the coordinator with the results.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ShardExecutionStats {
+    /// From the shard receiving the command to the shard starting to execute the first round.
+    pub receive_to_start_time: Duration,
+    pub rounds: Vec<RoundExecutionStats>,
+    /// The dependencies the shard waited the longest for over the block, longest first, see
+    /// `MAX_DEPENDENCY_WAITS_REPORTED`.
+    pub dependency_waits: Vec<DependencyWait>,
+}
+
+impl ShardExecutionStats {
+    pub fn execution_time(&self) -> Duration {
+        self.rounds.iter().map(|round| round.execution_time).sum()
+    }
+
+    /// Add the waits of a round to the ones of the block, summing the waits for the same value
+    /// over the rounds. They are only cut down to the longest ones by
+    /// `keep_longest_dependency_waits()`, not to drop a dependency waited for a bit in every round.
+    pub fn add_dependency_waits(&mut self, dependency_waits: Vec<DependencyWait>) {
+        if self.dependency_waits.is_empty() {
+            self.dependency_waits = dependency_waits;
+            return;
+        }
+        let mut wait_times: HashMap<(ShardId, StateKey), Duration> = self
+            .dependency_waits
+            .drain(..)
+            .map(|wait| ((wait.source_shard, wait.state_key), wait.wait_time))
+            .collect();
+        for wait in dependency_waits {
+            *wait_times
+                .entry((wait.source_shard, wait.state_key))
+                .or_default() += wait.wait_time;
+        }
+        self.dependency_waits = wait_times
+            .into_iter()
+            .map(|((source_shard, state_key), wait_time)| DependencyWait {
+                source_shard,
+                state_key,
+                wait_time,
+            })
+            .collect();
+    }
+
+    /// Keep the `max_waits` longest dependency waits, longest first.
+    pub fn keep_longest_dependency_waits(&mut self, max_waits: usize) {
+        self.dependency_waits
+            .sort_by(|a, b| b.wait_time.cmp(&a.wait_time));
+        self.dependency_waits.truncate(max_waits);
+    }
+}
+
+/// The stats of all the shards for a block, to find the stragglers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlockExecutionBreakdown {
+    pub shard_stats: Vec<ShardExecutionStats>,
+    /// How long the coordinator took to put the outputs of the shards in the order of the block.
+    pub aggregation_time: Duration,
+}
+
+impl BlockExecutionBreakdown {
+    pub fn new(shard_stats: Vec<ShardExecutionStats>) -> Self {
+        Self {
+            shard_stats,
+            aggregation_time: Duration::ZERO,
+        }
+    }
+
+    /// The shard that took the longest to execute its sub-blocks.
+    pub fn slowest_shard(&self) -> Option<(ShardId, &ShardExecutionStats)> {
+        self.shard_stats
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, stats)| stats.receive_to_start_time + stats.execution_time())
+    }
+
+    /// The sub-block that took the longest to execute, over all the shards and rounds.
+    pub fn slowest_round(&self) -> Option<(ShardId, RoundId, &RoundExecutionStats)> {
+        self.shard_stats
+            .iter()
+            .enumerate()
+            .flat_map(|(shard_id, stats)| {
+                stats
+                    .rounds
+                    .iter()
+                    .enumerate()
+                    .map(move |(round, round_stats)| (shard_id, round, round_stats))
+            })
+            .max_by_key(|(_, _, round_stats)| round_stats.execution_time)
+    }
+
+    /// The `max_waits` longest dependency waits over all the shards, longest first, with the
+    /// shard that waited.
+    pub fn longest_dependency_waits(&self, max_waits: usize) -> Vec<(ShardId, &DependencyWait)> {
+        let mut dependency_waits: Vec<_> = self
+            .shard_stats
+            .iter()
+            .enumerate()
+            .flat_map(|(shard_id, stats)| {
+                stats
+                    .dependency_waits
+                    .iter()
+                    .map(move |wait| (shard_id, wait))
+            })
+            .collect();
+        dependency_waits.sort_by(|(_, a), (_, b)| b.wait_time.cmp(&a.wait_time));
+        dependency_waits.truncate(max_waits);
+        dependency_waits
+    }
+}
+
+impl fmt::Display for BlockExecutionBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((shard_id, stats)) = self.slowest_shard() {
+            writeln!(
+                f,
+                "Slowest shard: {} ({:?})",
+                shard_id,
+                stats.receive_to_start_time + stats.execution_time()
+            )?;
+        }
+        if let Some((shard_id, round, round_stats)) = self.slowest_round() {
+            writeln!(
+                f,
+                "Slowest round: {} on shard {} ({:?})",
+                round, shard_id, round_stats.execution_time
+            )?;
+        }
+        for (shard_id, stats) in self.shard_stats.iter().enumerate() {
+            writeln!(
+                f,
+                "Shard {}: {:?} to start",
+                shard_id, stats.receive_to_start_time
+            )?;
+            for (round, round_stats) in stats.rounds.iter().enumerate() {
+                writeln!(
+                    f,
+                    "  round {}: {} txns in {:?} with {} workers, {:?} waiting for other shards",
+                    round,
+                    round_stats.num_txns,
+                    round_stats.execution_time,
+                    round_stats.concurrency_level,
+                    round_stats.cross_shard_wait_time
+                )?;
+            }
+        }
+        writeln!(f, "Aggregation: {:?}", self.aggregation_time)?;
+        for (shard_id, wait) in self.longest_dependency_waits(MAX_DEPENDENCY_WAITS_REPORTED) {
+            writeln!(
+                f,
+                "Shard {} waited {:?} for {:?} from shard {}",
+                shard_id, wait.wait_time, wait.state_key, wait.source_shard
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,29 +1,43 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::sharded_block_executor::execution_stats::ShardExecutionStats;
 use aptos_types::{
     block_executor::{
-        config::BlockExecutorConfigFromOnchain, partitioner::PartitionedTransactions,
+        config::BlockExecutorConfigFromOnchain,
+        partitioner::{CrossShardMessageVolume, PartitionedTransactions, RoundId, ShardId},
     },
     state_store::StateView,
     transaction::TransactionOutput,
 };
-use move_core_types::vm_status::VMStatus;
-use std::sync::Arc;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use move_core_types::vm_status::{StatusCode, VMStatus};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 pub struct ShardedExecutionOutput {
     pub sharded_output: Vec<Vec<Vec<TransactionOutput>>>,
     pub global_output: Vec<TransactionOutput>,
+    /// How the execution went on each shard, indexed by shard id.
+    pub shard_stats: Vec<ShardExecutionStats>,
 }
 
 impl ShardedExecutionOutput {
     pub fn new(
         sharded_output: Vec<Vec<Vec<TransactionOutput>>>,
         global_output: Vec<TransactionOutput>,
+        shard_stats: Vec<ShardExecutionStats>,
     ) -> Self {
         Self {
             sharded_output,
             global_output,
+            shard_stats,
         }
     }
 
@@ -32,19 +46,281 @@ impl ShardedExecutionOutput {
     }
 }
 
+/// Called with the output of each shard for each round, in the order of the aggregated output of
+/// the block: by round, then by shard. A round is passed as soon as all the shards executed it,
+/// before the total supply is aggregated over the block.
+pub type RoundOutputCallback<'a> = dyn FnMut(ShardId, RoundId, &[TransactionOutput]) + 'a;
+
+/// Collects the round outputs streamed by the shards, which arrive in any order across the shards,
+/// and passes the completed rounds to a `RoundOutputCallback` in order.
+pub struct RoundOutputAssembler {
+    // The outputs by shard and round.
+    outputs: Vec<Vec<Option<Vec<TransactionOutput>>>>,
+    // The first round not passed to the callback yet.
+    next_round: RoundId,
+}
+
+impl RoundOutputAssembler {
+    pub fn new(num_shards: usize, num_rounds: usize) -> Self {
+        Self {
+            outputs: vec![vec![None; num_rounds]; num_shards],
+            next_round: 0,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        shard_id: ShardId,
+        round: RoundId,
+        output: Vec<TransactionOutput>,
+        on_round_output: &mut RoundOutputCallback,
+    ) {
+        assert!(
+            self.outputs[shard_id][round].replace(output).is_none(),
+            "Received the output of shard {} for round {} twice",
+            shard_id,
+            round
+        );
+        while self.next_round < self.outputs[0].len()
+            && self
+                .outputs
+                .iter()
+                .all(|outputs| outputs[self.next_round].is_some())
+        {
+            for (shard_id, outputs) in self.outputs.iter().enumerate() {
+                on_round_output(
+                    shard_id,
+                    self.next_round,
+                    outputs[self.next_round].as_ref().unwrap(),
+                );
+            }
+            self.next_round += 1;
+        }
+    }
+
+    /// The outputs by shard and round, once every shard sent the output of every round.
+    pub fn into_output(self) -> Vec<Vec<Vec<TransactionOutput>>> {
+        self.outputs
+            .into_iter()
+            .enumerate()
+            .map(|(shard_id, outputs)| {
+                outputs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(round, output)| {
+                        output.unwrap_or_else(|| {
+                            panic!("Missing output of shard {} for round {}", shard_id, round)
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum ShardedExecutionError {
+    /// The execution itself failed.
+    VMStatus(VMStatus),
+    /// A shard did not send back its results, even after the command was re-sent `num_attempts - 1` times.
+    ShardUnavailable {
+        shard_id: ShardId,
+        num_attempts: usize,
+    },
+    /// A shard panicked or stopped while executing its sub-blocks.
+    ShardFailure { shard_id: ShardId, reason: String },
+    /// The outputs of the shards do not line up with the txns of the block, see `OutputOrder`.
+    OutputOrderMismatch(String),
+    /// The block is partitioned for another number of shards than there are, e.g. it was
+    /// partitioned before the shards were updated.
+    PartitionMismatch {
+        num_shards: usize,
+        num_partitions: usize,
+    },
+    /// The shards could not be updated, see `ExecutorClient::update_shards()`.
+    ShardUpdateFailed(String),
+    /// The block was aborted with a `BlockAbort`, and what the shards executed of it discarded.
+    Aborted,
+}
+
+impl fmt::Display for ShardedExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VMStatus(status) => write!(f, "Execution failed: {:?}", status),
+            Self::ShardUnavailable {
+                shard_id,
+                num_attempts,
+            } => write!(
+                f,
+                "Shard {} did not respond after {} attempts",
+                shard_id, num_attempts
+            ),
+            Self::ShardFailure { shard_id, reason } => {
+                write!(f, "Shard {} failed: {}", shard_id, reason)
+            },
+            Self::OutputOrderMismatch(reason) => write!(f, "Outputs out of order: {}", reason),
+            Self::PartitionMismatch {
+                num_shards,
+                num_partitions,
+            } => write!(
+                f,
+                "Block partitioned into {} sub-blocks for {} shards",
+                num_partitions, num_shards
+            ),
+            Self::ShardUpdateFailed(reason) => write!(f, "Failed to update the shards: {}", reason),
+            Self::Aborted => write!(f, "Block aborted"),
+        }
+    }
+}
+
+impl std::error::Error for ShardedExecutionError {}
+
+impl From<VMStatus> for ShardedExecutionError {
+    fn from(status: VMStatus) -> Self {
+        Self::VMStatus(status)
+    }
+}
+
+impl From<ShardedExecutionError> for VMStatus {
+    fn from(error: ShardedExecutionError) -> Self {
+        match error {
+            ShardedExecutionError::VMStatus(status) => status,
+            error @ (ShardedExecutionError::ShardUnavailable { .. }
+            | ShardedExecutionError::ShardFailure { .. }
+            | ShardedExecutionError::OutputOrderMismatch(_)
+            | ShardedExecutionError::PartitionMismatch { .. }
+            | ShardedExecutionError::ShardUpdateFailed(_)
+            | ShardedExecutionError::Aborted) => VMStatus::error(
+                StatusCode::UNKNOWN_INVARIANT_VIOLATION_ERROR,
+                Some(error.to_string()),
+            ),
+        }
+    }
+}
+
+/// Aborts a block from another thread than the one waiting for its output, see
+/// `ExecutorClient::execute_abortable_block()`. The clones of a `BlockAbort` abort the same block.
+#[derive(Clone, Debug)]
+pub struct BlockAbort {
+    aborted: Arc<AtomicBool>,
+    // Gets a message once the block is aborted, for the client to wake up if it is waiting.
+    abort_tx: Sender<()>,
+    abort_rx: Receiver<()>,
+}
+
+impl Default for BlockAbort {
+    fn default() -> Self {
+        let (abort_tx, abort_rx) = unbounded();
+        Self {
+            aborted: Arc::new(AtomicBool::new(false)),
+            abort_tx,
+            abort_rx,
+        }
+    }
+}
+
+impl BlockAbort {
+    pub fn abort(&self) {
+        if !self.aborted.swap(true, Ordering::AcqRel) {
+            let _ = self.abort_tx.send(());
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    /// Has a message to receive once the block is aborted, to select on along with the results of
+    /// the shards. The message is only there once, whatever the number of clones.
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.abort_rx
+    }
+}
+
 // Interface to communicate from the block executor coordinator to the executor shards.
-pub trait ExecutorClient<S: StateView + Sync + Send + 'static>: Send + Sync {
+pub trait ExecutorClient<S: StateView + Sync + Send + 'static>: Send + Sync + 'static {
     fn num_shards(&self) -> usize;
 
+    // Called before `execute_block()` with the cross-shard messages expected in the block, so that
+    // the client can allocate its cross-shard channels for them upfront. The volume is an estimate,
+    // so the channels must not be bounded by it. Does nothing by default, e.g. for the remote
+    // shards, which set up their cross-shard channels once when they start.
+    fn prepare_cross_shard_channels(&self, _volume: &CrossShardMessageVolume) {}
+
     // A blocking call that executes the transactions in the block. It returns the execution results from each shard
-    // and in the round order and also the global output.
+    // and in the round order and also the global output. The outputs of the rounds are also passed to
+    // `on_round_output` as the shards execute them.
     fn execute_block(
         &self,
         state_view: Arc<S>,
         transactions: PartitionedTransactions,
         concurrency_level_per_shard: usize,
         onchain_config: BlockExecutorConfigFromOnchain,
-    ) -> Result<ShardedExecutionOutput, VMStatus>;
+        on_round_output: &mut RoundOutputCallback,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError>;
+
+    // Same as `execute_block()`, but the block can be aborted meanwhile with `abort`. The shards then
+    // stop at their next round boundary, and the block fails with `ShardedExecutionError::Aborted`
+    // once they are all ready for the next block. By default, the block is executed in full, and
+    // only fails if it was aborted meanwhile.
+    fn execute_abortable_block(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        on_round_output: &mut RoundOutputCallback,
+        abort: &BlockAbort,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
+        if abort.is_aborted() {
+            return Err(ShardedExecutionError::Aborted);
+        }
+        let output = self.execute_block(
+            state_view,
+            transactions,
+            concurrency_level_per_shard,
+            onchain_config,
+            on_round_output,
+        );
+        if abort.is_aborted() {
+            return Err(ShardedExecutionError::Aborted);
+        }
+        output
+    }
+
+    // Executes the blocks in order and passes their outputs to `on_block_output` in the same order.
+    // A client may send a block to the shards before the previous ones are done, with up to
+    // `pipeline_depth` blocks in flight, but by default it executes them one at a time.
+    fn execute_blocks(
+        &self,
+        blocks: &mut dyn Iterator<Item = (Arc<S>, PartitionedTransactions)>,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        _pipeline_depth: usize,
+        on_block_output: &mut dyn FnMut(Result<ShardedExecutionOutput, ShardedExecutionError>),
+    ) {
+        for (state_view, transactions) in blocks {
+            self.prepare_cross_shard_channels(transactions.cross_shard_message_volume());
+            on_block_output(self.execute_block(
+                state_view,
+                transactions,
+                concurrency_level_per_shard,
+                onchain_config.clone(),
+                &mut |_, _, _| {},
+            ));
+        }
+    }
+
+    // Grows or shrinks the shards to `num_shards` between blocks: the shards added are started, and
+    // the ones removed are stopped once done with what they were sent. The shards ids stay
+    // contiguous, so shrinking removes the last shards. By default, the shards cannot be updated.
+    fn update_shards(&mut self, num_shards: usize) -> Result<(), ShardedExecutionError> {
+        Err(ShardedExecutionError::ShardUpdateFailed(format!(
+            "the executor client cannot go from {} to {} shards",
+            self.num_shards(),
+            num_shards
+        )))
+    }
 
     fn shutdown(&mut self);
 }
@@ -5,9 +5,13 @@ use crate::sharded_block_executor::{
     coordinator_client::CoordinatorClient,
     counters::WAIT_FOR_SHARDED_OUTPUT_SECONDS,
     cross_shard_client::CrossShardClient,
-    executor_client::{ExecutorClient, ShardedExecutionOutput},
+    execution_stats::ShardExecutionStats,
+    executor_client::{
+        BlockAbort, ExecutorClient, RoundOutputAssembler, RoundOutputCallback,
+        ShardedExecutionError, ShardedExecutionOutput,
+    },
     global_executor::GlobalExecutor,
-    messages::CrossShardMsg,
+    messages::{CrossShardMsg, ShardExecutionMsg},
     sharded_aggregator_service,
     sharded_executor_service::ShardedExecutorService,
     ExecutorShardCommand, ShardedBlockExecutor,
@@ -17,16 +21,20 @@ use aptos_types::{
     block_executor::{
         config::BlockExecutorConfigFromOnchain,
         partitioner::{
-            PartitionedTransactions, RoundId, ShardId, GLOBAL_ROUND_ID,
+            CrossShardMessageVolume, PartitionedTransactions, RoundId, ShardId, GLOBAL_ROUND_ID,
             MAX_ALLOWED_PARTITIONING_ROUNDS,
         },
     },
     state_store::StateView,
     transaction::TransactionOutput,
 };
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use move_core_types::vm_status::VMStatus;
-use std::{sync::Arc, thread};
+use crossbeam_channel::{unbounded, Receiver, Select, Sender};
+use std::{
+    collections::VecDeque,
+    panic,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread,
+};
 
 /// Executor service that runs on local machine and waits for commands from the coordinator and executes
 /// them in parallel.
@@ -41,10 +49,15 @@ impl<S: StateView + Sync + Send + 'static> LocalExecutorService<S> {
         num_shards: usize,
         num_threads: usize,
         command_rx: Receiver<ExecutorShardCommand<S>>,
-        result_tx: Sender<Result<Vec<Vec<TransactionOutput>>, VMStatus>>,
+        result_tx: Sender<ShardExecutionMsg>,
         cross_shard_client: LocalCrossShardClient,
+        block_abort: Arc<RwLock<BlockAbort>>,
     ) -> Self {
-        let coordinator_client = Arc::new(LocalCoordinatorClient::new(command_rx, result_tx));
+        let coordinator_client = Arc::new(LocalCoordinatorClient::new(
+            command_rx,
+            result_tx,
+            block_abort,
+        ));
         let executor_service = Arc::new(ShardedExecutorService::new(
             shard_id,
             num_shards,
@@ -74,6 +87,35 @@ impl<S: StateView + Sync + Send + 'static> LocalExecutorService<S> {
         (global_executor, cross_shard_tx)
     }
 
+    // Start a shard, along with the channels to send it commands and receive its results.
+    fn spawn(
+        shard_id: ShardId,
+        num_shards: usize,
+        num_threads: usize,
+        global_cross_shard_tx: Sender<CrossShardMsg>,
+        cross_shard_channels: Arc<RwLock<LocalCrossShardChannels>>,
+        block_abort: Arc<RwLock<BlockAbort>>,
+    ) -> (
+        Sender<ExecutorShardCommand<S>>,
+        Receiver<ShardExecutionMsg>,
+        Self,
+    ) {
+        let (command_tx, command_rx) = unbounded();
+        let (result_tx, result_rx) = unbounded();
+        let cross_shard_client =
+            LocalCrossShardClient::new(global_cross_shard_tx, shard_id, cross_shard_channels);
+        let executor_service = Self::new(
+            shard_id,
+            num_shards,
+            num_threads,
+            command_rx,
+            result_tx,
+            cross_shard_client,
+            block_abort,
+        );
+        (command_tx, result_rx, executor_service)
+    }
+
     pub fn setup_local_executor_shards(
         num_shards: usize,
         num_threads: Option<usize>,
@@ -81,49 +123,98 @@ impl<S: StateView + Sync + Send + 'static> LocalExecutorService<S> {
         let (global_executor, global_cross_shard_tx) = Self::setup_global_executor();
         let num_threads = num_threads
             .unwrap_or_else(|| (num_cpus::get() as f64 / num_shards as f64).ceil() as usize);
-        let (command_txs, command_rxs): (
-            Vec<Sender<ExecutorShardCommand<S>>>,
-            Vec<Receiver<ExecutorShardCommand<S>>>,
-        ) = (0..num_shards).map(|_| unbounded()).unzip();
-        let (result_txs, result_rxs): (
-            Vec<Sender<Result<Vec<Vec<TransactionOutput>>, VMStatus>>>,
-            Vec<Receiver<Result<Vec<Vec<TransactionOutput>>, VMStatus>>>,
-        ) = (0..num_shards).map(|_| unbounded()).unzip();
-        // We need to create channels for each shard and each round. This is needed because individual
-        // shards might send cross shard messages to other shards that will be consumed in different rounds.
-        // Having a single channel per shard will cause a shard to receiver messages that is not intended in the current round.
-        let (cross_shard_msg_txs, cross_shard_msg_rxs): (
-            Vec<Vec<Sender<CrossShardMsg>>>,
-            Vec<Vec<Receiver<CrossShardMsg>>>,
-        ) = (0..num_shards)
-            .map(|_| {
+        let cross_shard_channels =
+            Arc::new(RwLock::new(LocalCrossShardChannels::new(num_shards, None)));
+        let mut client = LocalExecutorClient::new(
+            vec![],
+            vec![],
+            vec![],
+            global_executor,
+            global_cross_shard_tx,
+            cross_shard_channels,
+            num_threads,
+        );
+        client.add_shards(num_shards);
+        client
+    }
+}
+
+/// The channels of the cross-shard messages between the local shards.
+///
+/// We need a channel for each shard and each round. This is needed because individual shards might send
+/// cross shard messages to other shards that will be consumed in different rounds. Having a single channel
+/// per shard will cause a shard to receive messages that are not intended for the current round.
+pub struct LocalCrossShardChannels {
+    // The messages to shard i in round j.
+    queues: Vec<Vec<Arc<CrossShardMsgQueue>>>,
+}
+
+impl LocalCrossShardChannels {
+    /// Channels allocated upfront for the given number of messages to shard i in round j, if any.
+    pub fn new(num_shards: usize, capacities: Option<Vec<Vec<usize>>>) -> Self {
+        let queues = (0..num_shards)
+            .map(|shard_id| {
                 (0..MAX_ALLOWED_PARTITIONING_ROUNDS)
-                    .map(|_| unbounded())
-                    .unzip()
-            })
-            .unzip();
-        let executor_shards = command_rxs
-            .into_iter()
-            .zip(result_txs)
-            .zip(cross_shard_msg_rxs)
-            .enumerate()
-            .map(|(shard_id, ((command_rx, result_tx), cross_shard_rxs))| {
-                let cross_shard_client = LocalCrossShardClient::new(
-                    global_cross_shard_tx.clone(),
-                    cross_shard_msg_txs.clone(),
-                    cross_shard_rxs,
-                );
-                Self::new(
-                    shard_id as ShardId,
-                    num_shards,
-                    num_threads,
-                    command_rx,
-                    result_tx,
-                    cross_shard_client,
-                )
+                    .map(|round| {
+                        let capacity = capacities
+                            .as_ref()
+                            .map_or(0, |capacities| capacities[shard_id][round]);
+                        Arc::new(CrossShardMsgQueue::with_capacity(capacity))
+                    })
+                    .collect()
             })
             .collect();
-        LocalExecutorClient::new(command_txs, result_rxs, executor_shards, global_executor)
+        Self { queues }
+    }
+
+    /// The capacity needed by the channel of each shard and round for the messages in `volume`, and
+    /// the `StopMsg` a shard sends itself at the end of each round.
+    fn capacities(volume: &CrossShardMessageVolume) -> Vec<Vec<usize>> {
+        (0..volume.num_shards())
+            .map(|shard_id| {
+                (0..MAX_ALLOWED_PARTITIONING_ROUNDS)
+                    .map(|round| volume.inbound(shard_id, round).num_messages + 1)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The channel of the cross-shard messages to a shard in a round.
+///
+/// A send never blocks, as the messages of a round are only received once the shard reaches the round,
+/// and the partitioner's estimate can be short, e.g. when a txn is committed twice as parallel execution
+/// falls back to sequential execution. The buffer is allocated upfront for the messages expected instead.
+struct CrossShardMsgQueue {
+    msgs: Mutex<VecDeque<CrossShardMsg>>,
+    msg_available: Condvar,
+}
+
+impl CrossShardMsgQueue {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            msgs: Mutex::new(VecDeque::with_capacity(capacity)),
+            msg_available: Condvar::new(),
+        }
+    }
+
+    fn send(&self, msg: CrossShardMsg) {
+        self.msgs.lock().unwrap().push_back(msg);
+        self.msg_available.notify_one();
+    }
+
+    fn recv(&self) -> CrossShardMsg {
+        let mut msgs = self.msgs.lock().unwrap();
+        loop {
+            match msgs.pop_front() {
+                Some(msg) => return msg,
+                None => msgs = self.msg_available.wait(msgs).unwrap(),
+            }
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.msgs.lock().unwrap().capacity()
     }
 }
 
@@ -131,26 +222,85 @@ pub struct LocalExecutorClient<S: StateView + Sync + Send + 'static> {
     // Channels to send execute block commands to the executor shards.
     command_txs: Vec<Sender<ExecutorShardCommand<S>>>,
     // Channels to receive execution results from the executor shards.
-    result_rxs: Vec<Receiver<Result<Vec<Vec<TransactionOutput>>, VMStatus>>>,
+    result_rxs: Vec<Receiver<ShardExecutionMsg>>,
     executor_services: Vec<LocalExecutorService<S>>,
     global_executor: GlobalExecutor<S>,
+    // Shared with the cross shard clients of the executor shards, replaced before each block.
+    cross_shard_channels: Arc<RwLock<LocalCrossShardChannels>>,
+    // The channel capacities for the next block, see `prepare_cross_shard_channels()`.
+    next_cross_shard_channel_capacities: Mutex<Option<Vec<Vec<usize>>>>,
+    // Shared with the coordinator clients of the executor shards, replaced before each block.
+    block_abort: Arc<RwLock<BlockAbort>>,
+    // To start the shards added by `update_shards()` like the others.
+    global_cross_shard_tx: Sender<CrossShardMsg>,
+    num_threads_per_shard: usize,
 }
 
 impl<S: StateView + Sync + Send + 'static> LocalExecutorClient<S> {
     pub fn new(
         command_tx: Vec<Sender<ExecutorShardCommand<S>>>,
-        result_rx: Vec<Receiver<Result<Vec<Vec<TransactionOutput>>, VMStatus>>>,
+        result_rx: Vec<Receiver<ShardExecutionMsg>>,
         executor_shards: Vec<LocalExecutorService<S>>,
         global_executor: GlobalExecutor<S>,
+        global_cross_shard_tx: Sender<CrossShardMsg>,
+        cross_shard_channels: Arc<RwLock<LocalCrossShardChannels>>,
+        num_threads_per_shard: usize,
     ) -> Self {
         Self {
             command_txs: command_tx,
             result_rxs: result_rx,
             executor_services: executor_shards,
             global_executor,
+            cross_shard_channels,
+            next_cross_shard_channel_capacities: Mutex::new(None),
+            block_abort: Arc::new(RwLock::new(BlockAbort::default())),
+            global_cross_shard_tx,
+            num_threads_per_shard,
+        }
+    }
+
+    // Start shards up to `num_shards`, after the existing ones.
+    fn add_shards(&mut self, num_shards: usize) {
+        while self.num_shards() < num_shards {
+            let (command_tx, result_rx, executor_service) = LocalExecutorService::spawn(
+                self.num_shards(),
+                num_shards,
+                self.num_threads_per_shard,
+                self.global_cross_shard_tx.clone(),
+                self.cross_shard_channels.clone(),
+                self.block_abort.clone(),
+            );
+            self.command_txs.push(command_tx);
+            self.result_rxs.push(result_rx);
+            self.executor_services.push(executor_service);
         }
     }
 
+    // Stop the shards after the first `num_shards`, once they are done with what they were sent.
+    fn remove_shards(&mut self, num_shards: usize) {
+        while self.num_shards() > num_shards {
+            let _ = self
+                .command_txs
+                .pop()
+                .unwrap()
+                .send(ExecutorShardCommand::Stop);
+            self.result_rxs.pop();
+            let mut executor_service = self.executor_services.pop().unwrap();
+            let _ = executor_service.join_handle.take().unwrap().join();
+        }
+    }
+
+    /// The capacity the cross-shard channel of shard i in round j was allocated with.
+    pub fn cross_shard_channel_capacities(&self) -> Vec<Vec<usize>> {
+        self.cross_shard_channels
+            .read()
+            .unwrap()
+            .queues
+            .iter()
+            .map(|queues| queues.iter().map(|queue| queue.capacity()).collect())
+            .collect()
+    }
+
     pub fn create_local_sharded_block_executor(
         num_shards: usize,
         num_threads: Option<usize>,
@@ -161,17 +311,55 @@ impl<S: StateView + Sync + Send + 'static> LocalExecutorClient<S> {
         ))
     }
 
-    fn get_output_from_shards(&self) -> Result<Vec<Vec<Vec<TransactionOutput>>>, VMStatus> {
+    fn get_output_from_shards(
+        &self,
+        num_rounds: usize,
+        on_round_output: &mut RoundOutputCallback,
+    ) -> Result<(Vec<Vec<Vec<TransactionOutput>>>, Vec<ShardExecutionStats>), ShardedExecutionError>
+    {
         let _timer = WAIT_FOR_SHARDED_OUTPUT_SECONDS.start_timer();
         trace!("LocalExecutorClient Waiting for results");
-        let mut results = vec![];
-        for (i, rx) in self.result_rxs.iter().enumerate() {
-            results.push(
-                rx.recv()
-                    .unwrap_or_else(|_| panic!("Did not receive output from shard {}", i))?,
-            );
+        let mut assembler = RoundOutputAssembler::new(self.num_shards(), num_rounds);
+        let mut shard_stats = vec![ShardExecutionStats::default(); self.num_shards()];
+        let mut first_error = None;
+        let mut pending_shards: Vec<ShardId> = (0..self.num_shards()).collect();
+        // The messages of all the shards are received even after an error, so that none of them is
+        // mistaken for a message of the next block.
+        while !pending_shards.is_empty() {
+            let mut select = Select::new();
+            for shard_id in pending_shards.iter() {
+                select.recv(&self.result_rxs[*shard_id]);
+            }
+            let operation = select.select();
+            let index = operation.index();
+            let shard_id = pending_shards[index];
+            match operation.recv(&self.result_rxs[shard_id]) {
+                Ok(ShardExecutionMsg::RoundOutput(round, output)) => {
+                    if first_error.is_none() {
+                        assembler.add(shard_id, round, output, on_round_output);
+                    }
+                },
+                Ok(ShardExecutionMsg::Done(result, stats)) => {
+                    if let Err(error) = result {
+                        first_error.get_or_insert(error);
+                    }
+                    shard_stats[shard_id] = stats;
+                    pending_shards.swap_remove(index);
+                },
+                // The channel is closed only if the shard thread is gone.
+                Err(_) => {
+                    first_error.get_or_insert(ShardedExecutionError::ShardFailure {
+                        shard_id,
+                        reason: "the shard stopped without sending its results".to_string(),
+                    });
+                    pending_shards.swap_remove(index);
+                },
+            }
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok((assembler.into_output(), shard_stats)),
         }
-        Ok(results)
     }
 }
 
@@ -180,15 +368,54 @@ impl<S: StateView + Sync + Send + 'static> ExecutorClient<S> for LocalExecutorCl
         self.command_txs.len()
     }
 
+    fn prepare_cross_shard_channels(&self, volume: &CrossShardMessageVolume) {
+        *self.next_cross_shard_channel_capacities.lock().unwrap() =
+            Some(LocalCrossShardChannels::capacities(volume));
+    }
+
     fn execute_block(
         &self,
         state_view: Arc<S>,
         transactions: PartitionedTransactions,
         concurrency_level_per_shard: usize,
         onchain_config: BlockExecutorConfigFromOnchain,
-    ) -> Result<ShardedExecutionOutput, VMStatus> {
+        on_round_output: &mut RoundOutputCallback,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
+        self.execute_abortable_block(
+            state_view,
+            transactions,
+            concurrency_level_per_shard,
+            onchain_config,
+            on_round_output,
+            &BlockAbort::default(),
+        )
+    }
+
+    fn execute_abortable_block(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        on_round_output: &mut RoundOutputCallback,
+        abort: &BlockAbort,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
         assert_eq!(transactions.num_shards(), self.num_shards());
+        if abort.is_aborted() {
+            return Err(ShardedExecutionError::Aborted);
+        }
+        // The shards are idle between blocks, so the channels can be replaced. That also drops any message
+        // left over from the previous block, e.g. an aborted one.
+        let capacities = self
+            .next_cross_shard_channel_capacities
+            .lock()
+            .unwrap()
+            .take();
+        *self.cross_shard_channels.write().unwrap() =
+            LocalCrossShardChannels::new(self.num_shards(), capacities);
+        *self.block_abort.write().unwrap() = abort.clone();
         let (sub_blocks, global_txns) = transactions.into();
+        let num_rounds = sub_blocks[0].num_sub_blocks();
         for (i, sub_blocks_for_shard) in sub_blocks.into_iter().enumerate() {
             self.command_txs[i]
                 .send(ExecutorShardCommand::ExecuteSubBlocks(
@@ -204,13 +431,28 @@ impl<S: StateView + Sync + Send + 'static> ExecutorClient<S> for LocalExecutorCl
         // global transactions will be blocked for cross shard transaction results. This hopefully will help with
         // finishing the global transactions faster but we need to evaluate if this causes thread contention. If it
         // does, then we can simply move this call to the end of the function.
-        let mut global_output = self.global_executor.execute_global_txns(
-            global_txns,
-            state_view.as_ref(),
-            onchain_config,
-        )?;
-
-        let mut sharded_output = self.get_output_from_shards()?;
+        // The outputs of the shards are received meanwhile, to pass them to the callback as they come.
+        let (global_output, sharded_output) = thread::scope(|s| {
+            let global_output = s.spawn(|| {
+                self.global_executor.execute_global_txns(
+                    global_txns,
+                    state_view.as_ref(),
+                    onchain_config,
+                )
+            });
+            let sharded_output = self.get_output_from_shards(num_rounds, on_round_output);
+            let global_output = global_output
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload));
+            (global_output, sharded_output)
+        });
+        // The shards that were done with the block before it was aborted sent all their outputs,
+        // which are discarded all the same.
+        if abort.is_aborted() {
+            return Err(ShardedExecutionError::Aborted);
+        }
+        let (mut sharded_output, shard_stats) = sharded_output?;
+        let mut global_output = global_output?;
 
         sharded_aggregator_service::aggregate_and_update_total_supply(
             &mut sharded_output,
@@ -219,7 +461,30 @@ impl<S: StateView + Sync + Send + 'static> ExecutorClient<S> for LocalExecutorCl
             self.global_executor.get_executor_thread_pool(),
         );
 
-        Ok(ShardedExecutionOutput::new(sharded_output, global_output))
+        Ok(ShardedExecutionOutput::new(
+            sharded_output,
+            global_output,
+            shard_stats,
+        ))
+    }
+
+    fn update_shards(&mut self, num_shards: usize) -> Result<(), ShardedExecutionError> {
+        if num_shards == 0 {
+            return Err(ShardedExecutionError::ShardUpdateFailed(
+                "there must be at least one shard".to_string(),
+            ));
+        }
+        // A shard with results left to receive is still executing a block, or failed to.
+        if let Some(shard_id) = self.result_rxs.iter().position(|rx| !rx.is_empty()) {
+            return Err(ShardedExecutionError::ShardUpdateFailed(format!(
+                "shard {} has results of a block left, the shards can only be updated between blocks",
+                shard_id
+            )));
+        }
+        // The cross-shard channels are replaced for the number of shards before each block.
+        self.remove_shards(num_shards);
+        self.add_shards(num_shards);
+        Ok(())
     }
 
     fn shutdown(&mut self) {}
@@ -241,17 +506,21 @@ impl<S: StateView + Sync + Send + 'static> Drop for LocalExecutorClient<S> {
 pub struct LocalCoordinatorClient<S> {
     command_rx: Receiver<ExecutorShardCommand<S>>,
     // Channel to send execution results to the coordinator.
-    result_tx: Sender<Result<Vec<Vec<TransactionOutput>>, VMStatus>>,
+    result_tx: Sender<ShardExecutionMsg>,
+    // Aborts the block being executed, shared with the coordinator.
+    block_abort: Arc<RwLock<BlockAbort>>,
 }
 
 impl<S> LocalCoordinatorClient<S> {
     pub fn new(
         command_rx: Receiver<ExecutorShardCommand<S>>,
-        result_tx: Sender<Result<Vec<Vec<TransactionOutput>>, VMStatus>>,
+        result_tx: Sender<ShardExecutionMsg>,
+        block_abort: Arc<RwLock<BlockAbort>>,
     ) -> Self {
         Self {
             command_rx,
             result_tx,
+            block_abort,
         }
     }
 }
@@ -261,8 +530,24 @@ impl<S: StateView + Sync + Send + 'static> CoordinatorClient<S> for LocalCoordin
         self.command_rx.recv().unwrap()
     }
 
-    fn send_execution_result(&self, result: Result<Vec<Vec<TransactionOutput>>, VMStatus>) {
-        self.result_tx.send(result).unwrap()
+    fn is_block_aborted(&self) -> bool {
+        self.block_abort.read().unwrap().is_aborted()
+    }
+
+    fn send_round_output(&self, round: RoundId, output: Vec<TransactionOutput>) {
+        self.result_tx
+            .send(ShardExecutionMsg::RoundOutput(round, output))
+            .unwrap()
+    }
+
+    fn send_execution_result(
+        &self,
+        result: Result<(), ShardedExecutionError>,
+        stats: ShardExecutionStats,
+    ) {
+        self.result_tx
+            .send(ShardExecutionMsg::Done(result, stats))
+            .unwrap()
     }
 }
 
@@ -303,22 +588,21 @@ impl CrossShardClient for GlobalCrossShardClient {
 
 pub struct LocalCrossShardClient {
     global_message_tx: Sender<CrossShardMsg>,
-    // The senders of cross-shard messages to other shards per round.
-    message_txs: Vec<Vec<Sender<CrossShardMsg>>>,
-    // The receivers of cross shard messages from other shards per round.
-    message_rxs: Vec<Receiver<CrossShardMsg>>,
+    shard_id: ShardId,
+    // The channels of cross-shard messages to all the shards per round.
+    channels: Arc<RwLock<LocalCrossShardChannels>>,
 }
 
 impl LocalCrossShardClient {
     pub fn new(
         global_message_tx: Sender<CrossShardMsg>,
-        cross_shard_txs: Vec<Vec<Sender<CrossShardMsg>>>,
-        cross_shard_rxs: Vec<Receiver<CrossShardMsg>>,
+        shard_id: ShardId,
+        channels: Arc<RwLock<LocalCrossShardChannels>>,
     ) -> Self {
         Self {
             global_message_tx,
-            message_txs: cross_shard_txs,
-            message_rxs: cross_shard_rxs,
+            shard_id,
+            channels,
         }
     }
 }
@@ -329,10 +613,13 @@ impl CrossShardClient for LocalCrossShardClient {
     }
 
     fn send_cross_shard_msg(&self, shard_id: ShardId, round: RoundId, msg: CrossShardMsg) {
-        self.message_txs[shard_id][round].send(msg).unwrap()
+        let queue = self.channels.read().unwrap().queues[shard_id][round].clone();
+        queue.send(msg)
     }
 
     fn receive_cross_shard_msg(&self, current_round: RoundId) -> CrossShardMsg {
-        self.message_rxs[current_round].recv().unwrap()
+        // Not holding the lock while blocked on the channel.
+        let queue = self.channels.read().unwrap().queues[self.shard_id][current_round].clone();
+        queue.recv()
     }
 }
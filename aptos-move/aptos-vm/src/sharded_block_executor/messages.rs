@@ -1,7 +1,13 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use aptos_types::{state_store::state_key::StateKey, write_set::WriteOp};
+use crate::sharded_block_executor::{
+    execution_stats::ShardExecutionStats, executor_client::ShardedExecutionError,
+};
+use aptos_types::{
+    block_executor::partitioner::RoundId, state_store::state_key::StateKey,
+    transaction::TransactionOutput, write_set::WriteOp,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -29,3 +35,11 @@ impl RemoteTxnWrite {
         (self.state_key, self.write_op)
     }
 }
+
+/// A message from a shard to the coordinator about the block it executes: the outputs of the
+/// rounds in the round order, as they are executed, and then `Done`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ShardExecutionMsg {
+    RoundOutput(RoundId, Vec<TransactionOutput>),
+    Done(Result<(), ShardedExecutionError>, ShardExecutionStats),
+}
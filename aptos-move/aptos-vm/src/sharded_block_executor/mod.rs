@@ -5,38 +5,70 @@
 use crate::sharded_block_executor::{
     counters::{
         NUM_EXECUTOR_SHARDS, SHARDED_BLOCK_EXECUTION_SECONDS,
-        SHARDED_EXECUTION_RESULT_AGGREGATION_SECONDS,
+        SHARDED_EXECUTION_RESULT_AGGREGATION_SECONDS, SHARDED_EXECUTOR_IN_FLIGHT_BLOCKS,
     },
-    executor_client::ExecutorClient,
+    execution_stats::{BlockExecutionBreakdown, MAX_DEPENDENCY_WAITS_REPORTED},
+    executor_client::{BlockAbort, ExecutorClient, ShardedExecutionError, ShardedExecutionOutput},
+    messages::RemoteTxnWrite,
+    output_order::OutputOrder,
 };
-use aptos_logger::info;
+use aptos_logger::{info, warn};
 use aptos_types::{
     block_executor::{
         config::BlockExecutorConfigFromOnchain,
-        partitioner::{PartitionedTransactions, SubBlocksForShard},
+        partitioner::{PartitionedTransactions, RoundId, ShardId, SubBlock, SubBlocksForShard},
     },
     state_store::StateView,
     transaction::{analyzed_transaction::AnalyzedTransaction, TransactionOutput},
 };
-use move_core_types::vm_status::VMStatus;
-use std::{marker::PhantomData, sync::Arc};
+use crossbeam_channel::{unbounded, Sender};
+use futures::channel::oneshot;
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    iter,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
 
 pub mod aggr_overridden_state_view;
 pub mod coordinator_client;
 mod counters;
 pub mod cross_shard_client;
 mod cross_shard_state_view;
+pub mod execution_stats;
 pub mod executor_client;
 pub mod global_executor;
 pub mod local_executor_shard;
 pub mod messages;
+mod output_aggregation;
+pub mod output_order;
 pub mod remote_state_value;
 pub mod sharded_aggregator_service;
 pub mod sharded_executor_service;
 
 /// Coordinator for sharded block executors that manages multiple shards and aggregates the results.
 pub struct ShardedBlockExecutor<S: StateView + Sync + Send + 'static, C: ExecutorClient<S>> {
-    executor_client: C,
+    // Shared with the thread executing the blocks of `execute_block_async()`.
+    executor_client: Arc<C>,
+    // Held while a block is executed, for the blocks to be executed one after the other. An
+    // aborted block holds it until the shards are ready for the next block.
+    execution_lock: Arc<Mutex<()>>,
+    // Started with the first block of `execute_block_async()`, see `AsyncBlockExecutor`.
+    async_executor: Mutex<Option<AsyncBlockExecutor>>,
+    // The per-shard stats of the last block executed, see `last_block_breakdown()`.
+    last_block_breakdown: Mutex<Option<BlockExecutionBreakdown>>,
+    // The number of blocks `execute_blocks()` may have in flight at once.
+    pipeline_depth: usize,
+    // Whether to check the order of the outputs of each block, see `set_verify_output_order()`.
+    verify_output_order: bool,
+    // The blocks slower than this get the dependencies the shards waited the longest for logged,
+    // see `set_slow_block_threshold()`.
+    slow_block_threshold: Option<Duration>,
     phantom: PhantomData<S>,
 }
 
@@ -47,6 +79,16 @@ pub enum ExecutorShardCommand<S> {
         usize,
         BlockExecutorConfigFromOnchain,
     ),
+    // A single sub-block executed on its own, e.g. to reproduce the output of a shard for a round,
+    // with the writes of the other shards it depends on.
+    ExecuteSubBlock(
+        Arc<S>,
+        RoundId,
+        SubBlock<AnalyzedTransaction>,
+        Vec<RemoteTxnWrite>,
+        usize,
+        BlockExecutorConfigFromOnchain,
+    ),
     Stop,
 }
 
@@ -57,7 +99,13 @@ impl<S: StateView + Sync + Send + 'static, C: ExecutorClient<S>> ShardedBlockExe
             executor_client.num_shards()
         );
         Self {
-            executor_client,
+            executor_client: Arc::new(executor_client),
+            execution_lock: Arc::new(Mutex::new(())),
+            async_executor: Mutex::new(None),
+            last_block_breakdown: Mutex::new(None),
+            pipeline_depth: 1,
+            verify_output_order: cfg!(debug_assertions),
+            slow_block_threshold: None,
             phantom: PhantomData,
         }
     }
@@ -66,6 +114,79 @@ impl<S: StateView + Sync + Send + 'static, C: ExecutorClient<S>> ShardedBlockExe
         self.executor_client.num_shards()
     }
 
+    pub fn executor_client(&self) -> &C {
+        &self.executor_client
+    }
+
+    // The executor client to update, once the blocks of the dropped `execute_block_async()` futures
+    // are aborted, as the thread executing them holds on to it until then.
+    fn executor_client_mut(&mut self) -> &mut C {
+        if let Some(async_executor) = self.async_executor.get_mut().unwrap().take() {
+            async_executor.stop();
+        }
+        Arc::get_mut(&mut self.executor_client).expect("The executor client is still in use")
+    }
+
+    // A block that panicked while holding the lock leaves nothing to recover.
+    fn lock_execution(&self) -> MutexGuard<'_, ()> {
+        self.execution_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Grow or shrink the shards to `num_shards`, if the executor client supports it, see
+    /// `ExecutorClient::update_shards()`. No block is in flight, as the executor is borrowed
+    /// mutably, and the blocks executed afterwards must be partitioned for `num_shards`.
+    pub fn update_shards(&mut self, num_shards: usize) -> Result<(), ShardedExecutionError> {
+        info!(
+            "Updating the shards of the ShardedBlockExecutor from {} to {}",
+            self.executor_client.num_shards(),
+            num_shards
+        );
+        self.executor_client_mut().update_shards(num_shards)?;
+        NUM_EXECUTOR_SHARDS.set(num_shards as i64);
+        Ok(())
+    }
+
+    /// How the execution went on each shard for the last block, `None` before the first block.
+    pub fn last_block_breakdown(&self) -> Option<BlockExecutionBreakdown> {
+        self.last_block_breakdown.lock().unwrap().clone()
+    }
+
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    /// Let `execute_blocks()` send up to `pipeline_depth` blocks to the shards at once, so that a
+    /// shard can start on a block as soon as it is done with the previous ones. Defaults to 1, i.e.
+    /// one block at a time.
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        assert!(pipeline_depth > 0, "The pipeline depth must be at least 1");
+        self.pipeline_depth = pipeline_depth;
+    }
+
+    pub fn verify_output_order(&self) -> bool {
+        self.verify_output_order
+    }
+
+    /// Check that the outputs of the shards for each block add up to an output per txn, at the
+    /// index the partitioner gave the txn, and fail the block with an `OutputOrderMismatch`
+    /// otherwise. Enabled by default in debug builds only.
+    pub fn set_verify_output_order(&mut self, verify_output_order: bool) {
+        self.verify_output_order = verify_output_order;
+    }
+
+    pub fn slow_block_threshold(&self) -> Option<Duration> {
+        self.slow_block_threshold
+    }
+
+    /// Log the values the shards waited the longest for from other shards, and which shards they
+    /// came from, for the blocks the slowest shard takes longer than `slow_block_threshold` to
+    /// execute. Not logged if `None`, the default.
+    pub fn set_slow_block_threshold(&mut self, slow_block_threshold: Option<Duration>) {
+        self.slow_block_threshold = slow_block_threshold;
+    }
+
     /// Execute a block of transactions in parallel by splitting the block into num_remote_executors partitions and
     /// dispatching each partition to a remote executor shard.
     pub fn execute_block(
@@ -74,49 +195,299 @@ impl<S: StateView + Sync + Send + 'static, C: ExecutorClient<S>> ShardedBlockExe
         transactions: PartitionedTransactions,
         concurrency_level_per_shard: usize,
         onchain_config: BlockExecutorConfigFromOnchain,
-    ) -> Result<Vec<TransactionOutput>, VMStatus> {
+    ) -> Result<Vec<TransactionOutput>, ShardedExecutionError> {
+        self.execute_block_streaming(
+            state_view,
+            transactions,
+            concurrency_level_per_shard,
+            onchain_config,
+            |_, _, _| {},
+        )
+    }
+
+    /// Same as `execute_block()`, but without blocking: the future resolves to the output of the
+    /// block once the shards are done with it. Dropping the future aborts the block: the shards stop
+    /// at their next round boundary and what they executed is discarded. The next block waits for
+    /// them to be done with the aborted one.
+    pub fn execute_block_async(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+    ) -> impl Future<Output = Result<Vec<TransactionOutput>, ShardedExecutionError>> + '_ {
+        async move {
+            let _timer = SHARDED_BLOCK_EXECUTION_SECONDS.start_timer();
+            let num_executor_shards = self.executor_client.num_shards();
+            NUM_EXECUTOR_SHARDS.set(num_executor_shards as i64);
+            if transactions.num_shards() != num_executor_shards {
+                return Err(ShardedExecutionError::PartitionMismatch {
+                    num_shards: num_executor_shards,
+                    num_partitions: transactions.num_shards(),
+                });
+            }
+            let output_order = self
+                .verify_output_order
+                .then(|| OutputOrder::new(&transactions));
+            let abort = BlockAbort::default();
+            let mut abort_on_drop = AbortOnDrop(Some(abort.clone()));
+            let executor_client = self.executor_client.clone();
+            let execution_lock = self.execution_lock.clone();
+            let (output_tx, output_rx) = oneshot::channel();
+            self.async_executor
+                .lock()
+                .unwrap()
+                .get_or_insert_with(AsyncBlockExecutor::start)
+                .execute(Box::new(move || {
+                    let _execution_guard = execution_lock
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    let output = panic::catch_unwind(AssertUnwindSafe(|| {
+                        executor_client.prepare_cross_shard_channels(
+                            transactions.cross_shard_message_volume(),
+                        );
+                        executor_client.execute_abortable_block(
+                            state_view,
+                            transactions,
+                            concurrency_level_per_shard,
+                            onchain_config,
+                            &mut |_, _, _| {},
+                            &abort,
+                        )
+                    }));
+                    // Nobody is waiting for the output of an aborted block.
+                    let _ = output_tx.send(output);
+                }));
+            let output = match output_rx
+                .await
+                .expect("The thread executing the block is gone")
+            {
+                Ok(output) => output,
+                Err(payload) => panic::resume_unwind(payload),
+            };
+            abort_on_drop.disarm();
+            // wait for all remote executors to send the result back and append them in order by shard id
+            info!("ShardedBlockExecutor Received all results");
+            self.aggregate_output(output?, output_order.as_ref())
+        }
+    }
+
+    /// Same as `execute_block()`, but also passes the outputs of the shards to `on_round_output` as
+    /// soon as they executed a round, see `RoundOutputCallback`.
+    pub fn execute_block_streaming(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        mut on_round_output: impl FnMut(ShardId, RoundId, &[TransactionOutput]),
+    ) -> Result<Vec<TransactionOutput>, ShardedExecutionError> {
         let _timer = SHARDED_BLOCK_EXECUTION_SECONDS.start_timer();
         let num_executor_shards = self.executor_client.num_shards();
         NUM_EXECUTOR_SHARDS.set(num_executor_shards as i64);
-        assert_eq!(
-            num_executor_shards,
-            transactions.num_shards(),
-            "Block must be partitioned into {} sub-blocks",
-            num_executor_shards
-        );
-        let (sharded_output, global_output) = self
-            .executor_client
-            .execute_block(
-                state_view,
-                transactions,
-                concurrency_level_per_shard,
-                onchain_config,
-            )?
-            .into_inner();
+        if transactions.num_shards() != num_executor_shards {
+            return Err(ShardedExecutionError::PartitionMismatch {
+                num_shards: num_executor_shards,
+                num_partitions: transactions.num_shards(),
+            });
+        }
+        let _execution_guard = self.lock_execution();
+        self.executor_client
+            .prepare_cross_shard_channels(transactions.cross_shard_message_volume());
+        let output_order = self
+            .verify_output_order
+            .then(|| OutputOrder::new(&transactions));
+        let output = self.executor_client.execute_block(
+            state_view,
+            transactions,
+            concurrency_level_per_shard,
+            onchain_config,
+            &mut on_round_output,
+        )?;
         // wait for all remote executors to send the result back and append them in order by shard id
         info!("ShardedBlockExecutor Received all results");
-        let _aggregation_timer = SHARDED_EXECUTION_RESULT_AGGREGATION_SECONDS.start_timer();
-        let num_rounds = sharded_output[0].len();
-        let mut aggregated_results = vec![];
-        let mut ordered_results = vec![vec![]; num_executor_shards * num_rounds];
-        // Append the output from individual shards in the round order
-        for (shard_id, results_from_shard) in sharded_output.into_iter().enumerate() {
-            for (round, result) in results_from_shard.into_iter().enumerate() {
-                ordered_results[round * num_executor_shards + shard_id] = result;
+        self.aggregate_output(output, output_order.as_ref())
+    }
+
+    /// Execute the blocks one after the other, passing the output of each block to
+    /// `on_block_output` in order. Up to `pipeline_depth()` blocks are in flight, if the executor
+    /// client supports it. Each block is executed on the state view it comes with, so when blocks
+    /// overlap, a block only sees the writes of the previous ones if its state view has them.
+    /// A block partitioned for another number of shards gets a `PartitionMismatch` error, once the
+    /// blocks before it are executed.
+    pub fn execute_blocks(
+        &self,
+        blocks: impl IntoIterator<Item = (Arc<S>, PartitionedTransactions)>,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        mut on_block_output: impl FnMut(Result<Vec<TransactionOutput>, ShardedExecutionError>),
+    ) {
+        let _execution_guard = self.lock_execution();
+        let num_executor_shards = self.executor_client.num_shards();
+        NUM_EXECUTOR_SHARDS.set(num_executor_shards as i64);
+        // The blocks come out in the order they went in, so their output orders are queued in
+        // that order too.
+        let output_orders = RefCell::new(VecDeque::new());
+        let mut blocks = blocks.into_iter().peekable();
+        while let Some((_, transactions)) = blocks.peek() {
+            if transactions.num_shards() != num_executor_shards {
+                on_block_output(Err(ShardedExecutionError::PartitionMismatch {
+                    num_shards: num_executor_shards,
+                    num_partitions: transactions.num_shards(),
+                }));
+                blocks.next();
+                continue;
             }
+            // The blocks up to the next mismatched one are executed together.
+            let mut matching_blocks = iter::from_fn(|| {
+                blocks.next_if(|(_, transactions)| transactions.num_shards() == num_executor_shards)
+            })
+            .inspect(|(_, transactions)| {
+                if self.verify_output_order {
+                    output_orders
+                        .borrow_mut()
+                        .push_back(OutputOrder::new(transactions));
+                }
+                SHARDED_EXECUTOR_IN_FLIGHT_BLOCKS.inc();
+            });
+            self.executor_client.execute_blocks(
+                &mut matching_blocks,
+                concurrency_level_per_shard,
+                onchain_config.clone(),
+                self.pipeline_depth,
+                &mut |output| {
+                    SHARDED_EXECUTOR_IN_FLIGHT_BLOCKS.dec();
+                    let output_order = output_orders.borrow_mut().pop_front();
+                    on_block_output(
+                        output.and_then(|output| {
+                            self.aggregate_output(output, output_order.as_ref())
+                        }),
+                    );
+                },
+            );
         }
+    }
 
-        for result in ordered_results.into_iter() {
-            aggregated_results.extend(result);
+    // Order the outputs of the shards as in the block, after recording how the execution went, and
+    // checking them against `output_order` if any.
+    fn aggregate_output(
+        &self,
+        output: ShardedExecutionOutput,
+        output_order: Option<&OutputOrder>,
+    ) -> Result<Vec<TransactionOutput>, ShardedExecutionError> {
+        let ShardedExecutionOutput {
+            sharded_output,
+            global_output,
+            shard_stats,
+        } = output;
+        let breakdown = BlockExecutionBreakdown::new(shard_stats);
+        if let (Some((slowest_shard, shard_stats)), Some((shard_id, round, round_stats))) =
+            (breakdown.slowest_shard(), breakdown.slowest_round())
+        {
+            info!(
+                "Slowest shard {} took {:?} ({:?} to start), slowest round {} on shard {} took {:?} ({:?} waiting for other shards)",
+                slowest_shard,
+                shard_stats.receive_to_start_time + shard_stats.execution_time(),
+                shard_stats.receive_to_start_time,
+                round,
+                shard_id,
+                round_stats.execution_time,
+                round_stats.cross_shard_wait_time,
+            );
+            let block_time = shard_stats.receive_to_start_time + shard_stats.execution_time();
+            if self
+                .slow_block_threshold
+                .map_or(false, |threshold| block_time > threshold)
+            {
+                let dependency_waits: Vec<_> = breakdown
+                    .longest_dependency_waits(MAX_DEPENDENCY_WAITS_REPORTED)
+                    .into_iter()
+                    .map(|(shard_id, wait)| {
+                        format!(
+                            "shard {} waited {:?} for {:?} from shard {}",
+                            shard_id, wait.wait_time, wait.state_key, wait.source_shard
+                        )
+                    })
+                    .collect();
+                warn!(
+                    "Slow block took {:?}, longest waits for other shards: [{}]",
+                    block_time,
+                    dependency_waits.join(", ")
+                );
+            }
+        }
+        *self.last_block_breakdown.lock().unwrap() = Some(breakdown);
+        if let Some(output_order) = output_order {
+            output_order.verify(&sharded_output, &global_output)?;
+        }
+        let aggregation_timer = SHARDED_EXECUTION_RESULT_AGGREGATION_SECONDS.start_timer();
+        let aggregated_results =
+            output_aggregation::aggregate_outputs(sharded_output, global_output);
+        let aggregation_time = Duration::from_secs_f64(aggregation_timer.stop_and_record());
+        if let Some(breakdown) = self.last_block_breakdown.lock().unwrap().as_mut() {
+            breakdown.aggregation_time = aggregation_time;
         }
-
-        // Lastly append the global output
-        aggregated_results.extend(global_output);
-
         Ok(aggregated_results)
     }
 
     pub fn shutdown(&mut self) {
-        self.executor_client.shutdown();
+        self.executor_client_mut().shutdown();
+    }
+}
+
+// The thread executing the blocks of `execute_block_async()` one after the other, so that a
+// future only waits for the output of its block, and a dropped future leaves its block to be
+// aborted there. The blocks of `execute_block()` are executed on the calling thread instead.
+struct AsyncBlockExecutor {
+    block_tx: Sender<Box<dyn FnOnce() + Send>>,
+    join_handle: thread::JoinHandle<()>,
+}
+
+impl AsyncBlockExecutor {
+    fn start() -> Self {
+        let (block_tx, block_rx) = unbounded::<Box<dyn FnOnce() + Send>>();
+        let join_handle = thread::Builder::new()
+            .name("sharded-block-execution".to_string())
+            .spawn(move || {
+                for execute_block in block_rx {
+                    execute_block();
+                }
+            })
+            .expect("Failed to spawn the thread executing the blocks");
+        Self {
+            block_tx,
+            join_handle,
+        }
+    }
+
+    fn execute(&self, execute_block: Box<dyn FnOnce() + Send>) {
+        self.block_tx
+            .send(execute_block)
+            .expect("The thread executing the blocks is gone");
+    }
+
+    // Waits for the blocks sent so far, including the aborted ones, to be done.
+    fn stop(self) {
+        drop(self.block_tx);
+        if let Err(payload) = self.join_handle.join() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+// Aborts the block when dropped, unless disarmed once the block is executed.
+struct AbortOnDrop(Option<BlockAbort>);
+
+impl AbortOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(abort) = self.0.take() {
+            abort.abort();
+        }
     }
 }
@@ -0,0 +1,117 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use aptos_types::transaction::TransactionOutput;
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+
+static AGGREGATION_POOL: Lazy<rayon::ThreadPool> = Lazy::new(|| {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_cpus::get())
+        .thread_name(|index| format!("sharded-aggregation-{}", index))
+        .build()
+        .unwrap()
+});
+
+/// Put the outputs of the shards in the order of the block: by round, then by shard within a
+/// round, then the outputs of the global txns. The sub-blocks are moved into place in parallel, the
+/// collected outputs keeping the order of the sub-blocks.
+pub(crate) fn aggregate_outputs(
+    sharded_output: Vec<Vec<Vec<TransactionOutput>>>,
+    global_output: Vec<TransactionOutput>,
+) -> Vec<TransactionOutput> {
+    let ordered_outputs = order_sub_blocks(sharded_output, global_output);
+    AGGREGATION_POOL.install(|| ordered_outputs.into_par_iter().flatten().collect())
+}
+
+// The outputs of the sub-blocks in the order of the block, the global outputs last.
+fn order_sub_blocks(
+    sharded_output: Vec<Vec<Vec<TransactionOutput>>>,
+    global_output: Vec<TransactionOutput>,
+) -> Vec<Vec<TransactionOutput>> {
+    let num_shards = sharded_output.len();
+    let num_rounds = sharded_output.first().map_or(0, Vec::len);
+    let mut ordered_outputs: Vec<_> = (0..num_shards * num_rounds).map(|_| vec![]).collect();
+    for (shard_id, results_from_shard) in sharded_output.into_iter().enumerate() {
+        for (round, result) in results_from_shard.into_iter().enumerate() {
+            ordered_outputs[round * num_shards + shard_id] = result;
+        }
+    }
+    ordered_outputs.push(global_output);
+    ordered_outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_types::{
+        transaction::{ExecutionStatus, TransactionAuxiliaryData, TransactionStatus},
+        write_set::WriteSet,
+    };
+    use proptest::{collection::vec, prelude::*};
+
+    // How the outputs were aggregated before it was done in parallel.
+    fn aggregate_outputs_serially(
+        sharded_output: Vec<Vec<Vec<TransactionOutput>>>,
+        global_output: Vec<TransactionOutput>,
+    ) -> Vec<TransactionOutput> {
+        let num_shards = sharded_output.len();
+        let num_rounds = sharded_output[0].len();
+        let mut aggregated_results = vec![];
+        let mut ordered_results = vec![vec![]; num_shards * num_rounds];
+        for (shard_id, results_from_shard) in sharded_output.into_iter().enumerate() {
+            for (round, result) in results_from_shard.into_iter().enumerate() {
+                ordered_results[round * num_shards + shard_id] = result;
+            }
+        }
+        for result in ordered_results.into_iter() {
+            aggregated_results.extend(result);
+        }
+        aggregated_results.extend(global_output);
+        aggregated_results
+    }
+
+    // Outputs told apart by their gas used, which counts them over all the sub-blocks.
+    fn outputs(num_outputs: usize, next_gas_used: &mut u64) -> Vec<TransactionOutput> {
+        (0..num_outputs)
+            .map(|_| {
+                *next_gas_used += 1;
+                TransactionOutput::new(
+                    WriteSet::default(),
+                    vec![],
+                    *next_gas_used,
+                    TransactionStatus::Keep(ExecutionStatus::Success),
+                    TransactionAuxiliaryData::default(),
+                )
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn test_parallel_aggregation_matches_serial(
+            num_outputs in vec(vec(0usize..200, 1..4), 1..6),
+            num_global_outputs in 0usize..10,
+        ) {
+            // `num_outputs` is by shard then round, and all the shards have the rounds of the
+            // first one.
+            let num_rounds = num_outputs[0].len();
+            let mut gas_used = 0;
+            let sharded_output: Vec<Vec<Vec<TransactionOutput>>> = num_outputs
+                .iter()
+                .map(|rounds| {
+                    (0..num_rounds)
+                        .map(|round| {
+                            outputs(rounds.get(round).copied().unwrap_or(0), &mut gas_used)
+                        })
+                        .collect()
+                })
+                .collect();
+            let global_output = outputs(num_global_outputs, &mut gas_used);
+
+            let serial = aggregate_outputs_serially(sharded_output.clone(), global_output.clone());
+            let parallel = aggregate_outputs(sharded_output, global_output);
+            prop_assert_eq!(bcs::to_bytes(&parallel).unwrap(), bcs::to_bytes(&serial).unwrap());
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::sharded_block_executor::executor_client::ShardedExecutionError;
+use aptos_types::{
+    block_executor::partitioner::{PartitionedTransactions, TxnIndex},
+    transaction::TransactionOutput,
+};
+
+/// Where the partitioner put the txns of a block, to check that the outputs of the shards end up
+/// at the index of their txn in the output of the block, whatever order the shards finished in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputOrder {
+    // The index of the first txn and the number of txns of each sub-block, by shard and round.
+    sub_blocks: Vec<Vec<(TxnIndex, usize)>>,
+    num_global_txns: usize,
+}
+
+impl OutputOrder {
+    pub fn new(transactions: &PartitionedTransactions) -> Self {
+        Self {
+            sub_blocks: transactions
+                .sharded_txns()
+                .iter()
+                .map(|sub_blocks| {
+                    sub_blocks
+                        .sub_block_iter()
+                        .map(|sub_block| (sub_block.start_index, sub_block.num_txns()))
+                        .collect()
+                })
+                .collect(),
+            num_global_txns: transactions.global_txns.len(),
+        }
+    }
+
+    /// Recompute the index of each output in the block from its round, shard and index in the
+    /// sub-block, the way the outputs are aggregated, and check it against the index the
+    /// partitioner gave its txn.
+    pub fn verify(
+        &self,
+        sharded_output: &[Vec<Vec<TransactionOutput>>],
+        global_output: &[TransactionOutput],
+    ) -> Result<(), ShardedExecutionError> {
+        let mismatch = |reason: String| Err(ShardedExecutionError::OutputOrderMismatch(reason));
+        if sharded_output.len() != self.sub_blocks.len() {
+            return mismatch(format!(
+                "{} shards sent outputs for a block partitioned into {} shards",
+                sharded_output.len(),
+                self.sub_blocks.len()
+            ));
+        }
+        for (shard_id, (outputs, sub_blocks)) in
+            sharded_output.iter().zip(&self.sub_blocks).enumerate()
+        {
+            if outputs.len() != sub_blocks.len() {
+                return mismatch(format!(
+                    "Shard {} sent the outputs of {} rounds instead of {}",
+                    shard_id,
+                    outputs.len(),
+                    sub_blocks.len()
+                ));
+            }
+        }
+        let num_rounds = self.sub_blocks.iter().map(Vec::len).max().unwrap_or(0);
+        let mut next_index = 0;
+        for round in 0..num_rounds {
+            for (shard_id, sub_blocks) in self.sub_blocks.iter().enumerate() {
+                let Some(&(start_index, num_txns)) = sub_blocks.get(round) else {
+                    return mismatch(format!(
+                        "Shard {} has no sub-block for round {}",
+                        shard_id, round
+                    ));
+                };
+                if start_index != next_index {
+                    return mismatch(format!(
+                        "The outputs of shard {} for round {} start at index {}, but their txns at index {}",
+                        shard_id, round, next_index, start_index
+                    ));
+                }
+                let num_outputs = sharded_output[shard_id][round].len();
+                if num_outputs != num_txns {
+                    return mismatch(format!(
+                        "Shard {} sent {} outputs for the {} txns of round {}",
+                        shard_id, num_outputs, num_txns, round
+                    ));
+                }
+                next_index += num_txns;
+            }
+        }
+        if global_output.len() != self.num_global_txns {
+            return mismatch(format!(
+                "{} global outputs for {} global txns",
+                global_output.len(),
+                self.num_global_txns
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_crypto::HashValue;
+    use aptos_types::{
+        block_executor::partitioner::{
+            CrossShardDependencies, SubBlock, SubBlocksForShard, TransactionWithDependencies,
+        },
+        transaction::{
+            analyzed_transaction::AnalyzedTransaction, ExecutionStatus, Transaction,
+            TransactionAuxiliaryData, TransactionStatus,
+        },
+        write_set::WriteSet,
+    };
+
+    fn sub_block(start_index: TxnIndex, num_txns: usize) -> SubBlock<AnalyzedTransaction> {
+        let txn: AnalyzedTransaction = Transaction::StateCheckpoint(HashValue::zero()).into();
+        SubBlock::new(start_index, vec![
+            TransactionWithDependencies::new(
+                txn,
+                CrossShardDependencies::default()
+            );
+            num_txns
+        ])
+    }
+
+    fn outputs(num_txns: usize) -> Vec<TransactionOutput> {
+        let output = TransactionOutput::new(
+            WriteSet::default(),
+            vec![],
+            0,
+            TransactionStatus::Keep(ExecutionStatus::Success),
+            TransactionAuxiliaryData::default(),
+        );
+        vec![output; num_txns]
+    }
+
+    #[test]
+    fn test_output_order_follows_partitioner_indices() {
+        // Round 0 is txns 0..2 on shard 0 and 2..5 on shard 1, round 1 is 5..6 and 6..6.
+        let block = |shard_1_round_0_start| {
+            PartitionedTransactions::new(
+                vec![
+                    SubBlocksForShard::new(0, vec![sub_block(0, 2), sub_block(5, 1)]),
+                    SubBlocksForShard::new(1, vec![
+                        sub_block(shard_1_round_0_start, 3),
+                        sub_block(6, 0),
+                    ]),
+                ],
+                vec![],
+            )
+        };
+        let sharded_output = vec![vec![outputs(2), outputs(1)], vec![outputs(3), outputs(0)]];
+        let output_order = OutputOrder::new(&block(2));
+        assert_eq!(output_order.verify(&sharded_output, &[]), Ok(()));
+
+        // A shard missing an output.
+        let mut missing_output = sharded_output.clone();
+        missing_output[1][0].pop();
+        assert!(matches!(
+            output_order.verify(&missing_output, &[]),
+            Err(ShardedExecutionError::OutputOrderMismatch(_))
+        ));
+        // A shard missing a round.
+        let mut missing_round = sharded_output.clone();
+        missing_round[0].pop();
+        assert!(matches!(
+            output_order.verify(&missing_round, &[]),
+            Err(ShardedExecutionError::OutputOrderMismatch(_))
+        ));
+        // Global outputs for a block without global txns.
+        assert!(matches!(
+            output_order.verify(&sharded_output, &outputs(1)),
+            Err(ShardedExecutionError::OutputOrderMismatch(_))
+        ));
+        // Sub-blocks the aggregation would not put at the index of their txns.
+        assert!(matches!(
+            OutputOrder::new(&block(3)).verify(&sharded_output, &[]),
+            Err(ShardedExecutionError::OutputOrderMismatch(_))
+        ));
+    }
+}
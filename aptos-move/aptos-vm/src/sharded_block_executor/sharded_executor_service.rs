@@ -8,19 +8,27 @@ use crate::{
         coordinator_client::CoordinatorClient,
         counters::{
             SHARDED_BLOCK_EXECUTION_BY_ROUNDS_SECONDS, SHARDED_BLOCK_EXECUTOR_TXN_COUNT,
-            SHARDED_EXECUTOR_SERVICE_SECONDS,
+            SHARDED_EXECUTOR_CONCURRENCY_LEVEL, SHARDED_EXECUTOR_CROSS_SHARD_WAIT_SECONDS,
+            SHARDED_EXECUTOR_DEPENDENCY_WAIT_SECONDS, SHARDED_EXECUTOR_SERVICE_SECONDS,
+        },
+        cross_shard_client::{
+            CrossShardClient, CrossShardCommitReceiver, CrossShardCommitSender,
+            ReplayCrossShardClient,
         },
-        cross_shard_client::{CrossShardClient, CrossShardCommitReceiver, CrossShardCommitSender},
         cross_shard_state_view::CrossShardStateView,
-        messages::CrossShardMsg,
+        execution_stats::{
+            DependencyWait, RoundExecutionStats, ShardExecutionStats, MAX_DEPENDENCY_WAITS_REPORTED,
+        },
+        executor_client::ShardedExecutionError,
+        messages::{CrossShardMsg, RemoteTxnWrite},
         ExecutorShardCommand,
     },
 };
-use aptos_logger::{info, trace};
+use aptos_logger::{error, info, trace};
 use aptos_types::{
     block_executor::{
-        config::{BlockExecutorConfig, BlockExecutorLocalConfig},
-        partitioner::{ShardId, SubBlock, SubBlocksForShard, TransactionWithDependencies},
+        config::{BlockExecutorConfig, BlockExecutorConfigFromOnchain, BlockExecutorLocalConfig},
+        partitioner::{RoundId, ShardId, SubBlock, SubBlocksForShard, TransactionWithDependencies},
     },
     state_store::StateView,
     transaction::{
@@ -32,11 +40,19 @@ use aptos_types::{
 use aptos_vm_logging::disable_speculative_logging;
 use futures::{channel::oneshot, executor::block_on};
 use move_core_types::vm_status::VMStatus;
-use std::sync::Arc;
+use std::{
+    any::Any,
+    iter,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub struct ShardedExecutorService<S: StateView + Sync + Send + 'static> {
     shard_id: ShardId,
     num_shards: usize,
+    // The number of threads executing the txns, which bounds the concurrency level of a block.
+    num_threads: usize,
     executor_thread_pool: Arc<rayon::ThreadPool>,
     coordinator_client: Arc<dyn CoordinatorClient<S>>,
     cross_shard_client: Arc<dyn CrossShardClient>,
@@ -62,6 +78,7 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         Self {
             shard_id,
             num_shards,
+            num_threads,
             executor_thread_pool,
             coordinator_client,
             cross_shard_client,
@@ -74,7 +91,11 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         round: usize,
         state_view: &S,
         config: BlockExecutorConfig,
-    ) -> Result<Vec<TransactionOutput>, VMStatus> {
+    ) -> (
+        Result<Vec<TransactionOutput>, VMStatus>,
+        Duration,
+        Vec<DependencyWait>,
+    ) {
         disable_speculative_logging();
         trace!(
             "executing sub block for shard {} and round {}",
@@ -83,7 +104,7 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         );
         let cross_shard_commit_sender =
             CrossShardCommitSender::new(self.shard_id, self.cross_shard_client.clone(), &sub_block);
-        Self::execute_transactions_with_dependencies(
+        Self::execute_transactions_with_dependencies_and_wait_time(
             Some(self.shard_id),
             self.executor_thread_pool.clone(),
             sub_block.into_transactions_with_deps(),
@@ -105,6 +126,34 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         state_view: &S,
         config: BlockExecutorConfig,
     ) -> Result<Vec<TransactionOutput>, VMStatus> {
+        Self::execute_transactions_with_dependencies_and_wait_time(
+            shard_id,
+            executor_thread_pool,
+            transactions,
+            cross_shard_client,
+            cross_shard_commit_sender,
+            round,
+            state_view,
+            config,
+        )
+        .0
+    }
+
+    // Also returns the time the txns spent waiting for cross-shard values, in total and by value.
+    fn execute_transactions_with_dependencies_and_wait_time(
+        shard_id: Option<ShardId>,
+        executor_thread_pool: Arc<rayon::ThreadPool>,
+        transactions: Vec<TransactionWithDependencies<AnalyzedTransaction>>,
+        cross_shard_client: Arc<dyn CrossShardClient>,
+        cross_shard_commit_sender: Option<CrossShardCommitSender>,
+        round: usize,
+        state_view: &S,
+        config: BlockExecutorConfig,
+    ) -> (
+        Result<Vec<TransactionOutput>, VMStatus>,
+        Duration,
+        Vec<DependencyWait>,
+    ) {
         let (callback, callback_receiver) = oneshot::channel();
 
         let cross_shard_state_view = Arc::new(CrossShardStateView::create_cross_shard_state_view(
@@ -135,14 +184,18 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
                 );
             });
             s.spawn(move |_| {
-                let ret = BlockAptosVM::execute_block(
-                    executor_thread_pool,
-                    &signature_verified_transactions,
-                    aggr_overridden_state_view.as_ref(),
-                    config,
-                    cross_shard_commit_sender,
-                )
-                .map(BlockOutput::into_transaction_outputs_forced);
+                // A panic is caught so that the cross-shard commit receiver is stopped below,
+                // otherwise the scope would never end. It is resumed once the scope ends.
+                let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+                    BlockAptosVM::execute_block(
+                        executor_thread_pool,
+                        &signature_verified_transactions,
+                        aggr_overridden_state_view.as_ref(),
+                        config,
+                        cross_shard_commit_sender,
+                    )
+                    .map(BlockOutput::into_transaction_outputs_forced)
+                }));
                 if let Some(shard_id) = shard_id {
                     trace!(
                         "executed sub block for shard {} and round {}",
@@ -168,7 +221,14 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
             });
         });
 
-        block_on(callback_receiver).unwrap()
+        match block_on(callback_receiver).unwrap() {
+            Ok(ret) => (
+                ret,
+                cross_shard_state_view.cross_shard_wait_time(),
+                cross_shard_state_view.dependency_waits(),
+            ),
+            Err(payload) => panic::resume_unwind(payload),
+        }
     }
 
     fn execute_block(
@@ -176,29 +236,217 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         transactions: SubBlocksForShard<AnalyzedTransaction>,
         state_view: &S,
         config: BlockExecutorConfig,
-    ) -> Result<Vec<Vec<TransactionOutput>>, VMStatus> {
-        let mut result = vec![];
-        for (round, sub_block) in transactions.into_sub_blocks().into_iter().enumerate() {
-            let _timer = SHARDED_BLOCK_EXECUTION_BY_ROUNDS_SECONDS
-                .with_label_values(&[&self.shard_id.to_string(), &round.to_string()])
-                .start_timer();
+        received_at: Instant,
+        command_span: &tracing::Span,
+    ) -> (Result<(), ShardedExecutionError>, ShardExecutionStats) {
+        let shard_label = self.shard_id.to_string();
+        let mut stats = ShardExecutionStats {
+            receive_to_start_time: received_at.elapsed(),
+            rounds: vec![],
+            dependency_waits: vec![],
+        };
+        SHARDED_EXECUTOR_SERVICE_SECONDS
+            .with_label_values(&[&shard_label, "receive_to_start"])
+            .observe(stats.receive_to_start_time.as_secs_f64());
+        let mut sub_blocks = transactions.into_sub_blocks().into_iter().enumerate();
+        while let Some((round, sub_block)) = sub_blocks.next() {
+            if self.coordinator_client.is_block_aborted() {
+                info!(
+                    "Shard {} stops before round {} of the aborted block",
+                    self.shard_id, round
+                );
+                self.release_dependents(
+                    iter::once(sub_block).chain(sub_blocks.map(|(_, sub_block)| sub_block)),
+                );
+                return (Err(ShardedExecutionError::Aborted), stats);
+            }
+            let round_label = round.to_string();
+            let num_txns = sub_block.transactions.len();
             SHARDED_BLOCK_EXECUTOR_TXN_COUNT
-                .with_label_values(&[&self.shard_id.to_string(), &round.to_string()])
-                .observe(sub_block.transactions.len() as f64);
+                .with_label_values(&[&shard_label, &round_label])
+                .observe(num_txns as f64);
             info!(
                 "executing sub block for shard {} and round {}, number of txns {}",
+                self.shard_id, round, num_txns
+            );
+            // The sub-block is consumed by its execution, so what the other shards wait on from
+            // it is kept aside, to release them if the sub-block fails.
+            let dependents = CrossShardCommitSender::new(
                 self.shard_id,
-                round,
-                sub_block.transactions.len()
+                self.cross_shard_client.clone(),
+                &sub_block,
             );
-            result.push(self.execute_sub_block(sub_block, round, state_view, config.clone())?);
+            let round_span = round_span(command_span, round, num_txns);
+            let round_span_guard = round_span.enter();
+            let started_at = Instant::now();
+            let (ret, cross_shard_wait_time, dependency_waits) =
+                match panic::catch_unwind(AssertUnwindSafe(|| {
+                    self.execute_sub_block(sub_block, round, state_view, config.clone())
+                })) {
+                    Ok(ret) => ret,
+                    Err(payload) => {
+                        dependents.release_dependents();
+                        self.release_dependents(sub_blocks.map(|(_, sub_block)| sub_block));
+                        // Reported to the coordinator by `execute_and_send_result`.
+                        panic::resume_unwind(payload)
+                    },
+                };
+            drop(round_span_guard);
+            let execution_time = started_at.elapsed();
+            SHARDED_BLOCK_EXECUTION_BY_ROUNDS_SECONDS
+                .with_label_values(&[&shard_label, &round_label])
+                .observe(execution_time.as_secs_f64());
+            SHARDED_EXECUTOR_CROSS_SHARD_WAIT_SECONDS
+                .with_label_values(&[&shard_label, &round_label])
+                .observe(cross_shard_wait_time.as_secs_f64());
+            for wait in &dependency_waits {
+                SHARDED_EXECUTOR_DEPENDENCY_WAIT_SECONDS
+                    .with_label_values(&[&shard_label])
+                    .observe(wait.wait_time.as_secs_f64());
+            }
+            stats.add_dependency_waits(dependency_waits);
+            stats.rounds.push(RoundExecutionStats {
+                num_txns,
+                concurrency_level: config.local.concurrency_level,
+                execution_time,
+                cross_shard_wait_time,
+            });
+            let output = match ret {
+                Ok(output) => output,
+                Err(e) => {
+                    dependents.release_dependents();
+                    self.release_dependents(sub_blocks.map(|(_, sub_block)| sub_block));
+                    return (Err(e.into()), stats);
+                },
+            };
             trace!(
                 "Finished executing sub block for shard {} and round {}",
                 self.shard_id,
                 round
             );
+            // The output is sent right away, so that the coordinator can start processing it
+            // while the next rounds are executing.
+            let _result_tx_timer = SHARDED_EXECUTOR_SERVICE_SECONDS
+                .with_label_values(&[&shard_label, "result_tx"])
+                .start_timer();
+            self.coordinator_client.send_round_output(round, output);
         }
-        Ok(result)
+        (Ok(()), stats)
+    }
+
+    // Let the other shards go on with the block without the sub-blocks, which the shard does not
+    // execute as the block is aborted or one of its rounds failed.
+    fn release_dependents(&self, sub_blocks: impl Iterator<Item = SubBlock<AnalyzedTransaction>>) {
+        for sub_block in sub_blocks {
+            CrossShardCommitSender::new(self.shard_id, self.cross_shard_client.clone(), &sub_block)
+                .release_dependents();
+        }
+    }
+
+    // Execute the sub-block of a round on its own, the writes of the other shards it depends on
+    // being replayed from `cross_shard_writes` instead of received from them.
+    fn execute_sub_block_with_cross_shard_writes(
+        &self,
+        sub_block: SubBlock<AnalyzedTransaction>,
+        round: RoundId,
+        cross_shard_writes: Vec<RemoteTxnWrite>,
+        state_view: &S,
+        config: BlockExecutorConfig,
+        received_at: Instant,
+        command_span: &tracing::Span,
+    ) -> (Result<(), ShardedExecutionError>, ShardExecutionStats) {
+        disable_speculative_logging();
+        let mut stats = ShardExecutionStats {
+            receive_to_start_time: received_at.elapsed(),
+            rounds: vec![],
+            dependency_waits: vec![],
+        };
+        let num_txns = sub_block.transactions.len();
+        let concurrency_level = config.local.concurrency_level;
+        let round_span = round_span(command_span, round, num_txns);
+        let round_span_guard = round_span.enter();
+        let started_at = Instant::now();
+        let (ret, cross_shard_wait_time, dependency_waits) =
+            Self::execute_transactions_with_dependencies_and_wait_time(
+                Some(self.shard_id),
+                self.executor_thread_pool.clone(),
+                sub_block.into_transactions_with_deps(),
+                Arc::new(ReplayCrossShardClient::new(cross_shard_writes)),
+                None,
+                round,
+                state_view,
+                config,
+            );
+        drop(round_span_guard);
+        stats.add_dependency_waits(dependency_waits);
+        stats.rounds.push(RoundExecutionStats {
+            num_txns,
+            concurrency_level,
+            execution_time: started_at.elapsed(),
+            cross_shard_wait_time,
+        });
+        match ret {
+            Ok(output) => {
+                self.coordinator_client.send_round_output(round, output);
+                (Ok(()), stats)
+            },
+            Err(e) => (Err(e.into()), stats),
+        }
+    }
+
+    fn block_executor_config(
+        &self,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+    ) -> BlockExecutorConfig {
+        // More workers than threads would only contend for the threads.
+        let concurrency_level = concurrency_level_per_shard.min(self.num_threads);
+        SHARDED_EXECUTOR_CONCURRENCY_LEVEL
+            .with_label_values(&[&self.shard_id.to_string()])
+            .set(concurrency_level as i64);
+        BlockExecutorConfig {
+            local: BlockExecutorLocalConfig {
+                concurrency_level,
+                allow_fallback: true,
+                discard_failed_blocks: false,
+            },
+            onchain: onchain_config,
+        }
+    }
+
+    // Run `execute` and send its result to the coordinator. The state view of the command is
+    // expected to be dropped by `execute`, before the coordinator is told the command is done.
+    fn execute_and_send_result(
+        &self,
+        execute: impl FnOnce() -> (Result<(), ShardedExecutionError>, ShardExecutionStats),
+    ) {
+        let exe_timer = SHARDED_EXECUTOR_SERVICE_SECONDS
+            .with_label_values(&[&self.shard_id.to_string(), "execute_block"])
+            .start_timer();
+        // The shard reports a panic to the coordinator instead of dying silently, which would
+        // leave the coordinator waiting for its results.
+        let (ret, mut stats) = match panic::catch_unwind(AssertUnwindSafe(execute)) {
+            Ok(result) => result,
+            Err(payload) => {
+                let reason = panic_message(payload.as_ref());
+                error!(
+                    "Shard {} panicked while executing the block: {}",
+                    self.shard_id, reason
+                );
+                let error = ShardedExecutionError::ShardFailure {
+                    shard_id: self.shard_id,
+                    reason,
+                };
+                (Err(error), ShardExecutionStats::default())
+            },
+        };
+        drop(exe_timer);
+        stats.keep_longest_dependency_waits(MAX_DEPENDENCY_WAITS_REPORTED);
+
+        let _result_tx_timer = SHARDED_EXECUTOR_SERVICE_SECONDS
+            .with_label_values(&[&self.shard_id.to_string(), "result_tx"])
+            .start_timer();
+        self.coordinator_client.send_execution_result(ret, stats);
     }
 
     pub fn start(&self) {
@@ -210,6 +458,8 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         let mut num_txns = 0;
         loop {
             let command = self.coordinator_client.receive_execute_command();
+            let received_at = Instant::now();
+            let command_span = self.coordinator_client.command_span();
             match command {
                 ExecutorShardCommand::ExecuteSubBlocks(
                     state_view,
@@ -223,28 +473,45 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
                         self.shard_id,
                         num_txns
                     );
-                    let exe_timer = SHARDED_EXECUTOR_SERVICE_SECONDS
-                        .with_label_values(&[&self.shard_id.to_string(), "execute_block"])
-                        .start_timer();
-                    let ret = self.execute_block(
-                        transactions,
-                        state_view.as_ref(),
-                        BlockExecutorConfig {
-                            local: BlockExecutorLocalConfig {
-                                concurrency_level: concurrency_level_per_shard,
-                                allow_fallback: true,
-                                discard_failed_blocks: false,
-                            },
-                            onchain: onchain_config,
-                        },
+                    let config =
+                        self.block_executor_config(concurrency_level_per_shard, onchain_config);
+                    self.execute_and_send_result(move || {
+                        self.execute_block(
+                            transactions,
+                            state_view.as_ref(),
+                            config,
+                            received_at,
+                            &command_span,
+                        )
+                    });
+                },
+                ExecutorShardCommand::ExecuteSubBlock(
+                    state_view,
+                    round,
+                    sub_block,
+                    cross_shard_writes,
+                    concurrency_level_per_shard,
+                    onchain_config,
+                ) => {
+                    trace!(
+                        "Shard {} received ExecuteSubBlock command for round {} of {} txns",
+                        self.shard_id,
+                        round,
+                        sub_block.num_txns()
                     );
-                    drop(state_view);
-                    drop(exe_timer);
-
-                    let _result_tx_timer = SHARDED_EXECUTOR_SERVICE_SECONDS
-                        .with_label_values(&[&self.shard_id.to_string(), "result_tx"])
-                        .start_timer();
-                    self.coordinator_client.send_execution_result(ret);
+                    let config =
+                        self.block_executor_config(concurrency_level_per_shard, onchain_config);
+                    self.execute_and_send_result(move || {
+                        self.execute_sub_block_with_cross_shard_writes(
+                            sub_block,
+                            round,
+                            cross_shard_writes,
+                            state_view.as_ref(),
+                            config,
+                            received_at,
+                            &command_span,
+                        )
+                    });
                 },
                 ExecutorShardCommand::Stop => {
                     break;
@@ -264,3 +531,21 @@ impl<S: StateView + Sync + Send + 'static> ShardedExecutorService<S> {
         );
     }
 }
+
+// The span of the execution of a round, under the span of the command if the command is traced.
+fn round_span(command_span: &tracing::Span, round: usize, num_txns: usize) -> tracing::Span {
+    if command_span.is_disabled() {
+        return tracing::Span::none();
+    }
+    tracing::info_span!(parent: command_span, "execute_round", round, num_txns)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
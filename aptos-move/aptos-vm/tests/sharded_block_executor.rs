@@ -8,11 +8,12 @@
 /// the same process while testing, resulting in the counters failing to register with "AlreadyReg"
 /// error.
 use aptos_block_partitioner::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
     pre_partition::{
         connected_component::config::ConnectedComponentPartitionerConfig,
         uniform_partitioner::config::UniformPartitionerConfig,
     },
-    v2::config::PartitionerV2Config,
+    v2::{config::PartitionerV2Config, fallback::UnshardedFallbackConfig},
     PartitionerConfig,
 };
 use aptos_vm::sharded_block_executor::{
@@ -185,6 +186,51 @@ fn test_partitioner_v2_connected_component_sharded_block_executor_with_random_tr
     }
 }
 
+#[test]
+fn test_partitioner_v2_unsharded_fallback_sharded_block_executor_with_conflict() {
+    for merge_discard in [false, true] {
+        let num_shards = 7;
+        let client = LocalExecutorService::setup_local_executor_shards(num_shards, Some(4));
+        let sharded_block_executor = ShardedBlockExecutor::new(client);
+        // Always fall back.
+        let partitioner = PartitionerV2Config::default()
+            .partition_last_round(merge_discard)
+            .unsharded_fallback(Some(UnshardedFallbackConfig {
+                top_k_hot_keys: 1,
+                conflict_ratio_threshold: 0.0,
+            }))
+            .build();
+        test_utils::sharded_block_executor_with_conflict(partitioner, sharded_block_executor, 4);
+    }
+}
+
+#[test]
+fn test_connected_component_partitioner_sharded_block_executor_no_conflict() {
+    let num_shards = 8;
+    let client = LocalExecutorService::setup_local_executor_shards(num_shards, Some(2));
+    let sharded_block_executor = ShardedBlockExecutor::new(client);
+    let partitioner = ConnectedComponentBlockPartitionerConfig::default().build();
+    test_utils::test_sharded_block_executor_no_conflict(partitioner, sharded_block_executor);
+}
+
+#[test]
+fn test_connected_component_partitioner_sharded_block_executor_with_conflict() {
+    let num_shards = 7;
+    let client = LocalExecutorService::setup_local_executor_shards(num_shards, Some(4));
+    let sharded_block_executor = ShardedBlockExecutor::new(client);
+    let partitioner = ConnectedComponentBlockPartitionerConfig::default().build();
+    test_utils::sharded_block_executor_with_conflict(partitioner, sharded_block_executor, 4);
+}
+
+#[test]
+fn test_connected_component_partitioner_sharded_block_executor_with_random_transfers() {
+    let num_shards = 3;
+    let client = LocalExecutorService::setup_local_executor_shards(num_shards, Some(4));
+    let sharded_block_executor = ShardedBlockExecutor::new(client);
+    let partitioner = ConnectedComponentBlockPartitionerConfig::default().build();
+    test_utils::sharded_block_executor_with_random_transfers(partitioner, sharded_block_executor, 4)
+}
+
 mod test_utils {
     use aptos_block_partitioner::BlockPartitioner;
     use aptos_language_e2e_tests::{
@@ -8,6 +8,7 @@
 pub enum CompressionClient {
     Consensus,
     DKG,
+    ExecutorService,
     JWKConsensus,
     Mempool,
     StateSync,
@@ -19,6 +20,7 @@ impl CompressionClient {
         match self {
             Self::Consensus => "consensus",
             Self::DKG => "dkg",
+            Self::ExecutorService => "executor_service",
             Self::JWKConsensus => "jwk_consensus",
             Self::Mempool => "mempool",
             Self::StateSync => "state_sync",
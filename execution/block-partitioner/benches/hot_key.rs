@@ -0,0 +1,52 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+#[macro_use]
+extern crate criterion;
+
+use aptos_block_partitioner::{
+    test_utils::{create_signed_p2p_transaction, generate_test_account},
+    v2::config::PartitionerV2Config,
+    PartitionerConfig,
+};
+use aptos_types::transaction::analyzed_transaction::AnalyzedTransaction;
+use criterion::{BenchmarkId, Criterion};
+
+/// A block where every txn pays the same receiver, so that one key is written by all of them.
+/// Dependency resolution queries the tracker of that key once per txn.
+fn hot_key_block(block_size: usize) -> Vec<AnalyzedTransaction> {
+    let receiver = generate_test_account();
+    (0..block_size)
+        .map(|_| {
+            let mut sender = generate_test_account();
+            create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0)
+        })
+        .collect()
+}
+
+fn bench_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_key");
+
+    let block_size = 5_000;
+    let block = hot_key_block(block_size);
+    for num_shards in [4, 16] {
+        let partitioner = PartitionerV2Config::default().build();
+        group.bench_with_input(
+            BenchmarkId::new(format!("writers={block_size}"), format!("shd={num_shards}")),
+            &block,
+            |b, block| {
+                b.iter_with_setup(
+                    || block.clone(),
+                    |txns| partitioner.partition(txns, num_shards),
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    name = hot_key_benches;
+    config = Criterion::default();
+    targets = bench_group);
+criterion_main!(hot_key_benches);
@@ -0,0 +1,89 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+#[macro_use]
+extern crate criterion;
+
+use aptos_block_partitioner::{
+    pre_partition::connected_component::ConnectedComponentPartitioner,
+    test_utils::P2PBlockGenerator, v2::PartitionerV2, BlockPartitioner,
+};
+use criterion::Criterion;
+use rand::thread_rng;
+use std::time::{Duration, Instant};
+
+/// The latency from the arrival of the last batch of a block to its partitioned output.
+/// The earlier batches are assumed to arrive far enough apart to be added before the next one shows up,
+/// so only the last `add_transactions()` and `finalize()` are timed.
+fn bench_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("incremental");
+
+    let num_accounts = 10000;
+    let block_size = 5000;
+    let num_batches = 10;
+    let num_shards = 5;
+
+    let mut rng = thread_rng();
+    let block_gen = P2PBlockGenerator::new(num_accounts);
+    let partitioner = PartitionerV2::new(
+        8,
+        4,
+        0.9,
+        64,
+        true,
+        Box::new(ConnectedComponentPartitioner {
+            load_imbalance_tolerance: 2.0,
+            sender_affinity: true,
+        }),
+    );
+
+    group.bench_function(
+        format!("all_at_once/acc={num_accounts},blk={block_size},shd={num_shards}"),
+        |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let txns = block_gen.rand_block(&mut rng, block_size);
+                    let started_at = Instant::now();
+                    let _partitioned = partitioner.partition(txns, num_shards);
+                    total += started_at.elapsed();
+                }
+                total
+            })
+        },
+    );
+    group.bench_function(
+        format!(
+            "incremental/acc={num_accounts},blk={block_size},shd={num_shards},bat={num_batches}"
+        ),
+        |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut batches: Vec<_> = block_gen
+                        .rand_block(&mut rng, block_size)
+                        .chunks(block_size / num_batches)
+                        .map(|batch| batch.to_vec())
+                        .collect();
+                    let last_batch = batches.pop().unwrap();
+                    let mut incremental = partitioner.begin_block(num_shards);
+                    for batch in batches {
+                        incremental.add_transactions(batch);
+                    }
+                    let started_at = Instant::now();
+                    incremental.add_transactions(last_batch);
+                    let _partitioned = incremental.finalize();
+                    total += started_at.elapsed();
+                }
+                total
+            })
+        },
+    );
+    group.finish();
+}
+
+criterion_group!(
+    name = incremental_benches;
+    config = Criterion::default();
+    targets = bench_group);
+criterion_main!(incremental_benches);
@@ -0,0 +1,59 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+#[macro_use]
+extern crate criterion;
+
+use aptos_block_partitioner::v2::interner::ConcurrentInterner;
+use aptos_types::state_store::state_key::StateKey;
+use criterion::{BenchmarkId, Criterion};
+use rayon::{
+    iter::{IntoParallelIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+use std::sync::Arc;
+
+fn bench_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interner");
+
+    let accesses_per_key = 4;
+    let dashmap_num_shards = 64;
+
+    for num_keys in [1_000, 10_000, 100_000] {
+        // Every key is accessed a few times, spread over the block like the hints of a real block.
+        let accesses: Vec<StateKey> = (0..num_keys * accesses_per_key)
+            .map(|i| StateKey::raw(&((i * 7919) % num_keys).to_le_bytes()))
+            .collect();
+        for num_threads in [1, 2, 4, 8, 16, 32] {
+            let thread_pool = Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(num_threads)
+                    .build()
+                    .unwrap(),
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("keys={num_keys}"), format!("thr={num_threads}")),
+                &accesses,
+                |b, accesses| {
+                    b.iter(|| {
+                        let mut interner = ConcurrentInterner::new(dashmap_num_shards);
+                        thread_pool.install(|| {
+                            (0..accesses.len()).into_par_iter().for_each(|i| {
+                                interner.observe(&accesses[i], (i, 0));
+                            });
+                        });
+                        interner.finalize(&thread_pool);
+                        interner
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    name = interner_benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_group);
+criterion_main!(interner_benches);
@@ -34,6 +34,7 @@ fn bench_group(c: &mut Criterion) {
         merge_discards,
         Box::new(ConnectedComponentPartitioner {
             load_imbalance_tolerance: 2.0,
+            sender_affinity: true,
         }),
     );
     group.bench_function(format!("acc={num_accounts},blk={block_size},shd={num_shards}/thr={num_threads},rnd={num_rounds_limit},avd={avoid_pct},mds={merge_discards}"), move |b| {
@@ -0,0 +1,64 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+#[macro_use]
+extern crate criterion;
+
+use aptos_block_partitioner::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    v2::config::PartitionerV2Config,
+    workloads::{Workload, WorkloadGenerator},
+    BlockPartitioner, PartitionerConfig,
+};
+use criterion::Criterion;
+
+fn bench_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("workloads");
+
+    let num_accounts = 10000;
+    let block_size = 1000;
+    let num_shards = 8;
+    let seed = 0;
+
+    let workloads = [
+        Workload::UniformP2P,
+        Workload::HotSpot { zipf_exponent: 1.0 },
+        Workload::NftMintBurst { mint_ratio: 0.5 },
+        Workload::ModulePublish {
+            publish_ratio: 0.1,
+            modules_per_package: 5,
+        },
+    ];
+    let partitioners: Vec<(&str, Box<dyn BlockPartitioner>)> = vec![
+        ("noop", Box::new(NoOpPartitioner {})),
+        ("v2", PartitionerV2Config::default().build()),
+        (
+            "connected-component",
+            ConnectedComponentBlockPartitionerConfig::default().build(),
+        ),
+    ];
+    for workload in workloads {
+        let block = WorkloadGenerator::new(workload, num_accounts, seed).rand_block(block_size);
+        for (name, partitioner) in partitioners.iter() {
+            group.bench_function(
+                format!("{workload}/blk={block_size},shd={num_shards}/{name}"),
+                |b| {
+                    b.iter_with_setup(
+                        || block.clone(),
+                        |txns| {
+                            let _txns = partitioner.partition(txns, num_shards);
+                        },
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    name = workload_benches;
+    config = Criterion::default();
+    targets = bench_group);
+criterion_main!(workload_benches);
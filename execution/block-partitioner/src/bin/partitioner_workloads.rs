@@ -0,0 +1,111 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compare the `NoOpPartitioner` baseline, `PartitionerV2` and `ConnectedComponentBlockPartitioner` on the synthetic workloads,
+//! printing partition time, shard balance, cross-shard edge counts and `score_partition()` with unit txn costs.
+
+use aptos_block_partitioner::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    score::{score_partition, unit_cost},
+    v2::config::PartitionerV2Config,
+    workloads::{Workload, WorkloadGenerator},
+    BlockPartitioner, PartitionerConfig,
+};
+use aptos_types::block_executor::partitioner::PartitionedTransactions;
+use clap::Parser;
+use std::time::Instant;
+
+#[cfg(unix)]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(long, default_value_t = 10000)]
+    pub num_accounts: usize,
+
+    #[clap(long, default_value_t = 10000)]
+    pub block_size: usize,
+
+    #[clap(long, default_value_t = 3)]
+    pub num_blocks: usize,
+
+    #[clap(long, value_delimiter = ',', default_value = "4,8,16")]
+    pub num_shards: Vec<usize>,
+
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+/// Return `(max shard load / average shard load, number of required edges)`.
+fn partition_stats(partitioned: &PartitionedTransactions) -> (f64, usize) {
+    let shard_loads: Vec<usize> = partitioned
+        .sharded_txns()
+        .iter()
+        .map(|sub_blocks| sub_blocks.num_txns())
+        .collect();
+    let max_load = shard_loads.iter().copied().max().unwrap_or(0);
+    let avg_load = shard_loads.iter().sum::<usize>() as f64 / shard_loads.len().max(1) as f64;
+    let num_edges = partitioned
+        .sharded_txns()
+        .iter()
+        .flat_map(|sub_blocks| sub_blocks.iter())
+        .chain(partitioned.global_txns.iter())
+        .map(|txn| txn.cross_shard_dependencies().num_required_edges())
+        .sum();
+    let imbalance = if avg_load == 0.0 {
+        1.0
+    } else {
+        max_load as f64 / avg_load
+    };
+    (imbalance, num_edges)
+}
+
+fn main() {
+    let args = Args::parse();
+    let workloads = [
+        Workload::UniformP2P,
+        Workload::HotSpot { zipf_exponent: 1.0 },
+        Workload::NftMintBurst { mint_ratio: 0.5 },
+        Workload::ModulePublish {
+            publish_ratio: 0.1,
+            modules_per_package: 5,
+        },
+    ];
+    let partitioners: Vec<(&str, Box<dyn BlockPartitioner>)> = vec![
+        ("noop", Box::new(NoOpPartitioner {})),
+        ("v2", PartitionerV2Config::default().build()),
+        (
+            "connected-component",
+            ConnectedComponentBlockPartitionerConfig::default().build(),
+        ),
+    ];
+    for workload in workloads {
+        let mut generator = WorkloadGenerator::new(workload, args.num_accounts, args.seed);
+        let blocks: Vec<_> = (0..args.num_blocks)
+            .map(|_| generator.rand_block(args.block_size))
+            .collect();
+        for &num_shards in args.num_shards.iter() {
+            for (name, partitioner) in partitioners.iter() {
+                for (block_id, block) in blocks.iter().enumerate() {
+                    let timer = Instant::now();
+                    let partitioned = partitioner.partition(block.clone(), num_shards);
+                    let elapsed = timer.elapsed();
+                    let (imbalance, num_edges) = partition_stats(&partitioned);
+                    let score = score_partition(partitioned.sharded_txns(), unit_cost);
+                    println!(
+                        "workload={}, shards={}, partitioner={}, block={}, time={:?}, imbalance={:.2}, edges={}, {}",
+                        workload, num_shards, name, block_id, elapsed, imbalance, num_edges, score
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn verify_tool() {
+    use clap::CommandFactory;
+    Args::command().debug_assert()
+}
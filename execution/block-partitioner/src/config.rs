@@ -0,0 +1,465 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! A serializable choice of partitioner and of its main parameters,
+//! so that the partitioner can be selected from a config file instead of in code.
+
+use crate::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    pre_partition::{
+        connected_component::config::ConnectedComponentPartitionerConfig,
+        uniform_partitioner::config::UniformPartitionerConfig, PrePartitionerConfig,
+    },
+    v2::{
+        anchor::AnchorStrategyConfig, budget::PartitioningBudget, config::PartitionerV2Config,
+        fallback::UnshardedFallbackConfig,
+    },
+    BlockPartitioner, PartitionerConfig,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Which `BlockPartitioner` to use. The default is `PartitionerV2` with `PartitionerV2Config::default()`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockPartitionerConfig {
+    NoOp,
+    ConnectedComponent {
+        #[serde(default = "default_load_imbalance_tolerance")]
+        load_imbalance_tolerance: f32,
+    },
+    V2(PartitionerV2Params),
+}
+
+impl Default for BlockPartitionerConfig {
+    fn default() -> Self {
+        BlockPartitionerConfig::V2(PartitionerV2Params::default())
+    }
+}
+
+/// The parameters of `PartitionerV2Config` that can be set from a config file.
+/// See `PartitionerV2Config` for their meaning. Missing fields take their default value.
+///
+/// Only the deterministic budget is available here:
+/// a wall-clock budget makes the output depend on the machine.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartitionerV2Params {
+    pub num_threads: usize,
+    pub max_partitioning_rounds: usize,
+    pub cross_shard_dep_avoid_threshold: f32,
+    pub dashmap_num_shards: usize,
+    pub partition_last_round: bool,
+    pub balance_last_round: bool,
+    pub pre_partitioner: PrePartitionerParams,
+    pub state_pooling: bool,
+    pub anchor_strategy: AnchorStrategyParams,
+    pub verify_output: bool,
+    pub max_txns_per_shard_per_round: Option<usize>,
+    /// Execute the block unsharded if its estimated conflict ratio is at least this much.
+    pub unsharded_fallback_threshold: Option<f64>,
+    /// See `PartitioningBudget::TxnsProcessed`.
+    pub budget_txns_processed: Option<usize>,
+    pub affinity_load_imbalance_tolerance: f32,
+}
+
+impl Default for PartitionerV2Params {
+    fn default() -> Self {
+        Self {
+            num_threads: 8,
+            max_partitioning_rounds: 4,
+            cross_shard_dep_avoid_threshold: 0.9,
+            dashmap_num_shards: 64,
+            partition_last_round: false,
+            balance_last_round: false,
+            pre_partitioner: PrePartitionerParams::default(),
+            state_pooling: true,
+            anchor_strategy: AnchorStrategyParams::Hash,
+            verify_output: false,
+            max_txns_per_shard_per_round: None,
+            unsharded_fallback_threshold: None,
+            budget_txns_processed: None,
+            affinity_load_imbalance_tolerance: 1.2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PrePartitionerParams {
+    Uniform,
+    ConnectedComponent {
+        #[serde(default = "default_load_imbalance_tolerance")]
+        load_imbalance_tolerance: f32,
+        #[serde(default = "default_sender_affinity")]
+        sender_affinity: bool,
+    },
+}
+
+impl Default for PrePartitionerParams {
+    fn default() -> Self {
+        PrePartitionerParams::ConnectedComponent {
+            load_imbalance_tolerance: default_load_imbalance_tolerance(),
+            sender_affinity: default_sender_affinity(),
+        }
+    }
+}
+
+/// The anchor strategies that need no per-key input.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnchorStrategyParams {
+    Hash,
+    LoadAware,
+}
+
+fn default_load_imbalance_tolerance() -> f32 {
+    2.0
+}
+
+fn default_sender_affinity() -> bool {
+    false
+}
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum InvalidPartitionerConfig {
+    #[error("{field} must be at least 1")]
+    Zero { field: &'static str },
+    #[error("{field} must be in [{min}, {max}], got {value}")]
+    OutOfRange {
+        field: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+    #[error(
+        "max_txns_per_shard_per_round needs max_partitioning_rounds >= 2, as the last round takes all the remaining txns"
+    )]
+    CapWithoutDiscardingRound,
+    #[error("balance_last_round needs partition_last_round, otherwise the last round is all global txns")]
+    BalanceWithoutPartitionedLastRound,
+}
+
+impl BlockPartitionerConfig {
+    pub fn validate(&self) -> Result<(), InvalidPartitionerConfig> {
+        match self {
+            BlockPartitionerConfig::NoOp => Ok(()),
+            BlockPartitionerConfig::ConnectedComponent {
+                load_imbalance_tolerance,
+            } => check_load_imbalance_tolerance(
+                "load_imbalance_tolerance",
+                *load_imbalance_tolerance,
+            ),
+            BlockPartitionerConfig::V2(params) => params.validate(),
+        }
+    }
+}
+
+impl PartitionerV2Params {
+    pub fn validate(&self) -> Result<(), InvalidPartitionerConfig> {
+        check_non_zero("num_threads", self.num_threads)?;
+        check_non_zero("max_partitioning_rounds", self.max_partitioning_rounds)?;
+        check_non_zero("dashmap_num_shards", self.dashmap_num_shards)?;
+        check_range(
+            "cross_shard_dep_avoid_threshold",
+            self.cross_shard_dep_avoid_threshold as f64,
+            0.0,
+            1.0,
+        )?;
+        if let PrePartitionerParams::ConnectedComponent {
+            load_imbalance_tolerance,
+            ..
+        } = self.pre_partitioner
+        {
+            check_load_imbalance_tolerance(
+                "pre_partitioner.load_imbalance_tolerance",
+                load_imbalance_tolerance,
+            )?;
+        }
+        if let Some(cap) = self.max_txns_per_shard_per_round {
+            check_non_zero("max_txns_per_shard_per_round", cap)?;
+            if self.max_partitioning_rounds < 2 {
+                return Err(InvalidPartitionerConfig::CapWithoutDiscardingRound);
+            }
+        }
+        if self.balance_last_round && !self.partition_last_round {
+            return Err(InvalidPartitionerConfig::BalanceWithoutPartitionedLastRound);
+        }
+        if let Some(threshold) = self.unsharded_fallback_threshold {
+            check_range("unsharded_fallback_threshold", threshold, 0.0, 1.0)?;
+        }
+        if let Some(limit) = self.budget_txns_processed {
+            check_non_zero("budget_txns_processed", limit)?;
+        }
+        check_load_imbalance_tolerance(
+            "affinity_load_imbalance_tolerance",
+            self.affinity_load_imbalance_tolerance,
+        )
+    }
+
+    pub fn to_v2_config(&self) -> PartitionerV2Config {
+        let pre_partitioner_config: Box<dyn PrePartitionerConfig> = match self.pre_partitioner {
+            PrePartitionerParams::Uniform => Box::new(UniformPartitionerConfig {}),
+            PrePartitionerParams::ConnectedComponent {
+                load_imbalance_tolerance,
+                sender_affinity,
+            } => Box::new(ConnectedComponentPartitionerConfig {
+                load_imbalance_tolerance,
+                sender_affinity,
+            }),
+        };
+        let anchor_strategy = match self.anchor_strategy {
+            AnchorStrategyParams::Hash => AnchorStrategyConfig::Hash,
+            AnchorStrategyParams::LoadAware => AnchorStrategyConfig::LoadAware,
+        };
+        PartitionerV2Config::default()
+            .num_threads(self.num_threads)
+            .max_partitioning_rounds(self.max_partitioning_rounds)
+            .cross_shard_dep_avoid_threshold(self.cross_shard_dep_avoid_threshold)
+            .dashmap_num_shards(self.dashmap_num_shards)
+            .partition_last_round(self.partition_last_round)
+            .balance_last_round(self.balance_last_round)
+            .pre_partitioner_config(pre_partitioner_config)
+            .state_pooling(self.state_pooling)
+            .anchor_strategy(anchor_strategy)
+            .verify_output(self.verify_output)
+            .max_txns_per_shard_per_round(self.max_txns_per_shard_per_round)
+            .unsharded_fallback(
+                self.unsharded_fallback_threshold
+                    .map(|conflict_ratio_threshold| UnshardedFallbackConfig {
+                        conflict_ratio_threshold,
+                        ..Default::default()
+                    }),
+            )
+            .budget(
+                self.budget_txns_processed
+                    .map(PartitioningBudget::TxnsProcessed),
+            )
+            .affinity_load_imbalance_tolerance(self.affinity_load_imbalance_tolerance)
+    }
+}
+
+fn check_non_zero(field: &'static str, value: usize) -> Result<(), InvalidPartitionerConfig> {
+    if value == 0 {
+        return Err(InvalidPartitionerConfig::Zero { field });
+    }
+    Ok(())
+}
+
+fn check_range(
+    field: &'static str,
+    value: f64,
+    min: f64,
+    max: f64,
+) -> Result<(), InvalidPartitionerConfig> {
+    if !(min..=max).contains(&value) {
+        return Err(InvalidPartitionerConfig::OutOfRange {
+            field,
+            value,
+            min,
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// A tolerance below 1 cannot be met by any partitioning.
+fn check_load_imbalance_tolerance(
+    field: &'static str,
+    value: f32,
+) -> Result<(), InvalidPartitionerConfig> {
+    check_range(field, value as f64, 1.0, f64::MAX)
+}
+
+/// Build the partitioner described by a config.
+///
+/// Panics if the config is invalid: call `BlockPartitionerConfig::validate()` where the config is loaded.
+pub fn create_partitioner(config: &BlockPartitionerConfig) -> Box<dyn BlockPartitioner> {
+    if let Err(e) = config.validate() {
+        panic!("Invalid partitioner config {:?}: {}", config, e);
+    }
+    match config {
+        BlockPartitionerConfig::NoOp => Box::new(NoOpPartitioner {}),
+        BlockPartitionerConfig::ConnectedComponent {
+            load_imbalance_tolerance,
+        } => ConnectedComponentBlockPartitionerConfig {
+            load_imbalance_tolerance: *load_imbalance_tolerance,
+        }
+        .build(),
+        BlockPartitionerConfig::V2(params) => params.to_v2_config().build(),
+    }
+}
+
+impl PartitionerConfig for BlockPartitionerConfig {
+    fn build(&self) -> Box<dyn BlockPartitioner> {
+        create_partitioner(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{
+            create_partitioner, AnchorStrategyParams, BlockPartitionerConfig,
+            InvalidPartitionerConfig, PartitionerV2Params, PrePartitionerParams,
+        },
+        test_utils::{create_non_conflicting_p2p_transaction, verify_partitioner_output},
+        v2::config::PartitionerV2Config,
+    };
+
+    #[test]
+    fn test_default_matches_v2_default() {
+        let BlockPartitionerConfig::V2(params) = BlockPartitionerConfig::default() else {
+            panic!("the default partitioner should be v2");
+        };
+        assert_eq!(
+            format!("{:?}", PartitionerV2Config::default()),
+            format!("{:?}", params.to_v2_config())
+        );
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: BlockPartitionerConfig = serde_yaml::from_str("type: no_op").unwrap();
+        assert_eq!(BlockPartitionerConfig::NoOp, config);
+
+        let config: BlockPartitionerConfig =
+            serde_yaml::from_str("type: connected_component").unwrap();
+        assert_eq!(
+            BlockPartitionerConfig::ConnectedComponent {
+                load_imbalance_tolerance: 2.0
+            },
+            config
+        );
+
+        // Unset fields take their default value.
+        let config: BlockPartitionerConfig = serde_yaml::from_str(
+            r#"
+            type: v2
+            max_partitioning_rounds: 2
+            max_txns_per_shard_per_round: 100
+            pre_partitioner:
+                type: uniform
+            anchor_strategy: load_aware
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            BlockPartitionerConfig::V2(PartitionerV2Params {
+                max_partitioning_rounds: 2,
+                max_txns_per_shard_per_round: Some(100),
+                pre_partitioner: PrePartitionerParams::Uniform,
+                anchor_strategy: AnchorStrategyParams::LoadAware,
+                ..Default::default()
+            }),
+            config
+        );
+        assert_eq!(Ok(()), config.validate());
+
+        let config: BlockPartitionerConfig = serde_yaml::from_str("type: v2").unwrap();
+        assert_eq!(BlockPartitionerConfig::default(), config);
+
+        // Round trip.
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(config, serde_yaml::from_str(&yaml).unwrap());
+
+        assert!(serde_yaml::from_str::<BlockPartitionerConfig>("type: v3").is_err());
+        assert!(serde_yaml::from_str::<BlockPartitionerConfig>("type: v2\nnum_rounds: 2").is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let invalid = |params: PartitionerV2Params| BlockPartitionerConfig::V2(params).validate();
+        assert_eq!(
+            Err(InvalidPartitionerConfig::Zero {
+                field: "max_partitioning_rounds"
+            }),
+            invalid(PartitionerV2Params {
+                max_partitioning_rounds: 0,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            Err(InvalidPartitionerConfig::Zero {
+                field: "max_txns_per_shard_per_round"
+            }),
+            invalid(PartitionerV2Params {
+                max_txns_per_shard_per_round: Some(0),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            Err(InvalidPartitionerConfig::CapWithoutDiscardingRound),
+            invalid(PartitionerV2Params {
+                max_partitioning_rounds: 1,
+                max_txns_per_shard_per_round: Some(10),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            Err(InvalidPartitionerConfig::BalanceWithoutPartitionedLastRound),
+            invalid(PartitionerV2Params {
+                balance_last_round: true,
+                ..Default::default()
+            })
+        );
+        assert!(invalid(PartitionerV2Params {
+            cross_shard_dep_avoid_threshold: 1.5,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(invalid(PartitionerV2Params {
+            pre_partitioner: PrePartitionerParams::ConnectedComponent {
+                load_imbalance_tolerance: 0.5,
+                sender_affinity: true,
+            },
+            ..Default::default()
+        })
+        .is_err());
+        assert!(BlockPartitionerConfig::ConnectedComponent {
+            load_imbalance_tolerance: 0.0
+        }
+        .validate()
+        .is_err());
+        assert_eq!(Ok(()), BlockPartitionerConfig::default().validate());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_create_partitioner_rejects_invalid_config() {
+        create_partitioner(&BlockPartitionerConfig::V2(PartitionerV2Params {
+            num_threads: 0,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_every_variant_partitions() {
+        let configs = vec![
+            BlockPartitionerConfig::NoOp,
+            BlockPartitionerConfig::ConnectedComponent {
+                load_imbalance_tolerance: 2.0,
+            },
+            BlockPartitionerConfig::default(),
+            BlockPartitionerConfig::V2(PartitionerV2Params {
+                max_partitioning_rounds: 2,
+                partition_last_round: true,
+                balance_last_round: true,
+                pre_partitioner: PrePartitionerParams::Uniform,
+                anchor_strategy: AnchorStrategyParams::LoadAware,
+                max_txns_per_shard_per_round: Some(5),
+                unsharded_fallback_threshold: Some(0.9),
+                budget_txns_processed: Some(1000),
+                ..Default::default()
+            }),
+        ];
+        for config in configs {
+            let block: Vec<_> = (0..20)
+                .map(|_| create_non_conflicting_p2p_transaction())
+                .collect();
+            let partitioned = create_partitioner(&config).partition(block.clone(), 4);
+            verify_partitioner_output(&block, &partitioned);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    connected_component::ConnectedComponentBlockPartitioner, BlockPartitioner, PartitionerConfig,
+};
+
+#[derive(Clone, Debug)]
+pub struct ConnectedComponentBlockPartitionerConfig {
+    /// A connected component larger than `load_imbalance_tolerance * block_size / num_shards`
+    /// is moved to the last shard in a separate round.
+    pub load_imbalance_tolerance: f32,
+}
+
+impl ConnectedComponentBlockPartitionerConfig {
+    pub fn load_imbalance_tolerance(mut self, val: f32) -> Self {
+        self.load_imbalance_tolerance = val;
+        self
+    }
+}
+
+impl Default for ConnectedComponentBlockPartitionerConfig {
+    fn default() -> Self {
+        Self {
+            load_imbalance_tolerance: 2.0,
+        }
+    }
+}
+
+impl PartitionerConfig for ConnectedComponentBlockPartitionerConfig {
+    fn build(&self) -> Box<dyn BlockPartitioner> {
+        Box::new(ConnectedComponentBlockPartitioner {
+            load_imbalance_tolerance: self.load_imbalance_tolerance,
+        })
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    count_affinity_hints,
+    report::PartitionReport,
+    v2::{load_balance::longest_processing_time_first_with_loads, union_find::UnionFind},
+    BlockPartitioner, Sender,
+};
+use aptos_types::{
+    block_executor::partitioner::{
+        CrossShardDependencies, PartitionedTransactions, ShardId, SubBlock, SubBlocksForShard,
+        TransactionWithDependencies,
+    },
+    transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+};
+use std::collections::{HashMap, HashSet};
+
+pub mod config;
+
+/// A `BlockPartitioner` that simply assigns connected components of the conflict graph to shards.
+///
+/// Two txns are in the same component if they have the same sender,
+/// or if they both access a storage location that at least one txn in the block writes.
+/// Components are then assigned to the shards using Longest-processing-time-first (LPT) scheduling, all in round 0.
+/// Since different components never conflict, there is no cross-shard dependency in round 0.
+///
+/// A component larger than `load_imbalance_tolerance * block_size / num_shards` is not assigned in round 0.
+/// Instead, all such components go to round 1 of the last shard,
+/// which also needs no cross-shard dependency, for the same reason.
+///
+/// With `partition_with_affinity()`, a component is first offered to the shard most of its hinted txns prefer,
+/// which takes it if its load stays within the same limit.
+///
+/// Compared with `PartitionerV2`, this is much cheaper, and works well for blocks of many small independent account clusters.
+pub struct ConnectedComponentBlockPartitioner {
+    pub load_imbalance_tolerance: f32,
+}
+
+impl ConnectedComponentBlockPartitioner {
+    /// Return the components as lists of txn indices, ordered by their first txn.
+    fn connected_components(txns: &[AnalyzedTransaction]) -> Vec<Vec<usize>> {
+        let written: HashSet<&StorageLocation> = txns
+            .iter()
+            .flat_map(|txn| txn.write_hints().iter())
+            .collect();
+        let mut uf = UnionFind::new(txns.len());
+        let mut first_txn_by_key: HashMap<&StorageLocation, usize> = HashMap::new();
+        let mut first_txn_by_sender: HashMap<Sender, usize> = HashMap::new();
+        for (txn_idx, txn) in txns.iter().enumerate() {
+            let first_txn_idx = *first_txn_by_sender.entry(txn.sender()).or_insert(txn_idx);
+            uf.union(txn_idx, first_txn_idx);
+            for loc in txn.write_hints().iter().chain(txn.read_hints().iter()) {
+                if written.contains(loc) {
+                    let first_txn_idx = *first_txn_by_key.entry(loc).or_insert(txn_idx);
+                    uf.union(txn_idx, first_txn_idx);
+                }
+            }
+        }
+
+        let mut components: Vec<Vec<usize>> = Vec::new();
+        let mut component_idx_registry: HashMap<usize, usize> = HashMap::new();
+        for txn_idx in 0..txns.len() {
+            let root = uf.find(txn_idx);
+            let component_idx = *component_idx_registry.entry(root).or_insert_with(|| {
+                components.push(vec![]);
+                components.len() - 1
+            });
+            components[component_idx].push(txn_idx);
+        }
+        components
+    }
+
+    /// The shard most hinted by the txns of a component. Ties go to the lowest shard.
+    fn preferred_shard(
+        component: &[usize],
+        affinity: &[Option<ShardId>],
+        num_shards: usize,
+    ) -> Option<ShardId> {
+        let mut num_hints_by_shard = vec![0; num_shards];
+        for &txn_idx in component {
+            if let Some(shard_id) = affinity[txn_idx] {
+                if shard_id < num_shards {
+                    num_hints_by_shard[shard_id] += 1;
+                }
+            }
+        }
+        let (shard_id, num_hints) = num_hints_by_shard
+            .into_iter()
+            .enumerate()
+            .max_by_key(|&(shard_id, num_hints)| (num_hints, std::cmp::Reverse(shard_id)))?;
+        (num_hints > 0).then_some(shard_id)
+    }
+}
+
+impl BlockPartitioner for ConnectedComponentBlockPartitioner {
+    fn partition(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+    ) -> PartitionedTransactions {
+        self.partition_with_affinity(transactions, num_shards, None)
+            .0
+    }
+
+    fn partition_with_affinity(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+        affinity: Option<Vec<Option<ShardId>>>,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let num_txns = transactions.len();
+        let affinity = affinity.unwrap_or_default();
+        assert!(affinity.is_empty() || affinity.len() == num_txns);
+        let components = Self::connected_components(&transactions);
+        let component_size_limit = ((num_txns as f32) * self.load_imbalance_tolerance
+            / (num_shards as f32))
+            .ceil() as usize;
+
+        let (small_components, oversized_components): (Vec<Vec<usize>>, Vec<Vec<usize>>) =
+            components
+                .into_iter()
+                .partition(|component| component.len() <= component_size_limit);
+
+        // Components with affinity hints go first, to their preferred shard if it has room.
+        let mut loads = vec![0; num_shards];
+        let mut shards_by_component: Vec<Option<ShardId>> = vec![None; small_components.len()];
+        if !affinity.is_empty() {
+            for (component, assigned_shard_id) in
+                small_components.iter().zip(shards_by_component.iter_mut())
+            {
+                if let Some(shard_id) = Self::preferred_shard(component, &affinity, num_shards) {
+                    if loads[shard_id] + component.len() as u64 <= component_size_limit as u64 {
+                        loads[shard_id] += component.len() as u64;
+                        *assigned_shard_id = Some(shard_id);
+                    }
+                }
+            }
+        }
+        let (unassigned, tasks): (Vec<usize>, Vec<u64>) = small_components
+            .iter()
+            .enumerate()
+            .filter(|(component_idx, _)| shards_by_component[*component_idx].is_none())
+            .map(|(component_idx, component)| (component_idx, component.len() as u64))
+            .unzip();
+        let (_longest_pole, shard_ids) = longest_processing_time_first_with_loads(&tasks, loads);
+        for (component_idx, shard_id) in unassigned.into_iter().zip(shard_ids) {
+            shards_by_component[component_idx] = Some(shard_id);
+        }
+
+        // Original txn indices in round 0 of each shard, and in round 1 of the last shard.
+        let mut txn_idxs_by_shard: Vec<Vec<usize>> = vec![vec![]; num_shards];
+        for (component, shard_id) in small_components.into_iter().zip(shards_by_component) {
+            txn_idxs_by_shard[shard_id.unwrap()].extend(component);
+        }
+        let mut overflow_txn_idxs: Vec<usize> =
+            oversized_components.into_iter().flatten().collect();
+        // Sorting keeps the relative order of the txns from the same sender.
+        txn_idxs_by_shard
+            .iter_mut()
+            .for_each(|txn_idxs| txn_idxs.sort());
+        overflow_txn_idxs.sort();
+
+        let mut shard_ids_by_txn: Vec<Option<ShardId>> = vec![None; num_txns];
+        for (shard_id, txn_idxs) in txn_idxs_by_shard.iter().enumerate() {
+            for &txn_idx in txn_idxs {
+                shard_ids_by_txn[txn_idx] = Some(shard_id);
+            }
+        }
+        for &txn_idx in overflow_txn_idxs.iter() {
+            shard_ids_by_txn[txn_idx] = Some(num_shards - 1);
+        }
+        let (num_affinity_hints_honored, num_affinity_hints_ignored) =
+            count_affinity_hints(&affinity, shard_ids_by_txn.into_iter());
+
+        let mut txns: Vec<Option<AnalyzedTransaction>> =
+            transactions.into_iter().map(Some).collect();
+        let mut take_sub_block = |start_index: usize, txn_idxs: &[usize]| {
+            let txns_with_deps = txn_idxs
+                .iter()
+                .map(|&txn_idx| {
+                    TransactionWithDependencies::new(
+                        txns[txn_idx].take().unwrap(),
+                        CrossShardDependencies::default(),
+                    )
+                })
+                .collect();
+            SubBlock::new(start_index, txns_with_deps)
+        };
+
+        let mut sub_blocks_by_shard: Vec<Vec<SubBlock<AnalyzedTransaction>>> =
+            vec![vec![]; num_shards];
+        let mut start_index = 0;
+        for (shard_id, txn_idxs) in txn_idxs_by_shard.iter().enumerate() {
+            sub_blocks_by_shard[shard_id].push(take_sub_block(start_index, txn_idxs));
+            start_index += txn_idxs.len();
+        }
+        if !overflow_txn_idxs.is_empty() {
+            for (shard_id, sub_blocks) in sub_blocks_by_shard.iter_mut().enumerate() {
+                let txn_idxs: &[usize] = if shard_id == num_shards - 1 {
+                    &overflow_txn_idxs
+                } else {
+                    &[]
+                };
+                sub_blocks.push(take_sub_block(start_index, txn_idxs));
+                start_index += txn_idxs.len();
+            }
+        }
+
+        let sharded_txns = sub_blocks_by_shard
+            .into_iter()
+            .enumerate()
+            .map(|(shard_id, sub_blocks)| SubBlocksForShard::new(shard_id, sub_blocks))
+            .collect();
+        let report = PartitionReport {
+            num_affinity_hints_honored,
+            num_affinity_hints_ignored,
+            ..Default::default()
+        };
+        (PartitionedTransactions::new(sharded_txns, vec![]), report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connected_component::ConnectedComponentBlockPartitioner,
+        test_utils::{
+            create_non_conflicting_p2p_transaction, create_signed_p2p_transaction,
+            generate_test_account, verify_partitioner_output, P2PBlockGenerator,
+        },
+        BlockPartitioner,
+    };
+    use rand::thread_rng;
+
+    #[test]
+    fn test_non_conflicting_txns() {
+        let txns: Vec<_> = (0..40)
+            .map(|_| create_non_conflicting_p2p_transaction())
+            .collect();
+        let partitioner = ConnectedComponentBlockPartitioner {
+            load_imbalance_tolerance: 2.0,
+        };
+        let partitioned = partitioner.partition(txns.clone(), 4);
+        verify_partitioner_output(&txns, &partitioned);
+        for sub_blocks in partitioned.sharded_txns() {
+            assert_eq!(1, sub_blocks.num_sub_blocks());
+            assert_eq!(10, sub_blocks.num_txns());
+        }
+    }
+
+    #[test]
+    fn test_oversized_component_goes_to_last_shard() {
+        // 20 txns from one sender, plus 20 independent txns.
+        let mut sender = generate_test_account();
+        let receivers: Vec<_> = (0..20).map(|_| generate_test_account()).collect();
+        let mut txns = create_signed_p2p_transaction(&mut sender, receivers.iter().collect());
+        txns.extend((0..20).map(|_| create_non_conflicting_p2p_transaction()));
+
+        let partitioner = ConnectedComponentBlockPartitioner {
+            load_imbalance_tolerance: 1.0,
+        };
+        let partitioned = partitioner.partition(txns.clone(), 4);
+        verify_partitioner_output(&txns, &partitioned);
+        let sharded_txns = partitioned.sharded_txns();
+        assert_eq!(2, sharded_txns[0].num_sub_blocks());
+        assert_eq!(20, sharded_txns[3].get_sub_block(1).unwrap().num_txns());
+        for sub_blocks in sharded_txns.iter() {
+            assert_eq!(5, sub_blocks.get_sub_block(0).unwrap().num_txns());
+        }
+    }
+
+    #[test]
+    fn test_random_blocks() {
+        let block_gen = P2PBlockGenerator::new(1000);
+        let partitioner = ConnectedComponentBlockPartitioner {
+            load_imbalance_tolerance: 2.0,
+        };
+        let mut rng = thread_rng();
+        for block_size in [0, 1, 10, 100, 1000] {
+            let txns = block_gen.rand_block(&mut rng, block_size);
+            let partitioned = partitioner.partition(txns.clone(), 7);
+            verify_partitioner_output(&txns, &partitioned);
+            for txn in partitioned.sharded_txns().iter().flat_map(|sbs| sbs.iter()) {
+                assert_eq!(0, txn.cross_shard_dependencies().num_required_edges());
+                assert!(txn.cross_shard_dependencies().dependent_edges().is_empty());
+            }
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Export of the cross-shard dependency structure of a partitioned block, for debugging and visualization.
+//!
+//! A dump can be written as JSON (the default) or as Graphviz DOT (if the file name ends with `.dot`).
+//! JSON dumps can be loaded back with `PartitionDebugDump::load()` and summarized with `PartitionDebugDump::summary()`.
+
+use aptos_types::block_executor::partitioner::{
+    CrossShardEdges, RoundId, ShardId, SubBlocksForShard, TxnIndex,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter, Write},
+    fs, io,
+    path::Path,
+};
+
+/// A cross-shard edge as seen from one of its ends.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct EdgeDebugInfo {
+    pub round_id: RoundId,
+    pub shard_id: ShardId,
+    pub txn_index: TxnIndex,
+    /// The state keys that caused the dependency.
+    pub state_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TxnDebugInfo {
+    pub txn_index: TxnIndex,
+    /// The txns this txn depends on.
+    pub required_edges: Vec<EdgeDebugInfo>,
+    /// The txns that depend on this txn.
+    pub dependent_edges: Vec<EdgeDebugInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SubBlockDebugInfo {
+    pub round_id: RoundId,
+    pub shard_id: ShardId,
+    pub start_index: TxnIndex,
+    pub txns: Vec<TxnDebugInfo>,
+}
+
+/// The dependency structure of a partitioned block: every sub-block in (round, shard) order, with the edges of its txns.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PartitionDebugDump {
+    pub num_shards: usize,
+    pub num_rounds: usize,
+    pub sub_blocks: Vec<SubBlockDebugInfo>,
+}
+
+impl PartitionDebugDump {
+    pub fn new<T: Clone>(sharded_txns: &[SubBlocksForShard<T>]) -> Self {
+        let num_shards = sharded_txns.len();
+        let num_rounds = sharded_txns
+            .iter()
+            .map(|sub_blocks| sub_blocks.num_sub_blocks())
+            .max()
+            .unwrap_or(0);
+        let mut sub_blocks = Vec::new();
+        for round_id in 0..num_rounds {
+            for (shard_id, sub_blocks_for_shard) in sharded_txns.iter().enumerate() {
+                let Some(sub_block) = sub_blocks_for_shard.get_sub_block(round_id) else {
+                    continue;
+                };
+                let txns = sub_block
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, txn_with_deps)| {
+                        let deps = txn_with_deps.cross_shard_dependencies();
+                        TxnDebugInfo {
+                            txn_index: sub_block.start_index + pos,
+                            required_edges: edge_debug_infos(deps.required_edges()),
+                            dependent_edges: edge_debug_infos(deps.dependent_edges()),
+                        }
+                    })
+                    .collect();
+                sub_blocks.push(SubBlockDebugInfo {
+                    round_id,
+                    shard_id,
+                    start_index: sub_block.start_index,
+                    txns,
+                });
+            }
+        }
+        Self {
+            num_shards,
+            num_rounds,
+            sub_blocks,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("PartitionDebugDump should be serializable")
+    }
+
+    /// Render the dump as a Graphviz digraph: one cluster per sub-block, one arrow per required edge.
+    pub fn to_dot(&self) -> String {
+        let mut ret = String::from("digraph partition {\n");
+        for sub_block in self.sub_blocks.iter() {
+            let _ = writeln!(
+                ret,
+                "  subgraph cluster_r{}_s{} {{\n    label=\"round {} shard {}\";",
+                sub_block.round_id, sub_block.shard_id, sub_block.round_id, sub_block.shard_id
+            );
+            for txn in sub_block.txns.iter() {
+                let _ = writeln!(ret, "    t{};", txn.txn_index);
+            }
+            ret.push_str("  }\n");
+        }
+        for sub_block in self.sub_blocks.iter() {
+            for txn in sub_block.txns.iter() {
+                for edge in txn.required_edges.iter() {
+                    let _ = writeln!(
+                        ret,
+                        "  t{} -> t{} [label=\"{}\"];",
+                        edge.txn_index,
+                        txn.txn_index,
+                        edge.state_keys.join("\\n").replace('"', "\\\"")
+                    );
+                }
+            }
+        }
+        ret.push_str("}\n");
+        ret
+    }
+
+    pub fn summary(&self) -> PartitionDebugSummary {
+        let mut summary = PartitionDebugSummary {
+            num_txns_by_round_and_shard: vec![vec![0; self.num_shards]; self.num_rounds],
+            ..PartitionDebugSummary::default()
+        };
+        for sub_block in self.sub_blocks.iter() {
+            summary.num_txns += sub_block.txns.len();
+            summary.num_txns_by_round_and_shard[sub_block.round_id][sub_block.shard_id] =
+                sub_block.txns.len();
+            for txn in sub_block.txns.iter() {
+                if !txn.required_edges.is_empty() {
+                    summary.num_txns_with_required_edges += 1;
+                }
+                summary.max_required_edges_per_txn = summary
+                    .max_required_edges_per_txn
+                    .max(txn.required_edges.len());
+                for edge in txn.required_edges.iter() {
+                    summary.num_required_edges += 1;
+                    if edge.round_id == sub_block.round_id {
+                        summary.num_in_round_edges += 1;
+                    }
+                }
+            }
+        }
+        summary
+    }
+}
+
+fn edge_debug_infos(edges: &CrossShardEdges) -> Vec<EdgeDebugInfo> {
+    let mut ret: Vec<EdgeDebugInfo> = edges
+        .iter()
+        .map(|(txn_idx, locations)| {
+            let mut state_keys: Vec<String> =
+                locations.iter().map(|loc| format!("{:?}", loc)).collect();
+            state_keys.sort();
+            EdgeDebugInfo {
+                round_id: txn_idx.round_id,
+                shard_id: txn_idx.shard_id,
+                txn_index: txn_idx.txn_index,
+                state_keys,
+            }
+        })
+        .collect();
+    ret.sort_by_key(|edge| (edge.round_id, edge.shard_id, edge.txn_index));
+    ret
+}
+
+/// Some statistics of a `PartitionDebugDump`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PartitionDebugSummary {
+    pub num_txns: usize,
+    /// For round i and shard j, the number of txns in sub-block (i, j).
+    pub num_txns_by_round_and_shard: Vec<Vec<usize>>,
+    pub num_required_edges: usize,
+    /// Number of required edges whose both ends are in the same round.
+    pub num_in_round_edges: usize,
+    pub num_txns_with_required_edges: usize,
+    pub max_required_edges_per_txn: usize,
+}
+
+impl Display for PartitionDebugSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "num_txns={}", self.num_txns)?;
+        for (round_id, sizes) in self.num_txns_by_round_and_shard.iter().enumerate() {
+            writeln!(f, "round={}, sub_block_sizes={:?}", round_id, sizes)?;
+        }
+        writeln!(
+            f,
+            "num_required_edges={}, num_in_round_edges={}",
+            self.num_required_edges, self.num_in_round_edges
+        )?;
+        write!(
+            f,
+            "num_txns_with_required_edges={}, max_required_edges_per_txn={}",
+            self.num_txns_with_required_edges, self.max_required_edges_per_txn
+        )
+    }
+}
+
+/// Write the dependency structure of `sharded_txns` to `path`, in DOT format if `path` ends with `.dot`, in JSON otherwise.
+pub fn dump_partition_debug<T: Clone>(
+    sharded_txns: &[SubBlocksForShard<T>],
+    path: &Path,
+) -> io::Result<()> {
+    let dump = PartitionDebugDump::new(sharded_txns);
+    let content = if path.extension().map_or(false, |ext| ext == "dot") {
+        dump.to_dot()
+    } else {
+        dump.to_json()
+    };
+    fs::write(path, content)
+}
+
+/// If env var `APTOS_PARTITIONER_DEBUG_DUMP_DIR` is set, dump blocks with at least
+/// `APTOS_PARTITIONER_DEBUG_DUMP_MIN_EDGES` (default: 0) required edges into that directory.
+#[cfg(feature = "debug-dump")]
+pub(crate) fn maybe_dump_partition_debug<T: Clone>(sharded_txns: &[SubBlocksForShard<T>]) {
+    use aptos_logger::{info, warn};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DUMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let Ok(dir) = std::env::var("APTOS_PARTITIONER_DEBUG_DUMP_DIR") else {
+        return;
+    };
+    let min_edges: usize = std::env::var("APTOS_PARTITIONER_DEBUG_DUMP_MIN_EDGES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(0);
+    let num_edges: usize = sharded_txns
+        .iter()
+        .flat_map(|sub_blocks| sub_blocks.iter())
+        .map(|txn| txn.cross_shard_dependencies().num_required_edges())
+        .sum();
+    if num_edges < min_edges {
+        return;
+    }
+    let file_name = format!(
+        "partition-{}-{}.json",
+        std::process::id(),
+        DUMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    );
+    let path = Path::new(&dir).join(file_name);
+    match dump_partition_debug(sharded_txns, &path) {
+        Ok(()) => info!(
+            "Dumped partition with {} required edges to {}.",
+            num_edges,
+            path.display()
+        ),
+        Err(e) => warn!("Failed to dump partition to {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_types::{
+        block_executor::partitioner::{
+            CrossShardDependencies, ShardedTxnIndex, SubBlock, TransactionWithDependencies,
+        },
+        state_store::state_key::StateKey,
+        transaction::analyzed_transaction::StorageLocation,
+    };
+
+    /// 2 shards, 2 rounds, 1 txn per sub-block.
+    /// Txn 3 (round 1, shard 1) depends on txn 0 (round 0, shard 0) via key `a`.
+    fn example() -> Vec<SubBlocksForShard<()>> {
+        let loc = StorageLocation::Specific(StateKey::raw(b"a"));
+        let src = ShardedTxnIndex::new(0, 0, 0);
+        let dst = ShardedTxnIndex::new(3, 1, 1);
+        let mut src_deps = CrossShardDependencies::default();
+        src_deps.add_dependent_edge(dst, vec![loc.clone()]);
+        let mut dst_deps = CrossShardDependencies::default();
+        dst_deps.add_required_edge(src, loc);
+        let txn = |deps| vec![TransactionWithDependencies::new((), deps)];
+        vec![
+            SubBlocksForShard::new(0, vec![
+                SubBlock::new(0, txn(src_deps)),
+                SubBlock::new(2, txn(CrossShardDependencies::default())),
+            ]),
+            SubBlocksForShard::new(1, vec![
+                SubBlock::new(1, txn(CrossShardDependencies::default())),
+                SubBlock::new(3, txn(dst_deps)),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let json: serde_json::Value =
+            serde_json::from_str(&PartitionDebugDump::new(&example()).to_json()).unwrap();
+        assert_eq!(2, json["num_shards"]);
+        assert_eq!(2, json["num_rounds"]);
+        let sub_blocks = json["sub_blocks"].as_array().unwrap();
+        assert_eq!(4, sub_blocks.len());
+        for sub_block in sub_blocks {
+            for field in ["round_id", "shard_id", "start_index"] {
+                assert!(sub_block[field].is_u64());
+            }
+            for txn in sub_block["txns"].as_array().unwrap() {
+                assert!(txn["txn_index"].is_u64());
+                assert!(txn["required_edges"].is_array());
+                assert!(txn["dependent_edges"].is_array());
+            }
+        }
+        let edge = &sub_blocks[3]["txns"][0]["required_edges"][0];
+        assert_eq!(0, edge["round_id"]);
+        assert_eq!(0, edge["shard_id"]);
+        assert_eq!(0, edge["txn_index"]);
+        assert_eq!(1, edge["state_keys"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_edges_and_summary() {
+        let dump = PartitionDebugDump::new(&example());
+        let sub_block_ids: Vec<(RoundId, ShardId, TxnIndex)> = dump
+            .sub_blocks
+            .iter()
+            .map(|sub_block| {
+                (
+                    sub_block.round_id,
+                    sub_block.shard_id,
+                    sub_block.start_index,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![(0, 0, 0), (0, 1, 1), (1, 0, 2), (1, 1, 3)],
+            sub_block_ids
+        );
+
+        let src = &dump.sub_blocks[0].txns[0];
+        assert!(src.required_edges.is_empty());
+        assert_eq!(
+            vec![(1, 1, 3)],
+            src.dependent_edges
+                .iter()
+                .map(|e| (e.round_id, e.shard_id, e.txn_index))
+                .collect::<Vec<_>>()
+        );
+        let dst = &dump.sub_blocks[3].txns[0];
+        assert!(dst.dependent_edges.is_empty());
+        assert_eq!(
+            src.dependent_edges[0].state_keys,
+            dst.required_edges[0].state_keys
+        );
+
+        let summary = dump.summary();
+        assert_eq!(4, summary.num_txns);
+        assert_eq!(
+            vec![vec![1, 1], vec![1, 1]],
+            summary.num_txns_by_round_and_shard
+        );
+        assert_eq!(1, summary.num_required_edges);
+        assert_eq!(0, summary.num_in_round_edges);
+        assert_eq!(1, summary.num_txns_with_required_edges);
+        assert!(dump.to_dot().contains("t0 -> t3"));
+    }
+
+    #[test]
+    fn test_dump_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partition.json");
+        dump_partition_debug(&example(), &path).unwrap();
+        assert_eq!(
+            PartitionDebugDump::new(&example()),
+            PartitionDebugDump::load(&path).unwrap()
+        );
+    }
+}
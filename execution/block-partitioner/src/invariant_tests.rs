@@ -0,0 +1,144 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Property-based tests of the invariants checked by `verify_partitioned_block()`, for every partitioner,
+//! on random blocks with arbitrary senders and read/write hints.
+
+use crate::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    pre_partition::uniform_partitioner::config::UniformPartitionerConfig,
+    test_utils::{create_signed_p2p_transaction, generate_test_account_for_address},
+    v2::{config::PartitionerV2Config, fallback::UnshardedFallbackConfig},
+    verify::verify_partitioned_block,
+    BlockPartitioner, PartitionerConfig,
+};
+use aptos_types::{
+    state_store::state_key::StateKey,
+    transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+};
+use itertools::Itertools;
+use move_core_types::account_address::AccountAddress;
+use proptest::{collection::vec, prelude::*};
+
+/// A txn of a random block, with its sender and the keys it accesses given as indices.
+#[derive(Clone, Debug)]
+struct TxnSpec {
+    sender: usize,
+    reads: Vec<usize>,
+    writes: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct BlockSpec {
+    num_senders: usize,
+    txns: Vec<TxnSpec>,
+}
+
+impl BlockSpec {
+    /// The txns are signed p2p transfers, but their hints are replaced by the keys of the spec.
+    fn build(&self) -> Vec<AnalyzedTransaction> {
+        let mut senders: Vec<_> = (0..self.num_senders)
+            .map(|i| {
+                generate_test_account_for_address(AccountAddress::new(
+                    [i as u8 + 1; AccountAddress::LENGTH],
+                ))
+            })
+            .collect();
+        let receiver = generate_test_account_for_address(AccountAddress::ZERO);
+        let location =
+            |key: &usize| StorageLocation::Specific(StateKey::raw(format!("key_{key}").as_bytes()));
+        self.txns
+            .iter()
+            .map(|spec| {
+                let mut txn =
+                    create_signed_p2p_transaction(&mut senders[spec.sender], vec![&receiver])
+                        .remove(0);
+                txn.write_hints = spec.writes.iter().unique().map(location).collect();
+                txn.read_hints = spec
+                    .reads
+                    .iter()
+                    .unique()
+                    .filter(|key| !spec.writes.contains(*key))
+                    .map(location)
+                    .collect();
+                txn
+            })
+            .collect()
+    }
+}
+
+/// Blocks of up to 48 txns. Key i is picked with a probability decreasing with i,
+/// more steeply for a larger `skew`, so some blocks have a few very hot keys.
+fn arb_block() -> impl Strategy<Value = BlockSpec> {
+    (1usize..8, 1usize..32, 1.0f64..4.0).prop_flat_map(|(num_senders, num_keys, skew)| {
+        let key = (0.0f64..1.0)
+            .prop_map(move |u| ((u.powf(skew) * num_keys as f64) as usize).min(num_keys - 1));
+        let txn = (0..num_senders, vec(key.clone(), 0..4), vec(key, 0..3)).prop_map(
+            |(sender, reads, writes)| TxnSpec {
+                sender,
+                reads,
+                writes,
+            },
+        );
+        vec(txn, 0..48).prop_map(move |txns| BlockSpec { num_senders, txns })
+    })
+}
+
+fn partitioners() -> Vec<(&'static str, Box<dyn BlockPartitioner>)> {
+    let v2 = || PartitionerV2Config::default().num_threads(2);
+    vec![
+        ("noop", Box::new(NoOpPartitioner {})),
+        (
+            "connected-component",
+            ConnectedComponentBlockPartitionerConfig::default().build(),
+        ),
+        ("v2", v2().build()),
+        (
+            "v2-uniform-partitioned-last-round",
+            v2().pre_partitioner_config(Box::new(UniformPartitionerConfig {}))
+                .partition_last_round(true)
+                .build(),
+        ),
+        (
+            "v2-single-round",
+            v2().max_partitioning_rounds(1)
+                .partition_last_round(true)
+                .build(),
+        ),
+        (
+            "v2-balanced-last-round",
+            v2().max_partitioning_rounds(2)
+                .partition_last_round(true)
+                .balance_last_round(true)
+                .build(),
+        ),
+        (
+            "v2-capped",
+            v2().max_txns_per_shard_per_round(Some(2)).build(),
+        ),
+        (
+            "v2-unsharded-fallback",
+            v2().unsharded_fallback(Some(UnshardedFallbackConfig::default()))
+                .build(),
+        ),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_partitioner_invariants(block in arb_block(), num_shards in 1usize..6) {
+        let input = block.build();
+        for (name, partitioner) in partitioners() {
+            let output = partitioner.partition(input.clone(), num_shards);
+            prop_assert_eq!(
+                Ok(()),
+                verify_partitioned_block(&input, &output),
+                "partitioner: {}",
+                name
+            );
+        }
+    }
+}
@@ -2,13 +2,23 @@
 // Parts of the project are originally copyright © Meta Platforms, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod config;
+pub mod connected_component;
+pub mod debug_dump;
+pub mod no_op;
 pub mod v2;
 
 pub mod test_utils;
+pub mod verify;
+pub mod workloads;
 
+use crate::report::PartitionReport;
 use aptos_types::{
     block_executor::partitioner::{PartitionedTransactions, ShardId},
-    transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+    transaction::{
+        analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+        Transaction,
+    },
 };
 use move_core_types::account_address::AccountAddress;
 use std::{
@@ -18,11 +28,15 @@ use std::{
 };
 
 pub mod pre_partition;
+pub mod report;
+pub mod score;
 
 pub trait PartitionerConfig: Debug {
     fn build(&self) -> Box<dyn BlockPartitioner>;
 }
 
+#[cfg(test)]
+mod invariant_tests;
 #[cfg(test)]
 mod tests;
 
@@ -32,6 +46,49 @@ pub trait BlockPartitioner: Send {
         transactions: Vec<AnalyzedTransaction>,
         num_shards: usize, //TODO: rethink about whether this is needed as part of `BlockPartitioner` API.
     ) -> PartitionedTransactions;
+
+    /// Same as `partition()`, but also returns some statistics about the partitioning session.
+    fn partition_with_report(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        (
+            self.partition(transactions, num_shards),
+            PartitionReport::default(),
+        )
+    }
+
+    /// Same as `partition_with_report()`, but with a preferred shard for some txns: `affinity[i]` for txn i.
+    ///
+    /// The preferences are soft. A partitioner honors one only if that keeps the output correct
+    /// and the shards reasonably balanced, and reports how many it honored.
+    /// By default, all of them are ignored.
+    fn partition_with_affinity(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+        affinity: Option<Vec<Option<ShardId>>>,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let (partitioned, mut report) = self.partition_with_report(transactions, num_shards);
+        report.num_affinity_hints_ignored = affinity.iter().flatten().flatten().count();
+        (partitioned, report)
+    }
+
+    /// Same as `partition()`, but for txns that have not been analyzed yet.
+    /// The read/write hints are derived from the txns themselves,
+    /// so callers that already have the hints (or want to inject their own) should call `partition()` directly.
+    fn partition_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+        num_shards: usize,
+    ) -> PartitionedTransactions {
+        let analyzed_transactions = transactions
+            .into_iter()
+            .map(AnalyzedTransaction::from)
+            .collect();
+        self.partition(analyzed_transactions, num_shards)
+    }
 }
 
 /// When multiple transactions access the same storage location,
@@ -44,3 +101,21 @@ fn get_anchor_shard_id(storage_location: &StorageLocation, num_shards: usize) ->
 }
 
 type Sender = Option<AccountAddress>;
+
+/// Count the affinity hints honored and ignored by an assignment of the txns to shards.
+/// A txn without an assigned shard (e.g. because it is in a global round) does not honor its hint.
+fn count_affinity_hints(
+    affinity: &[Option<ShardId>],
+    shard_ids: impl Iterator<Item = Option<ShardId>>,
+) -> (usize, usize) {
+    let mut num_honored = 0;
+    let mut num_ignored = 0;
+    for (hint, shard_id) in affinity.iter().zip(shard_ids) {
+        match hint {
+            None => {},
+            Some(hint) if Some(*hint) == shard_id => num_honored += 1,
+            Some(_) => num_ignored += 1,
+        }
+    }
+    (num_honored, num_ignored)
+}
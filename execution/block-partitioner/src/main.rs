@@ -2,12 +2,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use aptos_block_partitioner::{
-    test_utils::P2PBlockGenerator, v2::config::PartitionerV2Config, PartitionerConfig,
+    debug_dump::PartitionDebugDump, test_utils::P2PBlockGenerator, v2::config::PartitionerV2Config,
+    PartitionerConfig,
 };
 use aptos_logger::info;
 use clap::Parser;
 use rand::thread_rng;
-use std::time::Instant;
+use std::{path::PathBuf, time::Instant};
 
 #[cfg(unix)]
 #[global_allocator]
@@ -26,12 +27,24 @@ struct Args {
 
     #[clap(long, default_value_t = 48)]
     pub num_shards: usize,
+
+    #[clap(long)]
+    pub disable_state_pooling: bool,
+
+    /// Instead of running the benchmark, print the summary of a JSON dump created by `debug_dump`.
+    #[clap(long)]
+    pub summarize_debug_dump: Option<PathBuf>,
 }
 
 fn main() {
     aptos_logger::Logger::new().init();
     info!("Starting the block partitioning benchmark");
     let args = Args::parse();
+    if let Some(path) = args.summarize_debug_dump {
+        let dump = PartitionDebugDump::load(&path).expect("Failed to load the debug dump");
+        println!("{}", dump.summary());
+        return;
+    }
     let block_gen = P2PBlockGenerator::new(args.num_accounts);
     let partitioner = PartitionerV2Config::default()
         .max_partitioning_rounds(4)
@@ -39,8 +52,10 @@ fn main() {
         .cross_shard_dep_avoid_threshold(0.9)
         .dashmap_num_shards(64)
         .partition_last_round(false)
+        .state_pooling(!args.disable_state_pooling)
         .build();
     let mut rng = thread_rng();
+    let mut latencies = Vec::with_capacity(args.num_blocks);
     for _ in 0..args.num_blocks {
         let transactions = block_gen.rand_block(&mut rng, args.block_size);
         info!("Starting to partition");
@@ -48,7 +63,24 @@ fn main() {
         let _partitioned = partitioner.partition(transactions.clone(), args.num_shards);
         let elapsed = now.elapsed();
         info!("Time taken to partition: {:?}", elapsed);
+        latencies.push(elapsed);
+    }
+    if latencies.is_empty() {
+        return;
     }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    info!(
+        "Partition latency over {} blocks (state pooling {}): p50={:?}, p99={:?}",
+        latencies.len(),
+        if args.disable_state_pooling {
+            "off"
+        } else {
+            "on"
+        },
+        percentile(50),
+        percentile(99),
+    );
 }
 
 #[test]
@@ -0,0 +1,59 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{count_affinity_hints, report::PartitionReport, BlockPartitioner};
+use aptos_types::{
+    block_executor::partitioner::{
+        CrossShardDependencies, PartitionedTransactions, ShardId, SubBlock, SubBlocksForShard,
+        TransactionWithDependencies,
+    },
+    transaction::analyzed_transaction::AnalyzedTransaction,
+};
+
+/// A `BlockPartitioner` that does not partition: the whole block goes to shard 0 in a single round.
+/// Useful as a baseline when evaluating real partitioners.
+pub struct NoOpPartitioner {}
+
+impl BlockPartitioner for NoOpPartitioner {
+    fn partition(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+    ) -> PartitionedTransactions {
+        let num_txns = transactions.len();
+        let mut txns_with_deps: Vec<TransactionWithDependencies<AnalyzedTransaction>> =
+            transactions
+                .into_iter()
+                .map(|txn| TransactionWithDependencies::new(txn, CrossShardDependencies::default()))
+                .collect();
+        let sharded_txns = (0..num_shards)
+            .map(|shard_id| {
+                let sub_block = if shard_id == 0 {
+                    SubBlock::new(0, std::mem::take(&mut txns_with_deps))
+                } else {
+                    SubBlock::new(num_txns, vec![])
+                };
+                SubBlocksForShard::new(shard_id, vec![sub_block])
+            })
+            .collect();
+        PartitionedTransactions::new(sharded_txns, vec![])
+    }
+
+    /// Only the hints for shard 0 are honored.
+    fn partition_with_affinity(
+        &self,
+        transactions: Vec<AnalyzedTransaction>,
+        num_shards: usize,
+        affinity: Option<Vec<Option<ShardId>>>,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let affinity = affinity.unwrap_or_default();
+        let (num_affinity_hints_honored, num_affinity_hints_ignored) =
+            count_affinity_hints(&affinity, std::iter::repeat(Some(0)));
+        let report = PartitionReport {
+            num_affinity_hints_honored,
+            num_affinity_hints_ignored,
+            ..Default::default()
+        };
+        (self.partition(transactions, num_shards), report)
+    }
+}
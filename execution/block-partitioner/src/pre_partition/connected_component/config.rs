@@ -12,12 +12,16 @@ pub struct ConnectedComponentPartitionerConfig {
     ///
     /// See the comments of `aptos_block_partitioner::pre_partition::connected_component::ConnectedComponentPartitioner` for more details.
     pub load_imbalance_tolerance: f32,
+
+    /// If set, try to keep all the txns of a sender in the same shard.
+    pub sender_affinity: bool,
 }
 
 impl Default for ConnectedComponentPartitionerConfig {
     fn default() -> Self {
         ConnectedComponentPartitionerConfig {
             load_imbalance_tolerance: 2.0,
+            sender_affinity: false,
         }
     }
 }
@@ -26,6 +30,7 @@ impl PrePartitionerConfig for ConnectedComponentPartitionerConfig {
     fn build(&self) -> Box<dyn PrePartitioner> {
         Box::new(ConnectedComponentPartitioner {
             load_imbalance_tolerance: self.load_imbalance_tolerance,
+            sender_affinity: self.sender_affinity,
         })
     }
 }
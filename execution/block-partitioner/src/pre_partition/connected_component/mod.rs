@@ -6,12 +6,14 @@ use crate::{
     v2::{
         load_balance::longest_processing_time_first,
         state::PartitionState,
-        types::{OriginalTxnIdx, PrePartitionedTxnIdx},
+        types::{OriginalTxnIdx, PrePartitionedTxnIdx, SenderIdx},
         union_find::UnionFind,
     },
 };
+use aptos_types::block_executor::partitioner::ShardId;
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::min,
+    collections::{HashMap, HashSet, VecDeque},
     sync::atomic::{AtomicUsize, Ordering},
 };
 
@@ -27,8 +29,79 @@ use std::{
 /// if `block_size=100, num_shards=10, load_imbalance_tolerance=2.0`,
 /// then the size of a conflicting txn group is not allowed to exceed 100/10*2.0 = 20.
 /// This fact, combined with the LPT algorithm, guarantees that shard load will not exceed 20.
+///
+/// When `sender_affinity` is on, a conflicting set that exceeds the group size limit is not cut into arbitrary chunks.
+/// Instead, its txns are grouped by sender, and the sender groups are packed into txn groups (first-fit, in order of first appearance),
+/// so that all the txns of a sender land in the same shard.
+/// A sender group is only split if it alone exceeds the group size limit.
 pub struct ConnectedComponentPartitioner {
     pub load_imbalance_tolerance: f32,
+    pub sender_affinity: bool,
+}
+
+impl ConnectedComponentPartitioner {
+    /// Break a conflicting set into per-sender txn queues (appended to `txn_queues`),
+    /// then pack them into txn groups of size no more than `group_size_limit` (appended to `group_metadata`).
+    fn pack_sender_groups(
+        state: &PartitionState,
+        txns: VecDeque<OriginalTxnIdx>,
+        group_size_limit: usize,
+        txn_queues: &mut Vec<VecDeque<OriginalTxnIdx>>,
+        group_metadata: &mut Vec<Vec<(usize, usize)>>,
+    ) {
+        let first_queue_idx = txn_queues.len();
+        let mut queue_idx_registry: HashMap<SenderIdx, usize> = HashMap::new();
+        for ori_txn_idx in txns {
+            let sender_idx = state.sender_idx(ori_txn_idx);
+            let queue_idx = *queue_idx_registry.entry(sender_idx).or_insert_with(|| {
+                txn_queues.push(VecDeque::new());
+                txn_queues.len() - 1
+            });
+            txn_queues[queue_idx].push_back(ori_txn_idx);
+        }
+
+        let first_group_idx = group_metadata.len();
+        let mut group_sizes: Vec<usize> = Vec::new();
+        for queue_idx in first_queue_idx..txn_queues.len() {
+            let mut remaining = txn_queues[queue_idx].len();
+            while remaining > group_size_limit {
+                group_metadata.push(vec![(queue_idx, group_size_limit)]);
+                group_sizes.push(group_size_limit);
+                remaining -= group_size_limit;
+            }
+            match group_sizes
+                .iter()
+                .position(|&size| size + remaining <= group_size_limit)
+            {
+                Some(pos) => {
+                    group_metadata[first_group_idx + pos].push((queue_idx, remaining));
+                    group_sizes[pos] += remaining;
+                },
+                None => {
+                    group_metadata.push(vec![(queue_idx, remaining)]);
+                    group_sizes.push(remaining);
+                },
+            }
+        }
+    }
+
+    /// The number of senders whose txns are in more than one shard.
+    fn num_split_senders(
+        state: &PartitionState,
+        ori_txns_idxs_by_shard: &[Vec<OriginalTxnIdx>],
+    ) -> usize {
+        let mut shard_by_sender: HashMap<SenderIdx, ShardId> = HashMap::new();
+        let mut split_senders: HashSet<SenderIdx> = HashSet::new();
+        for (shard_id, txn_idxs) in ori_txns_idxs_by_shard.iter().enumerate() {
+            for &ori_txn_idx in txn_idxs {
+                let sender_idx = state.sender_idx(ori_txn_idx);
+                if *shard_by_sender.entry(sender_idx).or_insert(shard_id) != shard_id {
+                    split_senders.insert(sender_idx);
+                }
+            }
+        }
+        split_senders.len()
+    }
 }
 
 impl PrePartitioner for ConnectedComponentPartitioner {
@@ -90,25 +163,36 @@ impl PrePartitioner for ConnectedComponentPartitioner {
             / (state.num_executor_shards as f32))
             .ceil() as usize;
 
-        // Prepare `group_metadata`, a group_metadata (i, r) will later be converted to a real group that takes `r` txns from set `i`.
+        // Prepare `group_metadata`, a group_metadata `[(q0, r0), (q1, r1), ...]` will later be converted to a real group
+        // that takes `r0` txns from queue `q0`, `r1` txns from queue `q1`, and so on.
         // NOTE: If we create actual txn groups now and then do load-balanced scheduling, we break the relative order of txns from the same sender.
         // The workaround is to only fix the group set and their sizes for now, then schedule, and materialize the txn groups at the very end (when assigning groups to shards).
-        let group_metadata: Vec<(usize, usize)> = txns_by_set
-            .iter()
-            .enumerate()
-            .flat_map(|(set_idx, txns)| {
-                let num_chunks = (txns.len() + group_size_limit - 1) / group_size_limit;
-                let mut ret = vec![(set_idx, group_size_limit); num_chunks];
-                let last_chunk_size = txns.len() - group_size_limit * (num_chunks - 1);
-                ret[num_chunks - 1] = (set_idx, last_chunk_size);
-                ret
-            })
-            .collect();
+        let mut txn_queues: Vec<VecDeque<OriginalTxnIdx>> = Vec::new();
+        let mut group_metadata: Vec<Vec<(usize, usize)>> = Vec::new();
+        for txns in txns_by_set {
+            if self.sender_affinity && txns.len() > group_size_limit {
+                Self::pack_sender_groups(
+                    state,
+                    txns,
+                    group_size_limit,
+                    &mut txn_queues,
+                    &mut group_metadata,
+                );
+            } else {
+                let queue_idx = txn_queues.len();
+                let num_txns = txns.len();
+                txn_queues.push(txns);
+                for chunk_start in (0..num_txns).step_by(group_size_limit) {
+                    let chunk_size = min(group_size_limit, num_txns - chunk_start);
+                    group_metadata.push(vec![(queue_idx, chunk_size)]);
+                }
+            }
+        }
 
         // Assign groups to shards using longest-processing-time first scheduling.
         let tasks: Vec<u64> = group_metadata
             .iter()
-            .map(|(_, size)| (*size) as u64)
+            .map(|portions| portions.iter().map(|(_, size)| *size as u64).sum())
             .collect();
         let (_longest_pole, shards_by_group) =
             longest_processing_time_first(&tasks, state.num_executor_shards);
@@ -123,13 +207,17 @@ impl PrePartitioner for ConnectedComponentPartitioner {
             vec![vec![]; state.num_executor_shards];
         for (shard_id, group_ids) in groups_by_shard.into_iter().enumerate() {
             for group_id in group_ids.into_iter() {
-                let (set_id, amount) = group_metadata[group_id];
-                for _ in 0..amount {
-                    let ori_txn_idx = txns_by_set[set_id].pop_front().unwrap();
-                    ori_txns_idxs_by_shard[shard_id].push(ori_txn_idx);
+                let mut group_txns = Vec::with_capacity(tasks[group_id] as usize);
+                for &(queue_id, amount) in group_metadata[group_id].iter() {
+                    group_txns.extend(txn_queues[queue_id].drain(..amount));
                 }
+                // Each portion is a prefix of a queue, so sorting keeps the relative order of txns from the same sender.
+                group_txns.sort();
+                ori_txns_idxs_by_shard[shard_id].extend(group_txns);
             }
         }
+        state.report.lock().unwrap().num_split_senders +=
+            Self::num_split_senders(state, &ori_txns_idxs_by_shard);
 
         // Prepare `ori_txn_idxs` and `start_txn_idxs_by_shard`.
         let mut start_txn_idxs_by_shard = vec![0; state.num_executor_shards];
@@ -157,7 +245,7 @@ impl PrePartitioner for ConnectedComponentPartitioner {
             .collect();
 
         state.thread_pool.spawn(move || {
-            drop(txns_by_set);
+            drop(txn_queues);
             drop(set_idx_registry);
             drop(group_metadata);
             drop(tasks);
@@ -0,0 +1,55 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use aptos_types::state_store::state_key::StateKey;
+use std::time::Duration;
+
+/// Statistics collected while partitioning a block, returned by `BlockPartitioner::partition_with_report()`.
+///
+/// Partitioners that do not collect a given statistic leave it at its default value.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PartitionReport {
+    /// Number of senders whose txns the pre-partitioner spread over more than one shard.
+    /// With sender affinity, only a sender whose txns alone exceed the per-shard capacity is split.
+    pub num_split_senders: usize,
+    /// For shard i, the number of storage locations anchored to it.
+    pub num_anchors_by_shard: Vec<usize>,
+    /// For shard i, the number of txns of the last round in shard i.
+    /// Empty if the last round is not partitioned, i.e. its txns are all global.
+    pub num_last_round_txns_by_shard: Vec<usize>,
+    /// Number of sub-blocks cut short by `max_txns_per_shard_per_round`.
+    pub num_capped_sub_blocks: usize,
+    /// Number of txns deferred to a later round because of `max_txns_per_shard_per_round`,
+    /// including the later txns of the same senders.
+    pub num_txns_deferred_by_cap: usize,
+    /// Whether the block was put in a single shard without partitioning, because of too many conflicts.
+    pub unsharded_fallback: bool,
+    /// Whether the partitioning budget ran out, so the remaining txns all went to the last round.
+    pub truncated_by_budget: bool,
+    /// Number of txns placed in the shard suggested by the caller, see `BlockPartitioner::partition_with_affinity()`.
+    pub num_affinity_hints_honored: usize,
+    /// Number of txns with a suggested shard that were placed elsewhere.
+    pub num_affinity_hints_ignored: usize,
+    /// For round i, what happened in it. The last round takes all the txns left after the discarding rounds.
+    pub rounds: Vec<RoundReport>,
+    /// For a block given batch by batch (see `PartitionerV2::begin_block()`), the time spent on the batches as they arrived.
+    /// Zero for a block partitioned all at once.
+    pub add_transactions_time: Duration,
+    /// For a block given batch by batch, the time spent once the block was complete. Zero for a block partitioned all at once.
+    pub finalize_time: Duration,
+}
+
+/// Statistics of a single round of a multi-round partitioning.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RoundReport {
+    /// Number of txns placed in the round.
+    pub num_txns: usize,
+    /// Number of txns given to the round because they were deferred by the previous round.
+    pub num_txns_deferred_from_previous_rounds: usize,
+    /// The state keys that caused the most deferrals out of the round, most first,
+    /// with the number of txns deferred because of a conflict on each of them.
+    ///
+    /// A txn conflicting on several keys counts for each of them.
+    /// Txns deferred only to keep the order of their sender, or because of `max_txns_per_shard_per_round`, are not attributed to any key.
+    pub top_conflicting_keys: Vec<(StateKey, usize)>,
+}
@@ -0,0 +1,254 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! A quality score for the output of a `BlockPartitioner`, to compare partitioners on the same blocks.
+
+use aptos_types::block_executor::partitioner::SubBlocksForShard;
+use std::fmt::{Display, Formatter};
+
+/// See `score_partition()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartitionScore {
+    /// Sum of the costs of all the txns.
+    pub total_cost: f64,
+    /// Sum over the rounds of the cost of the most expensive sub-block of the round,
+    /// i.e. the execution time with one executor per shard and a barrier after every round.
+    pub critical_path_cost: f64,
+    /// `total_cost / critical_path_cost`, or 1.0 for an empty block.
+    pub estimated_speedup: f64,
+    /// Number of required edges from a txn in another shard, per txn.
+    pub cross_shard_edge_density: f64,
+}
+
+impl PartitionScore {
+    /// A single number to rank partitioners, the higher the better.
+    ///
+    /// The estimated speedup is discounted by the cross-shard edges,
+    /// each of which is a message between shards and a potential wait for the receiving txn.
+    pub fn score(&self) -> f64 {
+        self.estimated_speedup / (1.0 + self.cross_shard_edge_density)
+    }
+}
+
+impl Display for PartitionScore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "score={:.3}, speedup={:.3}, critical_path={:.1}/{:.1}, edge_density={:.3}",
+            self.score(),
+            self.estimated_speedup,
+            self.critical_path_cost,
+            self.total_cost,
+            self.cross_shard_edge_density
+        )
+    }
+}
+
+/// A cost function for `score_partition()` where all the txns cost the same.
+pub fn unit_cost<T>(_txn: &T) -> f64 {
+    1.0
+}
+
+/// Score a partitioned block, given the estimated execution cost of every txn.
+/// Only the sub-blocks are looked at, so it works with the output of any partitioner.
+pub fn score_partition<T: Clone>(
+    sharded_txns: &[SubBlocksForShard<T>],
+    cost_fn: impl Fn(&T) -> f64,
+) -> PartitionScore {
+    let num_rounds = sharded_txns
+        .iter()
+        .map(|sub_blocks| sub_blocks.num_sub_blocks())
+        .max()
+        .unwrap_or(0);
+    let mut critical_path_cost_by_round = vec![0.0_f64; num_rounds];
+    let mut total_cost = 0.0;
+    let mut num_txns = 0;
+    let mut num_cross_shard_edges = 0;
+    for (shard_id, sub_blocks) in sharded_txns.iter().enumerate() {
+        for (round_id, sub_block) in sub_blocks.sub_blocks.iter().enumerate() {
+            let mut sub_block_cost = 0.0;
+            for txn in sub_block.iter() {
+                sub_block_cost += cost_fn(txn.txn());
+                num_cross_shard_edges += txn
+                    .cross_shard_dependencies()
+                    .required_edges_iter()
+                    .filter(|(src, _)| src.shard_id != shard_id)
+                    .count();
+            }
+            num_txns += sub_block.num_txns();
+            total_cost += sub_block_cost;
+            critical_path_cost_by_round[round_id] =
+                critical_path_cost_by_round[round_id].max(sub_block_cost);
+        }
+    }
+
+    let critical_path_cost: f64 = critical_path_cost_by_round.into_iter().sum();
+    let estimated_speedup = if critical_path_cost > 0.0 {
+        total_cost / critical_path_cost
+    } else {
+        1.0
+    };
+    let cross_shard_edge_density = if num_txns > 0 {
+        num_cross_shard_edges as f64 / num_txns as f64
+    } else {
+        0.0
+    };
+    PartitionScore {
+        total_cost,
+        critical_path_cost,
+        estimated_speedup,
+        cross_shard_edge_density,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::score::{score_partition, unit_cost};
+    use aptos_types::{
+        block_executor::partitioner::{
+            CrossShardDependencies, ShardedTxnIndex, SubBlock, SubBlocksForShard,
+            TransactionWithDependencies,
+        },
+        state_store::state_key::StateKey,
+        transaction::analyzed_transaction::StorageLocation,
+    };
+    use rand::{thread_rng, Rng};
+
+    /// Build sharded txns from their costs: `costs[shard_id][round_id]` are the costs of the txns of a sub-block.
+    fn sharded_txns(costs: &[Vec<Vec<u64>>]) -> Vec<SubBlocksForShard<u64>> {
+        let num_rounds = costs[0].len();
+        let mut sub_blocks_by_shard: Vec<Vec<SubBlock<u64>>> = vec![vec![]; costs.len()];
+        let mut start_index = 0;
+        for round_id in 0..num_rounds {
+            for (shard_id, costs_by_round) in costs.iter().enumerate() {
+                let txns: Vec<_> = costs_by_round[round_id]
+                    .iter()
+                    .map(|&cost| {
+                        TransactionWithDependencies::new(cost, CrossShardDependencies::default())
+                    })
+                    .collect();
+                let num_txns = txns.len();
+                sub_blocks_by_shard[shard_id].push(SubBlock::new(start_index, txns));
+                start_index += num_txns;
+            }
+        }
+        sub_blocks_by_shard
+            .into_iter()
+            .enumerate()
+            .map(|(shard_id, sub_blocks)| SubBlocksForShard::new(shard_id, sub_blocks))
+            .collect()
+    }
+
+    fn add_required_edge(
+        sharded_txns: &mut [SubBlocksForShard<u64>],
+        dst: (usize, usize, usize),
+        src: ShardedTxnIndex,
+    ) {
+        let (shard_id, round_id, pos) = dst;
+        sharded_txns[shard_id].sub_blocks[round_id].transactions[pos]
+            .cross_shard_dependencies
+            .add_required_edge(src, StorageLocation::Specific(StateKey::raw(b"key")));
+    }
+
+    fn cost(txn: &u64) -> f64 {
+        *txn as f64
+    }
+
+    #[test]
+    fn test_score_empty() {
+        let score = score_partition::<u64>(&[], unit_cost);
+        assert_eq!(0.0, score.total_cost);
+        assert_eq!(0.0, score.critical_path_cost);
+        assert_eq!(1.0, score.estimated_speedup);
+        assert_eq!(0.0, score.cross_shard_edge_density);
+        assert_eq!(1.0, score.score());
+    }
+
+    #[test]
+    fn test_score_hand_computed() {
+        // Round 0: shard 0 costs 1+1, shard 1 costs 1.
+        // Round 1: shard 0 costs 1, shard 1 costs 1+1+1.
+        let mut txns = sharded_txns(&[vec![vec![1, 1], vec![1]], vec![vec![1], vec![1, 1, 1]]]);
+        let score = score_partition(&txns, unit_cost);
+        assert_eq!(7.0, score.total_cost);
+        assert_eq!(5.0, score.critical_path_cost);
+        assert_eq!(1.4, score.estimated_speedup);
+        assert_eq!(0.0, score.cross_shard_edge_density);
+        assert_eq!(1.4, score.score());
+
+        // Round 0: shard 0 costs 3+4, shard 1 costs 5. Round 1: shard 0 costs 10, shard 1 costs 1+1+1.
+        let txns_with_costs =
+            sharded_txns(&[vec![vec![3, 4], vec![10]], vec![vec![5], vec![1, 1, 1]]]);
+        let score = score_partition(&txns_with_costs, cost);
+        assert_eq!(25.0, score.total_cost);
+        assert_eq!(17.0, score.critical_path_cost);
+        assert_eq!(25.0 / 17.0, score.estimated_speedup);
+
+        // An edge within shard 1 is not cross-shard. An edge from shard 0 to shard 1 is.
+        add_required_edge(&mut txns, (1, 1, 0), ShardedTxnIndex::new(2, 1, 0));
+        assert_eq!(
+            0.0,
+            score_partition(&txns, unit_cost).cross_shard_edge_density
+        );
+        add_required_edge(&mut txns, (1, 1, 1), ShardedTxnIndex::new(0, 0, 0));
+        let score = score_partition(&txns, unit_cost);
+        assert_eq!(1.0 / 7.0, score.cross_shard_edge_density);
+        assert_eq!(1.4 / (1.0 + 1.0 / 7.0), score.score());
+    }
+
+    #[test]
+    fn test_score_uneven_rounds() {
+        // Shard 1 has no sub-block in round 1.
+        let txns = vec![
+            SubBlocksForShard::new(0, vec![
+                SubBlock::new(0, vec![TransactionWithDependencies::new(
+                    2_u64,
+                    CrossShardDependencies::default(),
+                )]),
+                SubBlock::new(2, vec![TransactionWithDependencies::new(
+                    3_u64,
+                    CrossShardDependencies::default(),
+                )]),
+            ]),
+            SubBlocksForShard::new(1, vec![SubBlock::new(1, vec![
+                TransactionWithDependencies::new(4_u64, CrossShardDependencies::default()),
+            ])]),
+        ];
+        let score = score_partition(&txns, cost);
+        assert_eq!(9.0, score.total_cost);
+        assert_eq!(4.0 + 3.0, score.critical_path_cost);
+    }
+
+    #[test]
+    fn test_cross_shard_edge_never_improves_score() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let num_shards = rng.gen_range(2, 6);
+            let num_rounds = rng.gen_range(1, 4);
+            let costs: Vec<Vec<Vec<u64>>> = (0..num_shards)
+                .map(|_| {
+                    (0..num_rounds)
+                        .map(|_| {
+                            (0..rng.gen_range(1, 5))
+                                .map(|_| rng.gen_range(1, 10))
+                                .collect()
+                        })
+                        .collect()
+                })
+                .collect();
+            let mut txns = sharded_txns(&costs);
+            for _ in 0..10 {
+                let before = score_partition(&txns, cost);
+                let dst_shard = rng.gen_range(0, num_shards);
+                let dst_round = rng.gen_range(0, num_rounds);
+                let dst_pos = rng.gen_range(0, costs[dst_shard][dst_round].len());
+                let src_shard = (dst_shard + rng.gen_range(1, num_shards)) % num_shards;
+                let src = ShardedTxnIndex::new(rng.gen_range(0, 1000), src_shard, 0);
+                add_required_edge(&mut txns, (dst_shard, dst_round, dst_pos), src);
+                let after = score_partition(&txns, cost);
+                assert!(after.score() <= before.score());
+                assert_eq!(before.estimated_speedup, after.estimated_speedup);
+            }
+        }
+    }
+}
@@ -75,9 +75,38 @@ pub fn create_non_conflicting_p2p_transaction() -> AnalyzedTransaction {
     create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0)
 }
 
+/// The fields of the `RawTransaction` that the test txns do not derive from the accounts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxnParams {
+    pub max_gas_amount: u64,
+    pub gas_unit_price: u64,
+    pub expiration_timestamp_secs: u64,
+    pub chain_id: ChainId,
+}
+
+/// Good enough for partitioning, but the txns are not executable: they have no gas.
+impl Default for TxnParams {
+    fn default() -> Self {
+        Self {
+            max_gas_amount: 0,
+            gas_unit_price: 0,
+            expiration_timestamp_secs: 0,
+            chain_id: ChainId::new(10),
+        }
+    }
+}
+
 pub fn create_signed_p2p_transaction(
     sender: &mut TestAccount,
     receivers: Vec<&TestAccount>,
+) -> Vec<AnalyzedTransaction> {
+    create_signed_p2p_transaction_with_params(sender, receivers, &TxnParams::default())
+}
+
+pub fn create_signed_p2p_transaction_with_params(
+    sender: &mut TestAccount,
+    receivers: Vec<&TestAccount>,
+    params: &TxnParams,
 ) -> Vec<AnalyzedTransaction> {
     let mut transactions = Vec::new();
     for receiver in receivers.iter() {
@@ -95,10 +124,10 @@ pub fn create_signed_p2p_transaction(
             sender.account_address,
             sender.sequence_number,
             transaction_payload,
-            0,
-            0,
-            0,
-            ChainId::new(10),
+            params.max_gas_amount,
+            params.gas_unit_price,
+            params.expiration_timestamp_secs,
+            params.chain_id,
         );
         sender.sequence_number += 1;
         let txn = Transaction::UserTransaction(SignedTransaction::new(
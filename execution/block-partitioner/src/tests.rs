@@ -2,14 +2,26 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    score::{score_partition, unit_cost},
     test_utils::{
         create_non_conflicting_p2p_transaction, create_signed_p2p_transaction,
-        generate_test_account, verify_partitioner_output,
+        generate_test_account, verify_partitioner_output, P2PBlockGenerator,
     },
     v2::config::PartitionerV2Config,
-    PartitionerConfig,
+    workloads::{Workload, WorkloadGenerator},
+    BlockPartitioner, PartitionerConfig,
+};
+use aptos_types::{
+    block_executor::partitioner::{
+        CrossShardDependencies, MessageVolume, PartitionedTransactions, ShardedTxnIndex, SubBlock,
+        SubBlocksForShard, TransactionWithDependencies, ESTIMATED_CROSS_SHARD_WRITE_BYTES,
+        GLOBAL_ROUND_ID, GLOBAL_SHARD_ID, MAX_ALLOWED_PARTITIONING_ROUNDS,
+    },
+    state_store::state_key::StateKey,
+    transaction::{analyzed_transaction::StorageLocation, Transaction},
 };
-use aptos_types::{block_executor::partitioner::SubBlocksForShard, transaction::Transaction};
 use move_core_types::account_address::AccountAddress;
 use rand::{rngs::OsRng, Rng};
 use std::{collections::HashMap, sync::Mutex};
@@ -143,3 +155,228 @@ fn test_no_conflict_across_shards_in_non_last_rounds() {
         }
     }
 }
+
+const WORKLOADS: [Workload; 4] = [
+    Workload::UniformP2P,
+    Workload::HotSpot { zipf_exponent: 1.0 },
+    Workload::NftMintBurst { mint_ratio: 0.5 },
+    Workload::ModulePublish {
+        publish_ratio: 0.2,
+        modules_per_package: 3,
+    },
+];
+
+#[test]
+fn test_workloads_are_deterministic() {
+    for workload in WORKLOADS {
+        let block_0 = WorkloadGenerator::new(workload, 100, 7).rand_block(50);
+        let block_1 = WorkloadGenerator::new(workload, 100, 7).rand_block(50);
+        assert_eq!(block_0, block_1);
+        let block_2 = WorkloadGenerator::new(workload, 100, 8).rand_block(50);
+        assert_ne!(block_0, block_2);
+    }
+}
+
+#[test]
+fn test_partitioners_on_workloads() {
+    let partitioners: Vec<Box<dyn BlockPartitioner>> = vec![
+        Box::new(NoOpPartitioner {}),
+        PartitionerV2Config::default().build(),
+        ConnectedComponentBlockPartitionerConfig::default().build(),
+    ];
+    for workload in WORKLOADS {
+        let block = WorkloadGenerator::new(workload, 100, 0).rand_block(200);
+        for partitioner in partitioners.iter() {
+            let partitioned = partitioner.partition(block.clone(), 4);
+            verify_partitioner_output(&block, &partitioned);
+        }
+    }
+}
+
+#[test]
+fn test_partition_transactions_matches_partition() {
+    let partitioners: Vec<Box<dyn BlockPartitioner>> = vec![
+        Box::new(NoOpPartitioner {}),
+        PartitionerV2Config::default().build(),
+        ConnectedComponentBlockPartitionerConfig::default().build(),
+    ];
+    let block = P2PBlockGenerator::new(100).rand_block(&mut OsRng, 300);
+    let raw_block: Vec<Transaction> = block
+        .iter()
+        .map(|txn| txn.transaction().expect_valid().clone())
+        .collect();
+    for partitioner in partitioners.iter() {
+        let expected = partitioner.partition(block.clone(), 4);
+        let actual = partitioner.partition_transactions(raw_block.clone(), 4);
+        assert_eq!(expected, actual);
+    }
+}
+
+#[test]
+fn test_partitioners_on_tiny_blocks() {
+    let partitioners: Vec<Box<dyn BlockPartitioner>> = vec![
+        Box::new(NoOpPartitioner {}),
+        PartitionerV2Config::default().build(),
+        PartitionerV2Config::default()
+            .partition_last_round(true)
+            .build(),
+        ConnectedComponentBlockPartitionerConfig::default().build(),
+    ];
+    for num_shards in 1..5 {
+        for partitioner in partitioners.iter() {
+            let partitioned = partitioner.partition(vec![], num_shards);
+            assert_eq!(0, partitioned.num_txns());
+            assert_eq!(num_shards, partitioned.num_shards());
+            for (shard_id, sub_blocks) in partitioned.sharded_txns().iter().enumerate() {
+                assert_eq!(shard_id, sub_blocks.shard_id);
+                assert!(sub_blocks
+                    .sub_block_iter()
+                    .all(|sub_block| sub_block.start_index == 0));
+            }
+
+            // A single txn is always in round 0 of shard 0.
+            let block = vec![create_non_conflicting_p2p_transaction()];
+            let partitioned = partitioner.partition(block.clone(), num_shards);
+            verify_partitioner_output(&block, &partitioned);
+            assert_eq!(num_shards, partitioned.num_shards());
+            let sub_block = partitioned.sharded_txns()[0].get_sub_block(0).unwrap();
+            assert_eq!(0, sub_block.start_index);
+            assert_eq!(1, sub_block.num_txns());
+        }
+    }
+}
+
+#[test]
+fn test_partition_with_affinity() {
+    let block: Vec<_> = (0..40)
+        .map(|_| create_non_conflicting_p2p_transaction())
+        .collect();
+    let mut affinity = vec![None; 40];
+    affinity[0..15].fill(Some(2));
+    affinity[15] = Some(0);
+
+    // NoOp puts everything in shard 0.
+    let (partitioned, report) =
+        NoOpPartitioner {}.partition_with_affinity(block.clone(), 4, Some(affinity.clone()));
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(1, report.num_affinity_hints_honored);
+    assert_eq!(15, report.num_affinity_hints_ignored);
+
+    // Every txn is a component of its own, and shard 2 has room for all the txns preferring it.
+    let (partitioned, report) = ConnectedComponentBlockPartitionerConfig::default()
+        .build()
+        .partition_with_affinity(block.clone(), 4, Some(affinity.clone()));
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(16, report.num_affinity_hints_honored);
+    assert_eq!(0, report.num_affinity_hints_ignored);
+    assert_eq!(15, partitioned.sharded_txns()[2].num_txns());
+
+    // All partitioners produce a valid output and account for every hint, whatever the hints.
+    let partitioners: Vec<Box<dyn BlockPartitioner>> = vec![
+        Box::new(NoOpPartitioner {}),
+        PartitionerV2Config::default().build(),
+        ConnectedComponentBlockPartitionerConfig::default().build(),
+    ];
+    let block = P2PBlockGenerator::new(100).rand_block(&mut OsRng, 300);
+    let affinity: Vec<Option<usize>> = (0..block.len())
+        .map(|_| OsRng.gen_bool(0.5).then(|| OsRng.gen_range(0, 5)))
+        .collect();
+    let num_hints = affinity.iter().flatten().count();
+    for partitioner in partitioners.iter() {
+        let (partitioned, report) =
+            partitioner.partition_with_affinity(block.clone(), 4, Some(affinity.clone()));
+        verify_partitioner_output(&block, &partitioned);
+        assert_eq!(
+            num_hints,
+            report.num_affinity_hints_honored + report.num_affinity_hints_ignored
+        );
+    }
+}
+
+#[test]
+fn test_score_partitioners() {
+    let block = P2PBlockGenerator::new(1000).rand_block(&mut OsRng, 400);
+    let noop_score = score_partition(
+        NoOpPartitioner {}
+            .partition(block.clone(), 4)
+            .sharded_txns(),
+        unit_cost,
+    );
+    assert_eq!(1.0, noop_score.estimated_speedup);
+    assert_eq!(1.0, noop_score.score());
+
+    let v2_score = score_partition(
+        PartitionerV2Config::default()
+            .build()
+            .partition(block, 4)
+            .sharded_txns(),
+        unit_cost,
+    );
+    assert_eq!(400.0, v2_score.total_cost);
+    assert!(v2_score.estimated_speedup > 1.0);
+}
+
+#[test]
+fn test_cross_shard_message_volume() {
+    let loc = |key: &[u8]| StorageLocation::Specific(StateKey::raw(key));
+    let txn_with_deps = |dependent_edges: Vec<(ShardedTxnIndex, Vec<StorageLocation>)>| {
+        let mut deps = CrossShardDependencies::default();
+        for (txn_idx, storage_locations) in dependent_edges {
+            deps.add_dependent_edge(txn_idx, storage_locations);
+        }
+        TransactionWithDependencies::new(create_non_conflicting_p2p_transaction(), deps)
+    };
+    // Txn 0 in shard 0 round 0 and txn 1 in shard 1 round 0 write keys read by
+    // txn 2 in shard 0 round 1, txns 3 and 4 in shard 1 round 1, and the global txn 5.
+    let sharded_txns = vec![
+        SubBlocksForShard::new(0, vec![
+            SubBlock::new(0, vec![txn_with_deps(vec![
+                (ShardedTxnIndex::new(2, 0, 1), vec![loc(b"a")]),
+                (ShardedTxnIndex::new(3, 1, 1), vec![loc(b"a"), loc(b"b")]),
+                // Already sent to shard 1 for txn 3.
+                (ShardedTxnIndex::new(4, 1, 1), vec![loc(b"a")]),
+                (
+                    ShardedTxnIndex::new(5, GLOBAL_SHARD_ID, GLOBAL_ROUND_ID),
+                    vec![loc(b"b")],
+                ),
+            ])]),
+            SubBlock::new(2, vec![txn_with_deps(vec![])]),
+        ]),
+        SubBlocksForShard::new(1, vec![
+            SubBlock::new(1, vec![txn_with_deps(vec![(
+                ShardedTxnIndex::new(4, 1, 1),
+                vec![loc(b"cc")],
+            )])]),
+            SubBlock::new(3, vec![txn_with_deps(vec![]), txn_with_deps(vec![])]),
+        ]),
+    ];
+    let partitioned = PartitionedTransactions::new(sharded_txns, vec![txn_with_deps(vec![])]);
+
+    let volume = partitioned.cross_shard_message_volume();
+    let expected = |num_messages: usize, num_key_bytes: usize| MessageVolume {
+        num_messages,
+        num_bytes: num_key_bytes + num_messages * ESTIMATED_CROSS_SHARD_WRITE_BYTES,
+    };
+    assert_eq!(2, volume.num_shards());
+    assert_eq!(expected(1, 1), volume.get(0, 0, 1));
+    assert_eq!(expected(2, 2), volume.get(0, 1, 1));
+    assert_eq!(expected(1, 2), volume.get(1, 1, 1));
+    assert_eq!(MessageVolume::default(), volume.get(1, 0, 1));
+    assert_eq!(MessageVolume::default(), volume.get(0, 1, 0));
+    assert_eq!(expected(3, 4), volume.inbound(1, 1));
+    assert_eq!(expected(1, 1), volume.to_global(0));
+    assert_eq!(MessageVolume::default(), volume.to_global(1));
+    assert_eq!(expected(5, 6), volume.total());
+}
+
+#[test]
+#[should_panic(expected = "beyond the")]
+fn test_cross_shard_message_volume_refuses_round_beyond_max() {
+    let partitioned = PartitionedTransactions::new(
+        vec![SubBlocksForShard::empty(0), SubBlocksForShard::empty(1)],
+        vec![],
+    );
+    partitioned
+        .cross_shard_message_volume()
+        .get(1, 0, MAX_ALLOWED_PARTITIONING_ROUNDS);
+}
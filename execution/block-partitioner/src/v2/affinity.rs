@@ -0,0 +1,160 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    count_affinity_hints,
+    v2::{
+        counters::MISC_TIMERS_SECONDS,
+        state::PartitionState,
+        types::{OriginalTxnIdx, PrePartitionedTxnIdx, SenderIdx, StorageKeyIdx},
+        PartitionerV2,
+    },
+};
+use aptos_types::block_executor::partitioner::ShardId;
+use std::collections::{HashMap, HashSet};
+
+impl PartitionerV2 {
+    /// Move the txns with an affinity hint to the suggested shard, right after the pre-partitioning.
+    ///
+    /// A txn is moved only if, in the suggested shard, it conflicts with no txn of another shard
+    /// (i.e. every other txn of its sender, and every other txn accessing a key it shares with a writer, is already there),
+    /// and if the suggested shard then has at most `load_imbalance_tolerance * block_size / num_shards` txns.
+    /// Hints are considered in txn order.
+    pub(crate) fn apply_affinity(state: &mut PartitionState, load_imbalance_tolerance: f32) {
+        if state.affinity.is_empty() {
+            return;
+        }
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["apply_affinity"])
+            .start_timer();
+
+        let num_txns = state.num_txns();
+        let num_shards = state.num_executor_shards;
+        assert_eq!(num_txns, state.affinity.len());
+        let shard_size_limit =
+            ((num_txns as f32) * load_imbalance_tolerance / (num_shards as f32)).ceil() as usize;
+
+        let mut shard_ids_by_txn: Vec<ShardId> = vec![0; num_txns];
+        for (shard_id, txn_idxs) in state.pre_partitioned.iter().enumerate() {
+            for &txn_idx in txn_idxs {
+                shard_ids_by_txn[state.ori_idxs_by_pre_partitioned[txn_idx]] = shard_id;
+            }
+        }
+        let mut shard_sizes: Vec<usize> = state.pre_partitioned.iter().map(|ts| ts.len()).collect();
+
+        // The keys that need to stay in one shard, and for each of them (and for each sender), the number of txns in each shard.
+        let conflicting_keys: HashSet<StorageKeyIdx> = state
+            .write_sets
+            .iter()
+            .flat_map(|write_set| {
+                write_set
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let keys_of = |ori_txn_idx: OriginalTxnIdx| -> Vec<StorageKeyIdx> {
+            let write_set = state.write_sets[ori_txn_idx].read().unwrap();
+            let read_set = state.read_sets[ori_txn_idx].read().unwrap();
+            let keys: HashSet<StorageKeyIdx> = write_set
+                .iter()
+                .chain(read_set.iter())
+                .copied()
+                .filter(|key_idx| conflicting_keys.contains(key_idx))
+                .collect();
+            keys.into_iter().collect()
+        };
+        let mut txns_by_key_and_shard: HashMap<StorageKeyIdx, Vec<usize>> = HashMap::new();
+        let mut txns_by_sender_and_shard: HashMap<SenderIdx, Vec<usize>> = HashMap::new();
+        for ori_txn_idx in 0..num_txns {
+            let shard_id = shard_ids_by_txn[ori_txn_idx];
+            for key_idx in keys_of(ori_txn_idx) {
+                txns_by_key_and_shard
+                    .entry(key_idx)
+                    .or_insert_with(|| vec![0; num_shards])[shard_id] += 1;
+            }
+            txns_by_sender_and_shard
+                .entry(state.sender_idx(ori_txn_idx))
+                .or_insert_with(|| vec![0; num_shards])[shard_id] += 1;
+        }
+        // Whether all the txns counted in `txns_by_shard` (including the one to move, still in `from`) would be in `to`.
+        let all_in = |txns_by_shard: &[usize], from: ShardId, to: ShardId| {
+            txns_by_shard
+                .iter()
+                .enumerate()
+                .all(|(shard_id, &num_txns)| {
+                    shard_id == to || num_txns == (shard_id == from) as usize
+                })
+        };
+
+        let mut shards_with_new_txns = vec![false; num_shards];
+        for ori_txn_idx in 0..num_txns {
+            let Some(to) = state.affinity[ori_txn_idx] else {
+                continue;
+            };
+            let from = shard_ids_by_txn[ori_txn_idx];
+            if to == from || to >= num_shards || shard_sizes[to] >= shard_size_limit {
+                continue;
+            }
+            let keys = keys_of(ori_txn_idx);
+            let sender_idx = state.sender_idx(ori_txn_idx);
+            let conflict_free = keys
+                .iter()
+                .all(|key_idx| all_in(&txns_by_key_and_shard[key_idx], from, to))
+                && all_in(&txns_by_sender_and_shard[&sender_idx], from, to);
+            if !conflict_free {
+                continue;
+            }
+            for key_idx in keys {
+                let txns_by_shard = txns_by_key_and_shard.get_mut(&key_idx).unwrap();
+                txns_by_shard[from] -= 1;
+                txns_by_shard[to] += 1;
+            }
+            let txns_by_shard = txns_by_sender_and_shard.get_mut(&sender_idx).unwrap();
+            txns_by_shard[from] -= 1;
+            txns_by_shard[to] += 1;
+            shard_ids_by_txn[ori_txn_idx] = to;
+            shard_sizes[from] -= 1;
+            shard_sizes[to] += 1;
+            shards_with_new_txns[to] = true;
+        }
+
+        let (num_honored, num_ignored) = count_affinity_hints(
+            &state.affinity,
+            shard_ids_by_txn.iter().map(|&shard_id| Some(shard_id)),
+        );
+        {
+            let mut report = state.report.lock().unwrap();
+            report.num_affinity_hints_honored += num_honored;
+            report.num_affinity_hints_ignored += num_ignored;
+        }
+        if !shards_with_new_txns.contains(&true) {
+            return;
+        }
+
+        // Rebuild the pre-partitioned indices.
+        // The shards that received txns are sorted, which keeps the relative order of the txns from the same sender.
+        let mut ori_txn_idxs_by_shard: Vec<Vec<OriginalTxnIdx>> = vec![vec![]; num_shards];
+        for txn_idxs in state.pre_partitioned.iter() {
+            for &txn_idx in txn_idxs {
+                let ori_txn_idx = state.ori_idxs_by_pre_partitioned[txn_idx];
+                ori_txn_idxs_by_shard[shard_ids_by_txn[ori_txn_idx]].push(ori_txn_idx);
+            }
+        }
+        let mut pre_partitioned_txn_idx: PrePartitionedTxnIdx = 0;
+        for (shard_id, mut ori_txn_idxs) in ori_txn_idxs_by_shard.into_iter().enumerate() {
+            if shards_with_new_txns[shard_id] {
+                ori_txn_idxs.sort();
+            }
+            state.start_txn_idxs_by_shard[shard_id] = pre_partitioned_txn_idx;
+            state.pre_partitioned[shard_id] =
+                (pre_partitioned_txn_idx..pre_partitioned_txn_idx + ori_txn_idxs.len()).collect();
+            for ori_txn_idx in ori_txn_idxs {
+                state.ori_idxs_by_pre_partitioned[pre_partitioned_txn_idx] = ori_txn_idx;
+                pre_partitioned_txn_idx += 1;
+            }
+        }
+    }
+}
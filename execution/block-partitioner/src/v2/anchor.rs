@@ -0,0 +1,212 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{get_anchor_shard_id, v2::load_balance::longest_processing_time_first};
+use aptos_types::{
+    block_executor::partitioner::ShardId, state_store::state_key::StateKey,
+    transaction::analyzed_transaction::StorageLocation,
+};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+/// A storage location accessed by the block, as seen by `AnchorStrategy::rebalance()`.
+#[derive(Clone, Debug)]
+pub struct AnchorCandidate {
+    pub storage_location: StorageLocation,
+    /// Number of txns in the block that access the location.
+    pub num_accesses: usize,
+    /// The current anchor, initially from `AnchorStrategy::anchor_shard_id()`.
+    pub anchor_shard_id: ShardId,
+}
+
+/// Decides the anchor shard of every storage location in `PartitionerV2`.
+///
+/// The anchor of a location is the shard that "owns" it when resolving cross-shard conflicts in the discarding rounds.
+pub trait AnchorStrategy: Debug + Send + Sync {
+    /// The initial anchor of a storage location. Called concurrently in `init()`, once per location.
+    fn anchor_shard_id(&self, storage_location: &StorageLocation, num_shards: usize) -> ShardId;
+
+    /// Whether `rebalance()` needs to be called.
+    /// Rebalancing requires collecting all locations of the block, so it's skipped for strategies that don't need it.
+    fn rebalances(&self) -> bool {
+        false
+    }
+
+    /// Adjust the anchors of the block once the number of accesses of every location is known.
+    /// `candidates` are given in a deterministic order.
+    fn rebalance(&self, _candidates: &mut [AnchorCandidate], _num_shards: usize) {}
+}
+
+/// Pick the anchor by hashing the storage location. This is the default.
+#[derive(Debug, Default)]
+pub struct HashAnchorStrategy {}
+
+impl AnchorStrategy for HashAnchorStrategy {
+    fn anchor_shard_id(&self, storage_location: &StorageLocation, num_shards: usize) -> ShardId {
+        get_anchor_shard_id(storage_location, num_shards)
+    }
+}
+
+/// Assign the anchors with longest-processing-time-first scheduling, where the cost of a location is its number of accesses.
+/// Popular locations (e.g. the APT coin info) then end up on different shards.
+#[derive(Debug, Default)]
+pub struct LoadAwareAnchorStrategy {}
+
+impl AnchorStrategy for LoadAwareAnchorStrategy {
+    fn anchor_shard_id(&self, storage_location: &StorageLocation, num_shards: usize) -> ShardId {
+        get_anchor_shard_id(storage_location, num_shards)
+    }
+
+    fn rebalances(&self) -> bool {
+        true
+    }
+
+    fn rebalance(&self, candidates: &mut [AnchorCandidate], num_shards: usize) {
+        let costs: Vec<u64> = candidates
+            .iter()
+            .map(|candidate| candidate.num_accesses as u64)
+            .collect();
+        let (_longest_pole, shard_ids) = longest_processing_time_first(&costs, num_shards);
+        for (candidate, shard_id) in candidates.iter_mut().zip(shard_ids) {
+            candidate.anchor_shard_id = shard_id;
+        }
+    }
+}
+
+/// Anchor some configured state keys to fixed shards (modulo the number of shards),
+/// and delegate everything else to another strategy.
+#[derive(Debug)]
+pub struct PinnedAnchorStrategy {
+    pub pinned: HashMap<StateKey, ShardId>,
+    pub fallback: Arc<dyn AnchorStrategy>,
+}
+
+impl PinnedAnchorStrategy {
+    fn pinned_shard_id(
+        &self,
+        storage_location: &StorageLocation,
+        num_shards: usize,
+    ) -> Option<ShardId> {
+        match storage_location {
+            StorageLocation::Specific(state_key) => self
+                .pinned
+                .get(state_key)
+                .map(|shard_id| shard_id % num_shards),
+            _ => None,
+        }
+    }
+}
+
+impl AnchorStrategy for PinnedAnchorStrategy {
+    fn anchor_shard_id(&self, storage_location: &StorageLocation, num_shards: usize) -> ShardId {
+        self.pinned_shard_id(storage_location, num_shards)
+            .unwrap_or_else(|| self.fallback.anchor_shard_id(storage_location, num_shards))
+    }
+
+    fn rebalances(&self) -> bool {
+        self.fallback.rebalances()
+    }
+
+    fn rebalance(&self, candidates: &mut [AnchorCandidate], num_shards: usize) {
+        self.fallback.rebalance(candidates, num_shards);
+        for candidate in candidates.iter_mut() {
+            if let Some(shard_id) = self.pinned_shard_id(&candidate.storage_location, num_shards) {
+                candidate.anchor_shard_id = shard_id;
+            }
+        }
+    }
+}
+
+/// Describes which `AnchorStrategy` a `PartitionerV2Config` builds.
+#[derive(Clone, Debug, Default)]
+pub enum AnchorStrategyConfig {
+    #[default]
+    Hash,
+    LoadAware,
+    Pinned {
+        pinned: Vec<(StateKey, ShardId)>,
+        fallback: Box<AnchorStrategyConfig>,
+    },
+}
+
+impl AnchorStrategyConfig {
+    pub fn build(&self) -> Arc<dyn AnchorStrategy> {
+        match self {
+            AnchorStrategyConfig::Hash => Arc::new(HashAnchorStrategy {}),
+            AnchorStrategyConfig::LoadAware => Arc::new(LoadAwareAnchorStrategy {}),
+            AnchorStrategyConfig::Pinned { pinned, fallback } => Arc::new(PinnedAnchorStrategy {
+                pinned: pinned.iter().cloned().collect(),
+                fallback: fallback.build(),
+            }),
+        }
+    }
+}
+
+#[test]
+fn test_hash_anchor_strategy_matches_get_anchor_shard_id() {
+    let strategy = HashAnchorStrategy {};
+    assert!(!strategy.rebalances());
+    for i in 0..100u32 {
+        let loc = StorageLocation::Specific(StateKey::raw(&i.to_le_bytes()));
+        for num_shards in 1..10 {
+            assert_eq!(
+                get_anchor_shard_id(&loc, num_shards),
+                strategy.anchor_shard_id(&loc, num_shards)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_pinned_anchor_strategy() {
+    let hot = StateKey::raw(b"hot");
+    let strategy = AnchorStrategyConfig::Pinned {
+        pinned: vec![(hot.clone(), 5)],
+        fallback: Box::new(AnchorStrategyConfig::LoadAware),
+    }
+    .build();
+    let hot_loc = StorageLocation::Specific(hot);
+    assert_eq!(5, strategy.anchor_shard_id(&hot_loc, 8));
+    assert_eq!(1, strategy.anchor_shard_id(&hot_loc, 4));
+
+    let cold_loc = StorageLocation::Specific(StateKey::raw(b"cold"));
+    assert_eq!(
+        get_anchor_shard_id(&cold_loc, 8),
+        strategy.anchor_shard_id(&cold_loc, 8)
+    );
+
+    assert!(strategy.rebalances());
+    let mut candidates = vec![
+        AnchorCandidate {
+            storage_location: hot_loc,
+            num_accesses: 100,
+            anchor_shard_id: 5,
+        },
+        AnchorCandidate {
+            storage_location: cold_loc,
+            num_accesses: 1,
+            anchor_shard_id: 0,
+        },
+    ];
+    strategy.rebalance(&mut candidates, 8);
+    assert_eq!(5, candidates[0].anchor_shard_id);
+}
+
+#[test]
+fn test_load_aware_anchor_strategy() {
+    let strategy = LoadAwareAnchorStrategy {};
+    let mut candidates: Vec<AnchorCandidate> = [10, 10, 5, 5, 1]
+        .into_iter()
+        .enumerate()
+        .map(|(i, num_accesses)| AnchorCandidate {
+            storage_location: StorageLocation::Specific(StateKey::raw(&[i as u8])),
+            num_accesses,
+            anchor_shard_id: 0,
+        })
+        .collect();
+    strategy.rebalance(&mut candidates, 2);
+    let mut load_by_shard = [0; 2];
+    for candidate in candidates.iter() {
+        load_by_shard[candidate.anchor_shard_id] += candidate.num_accesses;
+    }
+    assert_eq!(16, load_by_shard.into_iter().max().unwrap());
+}
@@ -0,0 +1,95 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{Duration, Instant};
+
+/// The number of txns init goes through, and a discarding round goes through in every shard,
+/// between two checks of the budget.
+pub(crate) const BUDGET_CHECK_CHUNK_SIZE: usize = 64;
+
+/// How much work `PartitionerV2` may spend on a block.
+///
+/// Init and the discarding rounds charge the budget and check it every `BUDGET_CHECK_CHUNK_SIZE`
+/// txns, so the budget is exceeded by at most a chunk. A discarding round the budget runs out in
+/// stops at the end of the chunk, the txns it did not check being deferred, and no round comes
+/// after it. Once the budget is exhausted, all the txns not yet accepted go to the last round,
+/// which is always correct but less parallel. Init is still finished, as the last round needs the
+/// indices of all the txns, but the pre-partitioner is then replaced by an even split. The tracker
+/// update and `add_edges()` are needed by the last round too, so they are never skipped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PartitioningBudget {
+    /// Limit the number of txns processed, summed over all the phases (each phase processes the block once,
+    /// and each discarding round processes the txns it is given).
+    ///
+    /// The same block with the same config is always truncated at the same point,
+    /// so this is the mode to use when the output feeds consensus-critical execution.
+    TxnsProcessed(usize),
+    /// Limit the time spent since the partitioning started.
+    ///
+    /// Where the partitioning stops depends on the machine and its load, and so does the output.
+    /// Only use this where the output does not have to be reproduced by other nodes, e.g. benchmarks.
+    WallClock(Duration),
+}
+
+pub(crate) struct BudgetTracker {
+    budget: Option<PartitioningBudget>,
+    started_at: Instant,
+    num_txns_processed: usize,
+}
+
+impl BudgetTracker {
+    pub(crate) fn new(budget: Option<PartitioningBudget>) -> Self {
+        Self {
+            budget,
+            started_at: Instant::now(),
+            num_txns_processed: 0,
+        }
+    }
+
+    /// Start over for a new block.
+    pub(crate) fn restart(&mut self) {
+        self.started_at = Instant::now();
+        self.num_txns_processed = 0;
+    }
+
+    pub(crate) fn charge(&mut self, num_txns: usize) {
+        self.num_txns_processed += num_txns;
+    }
+
+    /// The number of txns to go through between two checks of the budget, all of them without a
+    /// budget.
+    pub(crate) fn chunk_size(&self) -> usize {
+        match self.budget {
+            None => usize::MAX,
+            Some(_) => BUDGET_CHECK_CHUNK_SIZE,
+        }
+    }
+
+    pub(crate) fn exhausted(&self) -> bool {
+        match self.budget {
+            None => false,
+            Some(PartitioningBudget::TxnsProcessed(limit)) => self.num_txns_processed >= limit,
+            Some(PartitioningBudget::WallClock(limit)) => self.started_at.elapsed() >= limit,
+        }
+    }
+}
+
+#[test]
+fn test_budget_tracker() {
+    let mut unlimited = BudgetTracker::new(None);
+    unlimited.charge(usize::MAX);
+    assert!(!unlimited.exhausted());
+    assert_eq!(usize::MAX, unlimited.chunk_size());
+
+    let mut tracker = BudgetTracker::new(Some(PartitioningBudget::TxnsProcessed(100)));
+    tracker.charge(60);
+    assert!(!tracker.exhausted());
+    tracker.charge(40);
+    assert!(tracker.exhausted());
+    tracker.restart();
+    assert!(!tracker.exhausted());
+    assert_eq!(BUDGET_CHECK_CHUNK_SIZE, tracker.chunk_size());
+
+    let tracker = BudgetTracker::new(Some(PartitioningBudget::WallClock(Duration::ZERO)));
+    assert!(tracker.exhausted());
+}
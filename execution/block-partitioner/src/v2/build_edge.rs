@@ -1,10 +1,13 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::v2::{counters::MISC_TIMERS_SECONDS, state::PartitionState, PartitionerV2};
+use crate::v2::{
+    counters::MISC_TIMERS_SECONDS, state::PartitionState, types::FinalTxnIdx, PartitionerV2,
+};
 use aptos_types::{
     block_executor::partitioner::{
-        PartitionedTransactions, SubBlock, SubBlocksForShard, TransactionWithDependencies,
+        CrossShardDependencies, PartitionedTransactions, SubBlock, SubBlocksForShard,
+        TransactionWithDependencies,
     },
     transaction::analyzed_transaction::AnalyzedTransaction,
 };
@@ -12,7 +15,6 @@ use rayon::{
     iter::ParallelIterator,
     prelude::{IntoParallelIterator, IntoParallelRefIterator},
 };
-use std::sync::Mutex;
 
 impl PartitionerV2 {
     pub(crate) fn add_edges(state: &mut PartitionState) -> PartitionedTransactions {
@@ -20,70 +22,85 @@ impl PartitionerV2 {
             .with_label_values(&["add_edges"])
             .start_timer();
 
-        state.sub_block_matrix = state.thread_pool.install(|| {
+        let deps_matrix: Vec<Vec<Vec<CrossShardDependencies>>> = state.thread_pool.install(|| {
             (0..state.num_rounds())
                 .into_par_iter()
-                .map(|_round_id| {
+                .map(|round_id| {
                     (0..state.num_executor_shards)
                         .into_par_iter()
-                        .map(|_shard_id| Mutex::new(None))
+                        .map(|shard_id| {
+                            state.finalized_txn_matrix[round_id][shard_id]
+                                .par_iter()
+                                .map(|&txn_idx1| {
+                                    state.cross_shard_deps(round_id, shard_id, txn_idx1)
+                                })
+                                .collect()
+                        })
                         .collect()
                 })
                 .collect()
         });
 
-        state.thread_pool.install(|| {
-            (0..state.num_rounds())
-                .into_par_iter()
-                .for_each(|round_id| {
-                    (0..state.num_executor_shards)
-                        .into_par_iter()
-                        .for_each(|shard_id| {
-                            let twds = state.finalized_txn_matrix[round_id][shard_id]
-                                .par_iter()
-                                .map(|&txn_idx1| {
-                                    state.take_txn_with_dep(round_id, shard_id, txn_idx1)
-                                })
-                                .collect();
-                            let sub_block =
-                                SubBlock::new(state.start_index_matrix[round_id][shard_id], twds);
-                            *state.sub_block_matrix[round_id][shard_id].lock().unwrap() =
-                                Some(sub_block);
-                        });
-                });
-        });
+        // The final txn indices follow the sub-blocks (round by round, then shard by shard), and
+        // are a permutation of the txns, so every txn goes straight into its slot, and every
+        // sub-block is then the next slice of the slots.
+        let mut final_idxs_by_ori: Vec<FinalTxnIdx> = vec![0; state.num_txns()];
+        for (txn_idx, &ori_txn_idx) in state.ori_idxs_by_pre_partitioned.iter().enumerate() {
+            final_idxs_by_ori[ori_txn_idx] =
+                *state.final_idxs_by_pre_partitioned[txn_idx].read().unwrap();
+        }
+        let txns = std::mem::take(&mut state.txns);
+        let mut slots: Vec<Option<AnalyzedTransaction>> = (0..txns.len()).map(|_| None).collect();
+        for (ori_txn_idx, txn) in txns.into_iter().enumerate() {
+            slots[final_idxs_by_ori[ori_txn_idx]] = Some(txn);
+        }
+        let mut ordered_txns = slots.into_iter().map(|txn| txn.unwrap());
+
+        let mut sub_block_matrix: Vec<Vec<SubBlock<AnalyzedTransaction>>> =
+            Vec::with_capacity(deps_matrix.len());
+        for (round_id, deps_row) in deps_matrix.into_iter().enumerate() {
+            let mut row = Vec::with_capacity(deps_row.len());
+            for (shard_id, deps_by_txn) in deps_row.into_iter().enumerate() {
+                let twds: Vec<TransactionWithDependencies<AnalyzedTransaction>> = deps_by_txn
+                    .into_iter()
+                    .map(|deps| {
+                        TransactionWithDependencies::new(ordered_txns.next().unwrap(), deps)
+                    })
+                    .collect();
+                row.push(SubBlock::new(
+                    state.start_index_matrix[round_id][shard_id],
+                    twds,
+                ));
+            }
+            sub_block_matrix.push(row);
+        }
+        debug_assert!(ordered_txns.next().is_none());
 
         let global_txns: Vec<TransactionWithDependencies<AnalyzedTransaction>> =
             if !state.partition_last_round {
-                state
-                    .sub_block_matrix
+                sub_block_matrix
                     .pop()
                     .unwrap()
-                    .last()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .take()
+                    .pop()
                     .unwrap()
                     .into_transactions_with_deps()
             } else {
                 vec![]
             };
 
-        let final_num_rounds = state.sub_block_matrix.len();
-        let sharded_txns = (0..state.num_executor_shards)
-            .map(|shard_id| {
-                let sub_blocks: Vec<SubBlock<AnalyzedTransaction>> = (0..final_num_rounds)
-                    .map(|round_id| {
-                        state.sub_block_matrix[round_id][shard_id]
-                            .lock()
-                            .unwrap()
-                            .take()
-                            .unwrap()
-                    })
-                    .collect();
-                SubBlocksForShard::new(shard_id, sub_blocks)
-            })
+        let mut sub_blocks_by_shard: Vec<Vec<SubBlock<AnalyzedTransaction>>> = (0..state
+            .num_executor_shards)
+            .map(|_| Vec::with_capacity(sub_block_matrix.len()))
+            .collect();
+        for row in sub_block_matrix {
+            for (shard_id, sub_block) in row.into_iter().enumerate() {
+                sub_blocks_by_shard[shard_id].push(sub_block);
+            }
+        }
+        let sharded_txns = sub_blocks_by_shard
+            .into_iter()
+            .enumerate()
+            .map(|(shard_id, sub_blocks)| SubBlocksForShard::new(shard_id, sub_blocks))
             .collect();
 
         PartitionedTransactions::new(sharded_txns, global_txns)
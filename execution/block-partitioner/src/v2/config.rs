@@ -5,7 +5,10 @@ use crate::{
     pre_partition::{
         connected_component::config::ConnectedComponentPartitionerConfig, PrePartitionerConfig,
     },
-    v2::PartitionerV2,
+    v2::{
+        anchor::AnchorStrategyConfig, budget::PartitioningBudget, deferral::DeferralReportConfig,
+        fallback::UnshardedFallbackConfig, PartitionerV2,
+    },
     BlockPartitioner, PartitionerConfig,
 };
 
@@ -16,7 +19,19 @@ pub struct PartitionerV2Config {
     pub cross_shard_dep_avoid_threshold: f32,
     pub dashmap_num_shards: usize,
     pub partition_last_round: bool,
+    /// Spread the txns of the partitioned last round over the shards by conflicting chains.
+    /// Ignored if `partition_last_round` is off.
+    pub balance_last_round: bool,
     pub pre_partitioner_config: Box<dyn PrePartitionerConfig>,
+    pub state_pooling: bool,
+    pub anchor_strategy: AnchorStrategyConfig,
+    pub verify_output: bool,
+    /// Cap of the sub-block size in every round but the last one, which has to take all the remaining txns.
+    pub max_txns_per_shard_per_round: Option<usize>,
+    pub unsharded_fallback: Option<UnshardedFallbackConfig>,
+    pub budget: Option<PartitioningBudget>,
+    pub deferral_report: DeferralReportConfig,
+    pub affinity_load_imbalance_tolerance: f32,
 }
 
 impl PartitionerV2Config {
@@ -45,10 +60,55 @@ impl PartitionerV2Config {
         self
     }
 
+    pub fn balance_last_round(mut self, val: bool) -> Self {
+        self.balance_last_round = val;
+        self
+    }
+
     pub fn pre_partitioner_config(mut self, val: Box<dyn PrePartitionerConfig>) -> Self {
         self.pre_partitioner_config = val;
         self
     }
+
+    pub fn state_pooling(mut self, val: bool) -> Self {
+        self.state_pooling = val;
+        self
+    }
+
+    pub fn anchor_strategy(mut self, val: AnchorStrategyConfig) -> Self {
+        self.anchor_strategy = val;
+        self
+    }
+
+    pub fn verify_output(mut self, val: bool) -> Self {
+        self.verify_output = val;
+        self
+    }
+
+    pub fn max_txns_per_shard_per_round(mut self, val: Option<usize>) -> Self {
+        self.max_txns_per_shard_per_round = val;
+        self
+    }
+
+    pub fn unsharded_fallback(mut self, val: Option<UnshardedFallbackConfig>) -> Self {
+        self.unsharded_fallback = val;
+        self
+    }
+
+    pub fn budget(mut self, val: Option<PartitioningBudget>) -> Self {
+        self.budget = val;
+        self
+    }
+
+    pub fn deferral_report(mut self, val: DeferralReportConfig) -> Self {
+        self.deferral_report = val;
+        self
+    }
+
+    pub fn affinity_load_imbalance_tolerance(mut self, val: f32) -> Self {
+        self.affinity_load_imbalance_tolerance = val;
+        self
+    }
 }
 
 impl Default for PartitionerV2Config {
@@ -59,7 +119,16 @@ impl Default for PartitionerV2Config {
             cross_shard_dep_avoid_threshold: 0.9,
             dashmap_num_shards: 64,
             partition_last_round: false,
+            balance_last_round: false,
             pre_partitioner_config: Box::<ConnectedComponentPartitionerConfig>::default(),
+            state_pooling: true,
+            anchor_strategy: AnchorStrategyConfig::default(),
+            verify_output: false,
+            max_txns_per_shard_per_round: None,
+            unsharded_fallback: None,
+            budget: None,
+            deferral_report: DeferralReportConfig::default(),
+            affinity_load_imbalance_tolerance: 1.2,
         }
     }
 }
@@ -67,13 +136,24 @@ impl Default for PartitionerV2Config {
 impl PartitionerConfig for PartitionerV2Config {
     fn build(&self) -> Box<dyn BlockPartitioner> {
         let pre_partitioner = self.pre_partitioner_config.build();
-        Box::new(PartitionerV2::new(
-            self.num_threads,
-            self.max_partitioning_rounds,
-            self.cross_shard_dep_avoid_threshold,
-            self.dashmap_num_shards,
-            self.partition_last_round,
-            pre_partitioner,
-        ))
+        Box::new(
+            PartitionerV2::new(
+                self.num_threads,
+                self.max_partitioning_rounds,
+                self.cross_shard_dep_avoid_threshold,
+                self.dashmap_num_shards,
+                self.partition_last_round,
+                pre_partitioner,
+            )
+            .balance_last_round(self.balance_last_round)
+            .state_pooling(self.state_pooling)
+            .anchor_strategy(self.anchor_strategy.build())
+            .verify_output(self.verify_output)
+            .max_txns_per_shard_per_round(self.max_txns_per_shard_per_round)
+            .unsharded_fallback(self.unsharded_fallback)
+            .budget(self.budget)
+            .deferral_report(self.deferral_report)
+            .affinity_load_imbalance_tolerance(self.affinity_load_imbalance_tolerance),
+        )
     }
 }
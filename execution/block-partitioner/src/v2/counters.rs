@@ -2,7 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use aptos_metrics_core::{
-    exponential_buckets, register_histogram, register_histogram_vec, Histogram, HistogramVec,
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    Histogram, HistogramVec, IntCounter,
 };
 use once_cell::sync::Lazy;
 
@@ -28,3 +29,23 @@ pub static MISC_TIMERS_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
     )
     .unwrap()
 });
+
+pub static UNSHARDED_FALLBACK_COUNT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        // metric name
+        "aptos_block_partitioner_v2_unsharded_fallback_count",
+        // metric description
+        "The number of blocks that block partitioner v2 did not partition, because of too many conflicts.",
+    )
+    .unwrap()
+});
+
+pub static TRUNCATED_BY_BUDGET_COUNT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        // metric name
+        "aptos_block_partitioner_v2_truncated_by_budget_count",
+        // metric description
+        "The number of blocks for which block partitioner v2 ran out of budget and skipped some discarding rounds.",
+    )
+    .unwrap()
+});
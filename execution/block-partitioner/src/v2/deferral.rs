@@ -0,0 +1,49 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::report::PartitionReport;
+use aptos_logger::info;
+
+/// How `PartitionerV2` reports the txns it defers to later rounds, see `RoundReport`.
+#[derive(Clone, Copy, Debug)]
+pub struct DeferralReportConfig {
+    /// Number of state keys kept in `RoundReport::top_conflicting_keys`.
+    pub num_top_keys: usize,
+    /// Log a summary of the rounds if the last round takes at least this fraction of the block.
+    pub last_round_share_log_threshold: f64,
+}
+
+impl Default for DeferralReportConfig {
+    fn default() -> Self {
+        Self {
+            num_top_keys: 5,
+            last_round_share_log_threshold: 0.5,
+        }
+    }
+}
+
+impl DeferralReportConfig {
+    pub(crate) fn maybe_log_summary(&self, report: &PartitionReport, num_txns: usize) {
+        let Some(last_round) = report.rounds.last() else {
+            return;
+        };
+        if num_txns == 0 {
+            return;
+        }
+        let last_round_share = last_round.num_txns as f64 / num_txns as f64;
+        if last_round_share < self.last_round_share_log_threshold {
+            return;
+        }
+        info!(
+            "PartitionerV2 put {} of {} txns in the last round. Txns by round: {:?}. Top conflicting keys of round 0: {:?}.",
+            last_round.num_txns,
+            num_txns,
+            report
+                .rounds
+                .iter()
+                .map(|round| round.num_txns)
+                .collect::<Vec<_>>(),
+            report.rounds[0].top_conflicting_keys,
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use aptos_types::{
+    state_store::state_key::StateKey, transaction::analyzed_transaction::AnalyzedTransaction,
+};
+use std::collections::HashMap;
+
+/// When to skip partitioning and put the whole block in a single shard.
+///
+/// Some blocks (e.g. airdrop claims against a single resource) are inherently sequential.
+/// Partitioning them is expensive, and the multi-round output executes slower than unsharded execution.
+#[derive(Clone, Copy, Debug)]
+pub struct UnshardedFallbackConfig {
+    /// Number of hottest keys to consider in `estimate_conflict_ratio()`.
+    pub top_k_hot_keys: usize,
+    /// Fall back if the estimated conflict ratio is at least this much.
+    pub conflict_ratio_threshold: f64,
+}
+
+impl Default for UnshardedFallbackConfig {
+    fn default() -> Self {
+        Self {
+            top_k_hot_keys: 4,
+            conflict_ratio_threshold: 0.8,
+        }
+    }
+}
+
+impl UnshardedFallbackConfig {
+    pub fn should_fall_back(&self, txns: &[AnalyzedTransaction]) -> bool {
+        !txns.is_empty()
+            && estimate_conflict_ratio(txns, self.top_k_hot_keys) >= self.conflict_ratio_threshold
+    }
+}
+
+/// The fraction of txns that read or write any of the `top_k` hottest keys of the block,
+/// where the hotness of a key is the number of txns writing it.
+///
+/// Ties are broken by the first txn writing the key, so the result does not depend on the hash map order.
+pub fn estimate_conflict_ratio(txns: &[AnalyzedTransaction], top_k: usize) -> f64 {
+    if txns.is_empty() {
+        return 0.0;
+    }
+
+    // Key -> (number of writers, first writer).
+    let mut writers_by_key: HashMap<&StateKey, (usize, usize)> = HashMap::new();
+    for (txn_idx, txn) in txns.iter().enumerate() {
+        for loc in txn.write_hints() {
+            writers_by_key
+                .entry(loc.state_key())
+                .or_insert((0, txn_idx))
+                .0 += 1;
+        }
+    }
+    let mut hot_keys: Vec<(&StateKey, (usize, usize))> = writers_by_key.into_iter().collect();
+    hot_keys.sort_by_key(|(_key, (num_writers, first_writer))| {
+        (std::cmp::Reverse(*num_writers), *first_writer)
+    });
+    hot_keys.truncate(top_k);
+    let hot_keys: Vec<&StateKey> = hot_keys.into_iter().map(|(key, _)| key).collect();
+
+    let num_conflicting_txns = txns
+        .iter()
+        .filter(|txn| {
+            txn.read_hints()
+                .iter()
+                .chain(txn.write_hints().iter())
+                .any(|loc| hot_keys.contains(&loc.state_key()))
+        })
+        .count();
+    num_conflicting_txns as f64 / txns.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_utils::{create_signed_p2p_transaction, generate_test_account},
+        v2::fallback::{estimate_conflict_ratio, UnshardedFallbackConfig},
+    };
+    use aptos_types::{
+        state_store::state_key::StateKey,
+        transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+    };
+
+    fn loc(name: &str) -> StorageLocation {
+        StorageLocation::Specific(StateKey::raw(name.as_bytes()))
+    }
+
+    /// A txn with the given hints.
+    fn txn(reads: &[&str], writes: &[&str]) -> AnalyzedTransaction {
+        let mut sender = generate_test_account();
+        let receiver = generate_test_account();
+        let mut txn = create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0);
+        txn.read_hints = reads.iter().map(|name| loc(name)).collect();
+        txn.write_hints = writes.iter().map(|name| loc(name)).collect();
+        txn
+    }
+
+    #[test]
+    fn test_estimate_conflict_ratio() {
+        assert_eq!(0.0, estimate_conflict_ratio(&[], 1));
+
+        // Everyone claims from the same resource.
+        let airdrop: Vec<_> = (0..10)
+            .map(|i| txn(&[], &["pool", &format!("claimer_{}", i)]))
+            .collect();
+        assert_eq!(1.0, estimate_conflict_ratio(&airdrop, 1));
+
+        // Disjoint writes: only the txns writing the top-k keys count.
+        let disjoint: Vec<_> = (0..10)
+            .map(|i| txn(&[], &[&format!("account_{}", i)]))
+            .collect();
+        assert_eq!(0.1, estimate_conflict_ratio(&disjoint, 1));
+        assert_eq!(0.4, estimate_conflict_ratio(&disjoint, 4));
+        assert_eq!(1.0, estimate_conflict_ratio(&disjoint, 100));
+
+        // Keys that are only read are never hot, but reading a hot key counts.
+        let oracle = vec![
+            txn(&["config"], &["price"]),
+            txn(&["config"], &["price"]),
+            txn(&["config", "price"], &["a"]),
+            txn(&["config", "price"], &["b"]),
+            txn(&["config"], &["c"]),
+        ];
+        assert_eq!(0.8, estimate_conflict_ratio(&oracle, 1));
+        assert_eq!(0.0, estimate_conflict_ratio(&oracle, 0));
+    }
+
+    #[test]
+    fn test_estimate_conflict_ratio_tie_break() {
+        // All keys have 1 writer, so the hottest key is the first one written.
+        let txns = vec![txn(&[], &["x"]), txn(&["x"], &["y"]), txn(&["y"], &["z"])];
+        assert_eq!(2.0 / 3.0, estimate_conflict_ratio(&txns, 1));
+    }
+
+    #[test]
+    fn test_should_fall_back() {
+        let config = UnshardedFallbackConfig {
+            top_k_hot_keys: 1,
+            conflict_ratio_threshold: 0.5,
+        };
+        assert!(!config.should_fall_back(&[]));
+
+        let mut txns: Vec<_> = (0..4).map(|_| txn(&[], &["hot"])).collect();
+        txns.extend((0..4).map(|i| txn(&[], &[&format!("cold_{}", i)])));
+        assert!(config.should_fall_back(&txns));
+        txns.push(txn(&[], &["cold"]));
+        assert!(!config.should_fall_back(&txns));
+    }
+}
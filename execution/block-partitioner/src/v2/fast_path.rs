@@ -0,0 +1,121 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{report::PartitionReport, v2::PartitionerV2};
+use aptos_types::{
+    block_executor::partitioner::{
+        CrossShardDependencies, PartitionedTransactions, ShardId, SubBlock, SubBlocksForShard,
+        TransactionWithDependencies,
+    },
+    state_store::state_key::StateKey,
+    transaction::analyzed_transaction::AnalyzedTransaction,
+};
+use std::collections::HashSet;
+
+impl PartitionerV2 {
+    /// Whether the block can be partitioned by `partition_trivial_block()`, skipping the state construction and the analysis.
+    ///
+    /// That's the case for an empty block, and for a single txn whose placement and report cannot be changed
+    /// by an affinity hint, a budget, a zero cap or anchor rebalancing.
+    pub(crate) fn can_skip_analysis(
+        &self,
+        txns: &[AnalyzedTransaction],
+        affinity: Option<&[Option<ShardId>]>,
+    ) -> bool {
+        if !self.fast_paths || self.budget.is_some() {
+            return false;
+        }
+        match txns.len() {
+            0 => true,
+            1 => {
+                self.max_txns_per_shard_per_round != Some(0)
+                    && !self.anchor_strategy.rebalances()
+                    && affinity.map_or(true, |hints| hints.iter().all(Option::is_none))
+            },
+            _ => false,
+        }
+    }
+
+    /// Partition a block checked by `can_skip_analysis()`, with the same output as the full pipeline:
+    /// the txn (if any) goes to shard 0 in round 0 with no dependency, and every other round is empty.
+    pub(crate) fn partition_trivial_block(
+        &self,
+        txns: Vec<AnalyzedTransaction>,
+        num_executor_shards: usize,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let num_txns = txns.len();
+        // Nothing is ever deferred: all the txns are in round 0, or in the last round if there is
+        // no discarding round.
+        let num_discarding_rounds = Self::num_discarding_rounds_without_deferral(
+            self.max_partitioning_rounds,
+            self.cross_shard_dep_avoid_threshold,
+            num_txns,
+        );
+        let num_txns_in_round = |round_id| if round_id == 0 { num_txns } else { 0 };
+
+        // A key is anchored by its first occurrence, as in `init()`.
+        let mut seen_keys: HashSet<&StateKey> = HashSet::new();
+        let anchor_shard_ids = txns
+            .iter()
+            .flat_map(|txn| txn.read_hints.iter().chain(txn.write_hints.iter()))
+            .filter(|&storage_location| seen_keys.insert(storage_location.state_key()))
+            .map(|storage_location| {
+                self.anchor_strategy
+                    .anchor_shard_id(storage_location, num_executor_shards)
+            });
+        let mut report = PartitionReport {
+            num_anchors_by_shard: Self::count_anchors(anchor_shard_ids, num_executor_shards),
+            rounds: (0..num_discarding_rounds)
+                .map(|round_id| {
+                    let num_txns = num_txns_in_round(round_id);
+                    Self::round_report(round_id, num_txns, num_txns, vec![])
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut num_last_round_txns_by_shard = vec![0; num_executor_shards];
+        num_last_round_txns_by_shard[0] = num_txns_in_round(num_discarding_rounds);
+        Self::report_last_round(
+            &mut report,
+            self.partition_last_round,
+            num_discarding_rounds,
+            num_last_round_txns_by_shard,
+        );
+
+        let mut txns_with_deps: Vec<TransactionWithDependencies<AnalyzedTransaction>> = txns
+            .into_iter()
+            .map(|txn| TransactionWithDependencies::new(txn, CrossShardDependencies::default()))
+            .collect();
+        // With a single round that is not partitioned, the block is all global txns.
+        if num_discarding_rounds == 0 && !self.partition_last_round {
+            let sharded_txns = (0..num_executor_shards)
+                .map(SubBlocksForShard::empty)
+                .collect();
+            return (
+                PartitionedTransactions::new(sharded_txns, txns_with_deps),
+                report,
+            );
+        }
+
+        let num_rounds = if self.partition_last_round {
+            num_discarding_rounds + 1
+        } else {
+            num_discarding_rounds
+        };
+        let sharded_txns = (0..num_executor_shards)
+            .map(|shard_id| {
+                let sub_blocks = (0..num_rounds)
+                    .map(|round_id| {
+                        if round_id == 0 && shard_id == 0 {
+                            SubBlock::new(0, std::mem::take(&mut txns_with_deps))
+                        } else {
+                            SubBlock::new(num_txns, vec![])
+                        }
+                    })
+                    .collect();
+                SubBlocksForShard::new(shard_id, sub_blocks)
+            })
+            .collect();
+        (PartitionedTransactions::new(sharded_txns, vec![]), report)
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    no_op::NoOpPartitioner,
+    report::PartitionReport,
+    v2::{
+        counters::{MISC_TIMERS_SECONDS, UNSHARDED_FALLBACK_COUNT},
+        state::PartitionState,
+        PartitionerV2,
+    },
+    BlockPartitioner,
+};
+use aptos_types::{
+    block_executor::partitioner::PartitionedTransactions,
+    transaction::analyzed_transaction::AnalyzedTransaction,
+};
+use std::time::{Duration, Instant};
+
+/// A block given to a `PartitionerV2` batch by batch, as the batches arrive (e.g. from the quorum store).
+///
+/// Each batch goes through the indexing of its senders and storage locations right away.
+/// Everything that needs the complete block (pre-partitioning, the rounds, the dependencies) waits for `finalize()`.
+/// The output is the same as `partition_with_report()` on all the batches concatenated.
+pub struct IncrementalPartitioning<'a> {
+    partitioner: &'a PartitionerV2,
+    state: PartitionState,
+    add_transactions_time: Duration,
+}
+
+impl PartitionerV2 {
+    /// Start partitioning a block whose txns will be given by `IncrementalPartitioning::add_transactions()`.
+    pub fn begin_block(&self, num_executor_shards: usize) -> IncrementalPartitioning<'_> {
+        IncrementalPartitioning {
+            partitioner: self,
+            state: self.take_state(vec![], num_executor_shards),
+            add_transactions_time: Duration::ZERO,
+        }
+    }
+}
+
+impl IncrementalPartitioning<'_> {
+    /// Append a batch of txns to the block.
+    pub fn add_transactions(&mut self, batch: Vec<AnalyzedTransaction>) {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["add_transactions"])
+            .start_timer();
+        let started_at = Instant::now();
+        let first_ori_txn_idx = self.state.num_txns();
+        self.state.append_txns(batch);
+        let num_txns = self.state.num_txns();
+        PartitionerV2::init_txns(&mut self.state, first_ori_txn_idx..num_txns);
+        self.add_transactions_time += started_at.elapsed();
+    }
+
+    pub fn num_txns(&self) -> usize {
+        self.state.num_txns()
+    }
+
+    /// Partition the txns added so far.
+    pub fn finalize(self) -> (PartitionedTransactions, PartitionReport) {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["finalize"])
+            .start_timer();
+        let started_at = Instant::now();
+        let IncrementalPartitioning {
+            partitioner,
+            mut state,
+            add_transactions_time,
+        } = self;
+        let num_executor_shards = state.num_executor_shards;
+        let num_txns = state.num_txns();
+
+        // Same decisions as `partition_with_affinity()`, on the complete block.
+        let (ret, mut report) = if partitioner.should_fall_back(&state.txns) {
+            UNSHARDED_FALLBACK_COUNT.inc();
+            let txns = std::mem::take(&mut state.txns);
+            partitioner.recycle_state(state);
+            let (partitioned, mut report) =
+                NoOpPartitioner {}.partition_with_report(txns, num_executor_shards);
+            report.unsharded_fallback = true;
+            (partitioned, report)
+        } else if partitioner.can_skip_analysis(&state.txns, None) {
+            let txns = std::mem::take(&mut state.txns);
+            partitioner.recycle_state(state);
+            partitioner.partition_trivial_block(txns, num_executor_shards)
+        } else {
+            // The budget is for the work that is left.
+            state.budget.restart();
+            partitioner.partition_initialized(state)
+        };
+        report.add_transactions_time = add_transactions_time;
+        report.finalize_time = started_at.elapsed();
+        partitioner.finish_block(&ret, &report, num_txns);
+        (ret, report)
+    }
+}
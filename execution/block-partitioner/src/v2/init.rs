@@ -1,29 +1,56 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{
-    get_anchor_shard_id,
-    v2::{
-        conflicting_txn_tracker::ConflictingTxnTracker, counters::MISC_TIMERS_SECONDS,
-        state::PartitionState, types::OriginalTxnIdx, PartitionerV2,
-    },
+use crate::v2::{
+    anchor::AnchorCandidate,
+    conflicting_txn_tracker::ConflictingTxnTracker,
+    counters::MISC_TIMERS_SECONDS,
+    state::PartitionState,
+    types::{OriginalTxnIdx, StorageKeyIdx},
+    PartitionerV2,
 };
+use aptos_types::block_executor::partitioner::ShardId;
 use rayon::{iter::ParallelIterator, prelude::IntoParallelIterator};
-use std::sync::RwLock;
+use std::{ops::Range, sync::RwLock};
 
 impl PartitionerV2 {
     pub(crate) fn init(state: &mut PartitionState) {
+        Self::init_txns(state, 0..state.num_txns());
+    }
+
+    /// Same as `init()`, but only for some txns appended to the block,
+    /// after all the txns before them went through `init_txns()`.
+    pub(crate) fn init_txns(state: &mut PartitionState, ori_txn_idxs: Range<OriginalTxnIdx>) {
         let _timer = MISC_TIMERS_SECONDS
             .with_label_values(&["init"])
             .start_timer();
 
+        // Collect the distinct senders and keys, then number them.
+        // Numbering happens after all the txns are seen, so the indices do not depend on thread scheduling.
+        state.thread_pool.install(|| {
+            ori_txn_idxs
+                .clone()
+                .into_par_iter()
+                .for_each(|ori_txn_idx: OriginalTxnIdx| {
+                    let txn = &state.txns[ori_txn_idx];
+                    state.add_sender(&txn.sender(), ori_txn_idx);
+                    txn.read_hints
+                        .iter()
+                        .chain(txn.write_hints.iter())
+                        .enumerate()
+                        .for_each(|(pos, storage_location)| {
+                            state.add_key(storage_location.state_key(), ori_txn_idx, pos);
+                        });
+                });
+        });
+        state.finalize_indices();
+
         state.thread_pool.install(|| {
-            (0..state.num_txns())
+            ori_txn_idxs
                 .into_par_iter()
                 .for_each(|ori_txn_idx: OriginalTxnIdx| {
-                    let txn_read_guard = state.txns[ori_txn_idx].read().unwrap();
-                    let txn = txn_read_guard.as_ref().unwrap();
-                    let sender_idx = state.add_sender(txn.sender());
+                    let txn = &state.txns[ori_txn_idx];
+                    let sender_idx = state.sender_idx_of(&txn.sender());
                     *state.sender_idxs[ori_txn_idx].write().unwrap() = Some(sender_idx);
 
                     let reads = txn.read_hints.iter().map(|loc| (loc, false));
@@ -31,7 +58,7 @@ impl PartitionerV2 {
                     reads
                         .chain(writes)
                         .for_each(|(storage_location, is_write)| {
-                            let key_idx = state.add_key(storage_location.state_key());
+                            let key_idx = state.key_idx_of(storage_location.state_key());
                             if is_write {
                                 state.write_sets[ori_txn_idx]
                                     .write()
@@ -44,10 +71,9 @@ impl PartitionerV2 {
                                     .insert(key_idx);
                             }
                             state.trackers.entry(key_idx).or_insert_with(|| {
-                                let anchor_shard_id = get_anchor_shard_id(
-                                    storage_location,
-                                    state.num_executor_shards,
-                                );
+                                let anchor_shard_id = state
+                                    .anchor_strategy
+                                    .anchor_shard_id(storage_location, state.num_executor_shards);
                                 RwLock::new(ConflictingTxnTracker::new(
                                     storage_location.clone(),
                                     anchor_shard_id,
@@ -57,4 +83,69 @@ impl PartitionerV2 {
                 });
         });
     }
+
+    /// Give the anchor strategy a chance to revisit the anchors, now that the trackers know all their candidates.
+    /// Also record the number of anchors of each shard.
+    pub(crate) fn rebalance_anchors(state: &mut PartitionState) {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["rebalance_anchors"])
+            .start_timer();
+
+        if state.anchor_strategy.rebalances() {
+            // Order the keys by their first access in the pre-partitioned order.
+            // Key indices are deterministic, so they break the ties.
+            let mut sort_keys: Vec<(usize, StorageKeyIdx)> = state
+                .trackers
+                .iter()
+                .map(|entry| {
+                    let tracker = entry.value().read().unwrap();
+                    (
+                        tracker.first_candidate().unwrap_or(usize::MAX),
+                        *entry.key(),
+                    )
+                })
+                .collect();
+            sort_keys.sort();
+            let mut candidates: Vec<AnchorCandidate> = sort_keys
+                .iter()
+                .map(|(_, key_idx)| {
+                    let tracker_ref = state.trackers.get(key_idx).unwrap();
+                    let tracker = tracker_ref.read().unwrap();
+                    AnchorCandidate {
+                        storage_location: tracker.storage_location.clone(),
+                        num_accesses: tracker.num_candidates(),
+                        anchor_shard_id: tracker.anchor_shard_id,
+                    }
+                })
+                .collect();
+            state
+                .anchor_strategy
+                .rebalance(&mut candidates, state.num_executor_shards);
+            for ((_, key_idx), candidate) in sort_keys.iter().zip(candidates) {
+                let tracker_ref = state.trackers.get(key_idx).unwrap();
+                tracker_ref.write().unwrap().anchor_shard_id = candidate.anchor_shard_id;
+            }
+        }
+
+        let num_anchors_by_shard = Self::count_anchors(
+            state
+                .trackers
+                .iter()
+                .map(|entry| entry.value().read().unwrap().anchor_shard_id),
+            state.num_executor_shards,
+        );
+        state.report.get_mut().unwrap().num_anchors_by_shard = num_anchors_by_shard;
+    }
+
+    /// The number of keys anchored to each shard, given the anchor of every key.
+    pub(crate) fn count_anchors(
+        anchor_shard_ids: impl Iterator<Item = ShardId>,
+        num_executor_shards: usize,
+    ) -> Vec<usize> {
+        let mut num_anchors_by_shard = vec![0; num_executor_shards];
+        for anchor_shard_id in anchor_shard_ids {
+            num_anchors_by_shard[anchor_shard_id] += 1;
+        }
+        num_anchors_by_shard
+    }
 }
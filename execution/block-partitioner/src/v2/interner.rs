@@ -0,0 +1,200 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use dashmap::DashMap;
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+    ThreadPool,
+};
+use std::hash::Hash;
+
+/// Where a value is seen in a block: (txn index, position in the txn).
+pub type Occurrence = (usize, usize);
+
+struct Entry {
+    first_seen: Occurrence,
+    idx: usize,
+}
+
+/// Assigns dense indices `0..n` to the distinct values seen in a block, in a way that does not depend on thread scheduling.
+///
+/// Usage has 3 phases:
+/// 1. `observe()` every occurrence of every value, concurrently.
+/// 2. `finalize()`, which numbers the values in the order of their first occurrence.
+/// 3. `idx_of()`/`value()` lookups, concurrently.
+///
+/// The phases can be repeated when txns are appended to the block: the next `finalize()` numbers the new values after the previous ones.
+/// If the new occurrences all come after the previous ones, the indices are the same as with a single `finalize()`.
+pub struct ConcurrentInterner<K> {
+    entries: DashMap<K, Entry>,
+    /// Index -> value. Filled by `finalize()`.
+    values: Vec<K>,
+}
+
+impl<K: Clone + Eq + Hash + Send + Sync> ConcurrentInterner<K> {
+    pub fn new(dashmap_num_shards: usize) -> Self {
+        Self {
+            entries: DashMap::with_shard_amount(dashmap_num_shards),
+            values: vec![],
+        }
+    }
+
+    pub fn observe(&self, value: &K, occurrence: Occurrence) {
+        if let Some(mut entry) = self.entries.get_mut(value) {
+            entry.first_seen = entry.first_seen.min(occurrence);
+            return;
+        }
+        self.entries
+            .entry(value.clone())
+            .and_modify(|entry| entry.first_seen = entry.first_seen.min(occurrence))
+            .or_insert(Entry {
+                first_seen: occurrence,
+                idx: usize::MAX,
+            });
+    }
+
+    /// Number the values observed since the previous `finalize()`, if any.
+    pub fn finalize(&mut self, thread_pool: &ThreadPool) {
+        let entries = &self.entries;
+        let num_finalized = self.values.len();
+        let new_values: Vec<K> = thread_pool.install(|| {
+            let mut values_with_first_seen: Vec<(Occurrence, K)> = entries
+                .iter()
+                .filter(|entry| entry.value().idx == usize::MAX)
+                .map(|entry| (entry.value().first_seen, entry.key().clone()))
+                .collect();
+            values_with_first_seen.par_sort_unstable_by_key(|(first_seen, _)| *first_seen);
+            values_with_first_seen
+                .par_iter()
+                .enumerate()
+                .for_each(|(idx, (_, value))| {
+                    entries.get_mut(value).unwrap().idx = num_finalized + idx;
+                });
+            values_with_first_seen
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect()
+        });
+        self.values.extend(new_values);
+    }
+
+    /// The index of a value, only available after `finalize()`.
+    pub fn idx_of(&self, value: &K) -> usize {
+        let idx = self.entries.get(value).unwrap().idx;
+        debug_assert_ne!(usize::MAX, idx, "not finalized");
+        idx
+    }
+
+    pub fn value(&self, idx: usize) -> &K {
+        &self.values[idx]
+    }
+
+    /// Number of distinct values, only available after `finalize()`.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.values.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::v2::interner::ConcurrentInterner;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rayon::{
+        iter::{IntoParallelIterator, ParallelIterator},
+        ThreadPoolBuilder,
+    };
+
+    /// Intern the values of `txns`, where txn i has values `txns[i]`, with the given number of threads.
+    fn intern(txns: &[Vec<u64>], num_threads: usize) -> ConcurrentInterner<u64> {
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        let mut interner = ConcurrentInterner::new(16);
+        thread_pool.install(|| {
+            (0..txns.len()).into_par_iter().for_each(|txn_idx| {
+                for (pos, value) in txns[txn_idx].iter().enumerate() {
+                    interner.observe(value, (txn_idx, pos));
+                }
+            });
+        });
+        interner.finalize(&thread_pool);
+        interner
+    }
+
+    #[test]
+    fn test_interner_numbers_by_first_occurrence() {
+        let txns = vec![vec![30, 10], vec![10, 20], vec![40, 30, 40]];
+        let interner = intern(&txns, 4);
+        assert_eq!(4, interner.len());
+        for (expected_idx, value) in [30, 10, 20, 40].into_iter().enumerate() {
+            assert_eq!(expected_idx, interner.idx_of(&value));
+            assert_eq!(value, *interner.value(expected_idx));
+        }
+    }
+
+    #[test]
+    fn test_interner_determinism_across_thread_counts() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let txns: Vec<Vec<u64>> = (0..10_000)
+            .map(|_| {
+                (0..rng.gen_range(1, 6))
+                    .map(|_| rng.gen_range(0, 5_000))
+                    .collect()
+            })
+            .collect();
+        let expected = intern(&txns, 1);
+        let expected_values: Vec<u64> = (0..expected.len())
+            .map(|idx| *expected.value(idx))
+            .collect();
+        for num_threads in [2, 4, 8, 32] {
+            let interner = intern(&txns, num_threads);
+            let values: Vec<u64> = (0..interner.len())
+                .map(|idx| *interner.value(idx))
+                .collect();
+            assert_eq!(expected_values, values);
+        }
+    }
+
+    #[test]
+    fn test_interner_incremental_finalize() {
+        let txns = vec![vec![30, 10], vec![10, 20], vec![40, 30, 40], vec![50, 20]];
+        let thread_pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut interner = ConcurrentInterner::new(16);
+        for batch in [0..1, 1..3, 3..3, 3..4] {
+            for txn_idx in batch {
+                for (pos, value) in txns[txn_idx].iter().enumerate() {
+                    interner.observe(value, (txn_idx, pos));
+                }
+            }
+            interner.finalize(&thread_pool);
+        }
+        let expected = intern(&txns, 2);
+        assert_eq!(expected.len(), interner.len());
+        for idx in 0..expected.len() {
+            assert_eq!(expected.value(idx), interner.value(idx));
+            assert_eq!(idx, interner.idx_of(expected.value(idx)));
+        }
+    }
+
+    #[test]
+    fn test_interner_clear() {
+        let mut interner = intern(&[vec![1, 2]], 2);
+        interner.clear();
+        assert!(interner.is_empty());
+        assert_eq!(0, interner.len());
+        interner.observe(&3, (0, 0));
+        interner.finalize(&ThreadPoolBuilder::new().build().unwrap());
+        assert_eq!(0, interner.idx_of(&3));
+    }
+}
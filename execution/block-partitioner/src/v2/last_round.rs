@@ -0,0 +1,108 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::v2::{
+    counters::MISC_TIMERS_SECONDS,
+    load_balance::longest_processing_time_first_with_loads,
+    state::PartitionState,
+    types::{PrePartitionedTxnIdx, SenderIdx, StorageKeyIdx},
+    union_find::UnionFind,
+    PartitionerV2,
+};
+use std::collections::{HashMap, HashSet};
+
+impl PartitionerV2 {
+    /// Spread the txns of the last round over the shards, instead of leaving them in their pre-partitioned shards,
+    /// where the txns that could not be untangled tend to pile up.
+    ///
+    /// The txns are first grouped into chains: two txns are in the same chain if they have the same sender,
+    /// or if they access a key that some txn of the last round writes.
+    /// Different chains never conflict, so short chains are assigned whole to the shards with LPT scheduling.
+    /// A chain longer than `num_txns / num_shards` is cut into consecutive pieces instead, piece i going to shard i:
+    /// the pieces are then executed in chain order, with cross-shard dependencies from one piece to the next.
+    pub(crate) fn spread_last_round(
+        state: &PartitionState,
+        remaining_txns: Vec<Vec<PrePartitionedTxnIdx>>,
+    ) -> Vec<Vec<PrePartitionedTxnIdx>> {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["balance_last_round"])
+            .start_timer();
+
+        let num_shards = remaining_txns.len();
+        let mut txn_idxs: Vec<PrePartitionedTxnIdx> =
+            remaining_txns.into_iter().flatten().collect();
+        txn_idxs.sort_unstable();
+        let num_txns = txn_idxs.len();
+        let ori_txn_idxs: Vec<_> = txn_idxs
+            .iter()
+            .map(|&txn_idx| state.ori_idxs_by_pre_partitioned[txn_idx])
+            .collect();
+
+        let written: HashSet<StorageKeyIdx> = ori_txn_idxs
+            .iter()
+            .flat_map(|&ori_txn_idx| {
+                state.write_sets[ori_txn_idx]
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let mut uf = UnionFind::new(num_txns);
+        let mut first_pos_by_sender: HashMap<SenderIdx, usize> = HashMap::new();
+        let mut first_pos_by_key: HashMap<StorageKeyIdx, usize> = HashMap::new();
+        for (pos, &ori_txn_idx) in ori_txn_idxs.iter().enumerate() {
+            let first_pos = *first_pos_by_sender
+                .entry(state.sender_idx(ori_txn_idx))
+                .or_insert(pos);
+            uf.union(pos, first_pos);
+            let write_set = state.write_sets[ori_txn_idx].read().unwrap();
+            let read_set = state.read_sets[ori_txn_idx].read().unwrap();
+            for &key_idx in write_set.iter().chain(read_set.iter()) {
+                if written.contains(&key_idx) {
+                    let first_pos = *first_pos_by_key.entry(key_idx).or_insert(pos);
+                    uf.union(pos, first_pos);
+                }
+            }
+        }
+
+        // Chains in the order of their first txn, each of them in txn order.
+        let mut chains: Vec<Vec<PrePartitionedTxnIdx>> = Vec::new();
+        let mut chain_idx_registry: HashMap<usize, usize> = HashMap::new();
+        for (pos, &txn_idx) in txn_idxs.iter().enumerate() {
+            let chain_idx = *chain_idx_registry.entry(uf.find(pos)).or_insert_with(|| {
+                chains.push(vec![]);
+                chains.len() - 1
+            });
+            chains[chain_idx].push(txn_idx);
+        }
+
+        let fair_share = num_txns.div_ceil(num_shards);
+        let (long_chains, short_chains): (Vec<_>, Vec<_>) = chains
+            .into_iter()
+            .partition(|chain| chain.len() > fair_share);
+        let mut balanced: Vec<Vec<PrePartitionedTxnIdx>> = vec![vec![]; num_shards];
+        let mut loads: Vec<u64> = vec![0; num_shards];
+        for chain in long_chains {
+            let piece_size = chain.len().div_ceil(num_shards);
+            for (shard_id, piece) in chain.chunks(piece_size).enumerate() {
+                balanced[shard_id].extend_from_slice(piece);
+                loads[shard_id] += piece.len() as u64;
+            }
+        }
+        let costs: Vec<u64> = short_chains
+            .iter()
+            .map(|chain| chain.len() as u64)
+            .collect();
+        let (_longest_pole, shard_ids) = longest_processing_time_first_with_loads(&costs, loads);
+        for (chain, shard_id) in short_chains.into_iter().zip(shard_ids) {
+            balanced[shard_id].extend(chain);
+        }
+        // Sorting keeps every chain, and every sender, in order.
+        for txn_idxs in balanced.iter_mut() {
+            txn_idxs.sort_unstable();
+        }
+        balanced
+    }
+}
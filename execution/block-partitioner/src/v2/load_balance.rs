@@ -2,17 +2,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use itertools::Itertools;
-use std::collections::BinaryHeap;
+use std::{cmp::Reverse, collections::BinaryHeap};
 
 /// The longest-processing-time-first algorithm that schedules some tasks into some workers in a load-balanced way.
 /// Time complexity: O(num_tasks * log2(num_workers))
 ///
+/// Among the least loaded workers, the one with the lowest id gets the task.
+///
 /// Read more at https://en.wikipedia.org/wiki/Longest-processing-time-first_scheduling.
 pub fn longest_processing_time_first(
     task_costs: &Vec<u64>,
     num_workers: usize,
 ) -> (u64, Vec<usize>) {
-    assert!(num_workers >= 1);
+    longest_processing_time_first_with_loads(task_costs, vec![0; num_workers])
+}
+
+/// Same as `longest_processing_time_first()`, but worker i is already loaded with `initial_loads[i]`.
+pub fn longest_processing_time_first_with_loads(
+    task_costs: &[u64],
+    initial_loads: Vec<u64>,
+) -> (u64, Vec<usize>) {
+    assert!(!initial_loads.is_empty());
     let num_tasks = task_costs.len();
     let mut cost_tid_pairs: Vec<(u64, usize)> = task_costs
         .iter()
@@ -20,14 +30,19 @@ pub fn longest_processing_time_first(
         .map(|(tid, cost)| (*cost, tid))
         .collect();
     cost_tid_pairs.sort_by(|a, b| b.cmp(a));
-    let mut worker_prio_heap: BinaryHeap<(u64, usize)> =
-        BinaryHeap::from((0..num_workers).map(|wid| (u64::MAX, wid)).collect_vec());
+    let mut worker_prio_heap: BinaryHeap<(u64, Reverse<usize>)> = BinaryHeap::from(
+        initial_loads
+            .into_iter()
+            .enumerate()
+            .map(|(wid, load)| (u64::MAX - load, Reverse(wid)))
+            .collect_vec(),
+    );
     let mut worker_ids_by_tid = vec![usize::MAX; num_tasks];
     for (cost, tid) in cost_tid_pairs.into_iter() {
-        let (availability, worker_id) = worker_prio_heap.pop().unwrap();
+        let (availability, Reverse(worker_id)) = worker_prio_heap.pop().unwrap();
         worker_ids_by_tid[tid] = worker_id;
         let new_availability = availability - cost;
-        worker_prio_heap.push((new_availability, worker_id));
+        worker_prio_heap.push((new_availability, Reverse(worker_id)));
     }
     let longest_pole = worker_prio_heap
         .into_iter()
@@ -58,3 +73,15 @@ fn test_longest_processing_time_first() {
     assert_eq!(17, actual);
     println!("{:?}", assignment);
 }
+
+#[test]
+fn test_longest_processing_time_first_with_loads() {
+    let (actual, assignment) = longest_processing_time_first_with_loads(&[1, 2, 3], vec![10, 0]);
+    assert_eq!(10, actual);
+    assert_eq!(vec![1, 1, 1], assignment);
+    let (actual, assignment) = longest_processing_time_first_with_loads(&[3, 3], vec![0, 2, 2]);
+    assert_eq!(5, actual);
+    assert_eq!(vec![1, 0], assignment);
+    let (_, assignment) = longest_processing_time_first(&vec![1], 3);
+    assert_eq!(vec![0], assignment);
+}
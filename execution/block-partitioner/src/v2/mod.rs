@@ -2,21 +2,45 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
-    pre_partition::PrePartitioner, v2::counters::BLOCK_PARTITIONING_SECONDS, BlockPartitioner,
+    no_op::NoOpPartitioner,
+    pre_partition::{uniform_partitioner::UniformPartitioner, PrePartitioner},
+    report::PartitionReport,
+    v2::{
+        anchor::{AnchorStrategy, HashAnchorStrategy},
+        budget::PartitioningBudget,
+        counters::{
+            BLOCK_PARTITIONING_SECONDS, MISC_TIMERS_SECONDS, TRUNCATED_BY_BUDGET_COUNT,
+            UNSHARDED_FALLBACK_COUNT,
+        },
+        deferral::DeferralReportConfig,
+        fallback::UnshardedFallbackConfig,
+    },
+    verify::verify_partition,
+    BlockPartitioner,
 };
+use aptos_logger::trace;
 use aptos_types::{
-    block_executor::partitioner::{PartitionedTransactions, RoundId},
+    block_executor::partitioner::{PartitionedTransactions, RoundId, ShardId},
     transaction::analyzed_transaction::AnalyzedTransaction,
 };
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use state::PartitionState;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
+mod affinity;
+pub mod anchor;
+pub mod budget;
 mod build_edge;
 pub mod config;
 mod conflicting_txn_tracker;
 pub mod counters;
+pub mod deferral;
+pub mod fallback;
+mod fast_path;
+pub mod incremental;
 mod init;
+pub mod interner;
+mod last_round;
 pub(crate) mod load_balance;
 mod partition_to_matrix;
 pub(crate) mod state;
@@ -101,6 +125,27 @@ pub struct PartitionerV2 {
     cross_shard_dep_avoid_threshold: f32,
     dashmap_num_shards: usize,
     partition_last_round: bool,
+    /// If set (with `partition_last_round`), the last round is rearranged by `spread_last_round()`.
+    balance_last_round: bool,
+    anchor_strategy: Arc<dyn AnchorStrategy>,
+    /// If set, a shard accepts at most this many txns in a discarding round. The rest is deferred to the next round.
+    max_txns_per_shard_per_round: Option<usize>,
+    /// If set, blocks with too many conflicts are not partitioned, see `UnshardedFallbackConfig`.
+    unsharded_fallback: Option<UnshardedFallbackConfig>,
+    budget: Option<PartitioningBudget>,
+    deferral_report: DeferralReportConfig,
+    /// A txn is moved to the shard suggested by its affinity hint only if that shard then has
+    /// at most `affinity_load_imbalance_tolerance * block_size / num_shards` txns.
+    affinity_load_imbalance_tolerance: f32,
+    /// If set, a `PartitionState` is cleared (instead of dropped) after use and reused by the next block.
+    /// On by default, as it only saves allocations: the output is the same either way.
+    state_pooling: bool,
+    state_pool: Arc<Mutex<Vec<PartitionState>>>,
+    /// If set, every output is checked with `verify_partition()`, and a violation is a panic.
+    /// Off by default, so that debug and release builds partition alike.
+    verify_output: bool,
+    /// If set, empty and single-txn blocks skip the analysis, see `can_skip_analysis()`.
+    fast_paths: bool,
 }
 
 impl PartitionerV2 {
@@ -125,36 +170,147 @@ impl PartitionerV2 {
             cross_shard_dep_avoid_threshold,
             dashmap_num_shards,
             partition_last_round,
+            balance_last_round: false,
+            anchor_strategy: Arc::new(HashAnchorStrategy {}),
+            max_txns_per_shard_per_round: None,
+            unsharded_fallback: None,
+            budget: None,
+            deferral_report: DeferralReportConfig::default(),
+            affinity_load_imbalance_tolerance: 1.2,
+            state_pooling: true,
+            state_pool: Arc::new(Mutex::new(vec![])),
+            verify_output: false,
+            fast_paths: true,
         }
     }
-}
 
-impl BlockPartitioner for PartitionerV2 {
-    fn partition(
+    pub fn balance_last_round(mut self, val: bool) -> Self {
+        self.balance_last_round = val;
+        self
+    }
+
+    pub fn anchor_strategy(mut self, val: Arc<dyn AnchorStrategy>) -> Self {
+        self.anchor_strategy = val;
+        self
+    }
+
+    pub fn max_txns_per_shard_per_round(mut self, val: Option<usize>) -> Self {
+        self.max_txns_per_shard_per_round = val;
+        self
+    }
+
+    pub fn unsharded_fallback(mut self, val: Option<UnshardedFallbackConfig>) -> Self {
+        self.unsharded_fallback = val;
+        self
+    }
+
+    pub fn budget(mut self, val: Option<PartitioningBudget>) -> Self {
+        self.budget = val;
+        self
+    }
+
+    pub fn deferral_report(mut self, val: DeferralReportConfig) -> Self {
+        self.deferral_report = val;
+        self
+    }
+
+    pub fn affinity_load_imbalance_tolerance(mut self, val: f32) -> Self {
+        self.affinity_load_imbalance_tolerance = val;
+        self
+    }
+
+    pub fn state_pooling(mut self, val: bool) -> Self {
+        self.state_pooling = val;
+        self
+    }
+
+    pub fn verify_output(mut self, val: bool) -> Self {
+        self.verify_output = val;
+        self
+    }
+
+    /// Run every block through the full pipeline, so tests can compare it with the fast paths.
+    #[cfg(test)]
+    pub(crate) fn without_fast_paths(mut self) -> Self {
+        self.fast_paths = false;
+        self
+    }
+
+    fn should_fall_back(&self, txns: &[AnalyzedTransaction]) -> bool {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["unsharded_fallback_check"])
+            .start_timer();
+        self.unsharded_fallback
+            .map_or(false, |fallback| fallback.should_fall_back(txns))
+    }
+
+    fn take_state(
         &self,
         txns: Vec<AnalyzedTransaction>,
         num_executor_shards: usize,
-    ) -> PartitionedTransactions {
-        let _timer = BLOCK_PARTITIONING_SECONDS.start_timer();
+    ) -> PartitionState {
+        let pooled_state = if self.state_pooling {
+            self.state_pool.lock().unwrap().pop()
+        } else {
+            None
+        };
+        match pooled_state {
+            Some(mut state) => {
+                state.reset(txns, num_executor_shards);
+                state
+            },
+            None => PartitionState::new(
+                self.thread_pool.clone(),
+                self.dashmap_num_shards,
+                txns,
+                num_executor_shards,
+                self.max_partitioning_rounds,
+                self.cross_shard_dep_avoid_threshold,
+                self.partition_last_round,
+                self.balance_last_round,
+                self.anchor_strategy.clone(),
+                self.max_txns_per_shard_per_round,
+                self.budget,
+                self.deferral_report,
+            ),
+        }
+    }
 
-        let mut state = PartitionState::new(
-            self.thread_pool.clone(),
-            self.dashmap_num_shards,
-            txns,
-            num_executor_shards,
-            self.max_partitioning_rounds,
-            self.cross_shard_dep_avoid_threshold,
-            self.partition_last_round,
-        );
+    fn partition_with_analysis(
+        &self,
+        txns: Vec<AnalyzedTransaction>,
+        num_executor_shards: usize,
+        affinity: Option<Vec<Option<ShardId>>>,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let mut state = self.take_state(txns, num_executor_shards);
+        state.affinity = affinity.unwrap_or_default();
         // Step 1: build some necessary indices for txn senders/storage locations.
         Self::init(&mut state);
+        self.partition_initialized(state)
+    }
+
+    /// Steps 2 to 6 of the partitioning, once all the txns of the state went through `init()`.
+    fn partition_initialized(
+        &self,
+        mut state: PartitionState,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        state.budget.charge(state.num_txns());
 
-        // Step 2: pre-partition.
+        // Step 2: pre-partition. With the budget exhausted by init, the txns are split evenly
+        // instead, as the rounds that would benefit from a better pre-partitioning are skipped.
+        let pre_partitioner: &dyn PrePartitioner = if state.budget.exhausted() {
+            trace!("Partitioning budget exhausted after init.");
+            state.report.get_mut().unwrap().truncated_by_budget = true;
+            &UniformPartitioner {}
+        } else {
+            self.pre_partitioner.as_ref()
+        };
         (
             state.ori_idxs_by_pre_partitioned,
             state.start_txn_idxs_by_shard,
             state.pre_partitioned,
-        ) = self.pre_partitioner.pre_partition(&state);
+        ) = pre_partitioner.pre_partition(&state);
+        Self::apply_affinity(&mut state, self.affinity_load_imbalance_tolerance);
 
         // Step 3: update trackers.
         for txn_idx1 in 0..state.num_txns() {
@@ -173,8 +329,12 @@ impl BlockPartitioner for PartitionerV2 {
                 }
             }
         }
+        state.budget.charge(state.num_txns());
+        Self::rebalance_anchors(&mut state);
 
-        // Step 4: remove cross-shard dependencies by move some txns into new rounds.
+        // Step 4: remove cross-shard dependencies by move some txns into new rounds. The rounds check
+        // the budget chunk by chunk, so with the budget exhausted by now, all the txns go to the last
+        // round, and a round the budget runs out in defers the txns it did not get to.
         // As a result, we get a txn matrix of no more than `self.max_partitioning_rounds` rows and exactly `num_executor_shards` columns.
         // It's guaranteed that inside every round other than the last round, there's no cross-shard dependency. (But cross-round dependencies are always possible.)
         Self::remove_cross_shard_dependencies(&mut state);
@@ -183,13 +343,91 @@ impl BlockPartitioner for PartitionerV2 {
         Self::build_index_from_txn_matrix(&mut state);
 
         // Step 6: calculate all the cross-shard dependencies and prepare the input for sharded execution.
+        // The txns are moved out of the state.
         let ret = Self::add_edges(&mut state);
+        let report = std::mem::take(state.report.get_mut().unwrap());
+        self.recycle_state(state);
+        (ret, report)
+    }
+
+    /// Async clean-up.
+    fn recycle_state(&self, mut state: PartitionState) {
+        if self.state_pooling {
+            let state_pool = self.state_pool.clone();
+            self.thread_pool.spawn(move || {
+                state.clear();
+                state_pool.lock().unwrap().push(state);
+            });
+        } else {
+            self.thread_pool.spawn(move || {
+                drop(state);
+            });
+        }
+    }
+
+    /// The checks and the logging done on every output computed by the partitioner.
+    fn finish_block(
+        &self,
+        ret: &PartitionedTransactions,
+        report: &PartitionReport,
+        num_txns: usize,
+    ) {
+        if self.verify_output {
+            let _timer = MISC_TIMERS_SECONDS
+                .with_label_values(&["verify_output"])
+                .start_timer();
+            if let Err(violation) = verify_partition(ret.sharded_txns()) {
+                panic!("PartitionerV2 produced an invalid partition: {}", violation);
+            }
+        }
+        #[cfg(feature = "debug-dump")]
+        crate::debug_dump::maybe_dump_partition_debug(ret.sharded_txns());
+        if report.truncated_by_budget {
+            TRUNCATED_BY_BUDGET_COUNT.inc();
+        }
+        self.deferral_report.maybe_log_summary(report, num_txns);
+    }
+}
+
+impl BlockPartitioner for PartitionerV2 {
+    fn partition(
+        &self,
+        txns: Vec<AnalyzedTransaction>,
+        num_executor_shards: usize,
+    ) -> PartitionedTransactions {
+        self.partition_with_report(txns, num_executor_shards).0
+    }
+
+    fn partition_with_report(
+        &self,
+        txns: Vec<AnalyzedTransaction>,
+        num_executor_shards: usize,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        self.partition_with_affinity(txns, num_executor_shards, None)
+    }
+
+    fn partition_with_affinity(
+        &self,
+        txns: Vec<AnalyzedTransaction>,
+        num_executor_shards: usize,
+        affinity: Option<Vec<Option<ShardId>>>,
+    ) -> (PartitionedTransactions, PartitionReport) {
+        let _timer = BLOCK_PARTITIONING_SECONDS.start_timer();
 
-        // Async clean-up.
-        self.thread_pool.spawn(move || {
-            drop(state);
-        });
-        ret
+        let num_txns = txns.len();
+        let (ret, report) = if self.should_fall_back(&txns) {
+            UNSHARDED_FALLBACK_COUNT.inc();
+            let (partitioned, mut report) =
+                NoOpPartitioner {}.partition_with_affinity(txns, num_executor_shards, affinity);
+            report.unsharded_fallback = true;
+            (partitioned, report)
+        } else if self.can_skip_analysis(&txns, affinity.as_deref()) {
+            self.partition_trivial_block(txns, num_executor_shards)
+        } else {
+            self.partition_with_analysis(txns, num_executor_shards, affinity)
+        };
+        self.finish_block(&ret, &report, num_txns);
+        (ret, report)
     }
 }
 
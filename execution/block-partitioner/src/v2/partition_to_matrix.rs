@@ -1,21 +1,29 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::v2::{
-    counters::MISC_TIMERS_SECONDS,
-    extract_and_sort,
-    state::PartitionState,
-    types::{PrePartitionedTxnIdx, SenderIdx},
-    PartitionerV2,
+use crate::{
+    report::{PartitionReport, RoundReport},
+    v2::{
+        counters::MISC_TIMERS_SECONDS,
+        extract_and_sort,
+        state::PartitionState,
+        types::{PrePartitionedTxnIdx, SenderIdx, StorageKeyIdx},
+        PartitionerV2,
+    },
 };
 use aptos_logger::trace;
-use aptos_types::block_executor::partitioner::{RoundId, TxnIndex};
+use aptos_types::{
+    block_executor::partitioner::{RoundId, ShardId, TxnIndex},
+    state_store::state_key::StateKey,
+};
 use dashmap::DashMap;
 use rayon::{
     iter::ParallelIterator,
     prelude::{IntoParallelIterator, IntoParallelRefIterator},
 };
 use std::{
+    cmp::Reverse,
+    collections::HashMap,
     mem,
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -36,14 +44,21 @@ impl PartitionerV2 {
 
         let mut num_remaining_txns: usize;
         for round_id in 0..(state.num_rounds_limit - 1) {
+            if state.budget.exhausted() {
+                trace!("Partitioning budget exhausted before round {}.", round_id);
+                state.report.get_mut().unwrap().truncated_by_budget = true;
+                break;
+            }
             let (accepted, discarded) = Self::discarding_round(state, round_id, remaining_txns);
             state.finalized_txn_matrix.push(accepted);
             remaining_txns = discarded;
             num_remaining_txns = remaining_txns.iter().map(|ts| ts.len()).sum();
 
-            if num_remaining_txns
-                < ((1.0 - state.cross_shard_dep_avoid_threshold) * state.num_txns() as f32) as usize
-            {
+            if Self::discarding_done(
+                state.cross_shard_dep_avoid_threshold,
+                state.num_txns(),
+                num_remaining_txns,
+            ) {
                 break;
             }
         }
@@ -58,9 +73,17 @@ impl PartitionerV2 {
                 remaining_txns.into_iter().flatten().collect();
             remaining_txns = vec![vec![]; state.num_executor_shards];
             remaining_txns[state.num_executor_shards - 1] = last_round_txns;
+        } else if state.balance_last_round {
+            remaining_txns = Self::spread_last_round(state, remaining_txns);
         }
 
         let last_round_id = state.finalized_txn_matrix.len();
+        Self::report_last_round(
+            state.report.get_mut().unwrap(),
+            state.partition_last_round,
+            last_round_id,
+            remaining_txns.iter().map(|ts| ts.len()).collect(),
+        );
         state.thread_pool.install(|| {
             (0..state.num_executor_shards)
                 .into_par_iter()
@@ -73,8 +96,71 @@ impl PartitionerV2 {
         state.finalized_txn_matrix.push(remaining_txns);
     }
 
+    /// Whether the discarding rounds can stop, with `num_remaining_txns` of the `num_txns` txns of
+    /// the block left for the next rounds.
+    pub(crate) fn discarding_done(
+        cross_shard_dep_avoid_threshold: f32,
+        num_txns: usize,
+        num_remaining_txns: usize,
+    ) -> bool {
+        num_remaining_txns < ((1.0 - cross_shard_dep_avoid_threshold) * num_txns as f32) as usize
+    }
+
+    /// The number of discarding rounds `remove_cross_shard_dependencies()` runs for a block of
+    /// `num_txns` txns that none of the rounds defers, without a budget.
+    pub(crate) fn num_discarding_rounds_without_deferral(
+        num_rounds_limit: usize,
+        cross_shard_dep_avoid_threshold: f32,
+        num_txns: usize,
+    ) -> usize {
+        let mut num_discarding_rounds = 0;
+        while num_discarding_rounds < num_rounds_limit - 1 {
+            num_discarding_rounds += 1;
+            if Self::discarding_done(cross_shard_dep_avoid_threshold, num_txns, 0) {
+                break;
+            }
+        }
+        num_discarding_rounds
+    }
+
+    /// The report of a round that accepted `num_accepted_txns` of the `num_input_txns` txns given
+    /// to it.
+    pub(crate) fn round_report(
+        round_id: RoundId,
+        num_input_txns: usize,
+        num_accepted_txns: usize,
+        top_conflicting_keys: Vec<(StateKey, usize)>,
+    ) -> RoundReport {
+        RoundReport {
+            num_txns: num_accepted_txns,
+            num_txns_deferred_from_previous_rounds: if round_id == 0 { 0 } else { num_input_txns },
+            top_conflicting_keys,
+        }
+    }
+
+    /// Add the last round to `report`, given the number of its txns in each shard.
+    pub(crate) fn report_last_round(
+        report: &mut PartitionReport,
+        partition_last_round: bool,
+        last_round_id: RoundId,
+        num_last_round_txns_by_shard: Vec<usize>,
+    ) {
+        let num_last_round_txns = num_last_round_txns_by_shard.iter().sum();
+        report.rounds.push(Self::round_report(
+            last_round_id,
+            num_last_round_txns,
+            num_last_round_txns,
+            vec![],
+        ));
+        if partition_last_round {
+            report.num_last_round_txns_by_shard = num_last_round_txns_by_shard;
+        }
+    }
+
     /// Given some pre-partitioned txns, pull some off from each shard to avoid cross-shard conflict.
     /// The pulled off txns become the pre-partitioned txns for the next round.
+    /// The txns are checked for conflicts chunk by chunk, charging the budget. If it runs out, the
+    /// txns not checked yet are pulled off too.
     pub(crate) fn discarding_round(
         state: &mut PartitionState,
         round_id: RoundId,
@@ -88,6 +174,7 @@ impl PartitionerV2 {
             .start_timer();
 
         let num_shards = remaining_txns.len();
+        let num_input_txns: usize = remaining_txns.iter().map(|ts| ts.len()).sum();
 
         // Overview of the logic:
         // 1. Key conflicts are analyzed and a txn from `remaining_txns` either goes to `discarded` or `tentatively_accepted`.
@@ -108,35 +195,65 @@ impl PartitionerV2 {
         let min_discard_table: DashMap<SenderIdx, AtomicUsize> =
             DashMap::with_shard_amount(state.dashmap_num_shards);
 
-        state.thread_pool.install(|| {
-            // Move some txns to the next round (stored in `discarded`).
-            // For those who remain in the current round (`tentatively_accepted`),
-            // it's guaranteed to have no cross-shard conflicts.
-            remaining_txns
-                .into_iter()
+        // For each key, the number of txns discarded because of a conflict on it.
+        let conflicts_by_key: DashMap<StorageKeyIdx, usize> =
+            DashMap::with_shard_amount(state.dashmap_num_shards);
+
+        let discard = |shard_id: ShardId, txn_idx: PrePartitionedTxnIdx, sender: SenderIdx| {
+            min_discard_table
+                .entry(sender)
+                .or_insert_with(|| AtomicUsize::new(usize::MAX))
+                .fetch_min(txn_idx, Ordering::SeqCst);
+            discarded[shard_id].write().unwrap().push(txn_idx);
+        };
+
+        // Move some txns to the next round (stored in `discarded`).
+        // For those who remain in the current round (`tentatively_accepted`),
+        // it's guaranteed to have no cross-shard conflicts.
+        // The chunks take the same positions in every shard, for a round cut short by the budget
+        // to still have txns in every shard.
+        let chunk_size = state.budget.chunk_size();
+        let max_num_shard_txns = remaining_txns.iter().map(|ts| ts.len()).max().unwrap_or(0);
+        let mut num_checked_per_shard = 0;
+        while num_checked_per_shard < max_num_shard_txns {
+            if state.budget.exhausted() {
+                trace!(
+                    "Partitioning budget exhausted in round {}, after {} txns per shard.",
+                    round_id,
+                    num_checked_per_shard
+                );
+                state.report.get_mut().unwrap().truncated_by_budget = true;
+                break;
+            }
+            let chunk_end = num_checked_per_shard.saturating_add(chunk_size);
+            let chunks: Vec<(ShardId, &[PrePartitionedTxnIdx])> = remaining_txns
+                .iter()
+                .map(|txn_idxs| {
+                    let num_txns = txn_idxs.len();
+                    &txn_idxs[num_checked_per_shard.min(num_txns)..chunk_end.min(num_txns)]
+                })
                 .enumerate()
-                .collect::<Vec<_>>()
-                .into_par_iter()
-                .for_each(|(shard_id, txn_idxs)| {
-                    txn_idxs.into_par_iter().for_each(|txn_idx| {
+                .collect();
+            state
+                .budget
+                .charge(chunks.iter().map(|(_, txn_idxs)| txn_idxs.len()).sum());
+            state.thread_pool.install(|| {
+                chunks.into_par_iter().for_each(|(shard_id, txn_idxs)| {
+                    txn_idxs.par_iter().for_each(|&txn_idx| {
                         let ori_txn_idx = state.ori_idxs_by_pre_partitioned[txn_idx];
                         let mut in_round_conflict_detected = false;
                         let write_set = state.write_sets[ori_txn_idx].read().unwrap();
                         let read_set = state.read_sets[ori_txn_idx].read().unwrap();
+                        // No early exit: every conflicting key is counted in the report.
                         for &key_idx in write_set.iter().chain(read_set.iter()) {
                             if state.key_owned_by_another_shard(shard_id, key_idx) {
                                 in_round_conflict_detected = true;
-                                break;
+                                *conflicts_by_key.entry(key_idx).or_insert(0) += 1;
                             }
                         }
 
                         if in_round_conflict_detected {
-                            let sender = state.sender_idx(ori_txn_idx);
-                            min_discard_table
-                                .entry(sender)
-                                .or_insert_with(|| AtomicUsize::new(usize::MAX))
-                                .fetch_min(txn_idx, Ordering::SeqCst);
-                            discarded[shard_id].write().unwrap().push(txn_idx);
+                            discard(shard_id, txn_idx, state.sender_idx(ori_txn_idx));
                         } else {
                             tentatively_accepted[shard_id]
                                 .write()
@@ -145,7 +262,18 @@ impl PartitionerV2 {
                         }
                     });
                 });
+            });
+            num_checked_per_shard = chunk_end;
+        }
+        // The txns the budget did not leave time to check.
+        for (shard_id, txn_idxs) in remaining_txns.iter().enumerate() {
+            for &txn_idx in txn_idxs.iter().skip(num_checked_per_shard) {
+                let ori_txn_idx = state.ori_idxs_by_pre_partitioned[txn_idx];
+                discard(shard_id, txn_idx, state.sender_idx(ori_txn_idx));
+            }
+        }
 
+        state.thread_pool.install(|| {
             // Additional discarding to preserve relative txn order for the same sender.
             tentatively_accepted
                 .into_iter()
@@ -162,7 +290,6 @@ impl PartitionerV2 {
                             .map(|kv| kv.load(Ordering::SeqCst))
                             .unwrap_or(usize::MAX);
                         if txn_idx < min_discarded {
-                            state.update_trackers_on_accepting(txn_idx, round_id, shard_id);
                             finally_accepted[shard_id].write().unwrap().push(txn_idx);
                         } else {
                             discarded[shard_id].write().unwrap().push(txn_idx);
@@ -175,10 +302,92 @@ impl PartitionerV2 {
             drop(min_discard_table);
         });
 
-        (
-            extract_and_sort(finally_accepted),
-            extract_and_sort(discarded),
-        )
+        let mut finally_accepted = extract_and_sort(finally_accepted);
+        let mut discarded = extract_and_sort(discarded);
+        if let Some(cap) = state.max_txns_per_shard_per_round {
+            Self::apply_sub_block_cap(state, cap, &mut finally_accepted, &mut discarded);
+        }
+
+        let mut top_conflicting_keys: Vec<(StorageKeyIdx, usize)> =
+            conflicts_by_key.into_iter().collect();
+        top_conflicting_keys
+            .sort_unstable_by_key(|&(key_idx, num_conflicts)| (Reverse(num_conflicts), key_idx));
+        top_conflicting_keys.truncate(state.deferral_report.num_top_keys);
+        let round_report = Self::round_report(
+            round_id,
+            num_input_txns,
+            finally_accepted.iter().map(|ts| ts.len()).sum(),
+            top_conflicting_keys
+                .into_iter()
+                .map(|(key_idx, num_conflicts)| (state.keys.value(key_idx).clone(), num_conflicts))
+                .collect(),
+        );
+        state.report.get_mut().unwrap().rounds.push(round_report);
+
+        state.thread_pool.install(|| {
+            finally_accepted
+                .par_iter()
+                .enumerate()
+                .for_each(|(shard_id, txn_idxs)| {
+                    txn_idxs.par_iter().for_each(|&txn_idx| {
+                        state.update_trackers_on_accepting(txn_idx, round_id, shard_id);
+                    });
+                });
+        });
+
+        (finally_accepted, discarded)
+    }
+
+    /// Defer the accepted txns of a shard beyond `cap` to the next round.
+    /// Later txns from the same senders are deferred as well, so the relative order of a sender's txns is preserved.
+    fn apply_sub_block_cap(
+        state: &PartitionState,
+        cap: usize,
+        accepted: &mut [Vec<PrePartitionedTxnIdx>],
+        discarded: &mut [Vec<PrePartitionedTxnIdx>],
+    ) {
+        let mut min_deferred_by_sender: HashMap<SenderIdx, PrePartitionedTxnIdx> = HashMap::new();
+        let mut num_capped_sub_blocks = 0;
+        let mut num_txns_deferred = 0;
+        for (shard_id, txn_idxs) in accepted.iter_mut().enumerate() {
+            if txn_idxs.len() <= cap {
+                continue;
+            }
+            num_capped_sub_blocks += 1;
+            for txn_idx in txn_idxs.drain(cap..) {
+                let sender_idx = state.sender_idx(state.ori_idxs_by_pre_partitioned[txn_idx]);
+                min_deferred_by_sender
+                    .entry(sender_idx)
+                    .and_modify(|min_deferred| *min_deferred = (*min_deferred).min(txn_idx))
+                    .or_insert(txn_idx);
+                discarded[shard_id].push(txn_idx);
+                num_txns_deferred += 1;
+            }
+        }
+        if num_capped_sub_blocks == 0 {
+            return;
+        }
+
+        for (shard_id, txn_idxs) in accepted.iter_mut().enumerate() {
+            txn_idxs.retain(|&txn_idx| {
+                let sender_idx = state.sender_idx(state.ori_idxs_by_pre_partitioned[txn_idx]);
+                let keep = min_deferred_by_sender
+                    .get(&sender_idx)
+                    .map_or(true, |&min_deferred| txn_idx < min_deferred);
+                if !keep {
+                    discarded[shard_id].push(txn_idx);
+                    num_txns_deferred += 1;
+                }
+                keep
+            });
+        }
+        for txn_idxs in discarded.iter_mut() {
+            txn_idxs.sort_unstable();
+        }
+
+        let mut report = state.report.lock().unwrap();
+        report.num_capped_sub_blocks += num_capped_sub_blocks;
+        report.num_txns_deferred_by_cap += num_txns_deferred;
     }
 
     pub(crate) fn build_index_from_txn_matrix(state: &mut PartitionState) {
@@ -4,9 +4,14 @@
 #![allow(unused_variables)]
 
 use crate::{
+    report::PartitionReport,
     v2::{
+        anchor::AnchorStrategy,
+        budget::{BudgetTracker, PartitioningBudget},
         conflicting_txn_tracker::ConflictingTxnTracker,
         counters::MISC_TIMERS_SECONDS,
+        deferral::DeferralReportConfig,
+        interner::ConcurrentInterner,
         types::{
             FinalTxnIdx, OriginalTxnIdx, PrePartitionedTxnIdx, SenderIdx, ShardedTxnIndexV2,
             StorageKeyIdx, SubBlockIdx,
@@ -15,24 +20,15 @@ use crate::{
     Sender,
 };
 use aptos_types::{
-    block_executor::partitioner::{
-        CrossShardDependencies, RoundId, ShardId, ShardedTxnIndex, SubBlock,
-        TransactionWithDependencies,
-    },
+    block_executor::partitioner::{CrossShardDependencies, RoundId, ShardId, ShardedTxnIndex},
     state_store::state_key::StateKey,
     transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
 };
 use dashmap::DashMap;
-use rayon::{
-    iter::{IntoParallelIterator, ParallelIterator},
-    ThreadPool,
-};
+use rayon::ThreadPool;
 use std::{
     collections::HashSet,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, RwLock,
-    },
+    sync::{Arc, Mutex, RwLock},
 };
 
 /// All the parameters, indexes, temporary states needed in a `PartitionerV2` session,
@@ -46,10 +42,17 @@ pub struct PartitionState {
     pub(crate) dashmap_num_shards: usize,
     pub(crate) cross_shard_dep_avoid_threshold: f32,
     pub(crate) partition_last_round: bool,
+    pub(crate) balance_last_round: bool,
+    pub(crate) anchor_strategy: Arc<dyn AnchorStrategy>,
+    pub(crate) max_txns_per_shard_per_round: Option<usize>,
+    pub(crate) budget: BudgetTracker,
+    pub(crate) deferral_report: DeferralReportConfig,
     pub(crate) thread_pool: Arc<ThreadPool>,
     /// OriginalTxnIdx -> the actual txn.
-    /// Wrapped in `RwLock` to allow being taking in parallel in `add_edges` phase and parallel reads in other phases.
-    pub(crate) txns: Vec<RwLock<Option<AnalyzedTransaction>>>,
+    /// Only read until `add_edges()`, which takes all of them at once.
+    pub(crate) txns: Vec<AnalyzedTransaction>,
+    /// OriginalTxnIdx -> the preferred shard of the txn, if any. Empty if the caller gave no preference.
+    pub(crate) affinity: Vec<Option<ShardId>>,
     //
     // Initial params/utils ends.
     //
@@ -69,11 +72,11 @@ pub struct PartitionState {
     /// For txn of OriginalTxnIdx i, the read set.
     pub(crate) read_sets: Vec<RwLock<HashSet<StorageKeyIdx>>>,
 
-    pub(crate) sender_counter: AtomicUsize,
-    pub(crate) sender_idx_table: DashMap<Sender, SenderIdx>,
+    /// Sender <-> SenderIdx, numbered by the first txn of each sender.
+    pub(crate) senders: ConcurrentInterner<Sender>,
 
-    pub(crate) storage_key_counter: AtomicUsize,
-    pub(crate) key_idx_table: DashMap<StateKey, StorageKeyIdx>,
+    /// StateKey <-> StorageKeyIdx, numbered by the first access to each key.
+    pub(crate) keys: ConcurrentInterner<StateKey>,
 
     //
     // States computed in `init()` end.
@@ -100,9 +103,8 @@ pub struct PartitionState {
     //
     // States computed in `remove_cross_shard_dependencies()` end.
     //
-
-    // Temporary sub-block matrix used in `add_edges()`.
-    pub(crate) sub_block_matrix: Vec<Vec<Mutex<Option<SubBlock<AnalyzedTransaction>>>>>,
+    /// Statistics of the session, filled by the different phases (including the pre-partitioner).
+    pub(crate) report: Mutex<PartitionReport>,
 }
 
 /// Some utils.
@@ -115,20 +117,19 @@ impl PartitionState {
         num_rounds_limit: usize,
         cross_shard_dep_avoid_threshold: f32,
         partition_last_round: bool,
+        balance_last_round: bool,
+        anchor_strategy: Arc<dyn AnchorStrategy>,
+        max_txns_per_shard_per_round: Option<usize>,
+        budget: Option<PartitioningBudget>,
+        deferral_report: DeferralReportConfig,
     ) -> Self {
         let _timer = MISC_TIMERS_SECONDS
             .with_label_values(&["new"])
             .start_timer();
         let num_txns = txns.len();
-        let sender_counter = AtomicUsize::new(0);
-        let key_counter = AtomicUsize::new(0);
         let mut senders: Vec<RwLock<Option<SenderIdx>>> = Vec::with_capacity(num_txns);
         let mut wsets: Vec<RwLock<HashSet<StorageKeyIdx>>> = Vec::with_capacity(num_txns);
         let mut rsets: Vec<RwLock<HashSet<StorageKeyIdx>>> = Vec::with_capacity(num_txns);
-        let sender_idx_table: DashMap<Sender, SenderIdx> =
-            DashMap::with_shard_amount(dashmap_num_shards);
-        let key_idx_table: DashMap<StateKey, StorageKeyIdx> =
-            DashMap::with_shard_amount(dashmap_num_shards);
         let trackers: DashMap<StorageKeyIdx, RwLock<ConflictingTxnTracker>> =
             DashMap::with_shard_amount(dashmap_num_shards);
         for txn in txns.iter() {
@@ -136,55 +137,157 @@ impl PartitionState {
             wsets.push(RwLock::new(HashSet::with_capacity(txn.write_hints().len())));
             rsets.push(RwLock::new(HashSet::with_capacity(txn.read_hints().len())));
         }
-        let takable_txns = thread_pool.install(|| {
-            txns.into_par_iter()
-                .map(|txn| RwLock::new(Some(txn)))
-                .collect()
-        });
-
         Self {
             dashmap_num_shards,
             partition_last_round,
+            balance_last_round,
+            anchor_strategy,
+            max_txns_per_shard_per_round,
+            budget: BudgetTracker::new(budget),
+            deferral_report,
             thread_pool,
             num_executor_shards,
             pre_partitioned: vec![],
             start_txn_idxs_by_shard: vec![0; num_executor_shards],
-            sender_counter,
-            storage_key_counter: key_counter,
             sender_idxs: senders,
             write_sets: wsets,
             read_sets: rsets,
-            sender_idx_table,
-            key_idx_table,
+            senders: ConcurrentInterner::new(dashmap_num_shards),
+            keys: ConcurrentInterner::new(dashmap_num_shards),
             trackers,
             cross_shard_dep_avoid_threshold,
             num_rounds_limit,
             finalized_txn_matrix: Vec::with_capacity(num_rounds_limit),
             final_idxs_by_pre_partitioned: vec![],
             start_index_matrix: vec![],
-            txns: takable_txns,
-            sub_block_matrix: vec![],
+            txns,
+            affinity: vec![],
             ori_idxs_by_pre_partitioned: vec![0; num_txns],
+            report: Mutex::new(PartitionReport::default()),
         }
     }
 
+    /// Clear all the block-specific states but keep the allocated capacity,
+    /// so the state can be reused for the next block with `reset()`.
+    pub(crate) fn clear(&mut self) {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["clear"])
+            .start_timer();
+        self.txns.clear();
+        self.affinity.clear();
+        self.trackers.clear();
+        for sender_idx in self.sender_idxs.iter_mut() {
+            *sender_idx.get_mut().unwrap() = None;
+        }
+        for key_set in self.write_sets.iter_mut().chain(self.read_sets.iter_mut()) {
+            key_set.get_mut().unwrap().clear();
+        }
+        self.senders.clear();
+        self.keys.clear();
+        self.pre_partitioned.clear();
+        self.start_txn_idxs_by_shard.clear();
+        self.ori_idxs_by_pre_partitioned.clear();
+        self.finalized_txn_matrix.clear();
+        self.start_index_matrix.clear();
+        self.final_idxs_by_pre_partitioned.clear();
+        *self.report.get_mut().unwrap() = PartitionReport::default();
+    }
+
+    /// Load a new block into a state that was cleared with `clear()`.
+    pub(crate) fn reset(&mut self, txns: Vec<AnalyzedTransaction>, num_executor_shards: ShardId) {
+        let _timer = MISC_TIMERS_SECONDS
+            .with_label_values(&["reset"])
+            .start_timer();
+        // Nothing from the previous block should survive `clear()`.
+        debug_assert!(self.txns.is_empty());
+        debug_assert!(self.affinity.is_empty());
+        debug_assert!(self.trackers.is_empty());
+        debug_assert!(self.senders.is_empty());
+        debug_assert!(self.keys.is_empty());
+        debug_assert_eq!(0, self.num_senders());
+        debug_assert_eq!(0, self.num_keys());
+        debug_assert!(self
+            .sender_idxs
+            .iter()
+            .all(|sender_idx| sender_idx.read().unwrap().is_none()));
+        debug_assert!(self
+            .write_sets
+            .iter()
+            .chain(self.read_sets.iter())
+            .all(|key_set| key_set.read().unwrap().is_empty()));
+        debug_assert!(self.pre_partitioned.is_empty());
+        debug_assert!(self.finalized_txn_matrix.is_empty());
+        debug_assert!(self.final_idxs_by_pre_partitioned.is_empty());
+        debug_assert_eq!(PartitionReport::default(), *self.report.lock().unwrap());
+
+        let num_txns = txns.len();
+        self.budget.restart();
+        self.num_executor_shards = num_executor_shards;
+        self.sender_idxs.truncate(num_txns);
+        self.sender_idxs.resize_with(num_txns, || RwLock::new(None));
+        self.write_sets.truncate(num_txns);
+        self.write_sets
+            .resize_with(num_txns, || RwLock::new(HashSet::new()));
+        self.read_sets.truncate(num_txns);
+        self.read_sets
+            .resize_with(num_txns, || RwLock::new(HashSet::new()));
+        self.start_txn_idxs_by_shard.resize(num_executor_shards, 0);
+        self.ori_idxs_by_pre_partitioned.resize(num_txns, 0);
+        self.txns = txns;
+    }
+
+    /// Append txns to the block. They still have to go through `PartitionerV2::init_txns()`.
+    pub(crate) fn append_txns(&mut self, txns: Vec<AnalyzedTransaction>) {
+        for txn in txns.iter() {
+            self.sender_idxs.push(RwLock::new(None));
+            self.write_sets
+                .push(RwLock::new(HashSet::with_capacity(txn.write_hints().len())));
+            self.read_sets
+                .push(RwLock::new(HashSet::with_capacity(txn.read_hints().len())));
+        }
+        self.txns.extend(txns);
+        self.ori_idxs_by_pre_partitioned.resize(self.txns.len(), 0);
+    }
+
     pub(crate) fn num_txns(&self) -> usize {
         self.txns.len()
     }
 
+    /// Only valid after `finalize_indices()`.
     pub(crate) fn num_keys(&self) -> usize {
-        self.storage_key_counter.load(Ordering::SeqCst)
+        self.keys.len()
     }
 
+    /// Only valid after `finalize_indices()`.
     pub(crate) fn num_senders(&self) -> usize {
-        self.sender_counter.load(Ordering::SeqCst)
+        self.senders.len()
+    }
+
+    /// Record that a key is accessed by txn `ori_txn_idx`, as the `pos`-th storage location of the txn.
+    /// Its `StorageKeyIdx` is available after `finalize_indices()`.
+    pub(crate) fn add_key(&self, key: &StateKey, ori_txn_idx: OriginalTxnIdx, pos: usize) {
+        self.keys.observe(key, (ori_txn_idx, pos));
     }
 
-    pub(crate) fn add_key(&self, key: &StateKey) -> StorageKeyIdx {
-        *self
-            .key_idx_table
-            .entry(key.clone())
-            .or_insert_with(|| self.storage_key_counter.fetch_add(1, Ordering::SeqCst))
+    /// Record that txn `ori_txn_idx` is sent by a sender.
+    /// Its `SenderIdx` is available after `finalize_indices()`.
+    pub(crate) fn add_sender(&self, sender: &Sender, ori_txn_idx: OriginalTxnIdx) {
+        self.senders.observe(sender, (ori_txn_idx, 0));
+    }
+
+    /// Assign the indices of all the keys and senders added so far.
+    /// The result only depends on the txns, not on the order in which they were added.
+    pub(crate) fn finalize_indices(&mut self) {
+        self.senders.finalize(&self.thread_pool);
+        self.keys.finalize(&self.thread_pool);
+    }
+
+    pub(crate) fn key_idx_of(&self, key: &StateKey) -> StorageKeyIdx {
+        self.keys.idx_of(key)
+    }
+
+    pub(crate) fn sender_idx_of(&self, sender: &Sender) -> SenderIdx {
+        self.senders.idx_of(sender)
     }
 
     pub(crate) fn storage_location(&self, key_idx: StorageKeyIdx) -> StorageLocation {
@@ -201,13 +304,6 @@ impl PartitionState {
             .unwrap()
     }
 
-    pub(crate) fn add_sender(&self, sender: Sender) -> SenderIdx {
-        *self
-            .sender_idx_table
-            .entry(sender)
-            .or_insert_with(|| self.sender_counter.fetch_add(1, Ordering::SeqCst))
-    }
-
     /// For a key, check if there is any write between the anchor shard and a given shard.
     pub(crate) fn key_owned_by_another_shard(&self, shard_id: ShardId, key: StorageKeyIdx) -> bool {
         let tracker_ref = self.trackers.get(&key).unwrap();
@@ -246,12 +342,10 @@ impl PartitionState {
         let tracker = tracker_ref.read().unwrap();
         let start = ShardedTxnIndexV2::new(sub_block.round_id, sub_block.shard_id, 0);
         let end = ShardedTxnIndexV2::new(sub_block.round_id, sub_block.shard_id + 1, 0);
-        let ret = tracker
-            .finalized_writes
-            .range(start..end)
-            .last()
-            .map(|t| t.pre_partitioned_txn_idx);
-        ret
+        tracker
+            .latest_write_before(end)
+            .filter(|t| *t >= start)
+            .map(|t| t.pre_partitioned_txn_idx)
     }
 
     /// Get the 1st txn after `since` that writes a given key.
@@ -262,7 +356,7 @@ impl PartitionState {
     ) -> Option<ShardedTxnIndexV2> {
         let tracker_ref = self.trackers.get(&key).unwrap();
         let tracker = tracker_ref.read().unwrap();
-        tracker.finalized_writes.range(since..).next().copied()
+        tracker.first_write_since(since)
     }
 
     /// Get all txns that access a certain key in a sub-block range.
@@ -274,7 +368,7 @@ impl PartitionState {
     ) -> Vec<ShardedTxnIndexV2> {
         let tracker_ref = self.trackers.get(&key).unwrap();
         let tracker = tracker_ref.read().unwrap();
-        tracker.finalized.range(start..end).copied().collect()
+        tracker.accesses_between(start, end).collect()
     }
 
     pub(crate) fn num_rounds(&self) -> usize {
@@ -289,15 +383,14 @@ impl PartitionState {
         }
     }
 
-    /// Take a txn out, wrap it as a `TransactionWithDependencies`.
-    pub(crate) fn take_txn_with_dep(
+    /// The cross-shard dependencies of a txn, given its final position.
+    pub(crate) fn cross_shard_deps(
         &self,
         round_id: RoundId,
         shard_id: ShardId,
         txn_idx: PrePartitionedTxnIdx,
-    ) -> TransactionWithDependencies<AnalyzedTransaction> {
+    ) -> CrossShardDependencies {
         let ori_txn_idx = self.ori_idxs_by_pre_partitioned[txn_idx];
-        let txn = self.txns[ori_txn_idx].write().unwrap().take().unwrap();
         let mut deps = CrossShardDependencies::default();
 
         // Build required edges.
@@ -306,10 +399,8 @@ impl PartitionState {
         for &key_idx in write_set.iter().chain(read_set.iter()) {
             let tracker_ref = self.trackers.get(&key_idx).unwrap();
             let tracker = tracker_ref.read().unwrap();
-            if let Some(txn_idx) = tracker
-                .finalized_writes
-                .range(..ShardedTxnIndexV2::new(round_id, shard_id, 0))
-                .last()
+            if let Some(txn_idx) =
+                tracker.latest_write_before(ShardedTxnIndexV2::new(round_id, shard_id, 0))
             {
                 let src_txn_idx = ShardedTxnIndex {
                     txn_index: *self.final_idxs_by_pre_partitioned[txn_idx.pre_partitioned_txn_idx]
@@ -349,6 +440,6 @@ impl PartitionState {
             }
         }
 
-        TransactionWithDependencies::new(txn, deps)
+        deps
     }
 }
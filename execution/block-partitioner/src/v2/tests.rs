@@ -4,15 +4,41 @@
 #![allow(clippy::arc_with_non_send_sync)]
 
 use crate::{
+    get_anchor_shard_id,
     pre_partition::{
-        connected_component::ConnectedComponentPartitioner, uniform_partitioner::UniformPartitioner,
+        connected_component::ConnectedComponentPartitioner,
+        uniform_partitioner::UniformPartitioner, PrePartitioner,
     },
-    test_utils::{assert_deterministic_result, P2PBlockGenerator},
-    v2::PartitionerV2,
-    BlockPartitioner,
+    report::RoundReport,
+    test_utils::{
+        assert_deterministic_result, create_non_conflicting_p2p_transaction,
+        create_signed_p2p_transaction, generate_test_account, verify_partitioner_output,
+        P2PBlockGenerator,
+    },
+    v2::{
+        anchor::{AnchorStrategyConfig, HashAnchorStrategy},
+        budget::{PartitioningBudget, BUDGET_CHECK_CHUNK_SIZE},
+        deferral::DeferralReportConfig,
+        fallback::UnshardedFallbackConfig,
+        state::PartitionState,
+        types::{OriginalTxnIdx, PrePartitionedTxnIdx},
+        PartitionerV2,
+    },
+    BlockPartitioner, Sender,
+};
+use aptos_types::{
+    block_executor::partitioner::{PartitionedTransactions, ShardId},
+    state_store::state_key::StateKey,
+    transaction::analyzed_transaction::{AnalyzedTransaction, StorageLocation},
 };
+use itertools::iproduct;
 use rand::{thread_rng, Rng};
-use std::sync::Arc;
+use rayon::ThreadPoolBuilder;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 #[test]
 fn test_partitioner_v2_uniform_correctness() {
@@ -55,7 +81,7 @@ fn test_partitioner_v2_uniform_determinism() {
 
 #[test]
 fn test_partitioner_v2_connected_component_correctness() {
-    for merge_discarded in [false, true] {
+    for (merge_discarded, sender_affinity) in iproduct!([false, true], [false, true]) {
         let block_generator = P2PBlockGenerator::new(100);
         let partitioner = PartitionerV2::new(
             8,
@@ -65,6 +91,7 @@ fn test_partitioner_v2_connected_component_correctness() {
             merge_discarded,
             Box::new(ConnectedComponentPartitioner {
                 load_imbalance_tolerance: 2.0,
+                sender_affinity,
             }),
         );
         let mut rng = thread_rng();
@@ -81,7 +108,7 @@ fn test_partitioner_v2_connected_component_correctness() {
 
 #[test]
 fn test_partitioner_v2_connected_component_determinism() {
-    for merge_discarded in [false, true] {
+    for (merge_discarded, sender_affinity) in iproduct!([false, true], [false, true]) {
         let partitioner = Arc::new(PartitionerV2::new(
             4,
             4,
@@ -90,8 +117,914 @@ fn test_partitioner_v2_connected_component_determinism() {
             merge_discarded,
             Box::new(ConnectedComponentPartitioner {
                 load_imbalance_tolerance: 2.0,
+                sender_affinity,
             }),
         ));
         assert_deterministic_result(partitioner);
     }
 }
+
+/// A single-round partitioner, so all the conflicts between pre-partitioned shards show up as cross-shard edges.
+fn connected_component_partitioner(sender_affinity: bool) -> PartitionerV2 {
+    PartitionerV2::new(
+        8,
+        1,
+        0.9,
+        64,
+        true,
+        Box::new(ConnectedComponentPartitioner {
+            load_imbalance_tolerance: 2.0,
+            sender_affinity,
+        }),
+    )
+    .verify_output(true)
+}
+
+fn num_required_edges(partitioned: &PartitionedTransactions) -> usize {
+    partitioned
+        .sharded_txns()
+        .iter()
+        .flat_map(|sub_blocks| sub_blocks.iter())
+        .map(|txn_with_deps| {
+            txn_with_deps
+                .cross_shard_dependencies()
+                .num_required_edges()
+        })
+        .sum()
+}
+
+#[test]
+fn test_sender_affinity_with_dominating_sender() {
+    // 40 txns from a hot sender, interleaved with 60 txns from unique senders that all send to the hot sender.
+    // Everything ends up in 1 conflicting set, which is larger than the group size limit (100 * 2.0 / 4 = 50).
+    let mut hot_sender = generate_test_account();
+    let mut block: Vec<AnalyzedTransaction> = Vec::new();
+    for i in 0..100 {
+        if i % 5 < 2 {
+            let receiver = generate_test_account();
+            block.extend(create_signed_p2p_transaction(&mut hot_sender, vec![
+                &receiver,
+            ]));
+        } else {
+            let mut sender = generate_test_account();
+            block.extend(create_signed_p2p_transaction(&mut sender, vec![
+                &hot_sender,
+            ]));
+        }
+    }
+
+    // Cut into 2 groups of 50 txns, the txns of the hot sender end up in 2 shards.
+    let (without_affinity, report) =
+        connected_component_partitioner(false).partition_with_report(block.clone(), 4);
+    assert_eq!(1, report.num_split_senders);
+
+    let (with_affinity, report) =
+        connected_component_partitioner(true).partition_with_report(block.clone(), 4);
+    assert_eq!(0, report.num_split_senders);
+
+    assert!(num_required_edges(&with_affinity) < num_required_edges(&without_affinity));
+}
+
+#[test]
+fn test_sender_affinity_with_unique_senders() {
+    // 100 txns from unique senders, all sending to the same receiver.
+    let hot_receiver = generate_test_account();
+    let block: Vec<AnalyzedTransaction> = (0..100)
+        .flat_map(|_| {
+            let mut sender = generate_test_account();
+            create_signed_p2p_transaction(&mut sender, vec![&hot_receiver])
+        })
+        .collect();
+
+    let (without_affinity, report) =
+        connected_component_partitioner(false).partition_with_report(block.clone(), 4);
+    assert_eq!(0, report.num_split_senders);
+
+    let (with_affinity, report) =
+        connected_component_partitioner(true).partition_with_report(block.clone(), 4);
+    assert_eq!(0, report.num_split_senders);
+
+    assert_eq!(
+        num_required_edges(&without_affinity),
+        num_required_edges(&with_affinity)
+    );
+    assert_eq!(without_affinity, with_affinity);
+}
+
+#[test]
+fn test_sender_affinity_splits_oversized_sender_group() {
+    let mut sender = generate_test_account();
+    let receivers: Vec<_> = (0..100).map(|_| generate_test_account()).collect();
+    let block = create_signed_p2p_transaction(&mut sender, receivers.iter().collect());
+
+    let (partitioned, report) =
+        connected_component_partitioner(true).partition_with_report(block.clone(), 4);
+    assert_eq!(1, report.num_split_senders);
+    verify_partitioner_output(&block, &partitioned);
+    // The txns of the sender are all there is, and they no longer fit in one shard.
+    let num_shards_used = partitioned
+        .sharded_txns()
+        .iter()
+        .filter(|sub_blocks| sub_blocks.num_txns() > 0)
+        .count();
+    assert!(num_shards_used > 1);
+}
+
+#[test]
+fn test_partitioner_v2_state_pooling() {
+    let block_gen = P2PBlockGenerator::new(1000);
+    let new_partitioner = |state_pooling| {
+        PartitionerV2::new(
+            4,
+            4,
+            0.9,
+            64,
+            false,
+            Box::new(ConnectedComponentPartitioner {
+                load_imbalance_tolerance: 2.0,
+                sender_affinity: true,
+            }),
+        )
+        .state_pooling(state_pooling)
+    };
+    let pooled = new_partitioner(true);
+    let unpooled = new_partitioner(false);
+    let mut rng = thread_rng();
+    // Vary the block size and the shard count, so a reused state has to grow and shrink.
+    for (block_size, num_shards) in [(500, 4), (50, 7), (1000, 3), (1, 1), (200, 8)] {
+        let block = block_gen.rand_block(&mut rng, block_size);
+        let expected = unpooled.partition(block.clone(), num_shards);
+        let actual = pooled.partition(block.clone(), num_shards);
+        verify_partitioner_output(&block, &actual);
+        assert_eq!(expected, actual);
+        // Wait for the async clean-up, so the next block does reuse the state.
+        while pooled.state_pool.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+    assert_eq!(1, pooled.state_pool.lock().unwrap().len());
+}
+
+fn anchor_strategy_partitioner(anchor_strategy: &AnchorStrategyConfig) -> PartitionerV2 {
+    PartitionerV2::new(
+        4,
+        4,
+        0.9,
+        64,
+        false,
+        Box::new(ConnectedComponentPartitioner {
+            load_imbalance_tolerance: 2.0,
+            sender_affinity: true,
+        }),
+    )
+    .anchor_strategy(anchor_strategy.build())
+}
+
+/// The number of anchors of each shard if the keys of `block` were anchored by hashing.
+fn hash_anchors_by_shard(block: &[AnalyzedTransaction], num_shards: usize) -> Vec<usize> {
+    let keys: HashSet<_> = block
+        .iter()
+        .flat_map(|txn| txn.read_hints().iter().chain(txn.write_hints().iter()))
+        .map(|loc| loc.state_key().clone())
+        .collect();
+    let mut num_anchors_by_shard = vec![0; num_shards];
+    for key in keys {
+        num_anchors_by_shard[get_anchor_shard_id(&StorageLocation::Specific(key), num_shards)] += 1;
+    }
+    num_anchors_by_shard
+}
+
+#[test]
+fn test_partitioner_v2_anchor_strategies() {
+    let block_gen = P2PBlockGenerator::new(100);
+    let hot_key = block_gen
+        .rand_block(&mut thread_rng(), 1)
+        .remove(0)
+        .write_hints()[0]
+        .state_key()
+        .clone();
+    let strategies = [
+        AnchorStrategyConfig::Hash,
+        AnchorStrategyConfig::LoadAware,
+        AnchorStrategyConfig::Pinned {
+            pinned: vec![(hot_key, 3)],
+            fallback: Box::new(AnchorStrategyConfig::LoadAware),
+        },
+    ];
+    let mut rng = thread_rng();
+    for strategy in strategies.iter() {
+        let partitioner = anchor_strategy_partitioner(strategy);
+        for _run_id in 0..20 {
+            let block_size = 10_u64.pow(rng.gen_range(0, 4)) as usize;
+            let num_shards = rng.gen_range(1, 10);
+            let block = block_gen.rand_block(&mut rng, block_size);
+            let (partitioned, report) =
+                partitioner.partition_with_report(block.clone(), num_shards);
+            verify_partitioner_output(&block, &partitioned);
+            assert_eq!(
+                hash_anchors_by_shard(&block, num_shards)
+                    .iter()
+                    .sum::<usize>(),
+                report.num_anchors_by_shard.iter().sum::<usize>()
+            );
+        }
+        assert_deterministic_result(Arc::new(anchor_strategy_partitioner(strategy)));
+    }
+}
+
+#[test]
+fn test_partitioner_v2_hash_anchors_unchanged() {
+    let block_gen = P2PBlockGenerator::new(100);
+    let block = block_gen.rand_block(&mut thread_rng(), 500);
+    let default_partitioner = PartitionerV2::new(
+        4,
+        4,
+        0.9,
+        64,
+        false,
+        Box::new(ConnectedComponentPartitioner {
+            load_imbalance_tolerance: 2.0,
+            sender_affinity: true,
+        }),
+    );
+    let hash_partitioner = anchor_strategy_partitioner(&AnchorStrategyConfig::Hash);
+    for num_shards in [1, 4, 7] {
+        let (expected, expected_report) =
+            default_partitioner.partition_with_report(block.clone(), num_shards);
+        let (actual, actual_report) =
+            hash_partitioner.partition_with_report(block.clone(), num_shards);
+        assert_eq!(expected, actual);
+        assert_eq!(expected_report, actual_report);
+        assert_eq!(
+            hash_anchors_by_shard(&block, num_shards),
+            actual_report.num_anchors_by_shard
+        );
+    }
+}
+
+#[test]
+fn test_partitioner_v2_pinned_anchor() {
+    let block_gen = P2PBlockGenerator::new(100);
+    let block = block_gen.rand_block(&mut thread_rng(), 500);
+    let num_shards = 4;
+    let hot_key = block[0].write_hints()[0].state_key().clone();
+    let hash_shard_id =
+        get_anchor_shard_id(&StorageLocation::Specific(hot_key.clone()), num_shards);
+    let pinned_shard_id = (hash_shard_id + 1) % num_shards;
+    let partitioner = anchor_strategy_partitioner(&AnchorStrategyConfig::Pinned {
+        pinned: vec![(hot_key, pinned_shard_id)],
+        fallback: Box::new(AnchorStrategyConfig::Hash),
+    });
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), num_shards);
+    verify_partitioner_output(&block, &partitioned);
+    let mut expected = hash_anchors_by_shard(&block, num_shards);
+    expected[hash_shard_id] -= 1;
+    expected[pinned_shard_id] += 1;
+    assert_eq!(expected, report.num_anchors_by_shard);
+}
+
+#[test]
+fn test_partitioner_v2_randomized_verify_partition() {
+    let mut rng = thread_rng();
+    for _run_id in 0..50 {
+        let num_accounts = rng.gen_range(2, 200);
+        let max_partitioning_rounds = rng.gen_range(1, 5);
+        let cross_shard_dep_avoid_threshold = rng.gen_range(0.0, 1.0);
+        let partition_last_round = rng.gen_bool(0.5);
+        let balance_last_round = partition_last_round && rng.gen_bool(0.5);
+        let max_txns_per_shard_per_round = if rng.gen_bool(0.5) {
+            Some(rng.gen_range(1, 100))
+        } else {
+            None
+        };
+        let pre_partitioner: Box<dyn PrePartitioner> = if rng.gen_bool(0.5) {
+            Box::new(UniformPartitioner {})
+        } else {
+            Box::new(ConnectedComponentPartitioner {
+                load_imbalance_tolerance: rng.gen_range(1.0, 4.0),
+                sender_affinity: rng.gen_bool(0.5),
+            })
+        };
+        let partitioner = PartitionerV2::new(
+            4,
+            max_partitioning_rounds,
+            cross_shard_dep_avoid_threshold,
+            64,
+            partition_last_round,
+            pre_partitioner,
+        )
+        .balance_last_round(balance_last_round)
+        .max_txns_per_shard_per_round(max_txns_per_shard_per_round);
+        let block_gen = P2PBlockGenerator::new(num_accounts);
+        let block_size = rng.gen_range(1, 500);
+        let num_shards = rng.gen_range(1, 10);
+        let block = block_gen.rand_block(&mut rng, block_size);
+        let partitioned = partitioner.partition(block, num_shards);
+        if let Err(violation) = verify_partition(partitioned.sharded_txns()) {
+            panic!(
+                "{} (num_accounts={}, max_partitioning_rounds={}, cross_shard_dep_avoid_threshold={}, partition_last_round={}, balance_last_round={}, max_txns_per_shard_per_round={:?}, block_size={}, num_shards={})",
+                violation,
+                num_accounts,
+                max_partitioning_rounds,
+                cross_shard_dep_avoid_threshold,
+                partition_last_round,
+                balance_last_round,
+                max_txns_per_shard_per_round,
+                block_size,
+                num_shards
+            );
+        }
+    }
+}
+
+/// Assigns consecutive txns to the shards, according to the given shard sizes.
+struct FixedSizePrePartitioner {
+    shard_sizes: Vec<usize>,
+}
+
+impl PrePartitioner for FixedSizePrePartitioner {
+    fn pre_partition(
+        &self,
+        state: &PartitionState,
+    ) -> (
+        Vec<OriginalTxnIdx>,
+        Vec<PrePartitionedTxnIdx>,
+        Vec<Vec<PrePartitionedTxnIdx>>,
+    ) {
+        assert_eq!(state.num_executor_shards, self.shard_sizes.len());
+        assert_eq!(state.num_txns(), self.shard_sizes.iter().sum::<usize>());
+        let mut start_txn_idxs_by_shard = vec![];
+        let mut pre_partitioned = vec![];
+        let mut txn_counter = 0;
+        for &shard_size in self.shard_sizes.iter() {
+            start_txn_idxs_by_shard.push(txn_counter);
+            pre_partitioned.push((txn_counter..txn_counter + shard_size).collect());
+            txn_counter += shard_size;
+        }
+        (
+            (0..state.num_txns()).collect(),
+            start_txn_idxs_by_shard,
+            pre_partitioned,
+        )
+    }
+}
+
+#[test]
+fn test_partitioner_v2_max_txns_per_shard_per_round() {
+    // Conflict-free txns, but shard 0 gets 10x as many txns as any other shard.
+    let block: Vec<AnalyzedTransaction> = (0..130)
+        .map(|_| {
+            let mut sender = generate_test_account();
+            let receiver = generate_test_account();
+            create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0)
+        })
+        .collect();
+    let new_partitioner = |max_txns_per_shard_per_round| {
+        PartitionerV2::new(
+            4,
+            4,
+            1.0,
+            64,
+            true,
+            Box::new(FixedSizePrePartitioner {
+                shard_sizes: vec![100, 10, 10, 10],
+            }),
+        )
+        .max_txns_per_shard_per_round(max_txns_per_shard_per_round)
+    };
+    let sub_block_sizes = |partitioned: &PartitionedTransactions| -> Vec<Vec<usize>> {
+        let num_rounds = partitioned.sharded_txns()[0].num_sub_blocks();
+        (0..num_rounds)
+            .map(|round_id| {
+                partitioned
+                    .sharded_txns()
+                    .iter()
+                    .map(|sub_blocks| sub_blocks.get_sub_block(round_id).unwrap().num_txns())
+                    .collect()
+            })
+            .collect()
+    };
+
+    let (partitioned, report) = new_partitioner(None).partition_with_report(block.clone(), 4);
+    assert_eq!(vec![100, 10, 10, 10], sub_block_sizes(&partitioned)[0]);
+    assert_eq!(0, report.num_capped_sub_blocks);
+
+    let (partitioned, report) = new_partitioner(Some(30)).partition_with_report(block.clone(), 4);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(Ok(()), verify_partition(partitioned.sharded_txns()));
+    assert_eq!(
+        vec![
+            vec![30, 10, 10, 10],
+            vec![30, 0, 0, 0],
+            vec![30, 0, 0, 0],
+            // The last round takes all the remaining txns.
+            vec![10, 0, 0, 0],
+        ],
+        sub_block_sizes(&partitioned)
+    );
+    assert_eq!(3, report.num_capped_sub_blocks);
+    assert_eq!(70 + 40 + 10, report.num_txns_deferred_by_cap);
+}
+
+#[test]
+fn test_partitioner_v2_unsharded_fallback() {
+    let partitioner = PartitionerV2::new(
+        4,
+        4,
+        0.9,
+        64,
+        true,
+        Box::new(ConnectedComponentPartitioner {
+            load_imbalance_tolerance: 2.0,
+            sender_affinity: true,
+        }),
+    )
+    .unsharded_fallback(Some(UnshardedFallbackConfig {
+        top_k_hot_keys: 1,
+        conflict_ratio_threshold: 0.8,
+    }))
+    // The fallback output goes through the same checks as the partitioned one.
+    .verify_output(true);
+    let mut rng = thread_rng();
+
+    // Transfers from a few accounts: no key is written by most of the txns.
+    let block = P2PBlockGenerator::new(100).rand_block(&mut rng, 200);
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+    assert!(!report.unsharded_fallback);
+    verify_partitioner_output(&block, &partitioned);
+
+    // Transfers from the same account.
+    let mut sender = generate_test_account();
+    let receivers: Vec<_> = (0..200).map(|_| generate_test_account()).collect();
+    let block = create_signed_p2p_transaction(&mut sender, receivers.iter().collect());
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+    assert!(report.unsharded_fallback);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(Ok(()), verify_partition(partitioned.sharded_txns()));
+    assert_eq!(200, partitioned.sharded_txns()[0].num_txns());
+}
+
+#[test]
+fn test_partitioner_v2_budget() {
+    let block_gen = P2PBlockGenerator::new(50);
+    let mut rng = thread_rng();
+    let block = block_gen.rand_block(&mut rng, 500);
+    let num_txns = block.len();
+    let new_partitioner = |partition_last_round, budget| {
+        PartitionerV2::new(
+            4,
+            4,
+            1.0,
+            64,
+            partition_last_round,
+            Box::new(ConnectedComponentPartitioner {
+                load_imbalance_tolerance: 2.0,
+                sender_affinity: true,
+            }),
+        )
+        .budget(budget)
+    };
+
+    for partition_last_round in [false, true] {
+        let (unlimited, report) =
+            new_partitioner(partition_last_round, None).partition_with_report(block.clone(), 4);
+        assert!(!report.truncated_by_budget);
+
+        // Init and tracker update take `2 * num_txns`, then round 0 takes `num_txns`.
+        for (budget, expected_num_rounds) in [
+            (PartitioningBudget::TxnsProcessed(0), 1),
+            (PartitioningBudget::TxnsProcessed(num_txns / 2), 1),
+            (PartitioningBudget::TxnsProcessed(2 * num_txns + 1), 2),
+            (PartitioningBudget::TxnsProcessed(3 * num_txns - 1), 2),
+            (PartitioningBudget::WallClock(Duration::ZERO), 1),
+        ] {
+            let partitioner = new_partitioner(partition_last_round, Some(budget));
+            let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+            assert!(report.truncated_by_budget);
+            verify_partitioner_output(&block, &partitioned);
+            assert_eq!(Ok(()), verify_partition(partitioned.sharded_txns()));
+            let num_rounds = partitioned.sharded_txns()[0].num_sub_blocks()
+                + if partition_last_round { 0 } else { 1 };
+            assert_eq!(expected_num_rounds, num_rounds);
+            assert_ne!(unlimited, partitioned);
+        }
+
+        // A large enough budget changes nothing.
+        let partitioner = new_partitioner(
+            partition_last_round,
+            Some(PartitioningBudget::TxnsProcessed(100 * num_txns)),
+        );
+        let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+        assert!(!report.truncated_by_budget);
+        assert_eq!(unlimited, partitioned);
+    }
+
+    // Exhausted by init, the txns are split evenly instead of being pre-partitioned.
+    let partitioner = new_partitioner(true, Some(PartitioningBudget::TxnsProcessed(num_txns)));
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+    assert!(report.truncated_by_budget);
+    verify_partitioner_output(&block, &partitioned);
+    for sub_blocks in partitioned.sharded_txns() {
+        assert_eq!(num_txns / 4, sub_blocks.num_txns());
+    }
+
+    // Running out in the middle of round 0, the round stops after the first chunk of every shard,
+    // the txns it did not check going to the last round.
+    let partitioner = new_partitioner(
+        true,
+        Some(PartitioningBudget::TxnsProcessed(2 * num_txns + 1)),
+    );
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), 4);
+    assert!(report.truncated_by_budget);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(Ok(()), verify_partition(partitioned.sharded_txns()));
+    assert_eq!(2, report.rounds.len());
+    for sub_blocks in partitioned.sharded_txns() {
+        assert!(sub_blocks.get_sub_block(0).unwrap().num_txns() <= BUDGET_CHECK_CHUNK_SIZE);
+    }
+    assert_eq!(
+        num_txns,
+        report.rounds[0].num_txns + report.rounds[1].num_txns
+    );
+
+    // Budgets in txns processed are deterministic.
+    let partitioner = Arc::new(new_partitioner(
+        true,
+        Some(PartitioningBudget::TxnsProcessed(2 * 100 + 10)),
+    ));
+    assert_deterministic_result(partitioner);
+}
+
+#[test]
+fn test_partitioner_v2_init_determinism_across_thread_counts() {
+    let block_gen = P2PBlockGenerator::new(1000);
+    let block = block_gen.rand_block(&mut thread_rng(), 2000);
+    // For every txn, its sender index and its read/write key indices.
+    let indices_with_threads = |num_threads: usize| {
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        let mut state = PartitionState::new(
+            Arc::new(thread_pool),
+            64,
+            block.clone(),
+            4,
+            4,
+            0.9,
+            true,
+            false,
+            Arc::new(HashAnchorStrategy {}),
+            None,
+            None,
+            DeferralReportConfig::default(),
+        );
+        PartitionerV2::init(&mut state);
+        (0..state.num_txns())
+            .map(|ori_txn_idx| {
+                let mut read_set: Vec<_> = state.read_sets[ori_txn_idx]
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .copied()
+                    .collect();
+                read_set.sort();
+                let mut write_set: Vec<_> = state.write_sets[ori_txn_idx]
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .copied()
+                    .collect();
+                write_set.sort();
+                (state.sender_idx(ori_txn_idx), read_set, write_set)
+            })
+            .collect::<Vec<_>>()
+    };
+    let expected = indices_with_threads(1);
+    for num_threads in [2, 8, 32] {
+        assert_eq!(expected, indices_with_threads(num_threads));
+    }
+}
+
+#[test]
+fn test_partitioner_v2_balance_last_round() {
+    // With a single round, every txn is in the last round, all of them pre-partitioned into shard 0.
+    let partitioner = |balance_last_round: bool| {
+        PartitionerV2::new(
+            4,
+            1,
+            0.9,
+            64,
+            true,
+            Box::new(FixedSizePrePartitioner {
+                shard_sizes: vec![20, 0, 0, 0],
+            }),
+        )
+        .balance_last_round(balance_last_round)
+    };
+
+    // Independent txns are spread evenly.
+    let block: Vec<AnalyzedTransaction> = (0..20)
+        .map(|_| create_non_conflicting_p2p_transaction())
+        .collect();
+    let (partitioned, report) = partitioner(false).partition_with_report(block.clone(), 4);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(vec![20, 0, 0, 0], report.num_last_round_txns_by_shard);
+    let (partitioned, report) = partitioner(true).partition_with_report(block.clone(), 4);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(vec![5, 5, 5, 5], report.num_last_round_txns_by_shard);
+    assert_eq!(0, num_required_edges(&partitioned));
+
+    // A single chain is cut into consecutive pieces, each depending on the previous one.
+    let mut sender = generate_test_account();
+    let receivers: Vec<_> = (0..20).map(|_| generate_test_account()).collect();
+    let block = create_signed_p2p_transaction(&mut sender, receivers.iter().collect());
+    let (partitioned, report) = partitioner(true).partition_with_report(block.clone(), 4);
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(vec![5, 5, 5, 5], report.num_last_round_txns_by_shard);
+    assert!(num_required_edges(&partitioned) >= 3);
+
+    // Balancing has no effect if the last round is not partitioned.
+    let (partitioned, report) =
+        PartitionerV2::new(4, 1, 0.9, 64, false, Box::new(UniformPartitioner {}))
+            .balance_last_round(true)
+            .partition_with_report(block.clone(), 4);
+    verify_partitioner_output(&block, &partitioned);
+    assert!(report.num_last_round_txns_by_shard.is_empty());
+}
+
+#[test]
+fn test_partitioner_v2_round_reports() {
+    // 2 shards of 10 txns. Both hot keys are anchored to shard 0 and written by its 1st txns,
+    // so the shard 1 txns writing them are deferred out of round 0.
+    let hot_key_a = StateKey::raw(b"hot_a");
+    let hot_key_b = StateKey::raw(b"hot_b");
+    let block: Vec<AnalyzedTransaction> = (0..20)
+        .map(|txn_idx| {
+            let mut sender = generate_test_account();
+            let receiver = generate_test_account();
+            let mut txn = create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0);
+            let key = match txn_idx {
+                0 | 10..=15 => hot_key_a.clone(),
+                1 | 16..=18 => hot_key_b.clone(),
+                _ => StateKey::raw(format!("cold_{}", txn_idx).as_bytes()),
+            };
+            txn.read_hints = vec![];
+            txn.write_hints = vec![StorageLocation::Specific(key)];
+            txn
+        })
+        .collect();
+    let partitioner = PartitionerV2::new(
+        4,
+        4,
+        0.9,
+        64,
+        true,
+        Box::new(FixedSizePrePartitioner {
+            shard_sizes: vec![10, 10],
+        }),
+    )
+    .anchor_strategy(
+        AnchorStrategyConfig::Pinned {
+            pinned: vec![(hot_key_a.clone(), 0), (hot_key_b.clone(), 0)],
+            fallback: Box::new(AnchorStrategyConfig::Hash),
+        }
+        .build(),
+    );
+    let (partitioned, report) = partitioner.partition_with_report(block.clone(), 2);
+    verify_partitioner_output(&block, &partitioned);
+
+    assert_eq!(
+        vec![
+            RoundReport {
+                num_txns: 11,
+                num_txns_deferred_from_previous_rounds: 0,
+                top_conflicting_keys: vec![(hot_key_a.clone(), 6), (hot_key_b.clone(), 3)],
+            },
+            RoundReport {
+                num_txns: 9,
+                num_txns_deferred_from_previous_rounds: 9,
+                top_conflicting_keys: vec![],
+            },
+            RoundReport::default(),
+        ],
+        report.rounds
+    );
+
+    let partitioner = partitioner.deferral_report(DeferralReportConfig {
+        num_top_keys: 1,
+        ..Default::default()
+    });
+    let (_, report) = partitioner.partition_with_report(block, 2);
+    assert_eq!(vec![(hot_key_a, 6)], report.rounds[0].top_conflicting_keys);
+}
+
+fn shard_ids_by_sender(partitioned: &PartitionedTransactions) -> HashMap<Sender, ShardId> {
+    partitioned
+        .sharded_txns()
+        .iter()
+        .enumerate()
+        .flat_map(|(shard_id, sub_blocks)| {
+            sub_blocks
+                .iter()
+                .map(move |txn| (txn.txn().sender(), shard_id))
+        })
+        .collect()
+}
+
+fn affinity_partitioner(load_imbalance_tolerance: f32) -> PartitionerV2 {
+    PartitionerV2::new(4, 4, 0.9, 64, true, Box::new(UniformPartitioner {}))
+        .affinity_load_imbalance_tolerance(load_imbalance_tolerance)
+}
+
+#[test]
+fn test_partitioner_v2_affinity_honored() {
+    let block: Vec<AnalyzedTransaction> = (0..40)
+        .map(|_| create_non_conflicting_p2p_transaction())
+        .collect();
+    // Txns 0..5 are pre-partitioned into shard 0, but prefer shard 3. Txn 10 is already in shard 1.
+    let mut affinity = vec![None; 40];
+    affinity[0..5].fill(Some(3));
+    affinity[10] = Some(1);
+    let (partitioned, report) =
+        affinity_partitioner(2.0).partition_with_affinity(block.clone(), 4, Some(affinity));
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(6, report.num_affinity_hints_honored);
+    assert_eq!(0, report.num_affinity_hints_ignored);
+    let shard_ids = shard_ids_by_sender(&partitioned);
+    for txn in block[0..5].iter() {
+        assert_eq!(3, shard_ids[&txn.sender()]);
+    }
+    assert_eq!(1, shard_ids[&block[10].sender()]);
+
+    // Without hints, nothing moves.
+    let (partitioned, report) =
+        affinity_partitioner(2.0).partition_with_affinity(block.clone(), 4, None);
+    assert_eq!(0, report.num_affinity_hints_honored);
+    assert_eq!(0, report.num_affinity_hints_ignored);
+    assert_eq!(0, shard_ids_by_sender(&partitioned)[&block[0].sender()]);
+}
+
+#[test]
+fn test_partitioner_v2_affinity_conflicts() {
+    // Txns 0..10 share a sender, txns 20 and 30 share a written key, the others are independent.
+    let mut sender = generate_test_account();
+    let receivers: Vec<_> = (0..10).map(|_| generate_test_account()).collect();
+    let mut block = create_signed_p2p_transaction(&mut sender, receivers.iter().collect());
+    block.extend((10..40).map(|_| create_non_conflicting_p2p_transaction()));
+    let hot_key = StorageLocation::Specific(StateKey::raw(b"hot"));
+    block[20].write_hints.push(hot_key.clone());
+    block[30].write_hints.push(hot_key);
+
+    let mut affinity = vec![None; 40];
+    // Away from the other txns of the sender, in shard 0.
+    affinity[0] = Some(1);
+    // Away from txn 30, in shard 3.
+    affinity[20] = Some(1);
+    // No conflict.
+    affinity[15] = Some(2);
+    let (partitioned, report) =
+        affinity_partitioner(2.0).partition_with_affinity(block.clone(), 4, Some(affinity));
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(1, report.num_affinity_hints_honored);
+    assert_eq!(2, report.num_affinity_hints_ignored);
+    let shard_ids = shard_ids_by_sender(&partitioned);
+    assert_eq!(0, shard_ids[&block[0].sender()]);
+    assert_eq!(2, shard_ids[&block[15].sender()]);
+    assert_eq!(2, shard_ids[&block[20].sender()]);
+}
+
+#[test]
+fn test_partitioner_v2_affinity_balance_tolerance() {
+    let block: Vec<AnalyzedTransaction> = (0..40)
+        .map(|_| create_non_conflicting_p2p_transaction())
+        .collect();
+    // All of shard 0 prefers shard 3, which can only grow to 1.5 * 40 / 4 = 15 txns.
+    let mut affinity = vec![None; 40];
+    affinity[0..10].fill(Some(3));
+    let (partitioned, report) =
+        affinity_partitioner(1.5).partition_with_affinity(block.clone(), 4, Some(affinity));
+    verify_partitioner_output(&block, &partitioned);
+    assert_eq!(5, report.num_affinity_hints_honored);
+    assert_eq!(5, report.num_affinity_hints_ignored);
+    let shard_sizes: Vec<usize> = partitioned
+        .sharded_txns()
+        .iter()
+        .map(|sub_blocks| sub_blocks.num_txns())
+        .collect();
+    assert_eq!(vec![5, 10, 10, 15], shard_sizes);
+}
+
+#[test]
+fn test_partitioner_v2_fast_paths_match_full_pipeline() {
+    let block_gen = P2PBlockGenerator::new(10);
+    let blocks = vec![
+        vec![],
+        vec![create_non_conflicting_p2p_transaction()],
+        block_gen.rand_block(&mut thread_rng(), 1),
+    ];
+    let last_round_modes = [(false, false), (true, false), (true, true)];
+    for (
+        (partition_last_round, balance_last_round),
+        num_rounds_limit,
+        threshold,
+        connected_component,
+        cap,
+    ) in iproduct!(last_round_modes, 1..=4, [0.0, 0.9], [false, true], [
+        None,
+        Some(1)
+    ]) {
+        let new_partitioner = || {
+            let pre_partitioner: Box<dyn PrePartitioner> = if connected_component {
+                Box::new(ConnectedComponentPartitioner {
+                    load_imbalance_tolerance: 2.0,
+                    sender_affinity: true,
+                })
+            } else {
+                Box::new(UniformPartitioner {})
+            };
+            PartitionerV2::new(
+                2,
+                num_rounds_limit,
+                threshold,
+                64,
+                partition_last_round,
+                pre_partitioner,
+            )
+            .balance_last_round(balance_last_round)
+            .max_txns_per_shard_per_round(cap)
+        };
+        let fast = new_partitioner();
+        let full = new_partitioner().without_fast_paths();
+        for (block, num_shards, with_affinity) in iproduct!(blocks.iter(), 1..=5, [false, true]) {
+            let affinity = with_affinity.then(|| vec![None; block.len()]);
+            assert!(fast.can_skip_analysis(block, affinity.as_deref()));
+            let expected =
+                full.partition_with_affinity(block.clone(), num_shards, affinity.clone());
+            let actual = fast.partition_with_affinity(block.clone(), num_shards, affinity);
+            verify_partitioner_output(block, &actual.0);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    // The cases where a single txn could be placed or reported differently go through the full pipeline.
+    let block = vec![create_non_conflicting_p2p_transaction()];
+    let partitioner = PartitionerV2::new(2, 4, 0.9, 64, false, Box::new(UniformPartitioner {}));
+    assert!(!partitioner.can_skip_analysis(&block, Some(&[Some(1)][..])));
+    let partitioner = partitioner.anchor_strategy(AnchorStrategyConfig::LoadAware.build());
+    assert!(!partitioner.can_skip_analysis(&block, None));
+    assert!(partitioner.can_skip_analysis(&[], None));
+    let partitioner = partitioner.budget(Some(PartitioningBudget::TxnsProcessed(1)));
+    assert!(!partitioner.can_skip_analysis(&[], None));
+}
+
+#[test]
+fn test_partitioner_v2_incremental_matches_all_at_once() {
+    let mut rng = thread_rng();
+    let partitioners = [
+        PartitionerV2::new(4, 4, 0.9, 64, false, Box::new(UniformPartitioner {})),
+        PartitionerV2::new(
+            4,
+            4,
+            0.9,
+            64,
+            true,
+            Box::new(ConnectedComponentPartitioner {
+                load_imbalance_tolerance: 2.0,
+                sender_affinity: true,
+            }),
+        )
+        .balance_last_round(true),
+        connected_component_partitioner(false).state_pooling(false),
+        connected_component_partitioner(true).unsharded_fallback(Some(UnshardedFallbackConfig {
+            conflict_ratio_threshold: 0.0,
+            ..Default::default()
+        })),
+    ];
+    for partitioner in partitioners.iter() {
+        for _run_id in 0..10 {
+            let block_gen = P2PBlockGenerator::new(rng.gen_range(2, 200));
+            let block_size = [0, 1, 10, 100, 500][rng.gen_range(0, 5)];
+            let num_shards = rng.gen_range(1, 10);
+            let block = block_gen.rand_block(&mut rng, block_size);
+            let (expected, expected_report) =
+                partitioner.partition_with_report(block.clone(), num_shards);
+
+            // Batches of random sizes, some of them empty.
+            let mut incremental = partitioner.begin_block(num_shards);
+            let mut remaining = block.as_slice();
+            while !remaining.is_empty() {
+                let batch_size = rng.gen_range(0, remaining.len() + 1);
+                let (batch, rest) = remaining.split_at(batch_size);
+                incremental.add_transactions(batch.to_vec());
+                remaining = rest;
+            }
+            assert_eq!(block_size, incremental.num_txns());
+            let (actual, mut report) = incremental.finalize();
+            assert_eq!(expected, actual);
+            report.add_transactions_time = Duration::ZERO;
+            report.finalize_time = Duration::ZERO;
+            assert_eq!(expected_report, report);
+        }
+    }
+}
@@ -0,0 +1,583 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! A checker for the output of a `BlockPartitioner`, independent of how the partitioning was done.
+
+use aptos_types::{
+    block_executor::partitioner::{
+        PartitionedTransactions, RoundId, ShardId, ShardedTxnIndex, SubBlocksForShard,
+        TransactionWithDependencies, TxnIndex, GLOBAL_ROUND_ID, GLOBAL_SHARD_ID,
+    },
+    state_store::state_key::StateKey,
+    transaction::analyzed_transaction::AnalyzedTransaction,
+};
+use move_core_types::account_address::AccountAddress;
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+};
+use thiserror::Error;
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum PartitionViolation {
+    #[error("shard {shard_id} has {actual} sub-blocks, expected {expected}")]
+    MismatchedNumRounds {
+        shard_id: ShardId,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("sub-block (round={round_id}, shard={shard_id}) starts at txn {actual}, expected {expected}")]
+    NonContiguousIndex {
+        round_id: RoundId,
+        shard_id: ShardId,
+        expected: TxnIndex,
+        actual: TxnIndex,
+    },
+    #[error("txn {second:?} is a duplicate of txn {first:?}")]
+    DuplicateTxn {
+        first: ShardedTxnIndex,
+        second: ShardedTxnIndex,
+    },
+    #[error("edge from {src:?} to {dst:?} does not point forward in (round, shard) order")]
+    BackwardEdge {
+        src: ShardedTxnIndex,
+        dst: ShardedTxnIndex,
+    },
+    #[error("edge from {src:?} to {dst:?} on {state_key:?}, but the source does not write it")]
+    InvalidEdgeSource {
+        src: ShardedTxnIndex,
+        dst: ShardedTxnIndex,
+        state_key: StateKey,
+    },
+    #[error("edge from {src:?} to {dst:?} on {state_key:?} is only recorded on one end")]
+    UnmatchedEdge {
+        src: ShardedTxnIndex,
+        dst: ShardedTxnIndex,
+        state_key: StateKey,
+    },
+    #[error(
+        "txn {reader:?} accesses {state_key:?} last written by {writer:?}, but has no edge from it"
+    )]
+    MissingDependency {
+        writer: ShardedTxnIndex,
+        reader: ShardedTxnIndex,
+        state_key: StateKey,
+    },
+    #[error("the sub-blocks at position {position} are for shard {shard_id}")]
+    MismatchedShardId { position: usize, shard_id: ShardId },
+    #[error("edge from {src:?} to {dst:?} points outside of the block")]
+    EdgeOutOfRange {
+        src: ShardedTxnIndex,
+        dst: ShardedTxnIndex,
+    },
+    #[error("txn {idx:?} is not in the input")]
+    UnexpectedTxn { idx: ShardedTxnIndex },
+    #[error("input txn {input_idx} is not in the output")]
+    MissingTxn { input_idx: usize },
+    #[error("txns {first:?} and {second:?} of sender {sender} are not in their input order")]
+    ReorderedSenderTxns {
+        sender: AccountAddress,
+        first: ShardedTxnIndex,
+        second: ShardedTxnIndex,
+    },
+}
+
+type Edge = (ShardedTxnIndex, ShardedTxnIndex, StateKey);
+
+/// Check that the sharded txns of a partitioned block can be executed correctly, by rebuilding the read/write sets
+/// from the txns themselves. Specifically:
+/// - the sub-blocks, taken in (round, shard) order, cover txn indices `0..n` exactly once, without duplicate txns;
+/// - every edge points forward in (round, shard) order, starts from a txn that writes the location,
+///   and is recorded both as a required edge and as a dependent edge;
+/// - a txn that accesses a location last written in an earlier sub-block has a required edge from that writer.
+///   In particular, no two txns of the same round on different shards conflict without an edge.
+///
+/// Dependent edges to the global txns (if any) are not checked.
+pub fn verify_partition<T>(sharded_txns: &[SubBlocksForShard<T>]) -> Result<(), PartitionViolation>
+where
+    T: Borrow<AnalyzedTransaction> + Clone,
+{
+    let num_rounds = sharded_txns
+        .first()
+        .map_or(0, |sub_blocks| sub_blocks.num_sub_blocks());
+    for (shard_id, sub_blocks) in sharded_txns.iter().enumerate() {
+        if sub_blocks.num_sub_blocks() != num_rounds {
+            return Err(PartitionViolation::MismatchedNumRounds {
+                shard_id,
+                expected: num_rounds,
+                actual: sub_blocks.num_sub_blocks(),
+            });
+        }
+    }
+
+    // All txns in their final order, along with their positions.
+    let mut txns: Vec<(ShardedTxnIndex, &TransactionWithDependencies<T>)> = vec![];
+    let mut positions_by_txn: HashMap<&AnalyzedTransaction, ShardedTxnIndex> = HashMap::new();
+    for round_id in 0..num_rounds {
+        for (shard_id, sub_blocks) in sharded_txns.iter().enumerate() {
+            let sub_block = sub_blocks.get_sub_block(round_id).unwrap();
+            if sub_block.start_index != txns.len() {
+                return Err(PartitionViolation::NonContiguousIndex {
+                    round_id,
+                    shard_id,
+                    expected: txns.len(),
+                    actual: sub_block.start_index,
+                });
+            }
+            for (txn_index, txn_with_deps) in sub_block.txn_with_index_iter() {
+                let idx = ShardedTxnIndex::new(txn_index, shard_id, round_id);
+                if let Some(&first) = positions_by_txn.get(txn_with_deps.txn().borrow()) {
+                    return Err(PartitionViolation::DuplicateTxn { first, second: idx });
+                }
+                positions_by_txn.insert(txn_with_deps.txn().borrow(), idx);
+                txns.push((idx, txn_with_deps));
+            }
+        }
+    }
+
+    let writes = |idx: &ShardedTxnIndex, state_key: &StateKey| -> bool {
+        txns.get(idx.txn_index)
+            .map_or(false, |(actual_idx, txn_with_deps)| {
+                actual_idx == idx
+                    && txn_with_deps
+                        .txn()
+                        .borrow()
+                        .write_hints()
+                        .iter()
+                        .any(|loc| loc.state_key() == state_key)
+            })
+    };
+
+    let mut required_edges: HashSet<Edge> = HashSet::new();
+    let mut dependent_edges: HashSet<Edge> = HashSet::new();
+    for (idx, txn_with_deps) in txns.iter() {
+        let deps = txn_with_deps.cross_shard_dependencies();
+        for (src, locs) in deps.required_edges().iter() {
+            for loc in locs {
+                let edge = (*src, *idx, loc.state_key().clone());
+                check_edge(&edge)?;
+                if !writes(src, loc.state_key()) {
+                    return Err(PartitionViolation::InvalidEdgeSource {
+                        src: edge.0,
+                        dst: edge.1,
+                        state_key: edge.2,
+                    });
+                }
+                required_edges.insert(edge);
+            }
+        }
+        for (dst, locs) in deps.dependent_edges().iter() {
+            if dst.round_id == GLOBAL_ROUND_ID {
+                continue;
+            }
+            for loc in locs {
+                let edge = (*idx, *dst, loc.state_key().clone());
+                check_edge(&edge)?;
+                if !writes(idx, loc.state_key()) {
+                    return Err(PartitionViolation::InvalidEdgeSource {
+                        src: edge.0,
+                        dst: edge.1,
+                        state_key: edge.2,
+                    });
+                }
+                dependent_edges.insert(edge);
+            }
+        }
+    }
+    if let Some((src, dst, state_key)) = required_edges
+        .symmetric_difference(&dependent_edges)
+        .next()
+        .cloned()
+    {
+        return Err(PartitionViolation::UnmatchedEdge {
+            src,
+            dst,
+            state_key,
+        });
+    }
+
+    // Walk the sub-blocks in order, with the last writer of every location from the previous sub-blocks.
+    let mut last_writers: HashMap<&StateKey, ShardedTxnIndex> = HashMap::new();
+    let mut sub_block_start = 0;
+    while sub_block_start < txns.len() {
+        let (first_idx, _) = txns[sub_block_start];
+        let sub_block_end = txns[sub_block_start..]
+            .iter()
+            .position(|(idx, _)| {
+                (idx.round_id, idx.shard_id) != (first_idx.round_id, first_idx.shard_id)
+            })
+            .map_or(txns.len(), |len| sub_block_start + len);
+        let sub_block = &txns[sub_block_start..sub_block_end];
+        for (idx, txn_with_deps) in sub_block {
+            let txn: &AnalyzedTransaction = txn_with_deps.txn().borrow();
+            for loc in txn.read_hints().iter().chain(txn.write_hints().iter()) {
+                if let Some(&writer) = last_writers.get(loc.state_key()) {
+                    let edge = (writer, *idx, loc.state_key().clone());
+                    if !required_edges.contains(&edge) {
+                        return Err(PartitionViolation::MissingDependency {
+                            writer: edge.0,
+                            reader: edge.1,
+                            state_key: edge.2,
+                        });
+                    }
+                }
+            }
+        }
+        for (idx, txn_with_deps) in sub_block {
+            for loc in txn_with_deps.txn().borrow().write_hints() {
+                last_writers.insert(loc.state_key(), *idx);
+            }
+        }
+        sub_block_start = sub_block_end;
+    }
+
+    Ok(())
+}
+
+/// Check the output of `BlockPartitioner::partition()` for the block `input`.
+/// On top of everything `verify_partition()` checks:
+/// - the sub-blocks of shard i are at position i;
+/// - every edge, including those of the global txns, points to a txn of the block;
+/// - the output, global txns included, has every input txn exactly once;
+/// - the txns of a sender are in their input order.
+pub fn verify_partitioned_block(
+    input: &[AnalyzedTransaction],
+    output: &PartitionedTransactions,
+) -> Result<(), PartitionViolation> {
+    for (position, sub_blocks) in output.sharded_txns().iter().enumerate() {
+        if sub_blocks.shard_id != position {
+            return Err(PartitionViolation::MismatchedShardId {
+                position,
+                shard_id: sub_blocks.shard_id,
+            });
+        }
+    }
+    verify_partition(output.sharded_txns())?;
+
+    let num_shards = output.num_shards();
+    let num_rounds = output
+        .sharded_txns()
+        .first()
+        .map_or(0, |sub_blocks| sub_blocks.num_sub_blocks());
+    let num_sharded_txns = output.num_sharded_txns();
+    let num_txns = output.num_txns();
+    let in_range = |idx: &ShardedTxnIndex| {
+        if idx.round_id == GLOBAL_ROUND_ID {
+            idx.shard_id == GLOBAL_SHARD_ID && (num_sharded_txns..num_txns).contains(&idx.txn_index)
+        } else {
+            idx.shard_id < num_shards
+                && idx.round_id < num_rounds
+                && idx.txn_index < num_sharded_txns
+        }
+    };
+
+    // All txns in their final order, the global ones last.
+    let mut txns: Vec<(
+        ShardedTxnIndex,
+        &TransactionWithDependencies<AnalyzedTransaction>,
+    )> = vec![];
+    for round_id in 0..num_rounds {
+        for (shard_id, sub_blocks) in output.sharded_txns().iter().enumerate() {
+            let sub_block = sub_blocks.get_sub_block(round_id).unwrap();
+            for (txn_index, txn_with_deps) in sub_block.txn_with_index_iter() {
+                txns.push((
+                    ShardedTxnIndex::new(txn_index, shard_id, round_id),
+                    txn_with_deps,
+                ));
+            }
+        }
+    }
+    for (i, txn_with_deps) in output.global_txns.iter().enumerate() {
+        let idx = ShardedTxnIndex::new(num_sharded_txns + i, GLOBAL_SHARD_ID, GLOBAL_ROUND_ID);
+        txns.push((idx, txn_with_deps));
+    }
+
+    let input_idxs: HashMap<&AnalyzedTransaction, usize> = input
+        .iter()
+        .enumerate()
+        .map(|(input_idx, txn)| (txn, input_idx))
+        .collect();
+    let mut positions: Vec<Option<ShardedTxnIndex>> = vec![None; input.len()];
+    let mut last_txn_by_sender: HashMap<AccountAddress, (usize, ShardedTxnIndex)> = HashMap::new();
+    for (idx, txn_with_deps) in txns {
+        let deps = txn_with_deps.cross_shard_dependencies();
+        for (src, _locs) in deps.required_edges().iter() {
+            if !in_range(src) {
+                return Err(PartitionViolation::EdgeOutOfRange {
+                    src: *src,
+                    dst: idx,
+                });
+            }
+        }
+        for (dst, _locs) in deps.dependent_edges().iter() {
+            if !in_range(dst) {
+                return Err(PartitionViolation::EdgeOutOfRange {
+                    src: idx,
+                    dst: *dst,
+                });
+            }
+        }
+
+        let txn = txn_with_deps.txn();
+        let Some(&input_idx) = input_idxs.get(txn) else {
+            return Err(PartitionViolation::UnexpectedTxn { idx });
+        };
+        if let Some(first) = positions[input_idx] {
+            return Err(PartitionViolation::DuplicateTxn { first, second: idx });
+        }
+        positions[input_idx] = Some(idx);
+        if let Some(sender) = txn.sender() {
+            if let Some((last_input_idx, last_idx)) =
+                last_txn_by_sender.insert(sender, (input_idx, idx))
+            {
+                if last_input_idx > input_idx {
+                    return Err(PartitionViolation::ReorderedSenderTxns {
+                        sender,
+                        first: last_idx,
+                        second: idx,
+                    });
+                }
+            }
+        }
+    }
+    if let Some(input_idx) = positions.iter().position(Option::is_none) {
+        return Err(PartitionViolation::MissingTxn { input_idx });
+    }
+
+    Ok(())
+}
+
+fn check_edge((src, dst, _state_key): &Edge) -> Result<(), PartitionViolation> {
+    if (src.round_id, src.shard_id) < (dst.round_id, dst.shard_id) {
+        Ok(())
+    } else {
+        Err(PartitionViolation::BackwardEdge {
+            src: *src,
+            dst: *dst,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        no_op::NoOpPartitioner,
+        test_utils::{create_signed_p2p_transaction, generate_test_account, P2PBlockGenerator},
+        v2::config::PartitionerV2Config,
+        verify::{verify_partition, verify_partitioned_block, PartitionViolation},
+        BlockPartitioner, PartitionerConfig,
+    };
+    use aptos_types::{
+        block_executor::partitioner::{
+            CrossShardDependencies, CrossShardEdges, PartitionedTransactions, ShardedTxnIndex,
+            SubBlock, SubBlocksForShard, TransactionWithDependencies, GLOBAL_ROUND_ID,
+            GLOBAL_SHARD_ID,
+        },
+        transaction::analyzed_transaction::AnalyzedTransaction,
+    };
+    use rand::thread_rng;
+
+    fn rebuild(
+        output: &PartitionedTransactions,
+        f: impl Fn(ShardedTxnIndex, &mut CrossShardDependencies),
+    ) -> Vec<SubBlocksForShard<AnalyzedTransaction>> {
+        output
+            .sharded_txns()
+            .iter()
+            .enumerate()
+            .map(|(shard_id, sub_blocks)| {
+                let sub_blocks = sub_blocks
+                    .sub_block_iter()
+                    .enumerate()
+                    .map(|(round_id, sub_block)| {
+                        let txns = sub_block
+                            .txn_with_index_iter()
+                            .map(|(txn_index, txn_with_deps)| {
+                                let mut deps = txn_with_deps.cross_shard_dependencies().clone();
+                                f(
+                                    ShardedTxnIndex::new(txn_index, shard_id, round_id),
+                                    &mut deps,
+                                );
+                                TransactionWithDependencies::new(txn_with_deps.txn().clone(), deps)
+                            })
+                            .collect();
+                        SubBlock::new(sub_block.start_index, txns)
+                    })
+                    .collect();
+                SubBlocksForShard::new(shard_id, sub_blocks)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_partition_accepts_v2_output() {
+        let block_gen = P2PBlockGenerator::new(20);
+        let partitioner = PartitionerV2Config::default()
+            .partition_last_round(true)
+            .build();
+        let block = block_gen.rand_block(&mut thread_rng(), 200);
+        let output = partitioner.partition(block, 4);
+        assert_eq!(Ok(()), verify_partition(output.sharded_txns()));
+    }
+
+    #[test]
+    fn test_verify_partition_detects_missing_dependency() {
+        // Two txns from the same sender on different shards in the same round.
+        let mut sender = generate_test_account();
+        let receiver = generate_test_account();
+        let mut txns = create_signed_p2p_transaction(&mut sender, vec![&receiver, &receiver]);
+        let txn_1 = TransactionWithDependencies::new(txns.pop().unwrap(), Default::default());
+        let txn_0 = TransactionWithDependencies::new(txns.pop().unwrap(), Default::default());
+        let sharded_txns = vec![
+            SubBlocksForShard::new(0, vec![SubBlock::new(0, vec![txn_0])]),
+            SubBlocksForShard::new(1, vec![SubBlock::new(1, vec![txn_1])]),
+        ];
+        assert!(matches!(
+            verify_partition(&sharded_txns),
+            Err(PartitionViolation::MissingDependency { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_partition_detects_removed_edges() {
+        let block_gen = P2PBlockGenerator::new(5);
+        let partitioner = PartitionerV2Config::default()
+            .partition_last_round(true)
+            .build();
+        let block = block_gen.rand_block(&mut thread_rng(), 100);
+        let output = partitioner.partition(block, 4);
+        let num_edges: usize = output
+            .sharded_txns()
+            .iter()
+            .flat_map(|sub_blocks| sub_blocks.iter())
+            .map(|txn| txn.cross_shard_dependencies().num_required_edges())
+            .sum();
+        assert!(num_edges > 0);
+
+        // Dropping all edges.
+        let sharded_txns = rebuild(&output, |_, deps| *deps = CrossShardDependencies::default());
+        assert!(matches!(
+            verify_partition(&sharded_txns),
+            Err(PartitionViolation::MissingDependency { .. })
+        ));
+
+        // Dropping only the dependent edges.
+        let sharded_txns = rebuild(&output, |_, deps| {
+            deps.dependent_edges = CrossShardEdges::default()
+        });
+        assert!(matches!(
+            verify_partition(&sharded_txns),
+            Err(PartitionViolation::UnmatchedEdge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_partition_detects_bad_indices() {
+        let block_gen = P2PBlockGenerator::new(100);
+        let block = block_gen.rand_block(&mut thread_rng(), 4);
+        let to_sub_block = |start_index, txns: &[AnalyzedTransaction]| {
+            SubBlock::new(
+                start_index,
+                txns.iter()
+                    .cloned()
+                    .map(|txn| TransactionWithDependencies::new(txn, Default::default()))
+                    .collect(),
+            )
+        };
+
+        let gap = vec![
+            SubBlocksForShard::new(0, vec![to_sub_block(0, &block[0..2])]),
+            SubBlocksForShard::new(1, vec![to_sub_block(3, &block[2..4])]),
+        ];
+        assert_eq!(
+            Err(PartitionViolation::NonContiguousIndex {
+                round_id: 0,
+                shard_id: 1,
+                expected: 2,
+                actual: 3,
+            }),
+            verify_partition(&gap)
+        );
+
+        let duplicate = vec![
+            SubBlocksForShard::new(0, vec![to_sub_block(0, &block[0..2])]),
+            SubBlocksForShard::new(1, vec![to_sub_block(2, &block[1..3])]),
+        ];
+        assert_eq!(
+            Err(PartitionViolation::DuplicateTxn {
+                first: ShardedTxnIndex::new(1, 0, 0),
+                second: ShardedTxnIndex::new(2, 1, 0),
+            }),
+            verify_partition(&duplicate)
+        );
+
+        let mismatched_rounds = vec![
+            SubBlocksForShard::new(0, vec![to_sub_block(0, &block[0..2])]),
+            SubBlocksForShard::new(1, vec![]),
+        ];
+        assert!(matches!(
+            verify_partition(&mismatched_rounds),
+            Err(PartitionViolation::MismatchedNumRounds { shard_id: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_partitioned_block_detects_lost_and_reordered_txns() {
+        let mut sender = generate_test_account();
+        let receiver = generate_test_account();
+        let block = create_signed_p2p_transaction(&mut sender, vec![&receiver, &receiver]);
+        let extra_txn = create_signed_p2p_transaction(&mut sender, vec![&receiver]).remove(0);
+        let output = |txns: Vec<AnalyzedTransaction>| {
+            let txns = txns
+                .into_iter()
+                .map(|txn| TransactionWithDependencies::new(txn, Default::default()))
+                .collect();
+            PartitionedTransactions::new(
+                vec![SubBlocksForShard::new(0, vec![SubBlock::new(0, txns)])],
+                vec![],
+            )
+        };
+
+        assert_eq!(
+            Ok(()),
+            verify_partitioned_block(&block, &NoOpPartitioner {}.partition(block.clone(), 3))
+        );
+        assert_eq!(
+            Err(PartitionViolation::ReorderedSenderTxns {
+                sender: sender.account_address,
+                first: ShardedTxnIndex::new(0, 0, 0),
+                second: ShardedTxnIndex::new(1, 0, 0),
+            }),
+            verify_partitioned_block(&block, &output(vec![block[1].clone(), block[0].clone()]))
+        );
+        assert_eq!(
+            Err(PartitionViolation::MissingTxn { input_idx: 1 }),
+            verify_partitioned_block(&block, &output(vec![block[0].clone()]))
+        );
+        assert_eq!(
+            Err(PartitionViolation::UnexpectedTxn {
+                idx: ShardedTxnIndex::new(2, 0, 0),
+            }),
+            verify_partitioned_block(
+                &block,
+                &output(vec![block[0].clone(), block[1].clone(), extra_txn])
+            )
+        );
+
+        // A dependent edge to a global txn that does not exist.
+        let mut partitioned = output(block.clone());
+        partitioned.sharded_txns[0].sub_blocks[0].transactions[0]
+            .cross_shard_dependencies
+            .add_dependent_edge(
+                ShardedTxnIndex::new(2, GLOBAL_SHARD_ID, GLOBAL_ROUND_ID),
+                vec![block[0].write_hints()[0].clone()],
+            );
+        assert_eq!(
+            Err(PartitionViolation::EdgeOutOfRange {
+                src: ShardedTxnIndex::new(0, 0, 0),
+                dst: ShardedTxnIndex::new(2, GLOBAL_SHARD_ID, GLOBAL_ROUND_ID),
+            }),
+            verify_partitioned_block(&block, &partitioned)
+        );
+    }
+}
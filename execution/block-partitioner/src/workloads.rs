@@ -0,0 +1,246 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Synthetic workloads for evaluating block partitioners without a full executor.
+//!
+//! All generators are seeded, so the same `(workload, num_accounts, seed)` always produces the same blocks.
+
+use crate::test_utils::{
+    create_signed_p2p_transaction_with_params, generate_test_account_for_address, TestAccount,
+    TxnParams,
+};
+use aptos_crypto::{ed25519::ed25519_keys::Ed25519PrivateKey, Uniform};
+use aptos_types::{
+    state_store::state_key::StateKey,
+    transaction::analyzed_transaction::{
+        account_resource_location, AnalyzedTransaction, StorageLocation,
+    },
+};
+use move_core_types::account_address::AccountAddress;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Workload {
+    /// P2P transfers between uniformly sampled accounts.
+    UniformP2P,
+    /// P2P transfers from uniformly sampled senders to Zipf-distributed receivers.
+    HotSpot { zipf_exponent: f64 },
+    /// P2P transfers mixed with a `mint_ratio` share of NFT mints into one collection.
+    NftMintBurst { mint_ratio: f64 },
+    /// P2P transfers mixed with a `publish_ratio` share of module publishing txns.
+    ModulePublish {
+        publish_ratio: f64,
+        modules_per_package: usize,
+    },
+}
+
+impl Display for Workload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Workload::UniformP2P => write!(f, "uniform-p2p"),
+            Workload::HotSpot { zipf_exponent } => write!(f, "hot-spot(s={})", zipf_exponent),
+            Workload::NftMintBurst { mint_ratio } => write!(f, "nft-mint(r={})", mint_ratio),
+            Workload::ModulePublish {
+                publish_ratio,
+                modules_per_package,
+            } => write!(
+                f,
+                "module-publish(r={},m={})",
+                publish_ratio, modules_per_package
+            ),
+        }
+    }
+}
+
+pub struct WorkloadGenerator {
+    workload: Workload,
+    rng: StdRng,
+    accounts: Vec<TestAccount>,
+    /// Only set for `Workload::HotSpot`.
+    receiver_distribution: Option<WeightedIndex<f64>>,
+    /// The creator of the NFT collection in `Workload::NftMintBurst`.
+    collection_creator: AccountAddress,
+    num_generated: u64,
+    txn_params: TxnParams,
+}
+
+impl WorkloadGenerator {
+    pub fn new(workload: Workload, num_accounts: usize, seed: u64) -> Self {
+        assert!(num_accounts >= 2);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let accounts = (0..num_accounts)
+            .map(|_| TestAccount {
+                account_address: AccountAddress::new(rng.gen()),
+                private_key: Ed25519PrivateKey::generate(&mut rng),
+                sequence_number: 0,
+            })
+            .collect();
+        let receiver_distribution = match workload {
+            Workload::HotSpot { zipf_exponent } => Some(
+                WeightedIndex::new(
+                    (1..=num_accounts).map(|rank| 1.0 / (rank as f64).powf(zipf_exponent)),
+                )
+                .unwrap(),
+            ),
+            _ => None,
+        };
+        let collection_creator = AccountAddress::new(rng.gen());
+        Self {
+            workload,
+            rng,
+            accounts,
+            receiver_distribution,
+            collection_creator,
+            num_generated: 0,
+            txn_params: TxnParams::default(),
+        }
+    }
+
+    /// Sign the txns with the given params, e.g. to make them executable.
+    pub fn txn_params(mut self, txn_params: TxnParams) -> Self {
+        self.txn_params = txn_params;
+        self
+    }
+
+    pub fn workload(&self) -> Workload {
+        self.workload
+    }
+
+    /// The senders of the txns, with the sequence number of their next txn.
+    pub fn accounts(&self) -> &[TestAccount] {
+        &self.accounts
+    }
+
+    /// The receiver of the mint payments in `Workload::NftMintBurst`.
+    /// It has to exist (with a coin store) for the mints to succeed when executed.
+    pub fn collection_creator(&self) -> AccountAddress {
+        self.collection_creator
+    }
+
+    pub fn rand_block(&mut self, block_size: usize) -> Vec<AnalyzedTransaction> {
+        (0..block_size).map(|_| self.rand_txn()).collect()
+    }
+
+    fn rand_txn(&mut self) -> AnalyzedTransaction {
+        self.num_generated += 1;
+        match self.workload {
+            Workload::UniformP2P => self.uniform_p2p(),
+            Workload::HotSpot { .. } => {
+                let sender_idx = self.rng.gen_range(0, self.accounts.len());
+                let mut receiver_idx = self
+                    .receiver_distribution
+                    .as_ref()
+                    .unwrap()
+                    .sample(&mut self.rng);
+                if receiver_idx == sender_idx {
+                    receiver_idx = (receiver_idx + 1) % self.accounts.len();
+                }
+                self.p2p(sender_idx, receiver_idx)
+            },
+            Workload::NftMintBurst { mint_ratio } => {
+                if self.rng.gen_bool(mint_ratio) {
+                    self.nft_mint()
+                } else {
+                    self.uniform_p2p()
+                }
+            },
+            Workload::ModulePublish {
+                publish_ratio,
+                modules_per_package,
+            } => {
+                if self.rng.gen_bool(publish_ratio) {
+                    self.module_publish(modules_per_package)
+                } else {
+                    self.uniform_p2p()
+                }
+            },
+        }
+    }
+
+    fn uniform_p2p(&mut self) -> AnalyzedTransaction {
+        let indices = rand::seq::index::sample(&mut self.rng, self.accounts.len(), 2);
+        self.p2p(indices.index(0), indices.index(1))
+    }
+
+    fn p2p(&mut self, sender_idx: usize, receiver_idx: usize) -> AnalyzedTransaction {
+        let receiver = self.accounts[receiver_idx].account_address;
+        self.p2p_to_address(sender_idx, receiver)
+    }
+
+    /// A txn from a random minter that pays for a new token in the shared collection.
+    /// Signed as a transfer to the collection creator, with the hints of a mint on top of those of the transfer,
+    /// so that the hints still cover what the txn touches when it is executed.
+    fn nft_mint(&mut self) -> AnalyzedTransaction {
+        let minter_idx = self.rng.gen_range(0, self.accounts.len());
+        let mut txn = self.p2p_to_address(minter_idx, self.collection_creator);
+        let minter = self.accounts[minter_idx].account_address;
+        add_hints(
+            &mut txn,
+            vec![
+                synthetic_location(self.collection_creator, "collection"),
+                synthetic_location(minter, &format!("token_{}", self.num_generated)),
+            ],
+            vec![account_resource_location(self.collection_creator)],
+        );
+        txn
+    }
+
+    /// A txn that publishes a package of `num_modules` modules under the sender's account.
+    /// Signed as a transfer to self, with the hints of a publish on top of those of the transfer.
+    fn module_publish(&mut self, num_modules: usize) -> AnalyzedTransaction {
+        let publisher_idx = self.rng.gen_range(0, self.accounts.len());
+        let publisher = self.accounts[publisher_idx].account_address;
+        let mut txn = self.p2p_to_address(publisher_idx, publisher);
+        let mut write_hints = vec![synthetic_location(publisher, "package_registry")];
+        write_hints.extend((0..num_modules).map(|i| {
+            synthetic_location(publisher, &format!("module_{}_{}", self.num_generated, i))
+        }));
+        add_hints(&mut txn, write_hints, vec![]);
+        txn
+    }
+
+    fn p2p_to_address(
+        &mut self,
+        sender_idx: usize,
+        receiver: AccountAddress,
+    ) -> AnalyzedTransaction {
+        let receiver = generate_test_account_for_address(receiver);
+        create_signed_p2p_transaction_with_params(
+            &mut self.accounts[sender_idx],
+            vec![&receiver],
+            &self.txn_params,
+        )
+        .remove(0)
+    }
+}
+
+/// Add hints that the txn does not have yet.
+fn add_hints(
+    txn: &mut AnalyzedTransaction,
+    write_hints: Vec<StorageLocation>,
+    read_hints: Vec<StorageLocation>,
+) {
+    for hint in write_hints {
+        if !txn.write_hints.contains(&hint) {
+            txn.write_hints.push(hint);
+        }
+    }
+    for hint in read_hints {
+        if !txn.read_hints.contains(&hint) && !txn.write_hints.contains(&hint) {
+            txn.read_hints.push(hint);
+        }
+    }
+}
+
+/// A storage location for resources that `aptos-types` has no typed key for.
+fn synthetic_location(address: AccountAddress, name: &str) -> StorageLocation {
+    StorageLocation::Specific(StateKey::raw(
+        format!("{}/{}", address.to_hex(), name).as_bytes(),
+    ))
+}
@@ -2,13 +2,8 @@
 // Parts of the project are originally copyright © Meta Platforms, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use aptos_block_partitioner::{
-    pre_partition::{
-        connected_component::config::ConnectedComponentPartitionerConfig,
-        default_pre_partitioner_config, uniform_partitioner::config::UniformPartitionerConfig,
-        PrePartitionerConfig,
-    },
-    v2::config::PartitionerV2Config,
+use aptos_block_partitioner::config::{
+    AnchorStrategyParams, BlockPartitionerConfig, PartitionerV2Params, PrePartitionerParams,
 };
 use aptos_config::config::{
     EpochSnapshotPrunerConfig, LedgerPrunerConfig, PrunerConfig, StateMerklePrunerConfig,
@@ -144,50 +139,117 @@ struct ShardingOpt {
     remote_executor_addresses: Option<Vec<SocketAddr>>,
     #[clap(long)]
     coordinator_address: Option<SocketAddr>,
+    /// Let the remote shards keep the state values they read across blocks, updated with the
+    /// values written by each block. Only valid as the blocks are executed one after the other.
+    #[clap(long)]
+    remote_executor_chained_state_views: bool,
     #[clap(long, default_value = "4")]
     max_partitioning_rounds: usize,
     #[clap(long, default_value = "0.90")]
     partitioner_cross_shard_dep_avoid_threshold: f32,
     #[clap(long)]
     partitioner_version: Option<String>,
+    /// A YAML `BlockPartitionerConfig`, instead of the partitioner flags.
+    #[clap(long)]
+    partitioner_config_path: Option<PathBuf>,
     #[clap(long)]
     pre_partitioner: Option<String>,
     #[clap(long, default_value = "2.0")]
     load_imbalance_tolerance: f32,
+    #[clap(long)]
+    sender_affinity: bool,
     #[clap(long, default_value = "8")]
     partitioner_v2_num_threads: usize,
     #[clap(long, default_value = "64")]
     partitioner_v2_dashmap_num_shards: usize,
+    #[clap(long)]
+    partitioner_v2_disable_state_pooling: bool,
+    #[clap(long)]
+    partitioner_v2_anchor_strategy: Option<String>,
+    #[clap(long)]
+    partitioner_v2_verify_output: bool,
+    #[clap(long)]
+    partitioner_v2_max_txns_per_shard_per_round: Option<usize>,
+    /// If set, blocks with at least this conflict ratio are executed unsharded.
+    #[clap(long)]
+    partitioner_v2_unsharded_fallback_threshold: Option<f64>,
+    /// Stop partitioning a block after processing this many txns (summed over all phases).
+    #[clap(long)]
+    partitioner_v2_budget_txns_processed: Option<usize>,
+    #[clap(long, default_value = "1.2")]
+    partitioner_v2_affinity_load_imbalance_tolerance: f32,
+    /// Spread the last round over the shards. Needs the last round to be partitioned (no `--use-global-executor`).
+    #[clap(long)]
+    partitioner_v2_balance_last_round: bool,
 }
 
 impl ShardingOpt {
-    fn pre_partitioner_config(&self) -> Box<dyn PrePartitionerConfig> {
+    fn pre_partitioner_params(&self) -> PrePartitionerParams {
         match self.pre_partitioner.as_deref() {
-            None => default_pre_partitioner_config(),
-            Some("uniform") => Box::new(UniformPartitionerConfig {}),
-            Some("connected-component") => Box::new(ConnectedComponentPartitionerConfig {
+            None => PrePartitionerParams::default(),
+            Some("uniform") => PrePartitionerParams::Uniform,
+            Some("connected-component") => PrePartitionerParams::ConnectedComponent {
                 load_imbalance_tolerance: self.load_imbalance_tolerance,
-            }),
+                sender_affinity: self.sender_affinity,
+            },
             _ => panic!("Unknown PrePartitioner: {:?}", self.pre_partitioner),
         }
     }
 
-    fn partitioner_config(&self) -> PartitionerV2Config {
-        match self.partitioner_version.as_deref() {
-            Some("v2") => PartitionerV2Config {
+    fn anchor_strategy_params(&self) -> AnchorStrategyParams {
+        match self.partitioner_v2_anchor_strategy.as_deref() {
+            None | Some("hash") => AnchorStrategyParams::Hash,
+            Some("load-aware") => AnchorStrategyParams::LoadAware,
+            _ => panic!(
+                "Unknown anchor strategy: {:?}",
+                self.partitioner_v2_anchor_strategy
+            ),
+        }
+    }
+
+    fn partitioner_config(&self) -> BlockPartitionerConfig {
+        let config = match (
+            &self.partitioner_config_path,
+            self.partitioner_version.as_deref(),
+        ) {
+            (Some(path), None) => {
+                let contents = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                    panic!("Failed to read the partitioner config {:?}: {}", path, e)
+                });
+                serde_yaml::from_str(&contents).unwrap_or_else(|e| {
+                    panic!("Failed to parse the partitioner config {:?}: {}", path, e)
+                })
+            },
+            (Some(_), Some(_)) => {
+                panic!("--partitioner-config-path and --partitioner-version are exclusive")
+            },
+            (None, Some("v2")) => BlockPartitionerConfig::V2(PartitionerV2Params {
                 num_threads: self.partitioner_v2_num_threads,
                 max_partitioning_rounds: self.max_partitioning_rounds,
                 cross_shard_dep_avoid_threshold: self.partitioner_cross_shard_dep_avoid_threshold,
                 dashmap_num_shards: self.partitioner_v2_dashmap_num_shards,
                 partition_last_round: !self.use_global_executor,
-                pre_partitioner_config: self.pre_partitioner_config(),
-            },
-            None => PartitionerV2Config::default(),
-            _ => panic!(
+                balance_last_round: self.partitioner_v2_balance_last_round,
+                pre_partitioner: self.pre_partitioner_params(),
+                state_pooling: !self.partitioner_v2_disable_state_pooling,
+                anchor_strategy: self.anchor_strategy_params(),
+                verify_output: self.partitioner_v2_verify_output,
+                max_txns_per_shard_per_round: self.partitioner_v2_max_txns_per_shard_per_round,
+                unsharded_fallback_threshold: self.partitioner_v2_unsharded_fallback_threshold,
+                budget_txns_processed: self.partitioner_v2_budget_txns_processed,
+                affinity_load_imbalance_tolerance: self
+                    .partitioner_v2_affinity_load_imbalance_tolerance,
+            }),
+            (None, None) => BlockPartitionerConfig::default(),
+            (None, _) => panic!(
                 "Unknown partitioner version: {:?}",
                 self.partitioner_version
             ),
+        };
+        if let Err(e) = config.validate() {
+            panic!("Invalid partitioner config {:?}: {}", config, e);
         }
+        config
     }
 }
 
@@ -485,6 +547,11 @@ fn main() {
         remote_executor_client::set_coordinator_address(
             opt.pipeline_opt.sharding_opt.coordinator_address.unwrap(),
         );
+        remote_executor_client::set_chained_state_views(
+            opt.pipeline_opt
+                .sharding_opt
+                .remote_executor_chained_state_views,
+        );
         // it does not matter because shards are on remote node, but for sake of correctness lets
         // set it
         execution_threads_per_shard = execution_threads;
@@ -524,6 +591,13 @@ fn main() {
         run::<AptosVM>(opt);
     }
 
+    if !remote_executor_client::get_remote_addresses().is_empty() {
+        println!(
+            "Remote executor shards fetched {} state values from the coordinator",
+            remote_executor_client::get_num_served_state_values()
+        );
+    }
+
     if cpu_profiling {
         let _cpu_end = cpu_profiler.end_profiling("");
     }
@@ -0,0 +1,554 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end benchmark of sharded execution: the blocks of a workload are partitioned, then
+//! executed by shards running in threads of this process, or by shards talking to the coordinator
+//! and to each other over the network on localhost. See `bin/sharded_execution_benchmark.rs`.
+
+use crate::{
+    config::RemoteExecutorConfig, error::Error, metrics::REMOTE_EXECUTOR_CROSS_SHARD_COUNT,
+    remote_executor_client::RemoteExecutorClient, test_utils,
+    thread_executor_service::ThreadExecutorService,
+};
+use aptos_block_partitioner::{config::BlockPartitionerConfig, PartitionerConfig};
+use aptos_config::utils;
+use aptos_language_e2e_tests::{data_store::FakeDataStore, executor::FakeExecutor};
+use aptos_types::{
+    block_executor::{
+        config::BlockExecutorConfigFromOnchain, partitioner::PartitionedTransactions,
+    },
+    transaction::{ExecutionStatus, TransactionStatus},
+};
+use aptos_vm::sharded_block_executor::{
+    executor_client::ExecutorClient, local_executor_shard::LocalExecutorClient,
+    ShardedBlockExecutor,
+};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The txns of the blocks, from the generators of `test_utils`. Every block has accounts of its
+/// own, so the blocks are all executed on top of the same state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BenchmarkWorkload {
+    /// Transfers between distinct accounts.
+    NoConflict,
+    /// Half of the transfers pay the same account.
+    HotSpot,
+    /// Chains of 10 transfers, each depending on the previous one.
+    Chained,
+    /// Hubs paid by 10 accounts, then paying 10 others.
+    FanInFanOut,
+    /// Transfers between distinct accounts, one in 10 paying 10 times the gas unit price.
+    MixedGas,
+    /// Transfers between a tenth as many accounts as txns, each paying all the others in turn.
+    AllToAll,
+}
+
+impl BenchmarkWorkload {
+    fn generate(
+        self,
+        executor: &mut FakeExecutor,
+        block_size: usize,
+    ) -> test_utils::TransferWorkload {
+        match self {
+            Self::NoConflict => test_utils::generate_hot_spot_workload(executor, block_size, 0.0),
+            Self::HotSpot => test_utils::generate_hot_spot_workload(executor, block_size, 0.5),
+            Self::Chained => {
+                test_utils::generate_chained_workload(executor, (block_size / 10).max(1), 10)
+            },
+            Self::FanInFanOut => {
+                test_utils::generate_fan_in_fan_out_workload(executor, (block_size / 20).max(1), 10)
+            },
+            Self::MixedGas => test_utils::generate_mixed_gas_workload(executor, block_size, 0.1),
+            Self::AllToAll => {
+                let num_accounts = (block_size / 10).max(2);
+                test_utils::generate_all_to_all_workload(
+                    executor,
+                    num_accounts,
+                    block_size + num_accounts,
+                )
+            },
+        }
+    }
+}
+
+/// The partitioners with their default config, see `BlockPartitionerConfig`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BenchmarkPartitioner {
+    NoOp,
+    ConnectedComponent,
+    V2,
+}
+
+impl BenchmarkPartitioner {
+    fn config(self) -> BlockPartitionerConfig {
+        match self {
+            Self::NoOp => BlockPartitionerConfig::NoOp,
+            // The tolerance a config file defaults to.
+            Self::ConnectedComponent => BlockPartitionerConfig::ConnectedComponent {
+                load_imbalance_tolerance: 2.0,
+            },
+            Self::V2 => BlockPartitionerConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecutionMode {
+    /// `LocalExecutorClient`, the shards exchanging messages over channels.
+    Thread,
+    /// `RemoteExecutorClient`, with a `ThreadExecutorService` per shard on localhost.
+    Network,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct BenchmarkConfig {
+    #[clap(long, default_value_t = 4)]
+    pub num_shards: usize,
+
+    /// Defaults to sharing the available parallelism evenly between the shards.
+    #[clap(long)]
+    pub threads_per_shard: Option<usize>,
+
+    #[clap(long, value_enum, default_value_t = BenchmarkWorkload::NoConflict)]
+    pub workload: BenchmarkWorkload,
+
+    /// Approximate number of txns per block, the workloads rounding it to their shape.
+    #[clap(long, default_value_t = 1000)]
+    pub block_size: usize,
+
+    #[clap(long, default_value_t = 5)]
+    pub num_blocks: usize,
+
+    #[clap(long, value_enum, default_value_t = BenchmarkPartitioner::V2)]
+    pub partitioner: BenchmarkPartitioner,
+
+    /// YAML or JSON file of a `BlockPartitionerConfig`, used instead of the default config of
+    /// `--partitioner`.
+    #[clap(long, conflicts_with = "partitioner")]
+    pub partitioner_config_path: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = ExecutionMode::Thread)]
+    pub mode: ExecutionMode,
+}
+
+impl BenchmarkConfig {
+    pub fn new(num_shards: usize) -> Self {
+        Self {
+            num_shards,
+            threads_per_shard: None,
+            workload: BenchmarkWorkload::NoConflict,
+            block_size: 1000,
+            num_blocks: 5,
+            partitioner: BenchmarkPartitioner::V2,
+            partitioner_config_path: None,
+            mode: ExecutionMode::Thread,
+        }
+    }
+
+    pub fn threads_per_shard(mut self, threads_per_shard: usize) -> Self {
+        self.threads_per_shard = Some(threads_per_shard);
+        self
+    }
+
+    pub fn workload(mut self, workload: BenchmarkWorkload) -> Self {
+        self.workload = workload;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn num_blocks(mut self, num_blocks: usize) -> Self {
+        self.num_blocks = num_blocks;
+        self
+    }
+
+    pub fn partitioner(mut self, partitioner: BenchmarkPartitioner) -> Self {
+        self.partitioner = partitioner;
+        self
+    }
+
+    pub fn mode(mut self, mode: ExecutionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    fn partitioner_config(&self) -> Result<BlockPartitionerConfig, Error> {
+        let config = match &self.partitioner_config_path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|e| {
+                    Error::InvalidConfig(format!("Failed to read {}: {}", path.display(), e))
+                })?;
+                serde_yaml::from_str(&contents).map_err(|e| {
+                    Error::InvalidConfig(format!("Failed to parse {}: {}", path.display(), e))
+                })?
+            },
+            None => self.partitioner.config(),
+        };
+        config
+            .validate()
+            .map_err(|e| Error::InvalidConfig(format!("Invalid partitioner config: {}", e)))?;
+        Ok(config)
+    }
+
+    fn remote_executor_config(&self) -> Result<RemoteExecutorConfig, Error> {
+        let mut config = RemoteExecutorConfig::new(self.num_shards);
+        if let Some(threads_per_shard) = self.threads_per_shard {
+            config = config.threads_per_shard(threads_per_shard);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Percentiles of a duration over the blocks, in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl LatencySummary {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        latencies.sort();
+        let percentile =
+            |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+        Self {
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: percentile(100),
+        }
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50={:.2}ms, p90={:.2}ms, p99={:.2}ms, max={:.2}ms",
+            self.p50_ms, self.p90_ms, self.p99_ms, self.max_ms
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchmarkReport {
+    pub num_shards: usize,
+    pub workload: BenchmarkWorkload,
+    pub partitioner: BlockPartitionerConfig,
+    pub mode: ExecutionMode,
+    pub num_blocks: usize,
+    pub num_txns: usize,
+    /// Txns not kept with a success status, which make the numbers meaningless if any.
+    pub num_failed_txns: usize,
+    /// Txns per second, partitioning included.
+    pub tps: f64,
+    /// Txns per second, execution only.
+    pub execution_tps: f64,
+    pub block_latency: LatencySummary,
+    pub partition_latency: LatencySummary,
+    /// What the coordinator took to put the outputs of the shards in order, a part of the block
+    /// latency.
+    pub aggregation_latency: LatencySummary,
+    /// The txns the partitioner gave each shard, over all the blocks. The global txns are not
+    /// counted.
+    pub txns_per_shard: Vec<usize>,
+    /// The txns of the most loaded shard over the average, 1 when the shards are balanced.
+    pub shard_imbalance: f64,
+    /// The cross-shard dependencies the partitioner found, one message each at most.
+    pub cross_shard_edges: usize,
+    /// The cross-shard messages the shards sent, only counted over the network.
+    pub cross_shard_messages: Option<u64>,
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} blocks of {:?} on {} shards ({:?} mode), partitioned by {:?}",
+            self.num_blocks, self.workload, self.num_shards, self.mode, self.partitioner
+        )?;
+        writeln!(
+            f,
+            "Txns: {} ({} failed)",
+            self.num_txns, self.num_failed_txns
+        )?;
+        writeln!(
+            f,
+            "TPS: {:.0} ({:.0} execution only)",
+            self.tps, self.execution_tps
+        )?;
+        writeln!(f, "Block latency: {}", self.block_latency)?;
+        writeln!(f, "Partition latency: {}", self.partition_latency)?;
+        writeln!(f, "Aggregation latency: {}", self.aggregation_latency)?;
+        writeln!(
+            f,
+            "Txns per shard: {:?} (imbalance {:.2})",
+            self.txns_per_shard, self.shard_imbalance
+        )?;
+        write!(f, "Cross-shard edges: {}", self.cross_shard_edges)?;
+        if let Some(cross_shard_messages) = self.cross_shard_messages {
+            write!(f, ", messages: {}", cross_shard_messages)?;
+        }
+        Ok(())
+    }
+}
+
+// What the blocks took to partition and execute, before the report sums it up.
+#[derive(Default)]
+struct BlockMeasurements {
+    partition_latencies: Vec<Duration>,
+    block_latencies: Vec<Duration>,
+    aggregation_latencies: Vec<Duration>,
+    num_txns: usize,
+    num_failed_txns: usize,
+    txns_per_shard: Vec<usize>,
+    cross_shard_edges: usize,
+}
+
+/// Generate the blocks of the workload, then partition and execute them one after the other.
+pub fn run(config: &BenchmarkConfig) -> Result<BenchmarkReport, Error> {
+    let partitioner_config = config.partitioner_config()?;
+    let remote_executor_config = config.remote_executor_config()?;
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workloads: Vec<_> = (0..config.num_blocks)
+        .map(|_| config.workload.generate(&mut executor, config.block_size))
+        .collect();
+    let data_store = Arc::new(executor.data_store().clone());
+
+    let cross_shard_messages_before = num_cross_shard_messages(config.num_shards);
+    let measurements = match config.mode {
+        ExecutionMode::Thread => execute_blocks(
+            LocalExecutorClient::create_local_sharded_block_executor(
+                config.num_shards,
+                config.threads_per_shard,
+            ),
+            &partitioner_config,
+            &remote_executor_config,
+            data_store,
+            workloads,
+        ),
+        ExecutionMode::Network => {
+            let coordinator_address = available_address();
+            let shard_addresses: Vec<_> = (0..config.num_shards)
+                .map(|_| available_address())
+                .collect();
+            let controller = remote_executor_config.network_controller(
+                "remote-executor-coordinator".to_string(),
+                coordinator_address,
+            )?;
+            let mut executor_services = (0..config.num_shards)
+                .map(|shard_id| {
+                    ThreadExecutorService::new(
+                        shard_id,
+                        &remote_executor_config,
+                        coordinator_address,
+                        shard_addresses.clone(),
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let measurements = execute_blocks(
+                ShardedBlockExecutor::new(RemoteExecutorClient::new(
+                    shard_addresses,
+                    controller,
+                    None,
+                )?),
+                &partitioner_config,
+                &remote_executor_config,
+                data_store,
+                workloads,
+            );
+            executor_services.iter_mut().for_each(|executor_service| {
+                executor_service.shutdown();
+            });
+            measurements
+        },
+    }?;
+    let cross_shard_messages = match config.mode {
+        ExecutionMode::Thread => None,
+        ExecutionMode::Network => {
+            Some(num_cross_shard_messages(config.num_shards) - cross_shard_messages_before)
+        },
+    };
+
+    let total_partition_time: Duration = measurements.partition_latencies.iter().sum();
+    let total_execution_time: Duration = measurements.block_latencies.iter().sum();
+    let txns_per_second = |duration: Duration| {
+        measurements.num_txns as f64 / duration.as_secs_f64().max(f64::EPSILON)
+    };
+    let max_shard_txns = measurements
+        .txns_per_shard
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0);
+    let avg_shard_txns = measurements.txns_per_shard.iter().sum::<usize>() as f64
+        / measurements.txns_per_shard.len().max(1) as f64;
+    Ok(BenchmarkReport {
+        num_shards: config.num_shards,
+        workload: config.workload,
+        partitioner: partitioner_config,
+        mode: config.mode,
+        num_blocks: config.num_blocks,
+        num_txns: measurements.num_txns,
+        num_failed_txns: measurements.num_failed_txns,
+        tps: txns_per_second(total_partition_time + total_execution_time),
+        execution_tps: txns_per_second(total_execution_time),
+        block_latency: LatencySummary::new(measurements.block_latencies),
+        partition_latency: LatencySummary::new(measurements.partition_latencies),
+        aggregation_latency: LatencySummary::new(measurements.aggregation_latencies),
+        txns_per_shard: measurements.txns_per_shard,
+        shard_imbalance: if avg_shard_txns == 0.0 {
+            1.0
+        } else {
+            max_shard_txns as f64 / avg_shard_txns
+        },
+        cross_shard_edges: measurements.cross_shard_edges,
+        cross_shard_messages,
+    })
+}
+
+fn execute_blocks<E: ExecutorClient<FakeDataStore>>(
+    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+    partitioner_config: &BlockPartitionerConfig,
+    remote_executor_config: &RemoteExecutorConfig,
+    data_store: Arc<FakeDataStore>,
+    workloads: Vec<test_utils::TransferWorkload>,
+) -> Result<BlockMeasurements, Error> {
+    let num_shards = sharded_block_executor.num_shards();
+    let partitioner = partitioner_config.build();
+    let mut measurements = BlockMeasurements {
+        txns_per_shard: vec![0; num_shards],
+        ..Default::default()
+    };
+    let mut result = Ok(());
+    for workload in workloads {
+        let started_at = Instant::now();
+        let partitioned_txns = partitioner.partition(workload.transactions, num_shards);
+        measurements.partition_latencies.push(started_at.elapsed());
+        for (shard_id, sub_blocks) in partitioned_txns.sharded_txns().iter().enumerate() {
+            measurements.txns_per_shard[shard_id] += sub_blocks.num_txns();
+        }
+        measurements.cross_shard_edges += num_cross_shard_edges(&partitioned_txns);
+
+        let started_at = Instant::now();
+        let outputs = match sharded_block_executor.execute_block(
+            data_store.clone(),
+            partitioned_txns,
+            remote_executor_config.num_threads_per_shard(),
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        ) {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                result = Err(Error::InternalError(format!(
+                    "Failed to execute the block: {}",
+                    e
+                )));
+                break;
+            },
+        };
+        measurements.block_latencies.push(started_at.elapsed());
+        if let Some(breakdown) = sharded_block_executor.last_block_breakdown() {
+            measurements
+                .aggregation_latencies
+                .push(breakdown.aggregation_time);
+        }
+        measurements.num_txns += outputs.len();
+        measurements.num_failed_txns += outputs
+            .iter()
+            .filter(|output| output.status() != &TransactionStatus::Keep(ExecutionStatus::Success))
+            .count();
+    }
+    sharded_block_executor.shutdown();
+    result.map(|_| measurements)
+}
+
+// The storage locations written by a txn that txns of other shards or rounds read.
+fn num_cross_shard_edges(partitioned_txns: &PartitionedTransactions) -> usize {
+    partitioned_txns
+        .sharded_txns()
+        .iter()
+        .flat_map(|sub_blocks| sub_blocks.iter())
+        .map(|txn| {
+            txn.cross_shard_dependencies()
+                .dependent_edges()
+                .iter()
+                .map(|(_, storage_locations)| storage_locations.len())
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+fn num_cross_shard_messages(num_shards: usize) -> u64 {
+    (0..num_shards)
+        .map(|shard_id| {
+            REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+                .with_label_values(&[&shard_id.to_string(), "messages"])
+                .get()
+        })
+        .sum()
+}
+
+fn available_address() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps the entry point of `sharded_execution_benchmark` from rotting, with blocks small
+    // enough for a unit test.
+    #[test]
+    fn test_benchmark_smoke() {
+        for mode in [ExecutionMode::Thread, ExecutionMode::Network] {
+            let report = run(&BenchmarkConfig::new(2)
+                .threads_per_shard(2)
+                .workload(BenchmarkWorkload::HotSpot)
+                .block_size(20)
+                .num_blocks(2)
+                .mode(mode))
+            .unwrap();
+            assert_eq!(report.num_blocks, 2);
+            assert_eq!(report.num_failed_txns, 0);
+            assert_eq!(report.num_txns, 40);
+            assert_eq!(report.txns_per_shard.len(), 2);
+            assert!(report.aggregation_latency.max_ms <= report.block_latency.max_ms);
+            assert!(report.tps > 0.0);
+            assert_eq!(
+                report.cross_shard_messages.is_some(),
+                mode == ExecutionMode::Network
+            );
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(json["num_blocks"], 2);
+            assert!(!report.to_string().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_benchmark_rejects_invalid_config() {
+        assert!(matches!(
+            run(&BenchmarkConfig::new(0).num_blocks(1)),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replay a block from the recording of the coordinator or of a shard (see
+//! `RemoteExecutorConfig::recording_dir`), without the rest of the cluster. A shard executes its
+//! sub-blocks again and fails if the outputs differ from the ones it recorded sending.
+
+use aptos_executor_service::recording::{self, BlockRecording, Participant};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// The recording to replay, e.g. `<command id>_shard_0.recording`.
+    pub recording: PathBuf,
+
+    /// Number of threads executing the transactions of a shard.
+    #[clap(long, default_value_t = num_cpus::get())]
+    pub num_threads: usize,
+}
+
+fn main() {
+    let args = Args::parse();
+    let recording = BlockRecording::load(&args.recording).unwrap_or_else(|e| panic!("{}", e));
+    match recording.participant {
+        Participant::Coordinator => {
+            let outputs =
+                recording::replay_coordinator(&recording).unwrap_or_else(|e| panic!("{}", e));
+            println!(
+                "Command {}: {} outputs received from the shards",
+                recording.command_id,
+                outputs.len()
+            );
+        },
+        Participant::Shard(shard_id) => {
+            let round_outputs = recording::replay_shard(&recording, args.num_threads)
+                .unwrap_or_else(|e| panic!("{}", e));
+            for (round, outputs) in round_outputs.iter().enumerate() {
+                println!(
+                    "Command {} on shard {}, round {}: {} outputs as recorded",
+                    recording.command_id,
+                    shard_id,
+                    round,
+                    outputs.len()
+                );
+            }
+        },
+    }
+}
+
+#[test]
+fn verify_tool() {
+    use clap::CommandFactory;
+    Args::command().debug_assert()
+}
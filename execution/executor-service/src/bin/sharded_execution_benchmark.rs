@@ -0,0 +1,37 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Partition and execute the blocks of a workload with N shards, printing the TPS, the latency of
+//! the blocks and of their partitioning, the balance of the shards and the cross-shard messages.
+
+use aptos_executor_service::benchmark::{self, BenchmarkConfig};
+use clap::Parser;
+
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(flatten)]
+    pub config: BenchmarkConfig,
+
+    /// Print the report as JSON, for scripts to compare runs.
+    #[clap(long)]
+    pub json: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let report = benchmark::run(&args.config).unwrap_or_else(|e| panic!("{}", e));
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Failed to serialize the report")
+        );
+    } else {
+        println!("{}", report);
+    }
+}
+
+#[test]
+fn verify_tool() {
+    use clap::CommandFactory;
+    Args::command().debug_assert()
+}
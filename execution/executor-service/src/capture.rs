@@ -0,0 +1,159 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capture of the inputs of the sub-blocks the shards execute, for a sub-block to be executed
+//! again on its own with `RemoteExecutorClient::execute_sub_block`, e.g. to look into an output
+//! that differs from the one of another executor.
+
+use crate::{error::Error, ExecuteBlockCommand, SubBlockInputs};
+use aptos_logger::warn;
+use aptos_types::{
+    block_executor::{
+        config::BlockExecutorConfigFromOnchain,
+        partitioner::{RoundId, ShardId, SubBlock},
+    },
+    transaction::analyzed_transaction::AnalyzedTransaction,
+};
+use aptos_vm::sharded_block_executor::messages::RemoteTxnWrite;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The inputs a shard executed the sub-block of a round of a block with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SubBlockCapture {
+    /// The id of the command the block was executed with.
+    pub command_id: u64,
+    pub shard_id: ShardId,
+    pub round: RoundId,
+    pub inputs: SubBlockInputs,
+}
+
+impl SubBlockCapture {
+    fn file_name(&self) -> String {
+        format!(
+            "{}_shard_{}_round_{}.bcs",
+            self.command_id, self.shard_id, self.round
+        )
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(self.file_name());
+        fs::write(&path, bcs::to_bytes(self)?).map_err(|e| {
+            Error::InternalError(format!("Failed to write {}: {}", path.display(), e))
+        })?;
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let bytes = fs::read(path).map_err(|e| {
+            Error::InternalError(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        Ok(bcs::from_bytes(&bytes)?)
+    }
+
+    /// All the captures in the directory, by command, shard and round.
+    pub fn load_all(dir: &Path) -> Result<Vec<Self>, Error> {
+        let entries = fs::read_dir(dir).map_err(|e| {
+            Error::InternalError(format!("Failed to read {}: {}", dir.display(), e))
+        })?;
+        let mut captures = vec![];
+        for entry in entries {
+            let path = entry
+                .map_err(|e| {
+                    Error::InternalError(format!("Failed to read {}: {}", dir.display(), e))
+                })?
+                .path();
+            if path
+                .extension()
+                .map_or(false, |extension| extension == "bcs")
+            {
+                captures.push(Self::load(&path)?);
+            }
+        }
+        captures.sort_by_key(|capture| (capture.command_id, capture.shard_id, capture.round));
+        Ok(captures)
+    }
+}
+
+// The block a shard is executing, with the writes it received from the other shards so far.
+struct CapturedBlock {
+    command_id: u64,
+    sub_blocks: Vec<SubBlock<AnalyzedTransaction>>,
+    concurrency_level: usize,
+    onchain_config: BlockExecutorConfigFromOnchain,
+    cross_shard_writes: Vec<Vec<RemoteTxnWrite>>,
+}
+
+/// Writes the inputs of each sub-block a shard executes to a directory, once the sub-block is
+/// executed.
+pub(crate) struct SubBlockRecorder {
+    shard_id: ShardId,
+    dir: PathBuf,
+    block: Mutex<Option<CapturedBlock>>,
+}
+
+impl SubBlockRecorder {
+    pub fn new(shard_id: ShardId, dir: PathBuf) -> Self {
+        Self {
+            shard_id,
+            dir,
+            block: Mutex::new(None),
+        }
+    }
+
+    pub fn start_block(&self, command: &ExecuteBlockCommand) {
+        let sub_blocks = command
+            .sub_blocks
+            .sub_block_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        *self.block.lock().unwrap() = Some(CapturedBlock {
+            command_id: command.command_id,
+            cross_shard_writes: vec![vec![]; sub_blocks.len()],
+            sub_blocks,
+            concurrency_level: command.concurrency_level,
+            onchain_config: command.onchain_config.clone(),
+        });
+    }
+
+    // Nothing is recorded for the sub-blocks executed on their own, which are not part of a block.
+    pub fn stop_block(&self) {
+        *self.block.lock().unwrap() = None;
+    }
+
+    pub fn record_cross_shard_write(&self, round: RoundId, write: &RemoteTxnWrite) {
+        if let Some(block) = self.block.lock().unwrap().as_mut() {
+            block.cross_shard_writes[round].push(write.clone());
+        }
+    }
+
+    /// Write the inputs of the round, which the shard is done executing. A failure to write them
+    /// is only logged, not to fail the block.
+    pub fn finish_round(&self, round: RoundId) {
+        let mut block = self.block.lock().unwrap();
+        let Some(block) = block.as_mut() else {
+            return;
+        };
+        let capture = SubBlockCapture {
+            command_id: block.command_id,
+            shard_id: self.shard_id,
+            round,
+            inputs: SubBlockInputs {
+                sub_block: block.sub_blocks[round].clone(),
+                cross_shard_writes: std::mem::take(&mut block.cross_shard_writes[round]),
+                concurrency_level: block.concurrency_level,
+                onchain_config: block.onchain_config.clone(),
+            },
+        };
+        if let Err(e) = capture.save(&self.dir) {
+            warn!(
+                "Shard {} failed to capture round {} of command {}: {}",
+                self.shard_id, round, capture.command_id, e
+            );
+        }
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Error;
+use aptos_secure_net::network_controller::{
+    security::{NetworkSecurity, SharedSecret, TlsConfig},
+    NetworkController,
+};
+use aptos_types::state_store::state_key::StateKey;
+use move_core_types::language_storage::ModuleId;
+use serde::{Deserialize, Serialize};
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+/// How the coordinator and the shards authenticate each other.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SecurityConfig {
+    /// Mutual TLS, with the PEM files of the certificate and key of the node, and of the CA that
+    /// signs the certificates of all the nodes, which must be issued for `server_name`.
+    Tls {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        ca_cert_path: PathBuf,
+        server_name: String,
+    },
+    /// Every message is signed with the secret in the file, which all the nodes share. This only
+    /// guarantees the integrity of the messages: they are not encrypted, and a message recorded
+    /// on the network can be replayed, e.g. to execute a block again. So this is only for trusted
+    /// networks, `Tls` protecting against both.
+    SharedSecret { secret_path: PathBuf },
+}
+
+impl SecurityConfig {
+    /// Read the files of the config.
+    pub fn load(&self) -> Result<NetworkSecurity, Error> {
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|e| {
+                Error::InvalidConfig(format!("Failed to read {}: {}", path.display(), e))
+            })
+        };
+        Ok(match self {
+            Self::Tls {
+                cert_path,
+                key_path,
+                ca_cert_path,
+                server_name,
+            } => NetworkSecurity::Tls(TlsConfig {
+                cert: read(cert_path)?,
+                key: read(key_path)?,
+                ca_cert: read(ca_cert_path)?,
+                server_name: server_name.clone(),
+            }),
+            Self::SharedSecret { secret_path } => {
+                let secret = read(secret_path)?;
+                if secret.is_empty() {
+                    return Err(Error::InvalidConfig(format!(
+                        "The shared secret in {} is empty",
+                        secret_path.display()
+                    )));
+                }
+                NetworkSecurity::SharedSecret(SharedSecret::new(&secret))
+            },
+        })
+    }
+}
+
+/// What the coordinator has the shards fetch into their state cache before the first block, for
+/// it not to wait on the values every block reads.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmUpConfig {
+    /// The modules to fetch, e.g. the ones of the framework.
+    pub module_ids: Vec<ModuleId>,
+    pub state_keys: Vec<StateKey>,
+    /// How long the coordinator waits for the shards to be warm before sending them the first
+    /// block, in milliseconds. Not waited for if not set, the shards warming up before executing
+    /// the block anyway.
+    pub timeout_ms: Option<u64>,
+}
+
+impl WarmUpConfig {
+    pub fn new(module_ids: Vec<ModuleId>, state_keys: Vec<StateKey>) -> Self {
+        Self {
+            module_ids,
+            state_keys,
+            timeout_ms: None,
+        }
+    }
+
+    pub fn timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    /// The keys of all the state values to fetch, modules included.
+    pub fn all_state_keys(&self) -> Vec<StateKey> {
+        self.module_ids
+            .iter()
+            .map(StateKey::module_id)
+            .chain(self.state_keys.iter().cloned())
+            .collect()
+    }
+}
+
+/// How the remote executor shards are set up. The coordinator and every shard must agree on it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteExecutorConfig {
+    /// Number of executor shards.
+    pub num_shards: usize,
+    /// Number of threads executing the transactions of a shard, which also bounds the concurrency
+    /// level a shard executes a block with. If not set, the available parallelism is shared
+    /// evenly between the shards.
+    pub threads_per_shard: Option<usize>,
+    /// Number of cross-shard messages a shard makes room for upfront in every round, for the ones
+    /// received and not consumed yet. It is not a bound, the messages of all the rounds being
+    /// received by the network controller, which must not block on a round that is behind.
+    pub cross_shard_channel_capacity: usize,
+    /// Timeout of the network requests between the coordinator and the shards, in milliseconds.
+    pub network_timeout_ms: u64,
+    /// Maximum number of cross-shard messages a shard sends together to another shard and round.
+    /// 1 sends every message on its own.
+    pub cross_shard_batch_size: usize,
+    /// How long a cross-shard message may wait for others to the same shard and round, in
+    /// microseconds. The pending messages go out once the first of them waited this long, even if
+    /// no message comes after it, or earlier if the batch is full or the round ends. 0 sends every
+    /// message on its own.
+    pub cross_shard_batch_flush_after_us: u64,
+    /// The cross-shard messages sent together are compressed if they take at least this many
+    /// bytes. Not compressed if not set.
+    pub cross_shard_compression_threshold: Option<usize>,
+    /// Maximum number of blocks the coordinator sends to a shard before the shard finishes them.
+    /// The coordinator holds the next blocks for the shard until it finishes one, so that the
+    /// commands do not pile up on a slow shard. 1 sends a shard a block once it is done with the
+    /// previous one.
+    pub max_queued_commands_per_shard: usize,
+    /// Maximum number of state values a shard keeps across blocks, which it does not fetch again
+    /// from the coordinator for the next blocks if they are still valid. Not kept if not set.
+    pub state_cache_size: Option<usize>,
+    /// How the coordinator and the shards authenticate each other, which must be set the same way
+    /// on all of them. Neither authenticated nor encrypted if not set.
+    pub security: Option<SecurityConfig>,
+    /// Directory the shards write the inputs of every sub-block they execute to, for it to be
+    /// executed again on its own (see `capture::SubBlockCapture`). Not captured if not set.
+    pub capture_dir: Option<PathBuf>,
+    /// Directory the coordinator and the shards write the messages they exchange for every block
+    /// to, for the block to be replayed from them (see `recording::replay_shard`). Not recorded if
+    /// not set.
+    pub recording_dir: Option<PathBuf>,
+    /// How many of the last blocks the coordinator and each shard keep the recording of, the
+    /// older ones being deleted.
+    pub max_recorded_blocks: usize,
+    /// The coordinator traces one block in this many, along with the execution of the block on
+    /// the shards. Not traced if not set.
+    pub trace_sampling_interval: Option<u64>,
+    /// What the coordinator has the shards fetch before the first block, which only lasts if the
+    /// shards keep state values (see `state_cache_size`). Not warmed up if not set.
+    pub warm_up: Option<WarmUpConfig>,
+}
+
+impl Default for RemoteExecutorConfig {
+    fn default() -> Self {
+        Self {
+            num_shards: 1,
+            threads_per_shard: None,
+            cross_shard_channel_capacity: 256,
+            network_timeout_ms: 5000,
+            cross_shard_batch_size: 1,
+            cross_shard_batch_flush_after_us: 1000,
+            cross_shard_compression_threshold: None,
+            max_queued_commands_per_shard: 1,
+            state_cache_size: None,
+            security: None,
+            capture_dir: None,
+            recording_dir: None,
+            max_recorded_blocks: 100,
+            trace_sampling_interval: None,
+            warm_up: None,
+        }
+    }
+}
+
+impl RemoteExecutorConfig {
+    pub fn new(num_shards: usize) -> Self {
+        Self {
+            num_shards,
+            ..Self::default()
+        }
+    }
+
+    pub fn threads_per_shard(mut self, threads_per_shard: usize) -> Self {
+        self.threads_per_shard = Some(threads_per_shard);
+        self
+    }
+
+    pub fn cross_shard_channel_capacity(mut self, cross_shard_channel_capacity: usize) -> Self {
+        self.cross_shard_channel_capacity = cross_shard_channel_capacity;
+        self
+    }
+
+    pub fn network_timeout_ms(mut self, network_timeout_ms: u64) -> Self {
+        self.network_timeout_ms = network_timeout_ms;
+        self
+    }
+
+    pub fn cross_shard_batch_size(mut self, cross_shard_batch_size: usize) -> Self {
+        self.cross_shard_batch_size = cross_shard_batch_size;
+        self
+    }
+
+    pub fn cross_shard_batch_flush_after_us(
+        mut self,
+        cross_shard_batch_flush_after_us: u64,
+    ) -> Self {
+        self.cross_shard_batch_flush_after_us = cross_shard_batch_flush_after_us;
+        self
+    }
+
+    pub fn cross_shard_compression_threshold(
+        mut self,
+        cross_shard_compression_threshold: usize,
+    ) -> Self {
+        self.cross_shard_compression_threshold = Some(cross_shard_compression_threshold);
+        self
+    }
+
+    pub fn max_queued_commands_per_shard(mut self, max_queued_commands_per_shard: usize) -> Self {
+        self.max_queued_commands_per_shard = max_queued_commands_per_shard;
+        self
+    }
+
+    pub fn state_cache_size(mut self, state_cache_size: usize) -> Self {
+        self.state_cache_size = Some(state_cache_size);
+        self
+    }
+
+    pub fn security(mut self, security: SecurityConfig) -> Self {
+        self.security = Some(security);
+        self
+    }
+
+    pub fn capture_dir(mut self, capture_dir: PathBuf) -> Self {
+        self.capture_dir = Some(capture_dir);
+        self
+    }
+
+    pub fn recording_dir(mut self, recording_dir: PathBuf) -> Self {
+        self.recording_dir = Some(recording_dir);
+        self
+    }
+
+    pub fn max_recorded_blocks(mut self, max_recorded_blocks: usize) -> Self {
+        self.max_recorded_blocks = max_recorded_blocks;
+        self
+    }
+
+    pub fn trace_sampling_interval(mut self, trace_sampling_interval: u64) -> Self {
+        self.trace_sampling_interval = Some(trace_sampling_interval);
+        self
+    }
+
+    pub fn warm_up(mut self, warm_up: WarmUpConfig) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// A network controller for a node of the config, listening on `listen_address`.
+    pub fn network_controller(
+        &self,
+        service: String,
+        listen_address: SocketAddr,
+    ) -> Result<NetworkController, Error> {
+        let mut controller =
+            NetworkController::new(service, listen_address, self.network_timeout_ms);
+        if let Some(security) = &self.security {
+            controller.set_security(security.load()?);
+        }
+        Ok(controller)
+    }
+
+    /// The configured number of threads per shard, or the default one.
+    pub fn num_threads_per_shard(&self) -> usize {
+        self.threads_per_shard.unwrap_or_else(|| {
+            (num_cpus::get() as f64 / self.num_shards.max(1) as f64).ceil() as usize
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.num_shards == 0 {
+            return Err(Error::InvalidConfig(
+                "num_shards must be at least 1".to_string(),
+            ));
+        }
+        if self.threads_per_shard == Some(0) {
+            return Err(Error::InvalidConfig(
+                "threads_per_shard must be at least 1".to_string(),
+            ));
+        }
+        if self.network_timeout_ms == 0 {
+            return Err(Error::InvalidConfig(
+                "network_timeout_ms must be at least 1".to_string(),
+            ));
+        }
+        if self.cross_shard_batch_size == 0 {
+            return Err(Error::InvalidConfig(
+                "cross_shard_batch_size must be at least 1".to_string(),
+            ));
+        }
+        if self.max_queued_commands_per_shard == 0 {
+            return Err(Error::InvalidConfig(
+                "max_queued_commands_per_shard must be at least 1".to_string(),
+            ));
+        }
+        if self.state_cache_size == Some(0) {
+            return Err(Error::InvalidConfig(
+                "state_cache_size must be at least 1".to_string(),
+            ));
+        }
+        if self.max_recorded_blocks == 0 {
+            return Err(Error::InvalidConfig(
+                "max_recorded_blocks must be at least 1".to_string(),
+            ));
+        }
+        if self.trace_sampling_interval == Some(0) {
+            return Err(Error::InvalidConfig(
+                "trace_sampling_interval must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Validate the config for a set of shards listening on `num_addresses` addresses.
+    pub fn validate_for_addresses(&self, num_addresses: usize) -> Result<(), Error> {
+        self.validate()?;
+        if num_addresses != self.num_shards {
+            return Err(Error::InvalidConfig(format!(
+                "{} shard addresses for {} shards",
+                num_addresses, self.num_shards
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config() {
+        let config = RemoteExecutorConfig::new(4);
+        config.validate().unwrap();
+        assert!(config.num_threads_per_shard() >= 1);
+        assert!(config.num_threads_per_shard() * 4 >= num_cpus::get());
+        assert_eq!(config.threads_per_shard(3).num_threads_per_shard(), 3);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: RemoteExecutorConfig =
+            serde_json::from_str(r#"{"num_shards": 2, "threads_per_shard": 8}"#).unwrap();
+        assert_eq!(config, RemoteExecutorConfig::new(2).threads_per_shard(8));
+        let config: RemoteExecutorConfig = serde_json::from_str(
+            r#"{"num_shards": 2, "threads_per_shard": 8, "cross_shard_channel_capacity": 4096}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            RemoteExecutorConfig::new(2)
+                .threads_per_shard(8)
+                .cross_shard_channel_capacity(4096)
+        );
+        assert!(serde_json::from_str::<RemoteExecutorConfig>(r#"{"num_threads": 8}"#).is_err());
+        let config: RemoteExecutorConfig = serde_json::from_str(
+            r#"{"num_shards": 2, "cross_shard_batch_size": 64, "cross_shard_compression_threshold": 1024}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            RemoteExecutorConfig::new(2)
+                .cross_shard_batch_size(64)
+                .cross_shard_compression_threshold(1024)
+        );
+        let config: RemoteExecutorConfig = serde_json::from_str(
+            r#"{"num_shards": 2, "security": {"shared_secret": {"secret_path": "/etc/secret"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            RemoteExecutorConfig::new(2).security(SecurityConfig::SharedSecret {
+                secret_path: "/etc/secret".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_security_config() {
+        let missing_secret = SecurityConfig::SharedSecret {
+            secret_path: "/nonexistent/secret".into(),
+        };
+        assert!(matches!(
+            missing_secret.load(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for config in [
+            RemoteExecutorConfig::new(0),
+            RemoteExecutorConfig::new(2).threads_per_shard(0),
+            RemoteExecutorConfig::new(2).network_timeout_ms(0),
+            RemoteExecutorConfig::new(2).cross_shard_batch_size(0),
+            RemoteExecutorConfig::new(2).max_queued_commands_per_shard(0),
+            RemoteExecutorConfig::new(2).state_cache_size(0),
+            RemoteExecutorConfig::new(2).max_recorded_blocks(0),
+            RemoteExecutorConfig::new(2).trace_sampling_interval(0),
+        ] {
+            assert!(
+                matches!(config.validate(), Err(Error::InvalidConfig(_))),
+                "{:?}",
+                config
+            );
+        }
+        assert!(RemoteExecutorConfig::new(2)
+            .validate_for_addresses(2)
+            .is_ok());
+        assert!(RemoteExecutorConfig::new(2)
+            .validate_for_addresses(3)
+            .is_err());
+    }
+}
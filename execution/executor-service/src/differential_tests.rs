@@ -0,0 +1,198 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Execute the same synthetic blocks unsharded and sharded, with every partitioner, on the local
+//! and the remote sharded executors, and check that the outputs are the same.
+
+use crate::{
+    config::RemoteExecutorConfig,
+    test_utils::{add_workload_accounts, executable_txn_params, execute_and_compare},
+    tests::create_thread_remote_executor_shards,
+};
+use aptos_block_partitioner::{
+    connected_component::config::ConnectedComponentBlockPartitionerConfig,
+    no_op::NoOpPartitioner,
+    pre_partition::{
+        connected_component::config::ConnectedComponentPartitionerConfig,
+        uniform_partitioner::config::UniformPartitionerConfig,
+    },
+    v2::{config::PartitionerV2Config, fallback::UnshardedFallbackConfig},
+    workloads::{Workload, WorkloadGenerator},
+    BlockPartitioner, PartitionerConfig,
+};
+use aptos_language_e2e_tests::{data_store::FakeDataStore, executor::FakeExecutor};
+use aptos_types::transaction::analyzed_transaction::AnalyzedTransaction;
+use aptos_vm::sharded_block_executor::{
+    executor_client::ExecutorClient, local_executor_shard::LocalExecutorService,
+    ShardedBlockExecutor,
+};
+use proptest::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+
+fn partitioners() -> Vec<(&'static str, Box<dyn BlockPartitioner>)> {
+    vec![
+        ("noop", Box::new(NoOpPartitioner {})),
+        (
+            "connected-component",
+            ConnectedComponentBlockPartitionerConfig::default().build(),
+        ),
+        (
+            "v2-uniform",
+            PartitionerV2Config::default()
+                .pre_partitioner_config(Box::new(UniformPartitionerConfig {}))
+                .build(),
+        ),
+        (
+            "v2-connected-component",
+            PartitionerV2Config::default()
+                .pre_partitioner_config(Box::<ConnectedComponentPartitionerConfig>::default())
+                .build(),
+        ),
+        (
+            "v2-merged-last-round",
+            PartitionerV2Config::default()
+                .max_partitioning_rounds(2)
+                .partition_last_round(false)
+                .build(),
+        ),
+        (
+            "v2-balanced-last-round",
+            PartitionerV2Config::default()
+                .max_partitioning_rounds(2)
+                .partition_last_round(true)
+                .balance_last_round(true)
+                .build(),
+        ),
+        (
+            "v2-unsharded-fallback",
+            PartitionerV2Config::default()
+                .unsharded_fallback(Some(UnshardedFallbackConfig::default()))
+                .build(),
+        ),
+    ]
+}
+
+/// A block to execute both ways. All the fields are inputs of the generator, so a case is reproducible from them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DifferentialCase {
+    #[serde(default)]
+    name: String,
+    workload: Workload,
+    num_accounts: usize,
+    seed: u64,
+    block_size: usize,
+    num_shards: usize,
+}
+
+impl DifferentialCase {
+    fn run(&self) {
+        let mut executor = FakeExecutor::from_head_genesis();
+        let mut generator = WorkloadGenerator::new(self.workload, self.num_accounts, self.seed)
+            .txn_params(executable_txn_params());
+        add_workload_accounts(&mut executor, &generator);
+        let block = generator.rand_block(self.block_size);
+
+        let client = LocalExecutorService::setup_local_executor_shards(self.num_shards, Some(2));
+        self.run_with_executor(
+            "local",
+            ShardedBlockExecutor::new(client),
+            executor.data_store(),
+            &block,
+        );
+
+        let (client, mut executor_services) = create_thread_remote_executor_shards(
+            &RemoteExecutorConfig::new(self.num_shards).threads_per_shard(2),
+        );
+        self.run_with_executor(
+            "remote",
+            ShardedBlockExecutor::new(client),
+            executor.data_store(),
+            &block,
+        );
+        executor_services.iter_mut().for_each(|executor_service| {
+            executor_service.shutdown();
+        });
+    }
+
+    /// Execute the block partitioned by every partitioner on the sharded executor.
+    fn run_with_executor<E: ExecutorClient<FakeDataStore>>(
+        &self,
+        executor_name: &str,
+        mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+        data_store: &FakeDataStore,
+        block: &[AnalyzedTransaction],
+    ) {
+        for (partitioner_name, partitioner) in partitioners() {
+            let partitioned_txns = partitioner.partition(block.to_vec(), self.num_shards);
+            // The mismatch itself is reported by the panic of the comparison.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                execute_and_compare(&sharded_block_executor, data_store, partitioned_txns, 2)
+            }));
+            assert!(
+                result.is_ok(),
+                "{} ({:?}) failed with {} on the {} sharded executor",
+                self.name,
+                self,
+                partitioner_name,
+                executor_name
+            );
+        }
+        sharded_block_executor.shutdown();
+    }
+}
+
+#[test]
+fn test_differential_corpus() {
+    let corpus: Vec<DifferentialCase> =
+        serde_json::from_str(include_str!("test_data/differential_corpus.json")).unwrap();
+    assert!(!corpus.is_empty());
+    for case in corpus {
+        case.run();
+    }
+}
+
+fn arb_workload() -> impl Strategy<Value = Workload> {
+    prop_oneof![
+        Just(Workload::UniformP2P),
+        (0.5..3.0f64).prop_map(|zipf_exponent| Workload::HotSpot { zipf_exponent }),
+        (0.0..=1.0f64).prop_map(|mint_ratio| Workload::NftMintBurst { mint_ratio }),
+        (0.0..=1.0f64, 1..4usize).prop_map(|(publish_ratio, modules_per_package)| {
+            Workload::ModulePublish {
+                publish_ratio,
+                modules_per_package,
+            }
+        }),
+    ]
+}
+
+fn arb_case() -> impl Strategy<Value = DifferentialCase> {
+    (
+        arb_workload(),
+        2..50usize,
+        any::<u64>(),
+        0..150usize,
+        1..6usize,
+    )
+        .prop_map(
+            |(workload, num_accounts, seed, block_size, num_shards)| DifferentialCase {
+                name: String::new(),
+                workload,
+                num_accounts,
+                seed,
+                block_size,
+                num_shards,
+            },
+        )
+}
+
+proptest! {
+    // Every case executes the block once per partitioner, keep the number of cases low.
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    /// When this fails, add the shrunk case to `test_data/differential_corpus.json`.
+    #[test]
+    fn test_differential_random_blocks(case in arb_case()) {
+        case.run();
+    }
+}
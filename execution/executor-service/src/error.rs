@@ -2,6 +2,7 @@
 // Parts of the project are originally copyright © Meta Platforms, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use aptos_types::block_executor::partitioner::{RoundId, ShardId};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -12,6 +13,16 @@ pub enum Error {
     InternalError(String),
     #[error("Serialization error: {0}")]
     SerializationError(String),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Message encoded with unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(u8),
+    #[error("Shard {shard_id} is incompatible with the coordinator: {reason}")]
+    IncompatibleShard { shard_id: ShardId, reason: String },
+    #[error(
+        "The outputs of round {round} replayed on shard {shard_id} differ from the recorded ones"
+    )]
+    ReplayMismatch { shard_id: ShardId, round: RoundId },
 }
 
 impl From<bcs::Error> for Error {
@@ -20,6 +31,17 @@ impl From<bcs::Error> for Error {
     }
 }
 
+impl From<aptos_secure_net::network_controller::error::Error> for Error {
+    fn from(error: aptos_secure_net::network_controller::error::Error) -> Self {
+        match error {
+            aptos_secure_net::network_controller::error::Error::InvalidTlsConfig(_) => {
+                Self::InvalidConfig(error.to_string())
+            },
+            _ => Self::InternalError(error.to_string()),
+        }
+    }
+}
+
 impl From<aptos_secure_net::Error> for Error {
     fn from(error: aptos_secure_net::Error) -> Self {
         Self::InternalError(error.to_string())
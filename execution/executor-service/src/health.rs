@@ -0,0 +1,393 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Health checks of the executor shards. The coordinator pings every shard periodically, and a
+//! shard answers with what it is doing, which tells the coordinator both that the shard is alive
+//! and whether it takes blocks. A shard process can also report its status over HTTP, for the
+//! orchestration of the shard processes.
+
+use crate::{error::Error, metrics::REMOTE_EXECUTOR_SHARD_HEALTHY, protocol};
+use aptos_logger::{info, warn};
+use aptos_secure_net::network_controller::Message;
+use aptos_types::block_executor::partitioner::ShardId;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long the HTTP endpoint waits for a request to come in on a connection.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a shard is doing.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ShardStatus {
+    /// Waiting for a block.
+    Ready,
+    /// Executing the block of the command with the given id.
+    ExecutingBlock(u64),
+    /// About to shut down, once done with the block it executes if any. The shard is not to be
+    /// sent any new block.
+    Draining,
+}
+
+impl fmt::Display for ShardStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardStatus::Ready => write!(f, "ready"),
+            ShardStatus::ExecutingBlock(command_id) => write!(f, "executing block {}", command_id),
+            ShardStatus::Draining => write!(f, "draining"),
+        }
+    }
+}
+
+/// The status of a shard, as updated by the parts of the shard that change it.
+#[derive(Debug, Default)]
+pub struct ShardStatusTracker {
+    // The id of the command being executed, if any.
+    executing: Mutex<Option<u64>>,
+    draining: AtomicBool,
+}
+
+impl ShardStatusTracker {
+    pub fn status(&self) -> ShardStatus {
+        if self.draining.load(Ordering::Acquire) {
+            return ShardStatus::Draining;
+        }
+        match *self.executing.lock().unwrap() {
+            Some(command_id) => ShardStatus::ExecutingBlock(command_id),
+            None => ShardStatus::Ready,
+        }
+    }
+
+    /// Whether the shard is executing a block, be it draining or not.
+    pub fn is_executing(&self) -> bool {
+        self.executing.lock().unwrap().is_some()
+    }
+
+    /// The id of the command being executed, if any.
+    pub fn executing_command_id(&self) -> Option<u64> {
+        *self.executing.lock().unwrap()
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn start_block(&self, command_id: u64) {
+        *self.executing.lock().unwrap() = Some(command_id);
+    }
+
+    pub(crate) fn finish_block(&self) {
+        *self.executing.lock().unwrap() = None;
+    }
+}
+
+// Sent by the coordinator to a shard, which answers with a `HealthCheckResponse`. Like the
+// registration of a shard, the health checks are encoded the same way by all the protocol
+// versions, as they are exchanged before the protocol is agreed on.
+#[derive(Deserialize, Serialize)]
+struct HealthCheckRequest;
+
+#[derive(Deserialize, Serialize)]
+struct HealthCheckResponse {
+    shard_id: ShardId,
+    status: ShardStatus,
+}
+
+/// Answer the health checks of the coordinator with the status of the shard, until the network
+/// controller of the shard is shutdown.
+pub(crate) fn answer_health_checks(
+    shard_id: ShardId,
+    request_rx: Receiver<Message>,
+    response_tx: Sender<Message>,
+    status: &ShardStatusTracker,
+) {
+    while let Ok(message) = request_rx.recv() {
+        if let Err(error) = protocol::decode_handshake::<HealthCheckRequest>(&message) {
+            warn!("Shard {} cannot decode a health check: {}", shard_id, error);
+            continue;
+        }
+        let response = HealthCheckResponse {
+            shard_id,
+            status: status.status(),
+        };
+        if response_tx
+            .send(protocol::encode_handshake(&response))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// How the coordinator checks the health of the shards.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthCheckPolicy {
+    /// How often the shards are pinged.
+    pub interval: Duration,
+    /// How long a shard can go without answering before it is deemed unhealthy.
+    pub timeout: Duration,
+    /// How long to wait for all the shards to be ready before dispatching blocks to them.
+    pub readiness_timeout: Duration,
+}
+
+impl Default for HealthCheckPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            readiness_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// What the coordinator knows of a shard from its health checks.
+#[derive(Clone, Copy, Default)]
+struct ShardHealth {
+    // The status the shard last reported, and when it did.
+    last_response: Option<(ShardStatus, Instant)>,
+    // Whether the shard answered within the timeout when last checked.
+    healthy: bool,
+}
+
+/// Pings the shards on behalf of the coordinator, and keeps track of their answers.
+pub(crate) struct ShardHealthMonitor {
+    policy: RwLock<HealthCheckPolicy>,
+    shards: Mutex<Vec<ShardHealth>>,
+}
+
+impl ShardHealthMonitor {
+    pub fn new(num_shards: usize) -> Self {
+        Self {
+            policy: RwLock::new(HealthCheckPolicy::default()),
+            shards: Mutex::new(vec![ShardHealth::default(); num_shards]),
+        }
+    }
+
+    pub fn policy(&self) -> HealthCheckPolicy {
+        *self.policy.read().unwrap()
+    }
+
+    pub fn set_policy(&self, policy: HealthCheckPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    /// Ping the shards every interval and record their answers, until the network controller of
+    /// the coordinator is shutdown.
+    pub fn run(&self, request_txs: Vec<Sender<Message>>, response_rx: Receiver<Message>) {
+        let mut next_ping = Instant::now();
+        loop {
+            if Instant::now() >= next_ping {
+                for request_tx in request_txs.iter() {
+                    // Fails once the network controller is shutdown, which the receiver tells too.
+                    request_tx
+                        .send(protocol::encode_handshake(&HealthCheckRequest))
+                        .ok();
+                }
+                self.update_health();
+                next_ping = Instant::now() + self.policy().interval;
+            }
+            match response_rx.recv_deadline(next_ping) {
+                Ok(message) => match protocol::decode_handshake::<HealthCheckResponse>(&message) {
+                    Ok(response) => self.record_response(response),
+                    Err(error) => warn!("Cannot decode the health of a shard: {}", error),
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn record_response(&self, response: HealthCheckResponse) {
+        let mut shards = self.shards.lock().unwrap();
+        let Some(shard) = shards.get_mut(response.shard_id) else {
+            warn!(
+                "Health check answered by unknown shard {}",
+                response.shard_id
+            );
+            return;
+        };
+        shard.last_response = Some((response.status, Instant::now()));
+        drop(shards);
+        self.update_health();
+    }
+
+    // Tell which shards became healthy or unhealthy since the last check.
+    fn update_health(&self) {
+        let timeout = self.policy().timeout;
+        let mut shards = self.shards.lock().unwrap();
+        for (shard_id, shard) in shards.iter_mut().enumerate() {
+            let healthy = shard
+                .last_response
+                .map_or(false, |(_, responded_at)| responded_at.elapsed() <= timeout);
+            if healthy != shard.healthy {
+                if healthy {
+                    info!("Executor shard {} is healthy", shard_id);
+                } else {
+                    warn!(
+                        "Executor shard {} did not answer the health checks for {:?}",
+                        shard_id, timeout
+                    );
+                }
+                shard.healthy = healthy;
+            }
+            REMOTE_EXECUTOR_SHARD_HEALTHY
+                .with_label_values(&[&shard_id.to_string()])
+                .set(healthy as i64);
+        }
+    }
+
+    /// The status the shard last reported, if it answered within the timeout.
+    pub fn status(&self, shard_id: ShardId) -> Option<ShardStatus> {
+        let timeout = self.policy().timeout;
+        self.shards.lock().unwrap()[shard_id]
+            .last_response
+            .filter(|(_, responded_at)| responded_at.elapsed() <= timeout)
+            .map(|(status, _)| status)
+    }
+
+    /// Whether the shard answered the health checks before, but not within the timeout since.
+    pub fn stopped_responding(&self, shard_id: ShardId) -> bool {
+        let timeout = self.policy().timeout;
+        self.shards.lock().unwrap()[shard_id]
+            .last_response
+            .map_or(false, |(_, responded_at)| responded_at.elapsed() > timeout)
+    }
+
+    /// Whether every shard answered within the timeout, and is not draining.
+    pub fn all_ready(&self) -> bool {
+        self.not_ready().is_empty()
+    }
+
+    // The shards that are not ready, with the status they reported if they answered in time.
+    fn not_ready(&self) -> Vec<(ShardId, Option<ShardStatus>)> {
+        let num_shards = self.shards.lock().unwrap().len();
+        (0..num_shards)
+            .map(|shard_id| (shard_id, self.status(shard_id)))
+            .filter(|(_, status)| matches!(status, None | Some(ShardStatus::Draining)))
+            .collect()
+    }
+
+    /// Wait up to the readiness timeout for all the shards to be ready. Returns the first shard
+    /// that is not, with the status it reported if it answered in time.
+    pub fn wait_until_ready(&self) -> Result<(), (ShardId, Option<ShardStatus>)> {
+        let deadline = Instant::now() + self.policy().readiness_timeout;
+        loop {
+            let not_ready = self.not_ready();
+            match not_ready.first() {
+                None => return Ok(()),
+                Some(shard) if Instant::now() >= deadline => return Err(*shard),
+                Some(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+}
+
+/// Serve the status of the shard over HTTP on `address`, for as long as the process runs:
+/// - `GET /health` answers 200 with the status, as long as the process is alive.
+/// - `GET /ready` answers the same, but 503 once the shard is draining.
+pub fn serve_health_endpoint(
+    address: SocketAddr,
+    status: Arc<ShardStatusTracker>,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).map_err(|error| {
+        Error::InternalError(format!(
+            "Cannot serve the health endpoint on {}: {}",
+            address, error
+        ))
+    })?;
+    info!("Serving the health of the shard on http://{}", address);
+    thread::Builder::new()
+        .name("executor-service-health".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| answer_http_health_check(stream, &status));
+                if let Err(error) = result {
+                    warn!("Failed to answer an HTTP health check: {}", error);
+                }
+            }
+        })
+        .map_err(|error| Error::InternalError(error.to_string()))?;
+    Ok(())
+}
+
+fn answer_http_health_check(mut stream: TcpStream, status: &ShardStatusTracker) -> io::Result<()> {
+    stream.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers too, as closing a connection with unread data resets it, which the client
+    // may see before the response.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let status = status.status();
+    let (code, body) = match request_line.split_whitespace().nth(1) {
+        Some("/ready") if status == ShardStatus::Draining => {
+            ("503 Service Unavailable", status.to_string())
+        },
+        Some("/health") | Some("/ready") => ("200 OK", status.to_string()),
+        _ => ("404 Not Found", "not found".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_config::utils;
+    use std::{
+        io::Read,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    fn http_get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_health_endpoint_reports_shard_status() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let status = Arc::new(ShardStatusTracker::default());
+        serve_health_endpoint(address, status.clone()).unwrap();
+
+        let response = http_get(address, "/ready");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nready"), "{}", response);
+
+        status.start_block(7);
+        assert!(http_get(address, "/health").ends_with("executing block 7"));
+
+        status.start_draining();
+        let response = http_get(address, "/ready");
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("draining"), "{}", response);
+        assert!(http_get(address, "/health").starts_with("HTTP/1.1 200 OK"));
+        assert!(http_get(address, "/metrics").starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
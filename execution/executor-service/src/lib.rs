@@ -1,54 +1,98 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
+use crate::protocol::{ProtocolFeatures, ProtocolSupport};
 use aptos_types::{
     block_executor::{
         config::BlockExecutorConfigFromOnchain,
-        partitioner::{ShardId, SubBlocksForShard},
+        partitioner::{RoundId, ShardId, SubBlock, SubBlocksForShard},
     },
     state_store::{state_key::StateKey, state_value::StateValue},
     transaction::{analyzed_transaction::AnalyzedTransaction, TransactionOutput},
-    vm_status::VMStatus,
+};
+use aptos_vm::sharded_block_executor::{
+    execution_stats::ShardExecutionStats,
+    messages::{RemoteTxnWrite, ShardExecutionMsg},
 };
 use serde::{Deserialize, Serialize};
 
-mod error;
+pub mod benchmark;
+pub mod capture;
+pub mod config;
+#[cfg(test)]
+mod differential_tests;
+pub mod error;
+pub mod health;
 pub mod local_executor_helper;
 mod metrics;
+#[cfg(test)]
+mod mock_executor_shard;
 pub mod process_executor_service;
+pub mod protocol;
+pub mod recording;
 mod remote_cordinator_client;
 mod remote_cross_shard_client;
 pub mod remote_executor_client;
 pub mod remote_executor_service;
 mod remote_state_view;
 mod remote_state_view_service;
-#[cfg(test)]
-mod test_utils;
+mod state_cache;
+pub mod test_utils;
 #[cfg(test)]
 mod tests;
-#[cfg(test)]
-mod thread_executor_service;
+pub mod thread_executor_service;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RemoteExecutionResult {
-    pub inner: Result<Vec<Vec<TransactionOutput>>, VMStatus>,
+    // The id of the `ExecuteBlockCommand` this is a result of.
+    pub command_id: u64,
+    // The position of the result among the results of the command, which end with
+    // `ShardExecutionMsg::Done`.
+    pub seq: u64,
+    pub inner: ShardExecutionMsg,
 }
 
 impl RemoteExecutionResult {
-    pub fn new(inner: Result<Vec<Vec<TransactionOutput>>, VMStatus>) -> Self {
-        Self { inner }
+    pub fn new(command_id: u64, seq: u64, inner: ShardExecutionMsg) -> Self {
+        Self {
+            command_id,
+            seq,
+            inner,
+        }
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum RemoteExecutionRequest {
     ExecuteBlock(ExecuteBlockCommand),
+    // Sent instead of `ExecuteBlock` when the shards support `ProtocolFeatures::STATE_CACHE`.
+    ExecuteBlockWithBaseState(ExecuteBlockCommand, BaseState),
+    // Sent on its own, outside of the blocks, to shards supporting
+    // `ProtocolFeatures::SUB_BLOCK_EXECUTION`.
+    ExecuteSubBlock(ExecuteSubBlockCommand),
+    // A command the coordinator traces, sent to shards supporting `ProtocolFeatures::TRACING`.
+    Traced(TraceContext, Box<RemoteExecutionRequest>),
+    // Sent before the blocks to shards supporting `ProtocolFeatures::WARM_UP`.
+    WarmUp(WarmUpCommand),
+}
+
+/// Where the coordinator traces a command, for the shards to tie their spans to it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TraceContext {
+    // The id of the command, which identifies the block.
+    pub(crate) block_id: u64,
+    // The id of the span of the command on the coordinator.
+    pub(crate) span_id: u64,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ExecuteBlockCommand {
+    // Identifies the block, so that a shard can recognize a command that is delivered again.
+    pub(crate) command_id: u64,
     pub(crate) sub_blocks: SubBlocksForShard<AnalyzedTransaction>,
     pub(crate) concurrency_level: usize,
     pub(crate) onchain_config: BlockExecutorConfigFromOnchain,
+    // The optional features the shard is to use, which all the shards support.
+    pub(crate) features: ProtocolFeatures,
 }
 
 impl ExecuteBlockCommand {
@@ -63,29 +107,117 @@ impl ExecuteBlockCommand {
     }
 }
 
+/// A command to execute the sub-block of a round on its own.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExecuteSubBlockCommand {
+    pub(crate) command_id: u64,
+    pub(crate) round: RoundId,
+    pub(crate) inputs: SubBlockInputs,
+    pub(crate) features: ProtocolFeatures,
+}
+
+/// What a shard needs to execute the sub-block of a round again, without the other shards: the
+/// sub-block, along with the writes of the other shards its txns read.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SubBlockInputs {
+    pub sub_block: SubBlock<AnalyzedTransaction>,
+    // In the order the shard received them.
+    pub cross_shard_writes: Vec<RemoteTxnWrite>,
+    pub concurrency_level: usize,
+    pub onchain_config: BlockExecutorConfigFromOnchain,
+}
+
+/// A command to fetch state values into the cache of a shard ahead of the blocks reading them,
+/// which the shard answers with `ShardExecutionMsg::Done` once it has them all.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WarmUpCommand {
+    pub(crate) command_id: u64,
+    pub(crate) state_keys: Vec<StateKey>,
+    // The state the values are read from, which the next blocks read too.
+    pub(crate) base_state: BaseState,
+    pub(crate) features: ProtocolFeatures,
+}
+
+/// Tells a shard supporting `ProtocolFeatures::ABORT` to stop executing a block at the next round
+/// boundary. Sent on a channel of its own, as the shard only reads the commands between blocks.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AbortCommand {
+    pub(crate) command_id: u64,
+}
+
+/// The output of a sub-block executed on its own.
+#[derive(Clone, Debug)]
+pub struct SubBlockOutput {
+    pub outputs: Vec<TransactionOutput>,
+    pub stats: ShardExecutionStats,
+}
+
+/// The state a block reads, for the shards to tell which of the state values they kept from the
+/// previous blocks are still valid.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BaseState {
+    // Identifies the state, which is the same for the blocks reading the same state view.
+    pub(crate) version: u64,
+    // The state of a previous block this state is derived from, if the coordinator knows it.
+    pub(crate) parent: Option<ParentState>,
+}
+
+/// A state read by a previous block, along with what makes it the next state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ParentState {
+    pub(crate) version: u64,
+    // The values written by the block that read the state.
+    pub(crate) writes: Vec<(StateKey, Option<StateValue>)>,
+}
+
+/// Sent by a shard to the coordinator once its executor service is up, with what it supports of
+/// the protocol.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ShardRegistration {
+    pub(crate) shard_id: ShardId,
+    pub(crate) protocol_support: ProtocolSupport,
+}
+
+impl ShardRegistration {
+    pub fn new(shard_id: ShardId, protocol_support: ProtocolSupport) -> Self {
+        Self {
+            shard_id,
+            protocol_support,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RemoteKVRequest {
     pub(crate) shard_id: ShardId,
+    // The command whose state view the keys are read from, as the shards may be executing
+    // different blocks.
+    pub(crate) command_id: u64,
     pub(crate) keys: Vec<StateKey>,
 }
 
 impl RemoteKVRequest {
-    pub fn new(shard_id: ShardId, keys: Vec<StateKey>) -> Self {
-        Self { shard_id, keys }
+    pub fn new(shard_id: ShardId, command_id: u64, keys: Vec<StateKey>) -> Self {
+        Self {
+            shard_id,
+            command_id,
+            keys,
+        }
     }
 
-    pub fn into(self) -> (ShardId, Vec<StateKey>) {
-        (self.shard_id, self.keys)
+    pub fn into(self) -> (ShardId, u64, Vec<StateKey>) {
+        (self.shard_id, self.command_id, self.keys)
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RemoteKVResponse {
+    pub(crate) command_id: u64,
     pub(crate) inner: Vec<(StateKey, Option<StateValue>)>,
 }
 
 impl RemoteKVResponse {
-    pub fn new(inner: Vec<(StateKey, Option<StateValue>)>) -> Self {
-        Self { inner }
+    pub fn new(command_id: u64, inner: Vec<(StateKey, Option<StateValue>)>) -> Self {
+        Self { command_id, inner }
     }
 }
@@ -1,32 +1,200 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use aptos_executor_service::process_executor_service::ProcessExecutorService;
-use aptos_logger::info;
+use aptos_executor_service::{
+    config::{RemoteExecutorConfig, SecurityConfig},
+    error::Error,
+    health,
+    process_executor_service::ProcessExecutorService,
+};
+use aptos_logger::{info, warn};
 use clap::Parser;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+// How long the shard can take to finish the block it executes once asked to shut down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
 
 #[derive(Debug, Parser)]
 struct Args {
-    #[clap(long, default_value_t = 8)]
-    pub num_executor_threads: usize,
+    /// Number of threads executing the transactions of the shard. Defaults to sharing the available
+    /// parallelism evenly between the shards.
+    #[clap(
+        long,
+        alias = "num-executor-threads",
+        env = "APTOS_EXECUTOR_SERVICE_THREADS_PER_SHARD"
+    )]
+    pub threads_per_shard: Option<usize>,
 
     #[clap(long)]
     pub shard_id: usize,
 
-    #[clap(long)]
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_NUM_SHARDS")]
     pub num_shards: usize,
 
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().network_timeout_ms,
+        env = "APTOS_EXECUTOR_SERVICE_NETWORK_TIMEOUT_MS"
+    )]
+    pub network_timeout_ms: u64,
+
+    /// Number of cross-shard messages of a round a shard makes room for upfront.
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().cross_shard_channel_capacity,
+        env = "APTOS_EXECUTOR_SERVICE_CROSS_SHARD_CHANNEL_CAPACITY"
+    )]
+    pub cross_shard_channel_capacity: usize,
+
+    /// Maximum number of cross-shard messages sent together, 1 to send every message on its own.
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().cross_shard_batch_size,
+        env = "APTOS_EXECUTOR_SERVICE_CROSS_SHARD_BATCH_SIZE"
+    )]
+    pub cross_shard_batch_size: usize,
+
+    /// Send the pending cross-shard messages once the first of them waited this many
+    /// microseconds, if the batch is not full or the round over before.
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().cross_shard_batch_flush_after_us,
+        env = "APTOS_EXECUTOR_SERVICE_CROSS_SHARD_BATCH_FLUSH_AFTER_US"
+    )]
+    pub cross_shard_batch_flush_after_us: u64,
+
+    /// Compress the cross-shard messages sent together if they take at least this many bytes.
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_CROSS_SHARD_COMPRESSION_THRESHOLD")]
+    pub cross_shard_compression_threshold: Option<usize>,
+
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().max_queued_commands_per_shard,
+        env = "APTOS_EXECUTOR_SERVICE_MAX_QUEUED_COMMANDS_PER_SHARD"
+    )]
+    pub max_queued_commands_per_shard: usize,
+
+    /// Maximum number of state values kept across blocks.
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_STATE_CACHE_SIZE")]
+    pub state_cache_size: Option<usize>,
+
     #[clap(long, num_args = 1..)]
     pub remote_executor_addresses: Vec<SocketAddr>,
 
     #[clap(long)]
     pub coordinator_address: SocketAddr,
+
+    /// Address to serve the health of the shard on over HTTP, at `/health` for liveness and
+    /// `/ready` for readiness. Not served if not set.
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_HEALTH_CHECK_ADDRESS")]
+    pub health_check_address: Option<SocketAddr>,
+
+    /// PEM file of the certificate of the shard, to talk to the coordinator and the other shards
+    /// over mutual TLS.
+    #[clap(
+        long,
+        env = "APTOS_EXECUTOR_SERVICE_TLS_CERT",
+        requires_all = ["tls_key", "tls_ca_cert", "tls_server_name"]
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file of the CA the certificates of all the nodes are signed by.
+    #[clap(
+        long,
+        env = "APTOS_EXECUTOR_SERVICE_TLS_CA_CERT",
+        requires = "tls_cert"
+    )]
+    pub tls_ca_cert: Option<PathBuf>,
+
+    /// The name the certificates of all the nodes are issued for.
+    #[clap(
+        long,
+        env = "APTOS_EXECUTOR_SERVICE_TLS_SERVER_NAME",
+        requires = "tls_cert"
+    )]
+    pub tls_server_name: Option<String>,
+
+    /// File of the secret the messages between the nodes are signed with, on trusted networks.
+    #[clap(
+        long,
+        env = "APTOS_EXECUTOR_SERVICE_SHARED_SECRET_FILE",
+        conflicts_with = "tls_cert"
+    )]
+    pub shared_secret_file: Option<PathBuf>,
+
+    /// Directory to write the inputs of every sub-block the shard executes to, for it to be
+    /// executed again on its own. Not captured if not set.
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_CAPTURE_DIR")]
+    pub capture_dir: Option<PathBuf>,
+
+    /// Directory to write the messages the shard exchanges for every block to, for the block to
+    /// be replayed on its own. Not recorded if not set.
+    #[clap(long, env = "APTOS_EXECUTOR_SERVICE_RECORDING_DIR")]
+    pub recording_dir: Option<PathBuf>,
+
+    /// How many of the last blocks the recordings are kept of.
+    #[clap(
+        long,
+        default_value_t = RemoteExecutorConfig::default().max_recorded_blocks,
+        env = "APTOS_EXECUTOR_SERVICE_MAX_RECORDED_BLOCKS"
+    )]
+    pub max_recorded_blocks: usize,
+}
+
+impl Args {
+    fn security(&self) -> Option<SecurityConfig> {
+        if let (Some(cert_path), Some(key_path), Some(ca_cert_path), Some(server_name)) = (
+            &self.tls_cert,
+            &self.tls_key,
+            &self.tls_ca_cert,
+            &self.tls_server_name,
+        ) {
+            return Some(SecurityConfig::Tls {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+                ca_cert_path: ca_cert_path.clone(),
+                server_name: server_name.clone(),
+            });
+        }
+        self.shared_secret_file
+            .as_ref()
+            .map(|secret_path| SecurityConfig::SharedSecret {
+                secret_path: secret_path.clone(),
+            })
+    }
+
+    fn config(&self) -> Result<RemoteExecutorConfig, Error> {
+        let config = RemoteExecutorConfig {
+            num_shards: self.num_shards,
+            threads_per_shard: self.threads_per_shard,
+            cross_shard_channel_capacity: self.cross_shard_channel_capacity,
+            network_timeout_ms: self.network_timeout_ms,
+            cross_shard_batch_size: self.cross_shard_batch_size,
+            cross_shard_batch_flush_after_us: self.cross_shard_batch_flush_after_us,
+            cross_shard_compression_threshold: self.cross_shard_compression_threshold,
+            max_queued_commands_per_shard: self.max_queued_commands_per_shard,
+            state_cache_size: self.state_cache_size,
+            security: self.security(),
+            capture_dir: self.capture_dir.clone(),
+            recording_dir: self.recording_dir.clone(),
+            max_recorded_blocks: self.max_recorded_blocks,
+            // The coordinator picks the blocks to trace, and tells the shards.
+            trace_sampling_interval: None,
+            // Sent by the coordinator along with the state values to fetch.
+            warm_up: None,
+        };
+        config.validate_for_addresses(self.remote_executor_addresses.len())?;
+        Ok(config)
+    }
 }
 
 fn main() {
     let args = Args::parse();
     aptos_logger::Logger::new().init();
+    let config = args.config().unwrap_or_else(|e| panic!("{}", e));
 
     let (tx, rx) = crossbeam_channel::unbounded();
     ctrlc::set_handler(move || {
@@ -34,16 +202,26 @@ fn main() {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let _exe_service = ProcessExecutorService::new(
+    let exe_service = ProcessExecutorService::new(
         args.shard_id,
-        args.num_shards,
-        args.num_executor_threads,
+        &config,
         args.coordinator_address,
         args.remote_executor_addresses,
-    );
+    )
+    .expect("Failed to start the executor service");
+    if let Some(health_check_address) = args.health_check_address {
+        health::serve_health_endpoint(health_check_address, exe_service.status())
+            .expect("Failed to serve the health endpoint");
+    }
 
     rx.recv()
         .expect("Could not receive Ctrl-C msg from channel.");
+    if !exe_service.drain(DRAIN_TIMEOUT) {
+        warn!(
+            "Process executor service still executing a block after {:?}, shutting down anyway",
+            DRAIN_TIMEOUT
+        );
+    }
     info!("Process executor service shutdown successfully.");
 }
 
@@ -52,3 +230,94 @@ fn verify_tool() {
     use clap::CommandFactory;
     Args::command().debug_assert()
 }
+
+#[test]
+fn test_parse_args() {
+    let args = Args::try_parse_from([
+        "executor-service",
+        "--shard-id",
+        "1",
+        "--num-shards",
+        "2",
+        "--num-executor-threads",
+        "4",
+        "--cross-shard-channel-capacity",
+        "1024",
+        "--coordinator-address",
+        "127.0.0.1:52200",
+        "--remote-executor-addresses",
+        "127.0.0.1:52201",
+        "127.0.0.1:52202",
+    ])
+    .unwrap();
+    assert_eq!(
+        args.config().unwrap(),
+        RemoteExecutorConfig::new(2)
+            .threads_per_shard(4)
+            .cross_shard_channel_capacity(1024)
+    );
+
+    let args = Args::try_parse_from([
+        "executor-service",
+        "--shard-id",
+        "0",
+        "--num-shards",
+        "2",
+        "--threads-per-shard",
+        "0",
+        "--coordinator-address",
+        "127.0.0.1:52200",
+        "--remote-executor-addresses",
+        "127.0.0.1:52201",
+        "127.0.0.1:52202",
+    ])
+    .unwrap();
+    assert!(matches!(args.config(), Err(Error::InvalidConfig(_))));
+
+    let shard_args = [
+        "executor-service",
+        "--shard-id",
+        "0",
+        "--num-shards",
+        "1",
+        "--coordinator-address",
+        "127.0.0.1:52200",
+        "--remote-executor-addresses",
+        "127.0.0.1:52201",
+    ];
+    let args = Args::try_parse_from(shard_args.iter().chain(&[
+        "--tls-cert",
+        "shard.pem",
+        "--tls-key",
+        "shard.key",
+        "--tls-ca-cert",
+        "ca.pem",
+        "--tls-server-name",
+        "executor-service",
+    ]))
+    .unwrap();
+    assert_eq!(
+        args.config().unwrap().security,
+        Some(SecurityConfig::Tls {
+            cert_path: "shard.pem".into(),
+            key_path: "shard.key".into(),
+            ca_cert_path: "ca.pem".into(),
+            server_name: "executor-service".to_string(),
+        })
+    );
+    // A certificate without its key.
+    assert!(Args::try_parse_from(shard_args.iter().chain(&["--tls-cert", "shard.pem"])).is_err());
+    assert!(Args::try_parse_from(shard_args.iter().chain(&[
+        "--tls-cert",
+        "shard.pem",
+        "--tls-key",
+        "shard.key",
+        "--tls-ca-cert",
+        "ca.pem",
+        "--tls-server-name",
+        "executor-service",
+        "--shared-secret-file",
+        "secret",
+    ]))
+    .is_err());
+}
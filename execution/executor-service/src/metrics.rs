@@ -2,8 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use aptos_metrics_core::{
-    exponential_buckets, register_histogram_vec, register_int_counter_vec, HistogramVec,
-    IntCounterVec,
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGaugeVec,
 };
 use once_cell::sync::Lazy;
 
@@ -38,9 +38,100 @@ pub static REMOTE_EXECUTOR_REMOTE_KV_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
         "KV counts on a shard for: \
          1. kv_responses: the number of remote key value responses received on a shard; \
          2. non_prefetch_kv: the number of remote key value responses received on a shard that were not prefetched; \
-         3. prefetch_kv: the number of remote key value responses received on a shard that were prefetched; ",
+         3. prefetch_kv: the number of remote key value responses received on a shard that were prefetched; \
+         4. stale_kv_responses: the number of remote key value responses for a previous block discarded on a shard; ",
         // metric labels (dimensions)
         &["shard_id", "name"],
     )
     .unwrap()
 });
+
+pub static REMOTE_EXECUTOR_COMMAND_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        // metric name
+        "remote_executor_command_count",
+        // metric description
+        "Execute command counts per shard for: \
+         1. timeouts: the number of times the coordinator timed out waiting for the results of a shard; \
+         2. retries: the number of execute commands the coordinator re-sent to a shard; \
+         3. stale_results: the number of results of previous commands the coordinator discarded; \
+         4. redelivered_commands: the number of re-sent commands a shard answered with the results it already sent; \
+         5. out_of_sequence_results: the number of results the coordinator discarded as already received or following a lost one; \
+         6. aborts: the number of blocks the coordinator told a shard to abort; ",
+        // metric labels (dimensions)
+        &["shard_id", "name"],
+    )
+    .unwrap()
+});
+
+pub static REMOTE_EXECUTOR_CROSS_SHARD_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        // metric name
+        "remote_executor_cross_shard_count",
+        // metric description
+        "Cross-shard messages sent by a shard: \
+         1. messages: the number of cross-shard messages; \
+         2. batches: the number of network messages the cross-shard messages were sent in; \
+         3. raw_bytes: the size of the batches before compression; \
+         4. sent_bytes: the size of the batches as sent, after compression if any; \
+         5. stale_batches: the number of batches received by the shard for a previous block, e.g. an aborted one, and dropped; \
+         6. duplicate_batches: the number of batches delivered to the shard again, and dropped; ",
+        // metric labels (dimensions)
+        &["shard_id", "name"],
+    )
+    .unwrap()
+});
+
+pub static REMOTE_EXECUTOR_SHARD_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        // metric name
+        "remote_executor_shard_queue_depth",
+        // metric description
+        "The number of blocks the coordinator sent to a shard that the shard did not finish",
+        // metric labels (dimensions)
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub static REMOTE_EXECUTOR_BLOCKED_ON_SHARD_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        // metric name
+        "remote_executor_blocked_on_shard_seconds",
+        // metric description
+        "The time the coordinator held the command of a block for a shard, as the shard had as \
+         many blocks queued as allowed or was still busy with blocks it exchanges cross-shard \
+         messages with",
+        // metric labels (dimensions)
+        &["shard_id"],
+        exponential_buckets(/*start=*/ 1e-3, /*factor=*/ 2.0, /*count=*/ 20).unwrap(),
+    )
+    .unwrap()
+});
+
+pub static REMOTE_EXECUTOR_SHARD_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        // metric name
+        "remote_executor_shard_healthy",
+        // metric description
+        "Whether a shard answered the health checks of the coordinator within the timeout (1) or \
+         not (0)",
+        // metric labels (dimensions)
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub static REMOTE_EXECUTOR_WARM_UP_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        // metric name
+        "remote_executor_warm_up_seconds",
+        // metric description
+        "The time from the coordinator sending a shard the state values to fetch before the blocks \
+         to the shard having them all",
+        // metric labels (dimensions)
+        &["shard_id"],
+        exponential_buckets(/*start=*/ 1e-3, /*factor=*/ 2.0, /*count=*/ 20).unwrap(),
+    )
+    .unwrap()
+});
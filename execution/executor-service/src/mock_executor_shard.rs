@@ -0,0 +1,312 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    health::{self, ShardStatusTracker},
+    protocol::{self, ProtocolSupport},
+    remote_cordinator_client::RemoteCoordinatorClient,
+    remote_executor_service::join_with_timeout,
+    test_utils, ShardRegistration,
+};
+use aptos_block_partitioner::test_utils::create_non_conflicting_p2p_transaction;
+use aptos_logger::{info, warn};
+use aptos_secure_net::network_controller::{NetworkController, SHUTDOWN_TIMEOUT};
+use aptos_types::{
+    block_executor::partitioner::{
+        CrossShardDependencies, PartitionedTransactions, RoundId, ShardId, SubBlock,
+        SubBlocksForShard, TransactionWithDependencies,
+    },
+    transaction::{
+        ExecutionStatus, TransactionAuxiliaryData, TransactionOutput, TransactionStatus,
+    },
+    write_set::WriteSet,
+};
+use aptos_vm::sharded_block_executor::{
+    coordinator_client::CoordinatorClient,
+    execution_stats::{RoundExecutionStats, ShardExecutionStats},
+    executor_client::ShardedExecutionError,
+    ExecutorShardCommand,
+};
+use std::{
+    collections::HashMap,
+    iter,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What a `MockExecutorShard` does with a block.
+#[derive(Clone, Debug, Default)]
+pub struct MockBlockScript {
+    // How long the shard takes to execute a round, no time at all for the rounds not in there.
+    round_delays: HashMap<RoundId, Duration>,
+    // The round the shard fails on, with the error it reports instead of the output of the round.
+    failure: Option<(RoundId, ShardedExecutionError)>,
+    // The shard never answers the command.
+    unresponsive: bool,
+    // The shard stops answering anything once it gets the command, health checks included, as if
+    // it hung.
+    hang: bool,
+}
+
+impl MockBlockScript {
+    pub fn round_delay(mut self, round: RoundId, delay: Duration) -> Self {
+        self.round_delays.insert(round, delay);
+        self
+    }
+
+    /// Take `delay` to execute each of the first `num_rounds` rounds.
+    pub fn slow(mut self, num_rounds: usize, delay: Duration) -> Self {
+        for round in 0..num_rounds {
+            self.round_delays.insert(round, delay);
+        }
+        self
+    }
+
+    pub fn fail_at(mut self, round: RoundId, error: ShardedExecutionError) -> Self {
+        self.failure = Some((round, error));
+        self
+    }
+
+    pub fn unresponsive(mut self) -> Self {
+        self.unresponsive = true;
+        self
+    }
+
+    pub fn hang(mut self) -> Self {
+        self.hang = true;
+        self
+    }
+}
+
+/// Scripts for a block where `shard_id` takes `delay` to execute each of the `num_rounds` rounds,
+/// and the other shards execute instantly.
+pub fn slow_shard_scripts(
+    num_shards: usize,
+    shard_id: ShardId,
+    num_rounds: usize,
+    delay: Duration,
+) -> Vec<MockBlockScript> {
+    let mut scripts = vec![MockBlockScript::default(); num_shards];
+    scripts[shard_id] = MockBlockScript::default().slow(num_rounds, delay);
+    scripts
+}
+
+/// Scripts for a block where `shard_id` fails on `round` with a `ShardFailure`.
+pub fn failing_shard_scripts(
+    num_shards: usize,
+    shard_id: ShardId,
+    round: RoundId,
+) -> Vec<MockBlockScript> {
+    let mut scripts = vec![MockBlockScript::default(); num_shards];
+    scripts[shard_id] =
+        MockBlockScript::default().fail_at(round, ShardedExecutionError::ShardFailure {
+            shard_id,
+            reason: format!("Injected failure on round {}", round),
+        });
+    scripts
+}
+
+/// Scripts for a block whose rounds complete out of order across the shards: shard `i` takes
+/// `(num_shards - i) * delay` to execute round 0 and none for the other rounds, so the last shard
+/// is done with all its rounds before the first shard is done with round 0.
+pub fn out_of_order_rounds_scripts(num_shards: usize, delay: Duration) -> Vec<MockBlockScript> {
+    (0..num_shards)
+        .map(|shard_id| {
+            MockBlockScript::default().round_delay(0, delay * (num_shards - shard_id) as u32)
+        })
+        .collect()
+}
+
+/// A remote executor shard that does not execute anything, for testing the coordinator without
+/// the VM. It talks to the coordinator like an `ExecutorService`, and for each block, it sends
+/// back an output per txn, whose gas used is the index of the txn in the block, as scripted by the
+/// `MockBlockScript` of the block. Like an `ExecutorService`, it stops before the next round of a
+/// block the coordinator aborts.
+pub struct MockExecutorShard {
+    shard_id: ShardId,
+    controller: NetworkController,
+    join_handle: Option<thread::JoinHandle<()>>,
+    health_check_join_handle: Option<thread::JoinHandle<()>>,
+}
+
+impl MockExecutorShard {
+    /// Start a shard executing the blocks as scripted by `scripts`, in the order the commands come
+    /// in. The blocks after the scripted ones are executed instantly.
+    pub fn new(
+        shard_id: ShardId,
+        scripts: Vec<MockBlockScript>,
+        coordinator_address: SocketAddr,
+        remote_shard_addresses: &[SocketAddr],
+        network_timeout_ms: u64,
+    ) -> Self {
+        let mut controller = NetworkController::new(
+            format!("mock_executor_shard-{}", shard_id),
+            remote_shard_addresses[shard_id],
+            network_timeout_ms,
+        );
+        let protocol_support = ProtocolSupport::current();
+        let status = Arc::new(ShardStatusTracker::default());
+        let coordinator_client = RemoteCoordinatorClient::new(
+            shard_id,
+            &mut controller,
+            coordinator_address,
+            protocol_support,
+            Arc::new(RwLock::new(protocol_support.negotiated())),
+            None,
+            status.clone(),
+            None,
+            None,
+        );
+        let registration_tx = controller
+            .create_outbound_channel(coordinator_address, "shard_registration".to_string());
+        let hung = Arc::new(AtomicBool::new(false));
+        let health_check_rx = test_utils::drop_received_messages_when(
+            controller.create_inbound_channel("health_check_request".to_string()),
+            hung.clone(),
+        );
+        let health_check_tx = controller
+            .create_outbound_channel(coordinator_address, "health_check_response".to_string());
+        controller.start().unwrap();
+        let join_handle = thread::Builder::new()
+            .name(format!("mock-executor-shard-{}", shard_id))
+            .spawn(move || Self::run(shard_id, coordinator_client, scripts, hung))
+            .unwrap();
+        let health_check_join_handle = thread::Builder::new()
+            .name(format!("mock-executor-shard-{}-health", shard_id))
+            .spawn(move || {
+                health::answer_health_checks(shard_id, health_check_rx, health_check_tx, &status)
+            })
+            .unwrap();
+        let registration = ShardRegistration::new(shard_id, protocol_support);
+        registration_tx
+            .send(protocol::encode_handshake(&registration))
+            .unwrap();
+        Self {
+            shard_id,
+            controller,
+            join_handle: Some(join_handle),
+            health_check_join_handle: Some(health_check_join_handle),
+        }
+    }
+
+    fn run(
+        shard_id: ShardId,
+        coordinator_client: RemoteCoordinatorClient,
+        scripts: Vec<MockBlockScript>,
+        hung: Arc<AtomicBool>,
+    ) {
+        let mut scripts = scripts.into_iter();
+        while let ExecutorShardCommand::ExecuteSubBlocks(_, sub_blocks, concurrency_level, _) =
+            coordinator_client.receive_execute_command()
+        {
+            let received_at = Instant::now();
+            let script = scripts.next().unwrap_or_default();
+            if script.hang {
+                hung.store(true, Ordering::Relaxed);
+            }
+            if script.unresponsive || hung.load(Ordering::Relaxed) {
+                continue;
+            }
+            let mut stats = ShardExecutionStats {
+                receive_to_start_time: received_at.elapsed(),
+                rounds: vec![],
+                dependency_waits: vec![],
+            };
+            let mut result = Ok(());
+            for (round, sub_block) in sub_blocks.into_sub_blocks().into_iter().enumerate() {
+                if coordinator_client.is_block_aborted() {
+                    result = Err(ShardedExecutionError::Aborted);
+                    break;
+                }
+                let execution_time = script.round_delays.get(&round).copied().unwrap_or_default();
+                thread::sleep(execution_time);
+                stats.rounds.push(RoundExecutionStats {
+                    num_txns: sub_block.num_txns(),
+                    concurrency_level,
+                    execution_time,
+                    cross_shard_wait_time: Duration::ZERO,
+                });
+                if let Some((failed_round, error)) = &script.failure {
+                    if *failed_round == round {
+                        result = Err(error.clone());
+                        break;
+                    }
+                }
+                let output = (sub_block.start_index..sub_block.end_index())
+                    .map(|index| mock_txn_output(index as u64))
+                    .collect();
+                coordinator_client.send_round_output(round, output);
+            }
+            coordinator_client.send_execution_result(result, stats);
+        }
+        info!("Mock executor shard {} is shutting down", shard_id);
+    }
+
+    /// Shutdown the network controller, which stops the shard once it is done with its block.
+    /// Returns false if the shard did not stop in time.
+    pub fn close(mut self) -> bool {
+        let controller_stopped = self.controller.shutdown();
+        let shard_stopped = join_with_timeout(self.join_handle.take().unwrap(), SHUTDOWN_TIMEOUT);
+        let health_checks_stopped = join_with_timeout(
+            self.health_check_join_handle.take().unwrap(),
+            SHUTDOWN_TIMEOUT,
+        );
+        controller_stopped && shard_stopped && health_checks_stopped
+    }
+}
+
+impl Drop for MockExecutorShard {
+    fn drop(&mut self) {
+        if let Some(join_handle) = self.join_handle.take() {
+            self.controller.shutdown();
+            let join_handles = iter::once(join_handle).chain(self.health_check_join_handle.take());
+            if !join_handles.fold(true, |stopped, join_handle| {
+                join_with_timeout(join_handle, SHUTDOWN_TIMEOUT) && stopped
+            }) {
+                warn!("Mock executor shard {} did not stop", self.shard_id);
+            }
+        }
+    }
+}
+
+/// The output a `MockExecutorShard` sends for the txn at `index` in the block.
+pub fn mock_txn_output(index: u64) -> TransactionOutput {
+    TransactionOutput::new(
+        WriteSet::default(),
+        vec![],
+        index,
+        TransactionStatus::Keep(ExecutionStatus::Success),
+        TransactionAuxiliaryData::default(),
+    )
+}
+
+/// A block of `num_rounds` rounds with `num_txns_per_sub_block` txns in each sub-block and no
+/// cross-shard dependency. The txns are not executable, which does not matter to the mock shards.
+pub fn mock_partitioned_txns(
+    num_shards: usize,
+    num_rounds: usize,
+    num_txns_per_sub_block: usize,
+) -> PartitionedTransactions {
+    let mut sharded_txns: Vec<_> = (0..num_shards).map(SubBlocksForShard::empty).collect();
+    let mut start_index = 0;
+    for _ in 0..num_rounds {
+        for sub_blocks in sharded_txns.iter_mut() {
+            let transactions = (0..num_txns_per_sub_block)
+                .map(|_| {
+                    TransactionWithDependencies::new(
+                        create_non_conflicting_p2p_transaction(),
+                        CrossShardDependencies::default(),
+                    )
+                })
+                .collect();
+            sub_blocks.add_sub_block(SubBlock::new(start_index, transactions));
+            start_index += num_txns_per_sub_block;
+        }
+    }
+    PartitionedTransactions::new(sharded_txns, vec![])
+}
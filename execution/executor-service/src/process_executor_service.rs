@@ -1,12 +1,15 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::remote_executor_service::ExecutorService;
+use crate::{
+    config::RemoteExecutorConfig, error::Error, health::ShardStatusTracker,
+    remote_executor_service::ExecutorService,
+};
 use aptos_logger::info;
 use aptos_push_metrics::MetricsPusher;
 use aptos_types::block_executor::partitioner::ShardId;
 use aptos_vm::AptosVM;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 /// An implementation of the remote executor service that runs in a standalone process.
 pub struct ProcessExecutorService {
@@ -16,12 +19,13 @@ pub struct ProcessExecutorService {
 impl ProcessExecutorService {
     pub fn new(
         shard_id: ShardId,
-        num_shards: usize,
-        num_threads: usize,
+        config: &RemoteExecutorConfig,
         coordinator_address: SocketAddr,
         remote_shard_addresses: Vec<SocketAddr>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
+        config.validate_for_addresses(remote_shard_addresses.len())?;
         let self_address = remote_shard_addresses[shard_id];
+        let num_threads = config.num_threads_per_shard();
         info!(
             "Starting process remote executor service on {}; coordinator address: {}, other shard addresses: {:?}; num threads: {}",
             self_address, coordinator_address, remote_shard_addresses, num_threads
@@ -34,19 +38,33 @@ impl ProcessExecutorService {
         AptosVM::set_concurrency_level_once(num_threads);
         let mut executor_service = ExecutorService::new(
             shard_id,
-            num_shards,
-            num_threads,
+            config,
             self_address,
             coordinator_address,
             remote_shard_addresses,
-        );
-        executor_service.start();
-        Self { executor_service }
+        )?;
+        executor_service.start()?;
+        Ok(Self { executor_service })
+    }
+
+    /// What the shard is doing.
+    pub fn status(&self) -> Arc<ShardStatusTracker> {
+        self.executor_service.status()
     }
 
-    pub fn shutdown(&mut self) {
+    /// See `ExecutorService::drain`.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.executor_service.drain(timeout)
+    }
+
+    pub fn shutdown(&mut self) -> bool {
         self.executor_service.shutdown()
     }
+
+    /// Shutdown the shard and release it. Returns false if the shard did not stop cleanly.
+    pub fn close(mut self) -> bool {
+        self.shutdown()
+    }
 }
 
 impl Drop for ProcessExecutorService {
@@ -0,0 +1,238 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioning of the messages between the coordinator and the executor shards.
+//!
+//! Every message starts with the version of the protocol it is encoded with, followed by the bcs
+//! of the message, so that a peer tells a message of a version it does not support from a
+//! corrupted one. A shard sends the versions and the optional features it supports when it
+//! registers, and the coordinator picks the highest version and the features supported by all of
+//! them.
+
+use crate::error::Error;
+use aptos_secure_net::network_controller::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The latest version of the protocol.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The oldest version of the protocol still supported.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+// The version byte of the registration of a shard and of the health checks, which are encoded the
+// same way by all versions so that peers without any version in common can still tell each other
+// so.
+const HANDSHAKE_VERSION: u8 = 0;
+
+/// Optional features of the protocol, which are only used if the coordinator and all the shards
+/// support them.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct ProtocolFeatures(u64);
+
+impl ProtocolFeatures {
+    /// The coordinator tells the shards to stop executing a block it aborted, and the cross-shard
+    /// messages say which block they are for, for the shards to drop the ones left from the
+    /// aborted block.
+    pub const ABORT: Self = Self(1 << 7);
+    /// The cross-shard messages are sent in batches of the configured size.
+    pub const CROSS_SHARD_BATCHING: Self = Self(1 << 2);
+    /// The cross-shard messages are compressed above the configured size.
+    pub const CROSS_SHARD_COMPRESSION: Self = Self(1 << 1);
+    /// The shards keep the state values they read across blocks, and are told which of them the
+    /// next block can still read.
+    pub const STATE_CACHE: Self = Self(1 << 3);
+    /// The shards send the output of each round as soon as it is executed, instead of all of them
+    /// at the end of the block.
+    pub const STREAMING_RESULTS: Self = Self(1 << 0);
+    /// The shards execute the sub-block of a round on their own when asked to.
+    pub const SUB_BLOCK_EXECUTION: Self = Self(1 << 4);
+    /// The commands the coordinator traces tell the shards so, for them to trace their execution
+    /// under the span of the command.
+    pub const TRACING: Self = Self(1 << 5);
+    /// The shards fetch the state values the coordinator sends them ahead of the blocks, and
+    /// tell it once they have them.
+    pub const WARM_UP: Self = Self(1 << 6);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self(
+            Self::STREAMING_RESULTS.0
+                | Self::CROSS_SHARD_COMPRESSION.0
+                | Self::CROSS_SHARD_BATCHING.0
+                | Self::STATE_CACHE.0
+                | Self::SUB_BLOCK_EXECUTION.0
+                | Self::TRACING.0
+                | Self::WARM_UP.0
+                | Self::ABORT.0,
+        )
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// The protocol versions and features a peer supports.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ProtocolSupport {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub features: ProtocolFeatures,
+}
+
+impl ProtocolSupport {
+    /// What this build supports.
+    pub const fn current() -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: ProtocolFeatures::all(),
+        }
+    }
+
+    /// What both peers support, or `None` if they have no version in common.
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        let min_version = self.min_version.max(other.min_version);
+        let max_version = self.max_version.min(other.max_version);
+        (min_version <= max_version).then_some(Self {
+            min_version,
+            max_version,
+            features: self.features.intersection(other.features),
+        })
+    }
+
+    /// The highest version and all the features supported.
+    pub fn negotiated(&self) -> NegotiatedProtocol {
+        NegotiatedProtocol {
+            version: self.max_version,
+            features: self.features,
+        }
+    }
+}
+
+/// The version and features the coordinator and the shards use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NegotiatedProtocol {
+    pub version: u8,
+    pub features: ProtocolFeatures,
+}
+
+impl Default for NegotiatedProtocol {
+    fn default() -> Self {
+        ProtocolSupport::current().negotiated()
+    }
+}
+
+fn encode_with<T: Serialize>(version: u8, message: &T) -> Message {
+    let mut data = vec![version];
+    bcs::serialize_into(&mut data, message).expect("Failed to serialize the message");
+    Message::new(data)
+}
+
+fn decode_with<T: DeserializeOwned>(
+    message: &Message,
+    is_supported: impl FnOnce(u8) -> bool,
+) -> Result<(u8, T), Error> {
+    let (version, data) = message
+        .data
+        .split_first()
+        .ok_or_else(|| Error::SerializationError("Empty message".to_string()))?;
+    if !is_supported(*version) {
+        return Err(Error::UnsupportedProtocolVersion(*version));
+    }
+    Ok((*version, bcs::from_bytes(data)?))
+}
+
+/// Encode a message with the given protocol version.
+pub fn encode<T: Serialize>(version: u8, message: &T) -> Message {
+    encode_with(version, message)
+}
+
+/// Decode a message, along with the protocol version it is encoded with.
+pub fn decode<T: DeserializeOwned>(message: &Message) -> Result<(u8, T), Error> {
+    decode_with(message, |version| {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+    })
+}
+
+pub(crate) fn encode_handshake<T: Serialize>(message: &T) -> Message {
+    encode_with(HANDSHAKE_VERSION, message)
+}
+
+pub(crate) fn decode_handshake<T: DeserializeOwned>(message: &Message) -> Result<T, Error> {
+    decode_with(message, |version| version == HANDSHAKE_VERSION).map(|(_, message)| message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_tells_version_drift_from_corruption() {
+        let message = encode(PROTOCOL_VERSION, &(7u64, "value".to_string()));
+        assert_eq!(
+            decode::<(u64, String)>(&message).unwrap(),
+            (PROTOCOL_VERSION, (7, "value".to_string()))
+        );
+
+        let mut newer_message = message.clone();
+        newer_message.data[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            decode::<(u64, String)>(&newer_message),
+            Err(Error::UnsupportedProtocolVersion(PROTOCOL_VERSION + 1))
+        );
+
+        let mut corrupted_message = message;
+        corrupted_message.data.truncate(4);
+        assert!(matches!(
+            decode::<(u64, String)>(&corrupted_message),
+            Err(Error::SerializationError(_))
+        ));
+        assert!(matches!(
+            decode::<(u64, String)>(&Message::new(vec![])),
+            Err(Error::SerializationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        let coordinator = ProtocolSupport {
+            min_version: 2,
+            max_version: 4,
+            features: ProtocolFeatures::all(),
+        };
+        let older_shard = ProtocolSupport {
+            min_version: 1,
+            max_version: 3,
+            features: ProtocolFeatures::STREAMING_RESULTS,
+        };
+        assert_eq!(
+            coordinator.intersection(&older_shard).unwrap().negotiated(),
+            NegotiatedProtocol {
+                version: 3,
+                features: ProtocolFeatures::STREAMING_RESULTS,
+            }
+        );
+        let oldest_shard = ProtocolSupport {
+            min_version: 1,
+            max_version: 1,
+            features: ProtocolFeatures::all(),
+        };
+        assert!(coordinator.intersection(&oldest_shard).is_none());
+        assert!(ProtocolFeatures::all()
+            .without(ProtocolFeatures::CROSS_SHARD_BATCHING)
+            .contains(ProtocolFeatures::CROSS_SHARD_COMPRESSION));
+        assert!(!ProtocolFeatures::all()
+            .without(ProtocolFeatures::CROSS_SHARD_BATCHING)
+            .contains(ProtocolFeatures::CROSS_SHARD_BATCHING));
+    }
+}
@@ -0,0 +1,618 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recording of the messages the coordinator and the shards exchange for each block, for the block
+//! to be replayed from them without the rest of the cluster, e.g. to reproduce a failure reported
+//! from a test network.
+//!
+//! Every participant writes the messages it sent and received for a block to a file of its own,
+//! as they went over the network, which is what keeps them readable by later versions: a message
+//! carries the version of the protocol it is encoded with, and a recording the features the
+//! participant used for the block.
+
+use crate::{
+    error::Error,
+    protocol::{self, NegotiatedProtocol, ProtocolFeatures},
+    remote_cross_shard_client, RemoteExecutionRequest, RemoteExecutionResult, RemoteKVResponse,
+};
+use aptos_logger::warn;
+use aptos_secure_net::network_controller::Message;
+use aptos_types::{
+    block_executor::{
+        config::{BlockExecutorConfig, BlockExecutorLocalConfig},
+        partitioner::{RoundId, ShardId},
+    },
+    state_store::{
+        errors::StateviewError, state_key::StateKey, state_storage_usage::StateStorageUsage,
+        state_value::StateValue, TStateView,
+    },
+    transaction::TransactionOutput,
+};
+use aptos_vm::sharded_block_executor::{
+    cross_shard_client::ReplayCrossShardClient,
+    messages::{CrossShardMsg, ShardExecutionMsg},
+    sharded_executor_service::ShardedExecutorService,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The version of the format of the recordings, which the files start with.
+pub const RECORDING_FORMAT_VERSION: u8 = 1;
+
+const RECORDING_EXTENSION: &str = "recording";
+
+/// The coordinator or a shard.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Participant {
+    Coordinator,
+    Shard(ShardId),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MessageDirection {
+    Sent,
+    Received,
+}
+
+/// A message a participant sent or received.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RecordedMessage {
+    /// When the message was sent or received, in microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub direction: MessageDirection,
+    /// The participant at the other end, if the message tells.
+    pub peer: Option<Participant>,
+    /// The channel the message went through, e.g. `execute_command_0`.
+    pub channel: String,
+    /// The message as it went over the network.
+    pub data: Vec<u8>,
+}
+
+impl RecordedMessage {
+    pub fn sent(peer: Participant, channel: &str, message: &Message) -> Self {
+        Self::new(MessageDirection::Sent, Some(peer), channel, message)
+    }
+
+    pub fn received(peer: Option<Participant>, channel: &str, message: &Message) -> Self {
+        Self::new(MessageDirection::Received, peer, channel, message)
+    }
+
+    fn new(
+        direction: MessageDirection,
+        peer: Option<Participant>,
+        channel: &str,
+        message: &Message,
+    ) -> Self {
+        Self {
+            timestamp_us: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as u64,
+            direction,
+            peer,
+            channel: channel.to_string(),
+            data: message.data.clone(),
+        }
+    }
+
+    fn to_message(&self) -> Message {
+        Message::new(self.data.clone())
+    }
+}
+
+/// The messages a participant exchanged for a block, in the order it sent and received them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockRecording {
+    /// The id of the command the block was executed with.
+    pub command_id: u64,
+    pub participant: Participant,
+    /// The protocol the participant used for the block, which the messages are encoded with.
+    pub protocol_version: u8,
+    pub features: ProtocolFeatures,
+    pub messages: Vec<RecordedMessage>,
+}
+
+impl BlockRecording {
+    fn new(command_id: u64, participant: Participant, protocol: NegotiatedProtocol) -> Self {
+        Self {
+            command_id,
+            participant,
+            protocol_version: protocol.version,
+            features: protocol.features,
+            messages: vec![],
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let participant = match self.participant {
+            Participant::Coordinator => "coordinator".to_string(),
+            Participant::Shard(shard_id) => format!("shard_{}", shard_id),
+        };
+        format!(
+            "{}_{}.{}",
+            self.command_id, participant, RECORDING_EXTENSION
+        )
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(self.file_name());
+        let mut data = vec![RECORDING_FORMAT_VERSION];
+        bcs::serialize_into(&mut data, self)?;
+        fs::write(&path, data).map_err(|e| {
+            Error::InternalError(format!("Failed to write {}: {}", path.display(), e))
+        })?;
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(|e| {
+            Error::InternalError(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        match data.split_first() {
+            Some((&RECORDING_FORMAT_VERSION, data)) => Ok(bcs::from_bytes(data)?),
+            Some((version, _)) => Err(Error::SerializationError(format!(
+                "{} is a recording of unsupported format version {}",
+                path.display(),
+                version
+            ))),
+            None => Err(Error::SerializationError(format!(
+                "{} is empty",
+                path.display()
+            ))),
+        }
+    }
+
+    /// All the recordings in the directory, by command then participant.
+    pub fn load_all(dir: &Path) -> Result<Vec<Self>, Error> {
+        let entries = fs::read_dir(dir).map_err(|e| {
+            Error::InternalError(format!("Failed to read {}: {}", dir.display(), e))
+        })?;
+        let mut recordings = vec![];
+        for entry in entries {
+            let path = entry
+                .map_err(|e| {
+                    Error::InternalError(format!("Failed to read {}: {}", dir.display(), e))
+                })?
+                .path();
+            if path
+                .extension()
+                .map_or(false, |extension| extension == RECORDING_EXTENSION)
+            {
+                recordings.push(Self::load(&path)?);
+            }
+        }
+        recordings.sort_by_key(|recording| {
+            let shard_id = match recording.participant {
+                Participant::Coordinator => None,
+                Participant::Shard(shard_id) => Some(shard_id),
+            };
+            (recording.command_id, shard_id)
+        });
+        Ok(recordings)
+    }
+
+    fn messages<'a>(
+        &'a self,
+        direction: MessageDirection,
+        channel: &'a str,
+    ) -> impl Iterator<Item = &'a RecordedMessage> {
+        self.messages
+            .iter()
+            .filter(move |message| message.direction == direction && message.channel == channel)
+    }
+
+    // The requests on the channel, unwrapped from their trace context.
+    fn requests(
+        &self,
+        direction: MessageDirection,
+        channel: &str,
+    ) -> Result<Vec<RemoteExecutionRequest>, Error> {
+        self.messages(direction, channel)
+            .map(|message| {
+                let (_, request) = protocol::decode(&message.to_message())?;
+                Ok(match request {
+                    RemoteExecutionRequest::Traced(_, request) => *request,
+                    request => request,
+                })
+            })
+            .collect()
+    }
+
+    // The results of the block on the channel, in the order of the shard, each one only once.
+    fn results(
+        &self,
+        direction: MessageDirection,
+        channel: &str,
+    ) -> Result<Vec<ShardExecutionMsg>, Error> {
+        let mut results = vec![];
+        for message in self.messages(direction, channel) {
+            let (_, result): (u8, RemoteExecutionResult) = protocol::decode(&message.to_message())?;
+            if result.command_id == self.command_id && result.seq == results.len() as u64 {
+                results.push(result.inner);
+            }
+        }
+        Ok(results)
+    }
+}
+
+// The recordings of the blocks a participant is exchanging messages for.
+#[derive(Default)]
+struct OpenRecordings {
+    recordings: BTreeMap<u64, BlockRecording>,
+    // The last block started, which the messages not telling their block are for.
+    last_command_id: Option<u64>,
+    // The files written, oldest first.
+    saved: VecDeque<PathBuf>,
+}
+
+/// Writes the messages a participant exchanges for each block to a directory, once the block is
+/// done, keeping the files of the last blocks only.
+pub(crate) struct MessageRecorder {
+    participant: Participant,
+    dir: PathBuf,
+    max_recorded_blocks: usize,
+    open: Mutex<OpenRecordings>,
+}
+
+impl MessageRecorder {
+    pub fn new(participant: Participant, dir: PathBuf, max_recorded_blocks: usize) -> Self {
+        Self {
+            participant,
+            dir,
+            max_recorded_blocks,
+            open: Mutex::new(OpenRecordings::default()),
+        }
+    }
+
+    pub fn start_block(&self, command_id: u64, protocol: NegotiatedProtocol) {
+        let mut open = self.open.lock().unwrap();
+        // Messages for the block may have come before the block started.
+        let recording = open
+            .recordings
+            .entry(command_id)
+            .or_insert_with(|| BlockRecording::new(command_id, self.participant, protocol));
+        recording.protocol_version = protocol.version;
+        recording.features = protocol.features;
+        open.last_command_id = Some(command_id);
+    }
+
+    /// Record a message for the block of `command_id`, or for the last block started if the
+    /// message does not tell. The messages for the blocks already done are not recorded.
+    pub fn record(&self, command_id: Option<u64>, message: RecordedMessage) {
+        let mut open = self.open.lock().unwrap();
+        let Some(command_id) = command_id.or(open.last_command_id) else {
+            return;
+        };
+        let participant = self.participant;
+        match open.recordings.get_mut(&command_id) {
+            Some(recording) => recording.messages.push(message),
+            None if open
+                .last_command_id
+                .map_or(true, |last_command_id| command_id > last_command_id) =>
+            {
+                let mut recording =
+                    BlockRecording::new(command_id, participant, NegotiatedProtocol::default());
+                recording.messages.push(message);
+                open.recordings.insert(command_id, recording);
+            },
+            None => {},
+        }
+    }
+
+    /// Write the recording of the block, which is done. A failure to write it is only logged, not
+    /// to fail the block.
+    pub fn finish_block(&self, command_id: u64) {
+        let mut open = self.open.lock().unwrap();
+        let recording = open.recordings.remove(&command_id);
+        // The blocks before it are not finished by now, e.g. the ones recorded early that never
+        // started.
+        open.recordings = open.recordings.split_off(&command_id);
+        let Some(recording) = recording else {
+            return;
+        };
+        match recording.save(&self.dir) {
+            Ok(path) => open.saved.push_back(path),
+            Err(e) => warn!(
+                "{:?} failed to record command {}: {}",
+                self.participant, command_id, e
+            ),
+        }
+        while open.saved.len() > self.max_recorded_blocks {
+            let path = open.saved.pop_front().unwrap();
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to delete {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// The state values the shard received for the block.
+struct RecordedStateView {
+    state_values: HashMap<StateKey, Option<StateValue>>,
+}
+
+impl TStateView for RecordedStateView {
+    type Key = StateKey;
+
+    fn get_state_value(&self, state_key: &StateKey) -> Result<Option<StateValue>, StateviewError> {
+        // A value the shard did not receive, e.g. one it kept from a previous block.
+        self.state_values
+            .get(state_key)
+            .cloned()
+            .ok_or_else(|| StateviewError::NotFound(format!("{:?} in the recording", state_key)))
+    }
+
+    fn get_usage(&self) -> Result<StateStorageUsage, StateviewError> {
+        Ok(StateStorageUsage::new_untracked())
+    }
+}
+
+/// Execute the block of a shard recording again, from the command, the state values and the
+/// cross-shard writes the shard received, and check that every round the shard sent the output of
+/// has the same output. Returns the outputs of the rounds replayed.
+///
+/// The state values a shard keeps across blocks (see `RemoteExecutorConfig::state_cache_size`) are
+/// not received again, so the recordings of such a shard may not be replayable.
+pub fn replay_shard(
+    recording: &BlockRecording,
+    num_threads: usize,
+) -> Result<Vec<Vec<TransactionOutput>>, Error> {
+    let Participant::Shard(shard_id) = recording.participant else {
+        return Err(Error::InternalError(
+            "Not the recording of a shard".to_string(),
+        ));
+    };
+    let command = recording
+        .requests(
+            MessageDirection::Received,
+            &format!("execute_command_{}", shard_id),
+        )?
+        .into_iter()
+        .find_map(|request| match request {
+            RemoteExecutionRequest::ExecuteBlock(command)
+            | RemoteExecutionRequest::ExecuteBlockWithBaseState(command, _) => Some(command),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::InternalError(format!(
+                "No block command recorded for command {}",
+                recording.command_id
+            ))
+        })?;
+
+    let mut state_values = HashMap::new();
+    for message in recording.messages(MessageDirection::Received, "remote_kv_response") {
+        let (_, response): (u8, RemoteKVResponse) = protocol::decode(&message.to_message())?;
+        if response.command_id == recording.command_id {
+            state_values.extend(response.inner);
+        }
+    }
+    let state_view = RecordedStateView { state_values };
+
+    let num_rounds = command.sub_blocks.num_sub_blocks();
+    let mut cross_shard_writes = vec![vec![]; num_rounds];
+    let mut received_batches = HashSet::new();
+    for message in recording.messages.iter() {
+        let Some(round) = message
+            .channel
+            .strip_prefix("cross_shard_")
+            .and_then(|round| round.parse::<RoundId>().ok())
+            .filter(|round| *round < num_rounds)
+        else {
+            continue;
+        };
+        if message.direction != MessageDirection::Received {
+            continue;
+        }
+        let (id, msgs) =
+            remote_cross_shard_client::decode_batch(recording.features, &message.data)?;
+        if let Some(id) = id {
+            if id.command_id != recording.command_id || !received_batches.insert(id) {
+                continue;
+            }
+        }
+        cross_shard_writes[round].extend(msgs.into_iter().filter_map(|msg| match msg {
+            CrossShardMsg::RemoteTxnWriteMsg(write) => Some(write),
+            CrossShardMsg::StopMsg => None,
+        }));
+    }
+
+    let recorded_outputs: BTreeMap<RoundId, Vec<TransactionOutput>> = recording
+        .results(
+            MessageDirection::Sent,
+            &format!("execute_result_{}", shard_id),
+        )?
+        .into_iter()
+        .filter_map(|result| match result {
+            ShardExecutionMsg::RoundOutput(round, outputs) => Some((round, outputs)),
+            ShardExecutionMsg::Done(..) => None,
+        })
+        .collect();
+    if recorded_outputs.is_empty() {
+        return Err(Error::InternalError(format!(
+            "No output recorded for command {}",
+            recording.command_id
+        )));
+    }
+
+    let thread_pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .thread_name(move |index| format!("replay-shard-{}-{}", shard_id, index))
+            // Along with the cross-shard commit receiver and the thread waiting for the block.
+            .num_threads(num_threads + 2)
+            .build()
+            .unwrap(),
+    );
+    let (sub_blocks, concurrency_level, onchain_config) = command.into();
+    let config = BlockExecutorConfig {
+        local: BlockExecutorLocalConfig {
+            concurrency_level: concurrency_level.min(num_threads),
+            allow_fallback: true,
+            discard_failed_blocks: false,
+        },
+        onchain: onchain_config,
+    };
+    let mut replayed_outputs = vec![];
+    for (round, sub_block) in sub_blocks.into_sub_blocks().into_iter().enumerate() {
+        // The shard stopped before the round, e.g. as the block failed or was aborted.
+        let Some(recorded_outputs) = recorded_outputs.get(&round) else {
+            break;
+        };
+        let outputs =
+            ShardedExecutorService::<RecordedStateView>::execute_transactions_with_dependencies(
+                Some(shard_id),
+                thread_pool.clone(),
+                sub_block.into_transactions_with_deps(),
+                Arc::new(ReplayCrossShardClient::new(std::mem::take(
+                    &mut cross_shard_writes[round],
+                ))),
+                None,
+                round,
+                &state_view,
+                config.clone(),
+            )
+            .map_err(|status| {
+                Error::InternalError(format!("Failed to replay round {}: {:?}", round, status))
+            })?;
+        if bcs::to_bytes(&outputs)? != bcs::to_bytes(recorded_outputs)? {
+            return Err(Error::ReplayMismatch { shard_id, round });
+        }
+        replayed_outputs.push(outputs);
+    }
+    Ok(replayed_outputs)
+}
+
+/// Put the outputs the coordinator received for the block of a coordinator recording in the order
+/// of the block, as the coordinator did, checking that every shard executed all its rounds.
+pub fn replay_coordinator(recording: &BlockRecording) -> Result<Vec<TransactionOutput>, Error> {
+    if recording.participant != Participant::Coordinator {
+        return Err(Error::InternalError(
+            "Not the recording of the coordinator".to_string(),
+        ));
+    }
+    let mut sharded_outputs = vec![];
+    loop {
+        let shard_id = sharded_outputs.len();
+        let Some(command) = recording
+            .requests(
+                MessageDirection::Sent,
+                &format!("execute_command_{}", shard_id),
+            )?
+            .into_iter()
+            .find_map(|request| match request {
+                RemoteExecutionRequest::ExecuteBlock(command)
+                | RemoteExecutionRequest::ExecuteBlockWithBaseState(command, _) => Some(command),
+                _ => None,
+            })
+        else {
+            break;
+        };
+        let num_rounds = command.sub_blocks.num_sub_blocks();
+        let mut round_outputs = vec![];
+        for result in recording.results(
+            MessageDirection::Received,
+            &format!("execute_result_{}", shard_id),
+        )? {
+            match result {
+                ShardExecutionMsg::RoundOutput(_, outputs) => round_outputs.push(outputs),
+                ShardExecutionMsg::Done(Err(e), _) => {
+                    return Err(Error::InternalError(format!(
+                        "Shard {} failed command {}: {}",
+                        shard_id, recording.command_id, e
+                    )))
+                },
+                ShardExecutionMsg::Done(Ok(()), _) => break,
+            }
+        }
+        if round_outputs.len() != num_rounds {
+            return Err(Error::InternalError(format!(
+                "Shard {} sent the outputs of {} of its {} rounds for command {}",
+                shard_id,
+                round_outputs.len(),
+                num_rounds,
+                recording.command_id
+            )));
+        }
+        sharded_outputs.push(round_outputs);
+    }
+    let num_rounds = sharded_outputs.first().map_or(0, Vec::len);
+    Ok((0..num_rounds)
+        .flat_map(|round| {
+            sharded_outputs
+                .iter_mut()
+                .flat_map(move |rounds| std::mem::take(&mut rounds[round]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_temppath::TempPath;
+
+    #[test]
+    fn test_recorder_keeps_last_blocks() {
+        let dir = TempPath::new();
+        dir.create_as_dir().unwrap();
+        let recorder = MessageRecorder::new(Participant::Shard(1), dir.path().to_path_buf(), 2);
+        let message = |data: u8| {
+            RecordedMessage::sent(
+                Participant::Coordinator,
+                "channel",
+                &Message::new(vec![data]),
+            )
+        };
+        for command_id in 1..4 {
+            recorder.start_block(command_id, NegotiatedProtocol::default());
+            recorder.record(None, message(command_id as u8));
+            // For the next block before it starts, then for a block already done.
+            recorder.record(Some(command_id + 1), message(100));
+            recorder.record(Some(command_id - 1), message(200));
+            recorder.finish_block(command_id);
+        }
+
+        let recordings = BlockRecording::load_all(dir.path()).unwrap();
+        assert_eq!(
+            recordings
+                .iter()
+                .map(|recording| recording.command_id)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        for recording in recordings {
+            assert_eq!(recording.participant, Participant::Shard(1));
+            assert_eq!(
+                recording
+                    .messages
+                    .iter()
+                    .map(|message| message.data.clone())
+                    .collect::<Vec<_>>(),
+                vec![vec![100], vec![recording.command_id as u8]]
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_format_version() {
+        let dir = TempPath::new();
+        dir.create_as_dir().unwrap();
+        let recording =
+            BlockRecording::new(7, Participant::Coordinator, NegotiatedProtocol::default());
+        let path = recording.save(dir.path()).unwrap();
+        assert_eq!(BlockRecording::load(&path).unwrap().command_id, 7);
+
+        let mut data = fs::read(&path).unwrap();
+        data[0] = RECORDING_FORMAT_VERSION + 1;
+        fs::write(&path, data).unwrap();
+        assert!(matches!(
+            BlockRecording::load(&path),
+            Err(Error::SerializationError(_))
+        ));
+    }
+}
@@ -1,26 +1,78 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 use crate::{
-    metrics::REMOTE_EXECUTOR_TIMER, remote_state_view::RemoteStateViewClient, ExecuteBlockCommand,
-    RemoteExecutionRequest, RemoteExecutionResult,
+    capture::SubBlockRecorder,
+    health::ShardStatusTracker,
+    metrics::{REMOTE_EXECUTOR_COMMAND_COUNT, REMOTE_EXECUTOR_TIMER},
+    protocol::{self, NegotiatedProtocol, ProtocolFeatures, ProtocolSupport},
+    recording::{MessageRecorder, Participant, RecordedMessage},
+    remote_state_view::RemoteStateViewClient,
+    AbortCommand, RemoteExecutionRequest, RemoteExecutionResult, WarmUpCommand,
 };
+use aptos_logger::{info, warn};
 use aptos_secure_net::network_controller::{Message, NetworkController};
 use aptos_types::{
-    block_executor::partitioner::ShardId, state_store::state_key::StateKey,
-    transaction::TransactionOutput, vm_status::VMStatus,
+    block_executor::partitioner::{RoundId, ShardId, SubBlock},
+    state_store::{state_key::StateKey, TStateView},
+    transaction::{analyzed_transaction::AnalyzedTransaction, TransactionOutput},
 };
 use aptos_vm::sharded_block_executor::{
-    coordinator_client::CoordinatorClient, ExecutorShardCommand,
+    coordinator_client::CoordinatorClient, execution_stats::ShardExecutionStats,
+    executor_client::ShardedExecutionError, messages::ShardExecutionMsg, ExecutorShardCommand,
 };
 use crossbeam_channel::{Receiver, Sender};
 use rayon::prelude::*;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+// How many of the last commands the results are kept of. The coordinator can re-send a command
+// after the shard moved on to the next commands it queued, if the last results of the command were
+// lost.
+const NUM_COMMANDS_RESULTS_KEPT: usize = 8;
+
+// The span of a step of the command being executed, under the span of the command if it is traced.
+macro_rules! command_step_span {
+    ($command_span:expr, $name:literal $(, $($field:tt)+)?) => {
+        if $command_span.is_disabled() {
+            tracing::Span::none()
+        } else {
+            tracing::info_span!(parent: $command_span, $name $(, $($field)+)?)
+        }
+    };
+}
 
 pub struct RemoteCoordinatorClient {
     state_view_client: Arc<RemoteStateViewClient>,
     command_rx: Receiver<Message>,
     result_tx: Sender<Message>,
+    // The `AbortCommand`s, read before each round as they come while the shard executes a block.
+    abort_rx: Receiver<Message>,
+    // The last commands aborted, which can be the ones queued after the command being executed.
+    aborted_command_ids: Mutex<VecDeque<u64>>,
     shard_id: ShardId,
+    // The results sent to the coordinator for the command being executed and the last ones, with
+    // the id of the command, to send them again if the coordinator re-sends a command because it
+    // did not get all of them.
+    sent_results: Mutex<VecDeque<(u64, Vec<Message>)>>,
+    // What the shard supports of the protocol.
+    protocol_support: ProtocolSupport,
+    // The protocol of the command being executed, shared with the other clients of the shard.
+    protocol: Arc<RwLock<NegotiatedProtocol>>,
+    // The round outputs held back until the end of the block, when results are not streamed.
+    held_back_outputs: Mutex<Vec<ShardExecutionMsg>>,
+    // What the shard is doing, as reported to the health checks.
+    status: Arc<ShardStatusTracker>,
+    // Captures the inputs of the sub-blocks executed, if configured to.
+    recorder: Option<Arc<SubBlockRecorder>>,
+    // Records the messages exchanged for the blocks executed, if configured to.
+    message_recorder: Option<Arc<MessageRecorder>>,
+    // The span of the command being executed if the coordinator traces it, tied to the span of
+    // the coordinator by the id of the block and of its span.
+    command_span: Mutex<tracing::Span>,
 }
 
 impl RemoteCoordinatorClient {
@@ -28,31 +80,111 @@ impl RemoteCoordinatorClient {
         shard_id: ShardId,
         controller: &mut NetworkController,
         coordinator_address: SocketAddr,
+        protocol_support: ProtocolSupport,
+        protocol: Arc<RwLock<NegotiatedProtocol>>,
+        state_cache_size: Option<usize>,
+        status: Arc<ShardStatusTracker>,
+        recorder: Option<Arc<SubBlockRecorder>>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) -> Self {
         let execute_command_type = format!("execute_command_{}", shard_id);
         let execute_result_type = format!("execute_result_{}", shard_id);
         let command_rx = controller.create_inbound_channel(execute_command_type);
+        let abort_rx = controller.create_inbound_channel(format!("abort_command_{}", shard_id));
         let result_tx =
             controller.create_outbound_channel(coordinator_address, execute_result_type);
 
-        let state_view_client =
-            RemoteStateViewClient::new(shard_id, controller, coordinator_address);
+        let state_view_client = RemoteStateViewClient::new(
+            shard_id,
+            controller,
+            coordinator_address,
+            protocol.clone(),
+            state_cache_size,
+            message_recorder.clone(),
+        );
 
         Self {
             state_view_client: Arc::new(state_view_client),
             command_rx,
             result_tx,
+            abort_rx,
+            aborted_command_ids: Mutex::new(VecDeque::new()),
             shard_id,
+            sent_results: Mutex::new(VecDeque::new()),
+            protocol_support,
+            protocol,
+            held_back_outputs: Mutex::new(vec![]),
+            status,
+            recorder,
+            message_recorder,
+            command_span: Mutex::new(tracing::Span::none()),
         }
     }
 
-    // Extract all the state keys from the execute block command. It is possible that there are duplicate state keys.
+    fn send_result(&self, result: ShardExecutionMsg) {
+        let mut sent_results = self.sent_results.lock().unwrap();
+        let (command_id, results) = sent_results
+            .back_mut()
+            .expect("No command is being executed");
+        let command_span = self.command_span.lock().unwrap();
+        let _send_result =
+            command_step_span!(&*command_span, "send_result", index = results.len()).entered();
+        let remote_execution_result =
+            RemoteExecutionResult::new(*command_id, results.len() as u64, result);
+        let version = self.protocol.read().unwrap().version;
+        let output_message = protocol::encode(version, &remote_execution_result);
+        if let Some(message_recorder) = &self.message_recorder {
+            message_recorder.record(
+                Some(*command_id),
+                RecordedMessage::sent(
+                    Participant::Coordinator,
+                    &format!("execute_result_{}", self.shard_id),
+                    &output_message,
+                ),
+            );
+        }
+        results.push(output_message.clone());
+        self.result_tx.send(output_message).unwrap();
+    }
+
+    // Fetch the state values into the state cache, and tell the coordinator once they are all
+    // there.
+    fn warm_up(&self, command: WarmUpCommand) {
+        let started_at = Instant::now();
+        self.state_view_client.init_for_block(
+            command.command_id,
+            command.state_keys.clone(),
+            Some(&command.base_state),
+        );
+        for state_key in command.state_keys.iter() {
+            // Waits for the value to be fetched.
+            let _ = self.state_view_client.get_state_value(state_key);
+        }
+        info!(
+            "Shard {} fetched {} state values to warm up in {:?}",
+            self.shard_id,
+            command.state_keys.len(),
+            started_at.elapsed()
+        );
+        self.send_result(ShardExecutionMsg::Done(
+            Ok(()),
+            ShardExecutionStats::default(),
+        ));
+        self.status.finish_block();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state_view_client(&self) -> &RemoteStateViewClient {
+        &self.state_view_client
+    }
+
+    // Extract all the state keys from the sub-blocks of a command. It is possible that there are duplicate state keys.
     // We are not de-duplicating them here to avoid the overhead of deduplication. The state view server will deduplicate
     // the state keys.
-    fn extract_state_keys(command: &ExecuteBlockCommand) -> Vec<StateKey> {
-        command
-            .sub_blocks
-            .sub_block_iter()
+    fn extract_state_keys<'a>(
+        sub_blocks: impl Iterator<Item = &'a SubBlock<AnalyzedTransaction>>,
+    ) -> Vec<StateKey> {
+        sub_blocks
             .flat_map(|sub_block| {
                 sub_block
                     .transactions
@@ -78,43 +210,217 @@ impl RemoteCoordinatorClient {
 
 impl CoordinatorClient<RemoteStateViewClient> for RemoteCoordinatorClient {
     fn receive_execute_command(&self) -> ExecutorShardCommand<RemoteStateViewClient> {
-        match self.command_rx.recv() {
-            Ok(message) => {
-                let _rx_timer = REMOTE_EXECUTOR_TIMER
-                    .with_label_values(&[&self.shard_id.to_string(), "cmd_rx"])
-                    .start_timer();
-                let bcs_deser_timer = REMOTE_EXECUTOR_TIMER
-                    .with_label_values(&[&self.shard_id.to_string(), "cmd_rx_bcs_deser"])
-                    .start_timer();
-                let request: RemoteExecutionRequest = bcs::from_bytes(&message.data).unwrap();
-                drop(bcs_deser_timer);
-
-                match request {
-                    RemoteExecutionRequest::ExecuteBlock(command) => {
-                        let init_prefetch_timer = REMOTE_EXECUTOR_TIMER
-                            .with_label_values(&[&self.shard_id.to_string(), "init_prefetch"])
-                            .start_timer();
-                        let state_keys = Self::extract_state_keys(&command);
-                        self.state_view_client.init_for_block(state_keys);
-                        drop(init_prefetch_timer);
-
-                        let (sub_blocks, concurrency, onchain_config) = command.into();
-                        ExecutorShardCommand::ExecuteSubBlocks(
-                            self.state_view_client.clone(),
-                            sub_blocks,
-                            concurrency,
-                            onchain_config,
-                        )
-                    },
+        while let Ok(message) = self.command_rx.recv() {
+            let _rx_timer = REMOTE_EXECUTOR_TIMER
+                .with_label_values(&[&self.shard_id.to_string(), "cmd_rx"])
+                .start_timer();
+            let bcs_deser_timer = REMOTE_EXECUTOR_TIMER
+                .with_label_values(&[&self.shard_id.to_string(), "cmd_rx_bcs_deser"])
+                .start_timer();
+            let decode_start = Instant::now();
+            let (version, request): (u8, RemoteExecutionRequest) = protocol::decode(&message)
+                .unwrap_or_else(|error| {
+                    panic!("Shard {} cannot decode a command: {}", self.shard_id, error)
+                });
+            let decode_time = decode_start.elapsed();
+            drop(bcs_deser_timer);
+            let (trace_context, request) = match request {
+                RemoteExecutionRequest::Traced(trace_context, request) => {
+                    (Some(trace_context), *request)
+                },
+                request => (None, request),
+            };
+
+            let (command_id, features) = match &request {
+                RemoteExecutionRequest::ExecuteBlock(command)
+                | RemoteExecutionRequest::ExecuteBlockWithBaseState(command, _) => {
+                    (command.command_id, command.features)
+                },
+                RemoteExecutionRequest::ExecuteSubBlock(command) => {
+                    (command.command_id, command.features)
+                },
+                RemoteExecutionRequest::WarmUp(command) => (command.command_id, command.features),
+                RemoteExecutionRequest::Traced(..) => {
+                    panic!("Shard {} received a command traced twice", self.shard_id)
+                },
+            };
+            let mut sent_results = self.sent_results.lock().unwrap();
+            if let Some((_, results)) = sent_results
+                .iter()
+                .find(|(sent_command_id, _)| *sent_command_id == command_id)
+            {
+                // Executing the block again would give the same results.
+                REMOTE_EXECUTOR_COMMAND_COUNT
+                    .with_label_values(&[&self.shard_id.to_string(), "redelivered_commands"])
+                    .inc();
+                for result in results {
+                    self.result_tx.send(result.clone()).unwrap();
                 }
-            },
-            Err(_) => ExecutorShardCommand::Stop,
+                continue;
+            }
+            if sent_results.len() == NUM_COMMANDS_RESULTS_KEPT {
+                sent_results.pop_front();
+            }
+            sent_results.push_back((command_id, vec![]));
+            drop(sent_results);
+            self.status.start_block(command_id);
+            *self.protocol.write().unwrap() = NegotiatedProtocol {
+                version,
+                features: features.intersection(self.protocol_support.features),
+            };
+            self.held_back_outputs.lock().unwrap().clear();
+            // The span is not the child of the one of the coordinator, which is in another
+            // process, so it carries what to tie them with.
+            let command_span = match trace_context {
+                Some(trace_context) => tracing::info_span!(
+                    "shard_command",
+                    shard_id = self.shard_id,
+                    block_id = trace_context.block_id,
+                    parent_span_id = trace_context.span_id,
+                    decode_us = decode_time.as_micros() as u64,
+                ),
+                None => tracing::Span::none(),
+            };
+            *self.command_span.lock().unwrap() = command_span.clone();
+
+            let (command, base_state) = match request {
+                RemoteExecutionRequest::ExecuteBlock(command) => (command, None),
+                RemoteExecutionRequest::ExecuteBlockWithBaseState(command, base_state) => {
+                    (command, Some(base_state))
+                },
+                RemoteExecutionRequest::ExecuteSubBlock(command) => {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.stop_block();
+                    }
+                    let inputs = command.inputs;
+                    let _fetch_state = command_step_span!(&command_span, "fetch_state").entered();
+                    let state_keys = Self::extract_state_keys(std::iter::once(&inputs.sub_block));
+                    // The state the sub-block reads is not the one of the blocks, so the values
+                    // kept from them are dropped.
+                    self.state_view_client
+                        .init_for_block(command_id, state_keys, None);
+                    return ExecutorShardCommand::ExecuteSubBlock(
+                        self.state_view_client.clone(),
+                        command.round,
+                        inputs.sub_block,
+                        inputs.cross_shard_writes,
+                        inputs.concurrency_level,
+                        inputs.onchain_config,
+                    );
+                },
+                RemoteExecutionRequest::WarmUp(command) => {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.stop_block();
+                    }
+                    self.warm_up(command);
+                    continue;
+                },
+                RemoteExecutionRequest::Traced(..) => unreachable!("Unwrapped above"),
+            };
+            if let Some(recorder) = &self.recorder {
+                recorder.start_block(&command);
+            }
+            if let Some(message_recorder) = &self.message_recorder {
+                message_recorder.start_block(command_id, *self.protocol.read().unwrap());
+                message_recorder.record(
+                    Some(command_id),
+                    RecordedMessage::received(
+                        Some(Participant::Coordinator),
+                        &format!("execute_command_{}", self.shard_id),
+                        &message,
+                    ),
+                );
+            }
+
+            let init_prefetch_timer = REMOTE_EXECUTOR_TIMER
+                .with_label_values(&[&self.shard_id.to_string(), "init_prefetch"])
+                .start_timer();
+            let fetch_state = command_step_span!(&command_span, "fetch_state").entered();
+            let state_keys = Self::extract_state_keys(command.sub_blocks.sub_block_iter());
+            self.state_view_client.init_for_block(
+                command.command_id,
+                state_keys,
+                base_state.as_ref(),
+            );
+            drop(fetch_state);
+            drop(init_prefetch_timer);
+
+            let (sub_blocks, concurrency, onchain_config) = command.into();
+            return ExecutorShardCommand::ExecuteSubBlocks(
+                self.state_view_client.clone(),
+                sub_blocks,
+                concurrency,
+                onchain_config,
+            );
+        }
+        ExecutorShardCommand::Stop
+    }
+
+    fn is_block_aborted(&self) -> bool {
+        let mut aborted_command_ids = self.aborted_command_ids.lock().unwrap();
+        for message in self.abort_rx.try_iter() {
+            match protocol::decode::<AbortCommand>(&message) {
+                Ok((_, command)) => {
+                    if let Some(message_recorder) = &self.message_recorder {
+                        message_recorder.record(
+                            Some(command.command_id),
+                            RecordedMessage::received(
+                                Some(Participant::Coordinator),
+                                &format!("abort_command_{}", self.shard_id),
+                                &message,
+                            ),
+                        );
+                    }
+                    if aborted_command_ids.len() == NUM_COMMANDS_RESULTS_KEPT {
+                        aborted_command_ids.pop_front();
+                    }
+                    aborted_command_ids.push_back(command.command_id);
+                },
+                Err(error) => warn!(
+                    "Shard {} cannot decode an abort command: {}",
+                    self.shard_id, error
+                ),
+            }
+        }
+        self.status
+            .executing_command_id()
+            .map_or(false, |command_id| {
+                aborted_command_ids.contains(&command_id)
+            })
+    }
+
+    fn send_round_output(&self, round: RoundId, output: Vec<TransactionOutput>) {
+        if let Some(recorder) = &self.recorder {
+            recorder.finish_round(round);
+        }
+        let result = ShardExecutionMsg::RoundOutput(round, output);
+        let features = self.protocol.read().unwrap().features;
+        if features.contains(ProtocolFeatures::STREAMING_RESULTS) {
+            self.send_result(result);
+        } else {
+            self.held_back_outputs.lock().unwrap().push(result);
+        }
+    }
+
+    fn send_execution_result(
+        &self,
+        result: Result<(), ShardedExecutionError>,
+        stats: ShardExecutionStats,
+    ) {
+        for output in self.held_back_outputs.lock().unwrap().drain(..) {
+            self.send_result(output);
+        }
+        self.send_result(ShardExecutionMsg::Done(result, stats));
+        *self.command_span.lock().unwrap() = tracing::Span::none();
+        if let (Some(message_recorder), Some(command_id)) =
+            (&self.message_recorder, self.status.executing_command_id())
+        {
+            message_recorder.finish_block(command_id);
         }
+        self.status.finish_block();
     }
 
-    fn send_execution_result(&self, result: Result<Vec<Vec<TransactionOutput>>, VMStatus>) {
-        let remote_execution_result = RemoteExecutionResult::new(result);
-        let output_message = bcs::to_bytes(&remote_execution_result).unwrap();
-        self.result_tx.send(Message::new(output_message)).unwrap();
+    fn command_span(&self) -> tracing::Span {
+        self.command_span.lock().unwrap().clone()
     }
 }
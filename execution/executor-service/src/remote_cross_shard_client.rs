@@ -1,25 +1,125 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
+use crate::{
+    capture::SubBlockRecorder,
+    config::RemoteExecutorConfig,
+    error::Error,
+    health::ShardStatusTracker,
+    metrics::REMOTE_EXECUTOR_CROSS_SHARD_COUNT,
+    protocol::{NegotiatedProtocol, ProtocolFeatures},
+    recording::{MessageRecorder, Participant, RecordedMessage},
+};
+use aptos_compression::client::CompressionClient;
 use aptos_secure_net::network_controller::{Message, NetworkController};
 use aptos_types::block_executor::partitioner::{RoundId, ShardId, MAX_ALLOWED_PARTITIONING_ROUNDS};
 use aptos_vm::sharded_block_executor::{
     cross_shard_client::CrossShardClient, messages::CrossShardMsg,
 };
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashSet, VecDeque},
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread,
+    time::{Duration, Instant},
 };
 
+// The largest batch the network accepts.
+const MAX_BATCH_BYTES: usize = 80 * 1024 * 1024;
+
+// Cross-shard messages sent together to a shard and round. The receiver does not need to know how
+// the sender batches and compresses them.
+#[derive(Deserialize, Serialize)]
+struct CrossShardMsgBatch {
+    compressed: bool,
+    // The serialized messages, compressed if `compressed` is set.
+    data: Vec<u8>,
+}
+
+// Identifies a batch: the command it is for, the shard sending it, and its number among the batches
+// the shard sends for the command.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct BatchId {
+    pub(crate) command_id: u64,
+    pub(crate) source_shard: ShardId,
+    seq: u64,
+}
+
+// A batch along with its id, which is how the batches are sent when the shards support
+// `ProtocolFeatures::ABORT`: the messages left from an aborted block are then dropped by the blocks
+// after it, and a batch delivered twice is only received once.
+#[derive(Deserialize, Serialize)]
+struct CommandMsgBatch {
+    id: BatchId,
+    batch: CrossShardMsgBatch,
+}
+
+// The messages waiting to be sent to a shard and round.
+#[derive(Default)]
+struct PendingMsgs {
+    msgs: Vec<CrossShardMsg>,
+    // When the first of the messages was added.
+    since: Option<Instant>,
+}
+
+// The messages received for a round, with the ones of the last batch not consumed yet.
+struct RoundMsgReceiver {
+    rx: Receiver<Message>,
+    received_msgs: VecDeque<CrossShardMsg>,
+    // The messages of the batches received for the commands after the one being executed, in the
+    // order they came in, which is possible once a block failed on another shard.
+    later_msgs: Vec<(BatchId, Vec<CrossShardMsg>)>,
+    // The batches received for the command being executed, to drop the ones delivered again.
+    received_batches: HashSet<BatchId>,
+}
+
+impl RoundMsgReceiver {
+    // Whether the batch was not received before, in which case its messages are to be received.
+    fn is_new_batch(&mut self, id: Option<BatchId>) -> bool {
+        id.map_or(true, |id| self.received_batches.insert(id))
+    }
+}
+
 pub struct RemoteCrossShardClient {
+    shard_id: ShardId,
     // The senders of cross-shard messages to other shards per round.
     message_txs: Arc<Vec<Vec<Mutex<Sender<Message>>>>>,
+    // The messages not sent yet to other shards per round, along with the senders.
+    pending_msgs: Vec<Vec<Mutex<PendingMsgs>>>,
     // The receivers of cross shard messages from other shards per round.
-    message_rxs: Arc<Vec<Mutex<Receiver<Message>>>>,
+    message_rxs: Arc<Vec<Mutex<RoundMsgReceiver>>>,
+    batch_size: usize,
+    // The pending messages go out once the first of them waited this long.
+    flush_after: Duration,
+    // Tells the flusher when the pending messages of a shard and round started waiting, if the
+    // messages can wait at all.
+    flush_tx: Option<Sender<(ShardId, RoundId, Instant)>>,
+    compression_threshold: Option<usize>,
+    // The protocol of the block being executed, which tells whether all the shards support
+    // batching and compression.
+    protocol: Arc<RwLock<NegotiatedProtocol>>,
+    // Captures the writes received, if configured to.
+    recorder: Option<Arc<SubBlockRecorder>>,
+    // Records the batches sent and received, if configured to.
+    message_recorder: Option<Arc<MessageRecorder>>,
+    // Tells the command being executed, which the batches are tagged with.
+    status: Arc<ShardStatusTracker>,
+    // The command the batches sent are numbered for, with the number of the next batch.
+    next_batch_seq: Mutex<(u64, u64)>,
 }
 
 impl RemoteCrossShardClient {
-    pub fn new(controller: &mut NetworkController, shard_addresses: Vec<SocketAddr>) -> Self {
+    pub fn new(
+        shard_id: ShardId,
+        config: &RemoteExecutorConfig,
+        controller: &mut NetworkController,
+        shard_addresses: Vec<SocketAddr>,
+        protocol: Arc<RwLock<NegotiatedProtocol>>,
+        recorder: Option<Arc<SubBlockRecorder>>,
+        message_recorder: Option<Arc<MessageRecorder>>,
+        status: Arc<ShardStatusTracker>,
+    ) -> Arc<Self> {
         let mut message_txs = vec![];
         let mut message_rxs = vec![];
         // Create outbound channels for each shard per round.
@@ -32,17 +132,216 @@ impl RemoteCrossShardClient {
             }
             message_txs.push(txs);
         }
+        let pending_msgs = shard_addresses
+            .iter()
+            .map(|_| {
+                (0..MAX_ALLOWED_PARTITIONING_ROUNDS)
+                    .map(|_| Mutex::new(PendingMsgs::default()))
+                    .collect()
+            })
+            .collect();
 
         // Create inbound channels for each round
         for round in 0..MAX_ALLOWED_PARTITIONING_ROUNDS {
             let message_type = format!("cross_shard_{}", round);
             let rx = controller.create_inbound_channel(message_type);
-            message_rxs.push(Mutex::new(rx));
+            message_rxs.push(Mutex::new(RoundMsgReceiver {
+                rx,
+                received_msgs: VecDeque::with_capacity(config.cross_shard_channel_capacity),
+                later_msgs: vec![],
+                received_batches: HashSet::new(),
+            }));
+        }
+
+        let flush_after = Duration::from_micros(config.cross_shard_batch_flush_after_us);
+        Arc::new_cyclic(|client| {
+            // The messages only wait with batches of more than one message.
+            let flush_tx =
+                (config.cross_shard_batch_size > 1 && !flush_after.is_zero()).then(|| {
+                    let (flush_tx, flush_rx) = unbounded();
+                    let client = client.clone();
+                    thread::Builder::new()
+                        .name(format!("cross-shard-flusher-{}", shard_id))
+                        .spawn(move || Self::flush_when_due(client, flush_rx, flush_after))
+                        .expect("Failed to spawn thread");
+                    flush_tx
+                });
+            Self {
+                shard_id,
+                message_txs: Arc::new(message_txs),
+                pending_msgs,
+                message_rxs: Arc::new(message_rxs),
+                batch_size: config.cross_shard_batch_size,
+                flush_after,
+                flush_tx,
+                compression_threshold: config.cross_shard_compression_threshold,
+                protocol,
+                recorder,
+                message_recorder,
+                status,
+                next_batch_seq: Mutex::new((0, 0)),
+            }
+        })
+    }
+
+    // Send the pending messages of a shard and round once the first of them waited `flush_after`,
+    // unless they went out before, for a message not to wait for the end of the round when no
+    // other message comes after it. Stops once the client is dropped.
+    fn flush_when_due(
+        client: Weak<Self>,
+        flush_rx: Receiver<(ShardId, RoundId, Instant)>,
+        flush_after: Duration,
+    ) {
+        for (shard_id, round, since) in flush_rx {
+            thread::sleep((since + flush_after).saturating_duration_since(Instant::now()));
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            let mut pending_msgs = client.pending_msgs[shard_id][round].lock().unwrap();
+            // The messages left once a block failed go out with the next block.
+            if pending_msgs.since == Some(since) && client.status.is_executing() {
+                client.flush(shard_id, round, &mut pending_msgs);
+            }
+        }
+    }
+
+    // Send the pending messages to the shard and round, if any. Called with the pending messages
+    // locked, so that the batches of a shard and round are sent in order.
+    fn flush(&self, shard_id: ShardId, round: RoundId, pending_msgs: &mut PendingMsgs) {
+        if pending_msgs.msgs.is_empty() {
+            return;
+        }
+        let msgs = std::mem::take(&mut pending_msgs.msgs);
+        pending_msgs.since = None;
+        self.send_batch(shard_id, round, msgs);
+    }
+
+    fn features(&self) -> ProtocolFeatures {
+        self.protocol.read().unwrap().features
+    }
+
+    fn send_batch(&self, shard_id: ShardId, round: RoundId, msgs: Vec<CrossShardMsg>) {
+        let num_msgs = msgs.len();
+        let raw_data = bcs::to_bytes(&msgs).unwrap();
+        let raw_len = raw_data.len();
+        let compression_threshold = self.compression_threshold.filter(|_| {
+            self.features()
+                .contains(ProtocolFeatures::CROSS_SHARD_COMPRESSION)
+        });
+        let batch = match compression_threshold {
+            Some(threshold) if raw_len >= threshold => {
+                let compressed_data = aptos_compression::compress(
+                    raw_data.clone(),
+                    CompressionClient::ExecutorService,
+                    MAX_BATCH_BYTES,
+                )
+                .unwrap();
+                // Data that does not compress well is sent as is.
+                if compressed_data.len() < raw_len {
+                    CrossShardMsgBatch {
+                        compressed: true,
+                        data: compressed_data,
+                    }
+                } else {
+                    CrossShardMsgBatch {
+                        compressed: false,
+                        data: raw_data,
+                    }
+                }
+            },
+            _ => CrossShardMsgBatch {
+                compressed: false,
+                data: raw_data,
+            },
+        };
+
+        let shard_label = self.shard_id.to_string();
+        for (name, count) in [
+            ("messages", num_msgs),
+            ("batches", 1),
+            ("raw_bytes", raw_len),
+            ("sent_bytes", batch.data.len()),
+        ] {
+            REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+                .with_label_values(&[&shard_label, name])
+                .inc_by(count as u64);
+        }
+        let (command_id, data) = if self.features().contains(ProtocolFeatures::ABORT) {
+            let command_id = self
+                .status
+                .executing_command_id()
+                .expect("No command is being executed");
+            let seq = {
+                let mut next_batch_seq = self.next_batch_seq.lock().unwrap();
+                if next_batch_seq.0 != command_id {
+                    *next_batch_seq = (command_id, 0);
+                }
+                next_batch_seq.1 += 1;
+                next_batch_seq.1 - 1
+            };
+            let id = BatchId {
+                command_id,
+                source_shard: self.shard_id,
+                seq,
+            };
+            (
+                Some(command_id),
+                bcs::to_bytes(&CommandMsgBatch { id, batch }),
+            )
+        } else {
+            (None, bcs::to_bytes(&batch))
+        };
+        let message = Message::new(data.unwrap());
+        if let Some(message_recorder) = &self.message_recorder {
+            message_recorder.record(
+                command_id,
+                RecordedMessage::sent(
+                    Participant::Shard(shard_id),
+                    &format!("cross_shard_{}", round),
+                    &message,
+                ),
+            );
+        }
+        let tx = self.message_txs[shard_id][round].lock().unwrap();
+        tx.send(message).unwrap();
+    }
+
+    // Decode a batch received for the round, and record it if configured to.
+    fn receive_batch(
+        &self,
+        round: RoundId,
+        message: Message,
+    ) -> (Option<BatchId>, Vec<CrossShardMsg>) {
+        let (id, msgs) = decode_batch(self.features(), &message.data).unwrap();
+        if let Some(message_recorder) = &self.message_recorder {
+            message_recorder.record(
+                id.map(|id| id.command_id),
+                RecordedMessage::received(
+                    id.map(|id| Participant::Shard(id.source_shard)),
+                    &format!("cross_shard_{}", round),
+                    &message,
+                ),
+            );
         }
+        (id, msgs)
+    }
 
-        Self {
-            message_txs: Arc::new(message_txs),
-            message_rxs: Arc::new(message_rxs),
+    fn drop_batch(&self, reason: &str) {
+        REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+            .with_label_values(&[&self.shard_id.to_string(), reason])
+            .inc();
+    }
+}
+
+impl RemoteCrossShardClient {
+    /// Replace the channels used to send messages to the other shards with `wrap(channel)`.
+    #[cfg(test)]
+    pub(crate) fn wrap_message_txs(&self, wrap: impl Fn(Sender<Message>) -> Sender<Message>) {
+        for txs in self.message_txs.iter() {
+            for tx in txs {
+                let mut tx = tx.lock().unwrap();
+                *tx = wrap(tx.clone());
+            }
         }
     }
 }
@@ -53,15 +352,120 @@ impl CrossShardClient for RemoteCrossShardClient {
     }
 
     fn send_cross_shard_msg(&self, shard_id: ShardId, round: RoundId, msg: CrossShardMsg) {
-        let input_message = bcs::to_bytes(&msg).unwrap();
-        let tx = self.message_txs[shard_id][round].lock().unwrap();
-        tx.send(Message::new(input_message)).unwrap();
+        if self.batch_size == 1
+            || !self
+                .features()
+                .contains(ProtocolFeatures::CROSS_SHARD_BATCHING)
+        {
+            self.send_batch(shard_id, round, vec![msg]);
+            return;
+        }
+        let is_stop_msg = matches!(msg, CrossShardMsg::StopMsg);
+        {
+            let mut pending_msgs = self.pending_msgs[shard_id][round].lock().unwrap();
+            pending_msgs.msgs.push(msg);
+            let since = match pending_msgs.since {
+                Some(since) => since,
+                None => {
+                    let since = Instant::now();
+                    pending_msgs.since = Some(since);
+                    if let Some(flush_tx) = &self.flush_tx {
+                        // Only fails once the flusher is gone, with the client being dropped.
+                        let _ = flush_tx.send((shard_id, round, since));
+                    }
+                    since
+                },
+            };
+            if is_stop_msg
+                || pending_msgs.msgs.len() >= self.batch_size
+                || since.elapsed() >= self.flush_after
+            {
+                self.flush(shard_id, round, &mut pending_msgs);
+            }
+        }
+        if is_stop_msg {
+            // A shard sends itself a stop message once it executed a round, so the messages of the
+            // round for the next rounds are not held back any longer.
+            for (shard_id, pending_msgs) in self.pending_msgs.iter().enumerate() {
+                for (round, pending_msgs) in pending_msgs.iter().enumerate() {
+                    self.flush(shard_id, round, &mut pending_msgs.lock().unwrap());
+                }
+            }
+        }
     }
 
     fn receive_cross_shard_msg(&self, current_round: RoundId) -> CrossShardMsg {
-        let rx = self.message_rxs[current_round].lock().unwrap();
-        let message = rx.recv().unwrap();
-        let msg: CrossShardMsg = bcs::from_bytes(&message.to_bytes()).unwrap();
-        msg
+        let mut receiver = self.message_rxs[current_round].lock().unwrap();
+        let executing_command_id = self.status.executing_command_id();
+        if let Some(executing_command_id) = executing_command_id {
+            let num_later_msgs = receiver.later_msgs.len();
+            receiver
+                .later_msgs
+                .retain(|(id, _)| id.command_id >= executing_command_id);
+            for _ in receiver.later_msgs.len()..num_later_msgs {
+                self.drop_batch("stale_batches");
+            }
+            receiver
+                .received_batches
+                .retain(|id| id.command_id >= executing_command_id);
+        }
+        loop {
+            if let Some(msg) = receiver.received_msgs.pop_front() {
+                if let (Some(recorder), CrossShardMsg::RemoteTxnWriteMsg(write)) =
+                    (&self.recorder, &msg)
+                {
+                    recorder.record_cross_shard_write(current_round, write);
+                }
+                return msg;
+            }
+            let (id, msgs) = match receiver
+                .later_msgs
+                .iter()
+                .position(|(id, _)| Some(id.command_id) == executing_command_id)
+            {
+                Some(index) => {
+                    let (id, msgs) = receiver.later_msgs.remove(index);
+                    (Some(id), msgs)
+                },
+                None => {
+                    let message = receiver.rx.recv().unwrap();
+                    self.receive_batch(current_round, message)
+                },
+            };
+            match (id, executing_command_id) {
+                (Some(id), Some(executing_command_id)) if id.command_id < executing_command_id => {
+                    self.drop_batch("stale_batches");
+                },
+                (Some(id), Some(executing_command_id)) if id.command_id > executing_command_id => {
+                    receiver.later_msgs.push((id, msgs));
+                },
+                _ if receiver.is_new_batch(id) => receiver.received_msgs.extend(msgs),
+                _ => self.drop_batch("duplicate_batches"),
+            }
+        }
     }
 }
+
+/// The messages of a batch sent by a shard using `features`, along with its id if the batch says.
+pub(crate) fn decode_batch(
+    features: ProtocolFeatures,
+    data: &[u8],
+) -> Result<(Option<BatchId>, Vec<CrossShardMsg>), Error> {
+    let (id, batch) = if features.contains(ProtocolFeatures::ABORT) {
+        let CommandMsgBatch { id, batch } = bcs::from_bytes(data)?;
+        (Some(id), batch)
+    } else {
+        (None, bcs::from_bytes(data)?)
+    };
+    let data = if batch.compressed {
+        aptos_compression::decompress(
+            &batch.data,
+            CompressionClient::ExecutorService,
+            MAX_BATCH_BYTES,
+        )
+        .map_err(|e| Error::SerializationError(e.to_string()))?
+    } else {
+        batch.data
+    };
+    Ok((id, bcs::from_bytes(&data)?))
+}
@@ -2,36 +2,64 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 use crate::{
-    remote_state_view_service::RemoteStateViewService, ExecuteBlockCommand, RemoteExecutionRequest,
-    RemoteExecutionResult,
+    config::{RemoteExecutorConfig, SecurityConfig, WarmUpConfig},
+    error::Error,
+    health::{HealthCheckPolicy, ShardHealthMonitor, ShardStatus},
+    metrics::{
+        REMOTE_EXECUTOR_BLOCKED_ON_SHARD_SECONDS, REMOTE_EXECUTOR_COMMAND_COUNT,
+        REMOTE_EXECUTOR_REMOTE_KV_COUNT, REMOTE_EXECUTOR_SHARD_QUEUE_DEPTH,
+        REMOTE_EXECUTOR_WARM_UP_SECONDS,
+    },
+    protocol::{self, NegotiatedProtocol, ProtocolFeatures, ProtocolSupport},
+    recording::{MessageRecorder, Participant, RecordedMessage},
+    remote_executor_service::join_with_timeout,
+    remote_state_view_service::RemoteStateViewService,
+    AbortCommand, BaseState, ExecuteBlockCommand, ExecuteSubBlockCommand, ParentState,
+    RemoteExecutionRequest, RemoteExecutionResult, ShardRegistration, SubBlockInputs,
+    SubBlockOutput, TraceContext, WarmUpCommand,
 };
-use aptos_logger::{info, trace};
-use aptos_secure_net::network_controller::{Message, NetworkController};
+use aptos_logger::{info, trace, warn};
+use aptos_secure_net::network_controller::{Message, NetworkController, SHUTDOWN_TIMEOUT};
 use aptos_storage_interface::cached_state_view::CachedStateView;
 use aptos_types::{
     block_executor::{
-        config::BlockExecutorConfigFromOnchain, partitioner::PartitionedTransactions,
+        config::BlockExecutorConfigFromOnchain,
+        partitioner::{PartitionedTransactions, RoundId, ShardId},
     },
-    state_store::StateView,
-    transaction::TransactionOutput,
-    vm_status::VMStatus,
+    state_store::{state_key::StateKey, state_value::StateValue, StateView},
+    transaction::TransactionStatus,
+    write_set::TransactionWrite,
 };
 use aptos_vm::sharded_block_executor::{
-    executor_client::{ExecutorClient, ShardedExecutionOutput},
+    execution_stats::ShardExecutionStats,
+    executor_client::{
+        BlockAbort, ExecutorClient, RoundOutputAssembler, RoundOutputCallback,
+        ShardedExecutionError, ShardedExecutionOutput,
+    },
+    messages::ShardExecutionMsg,
     ShardedBlockExecutor,
 };
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, Sender};
 use once_cell::sync::{Lazy, OnceCell};
 use std::{
+    collections::VecDeque,
+    iter,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 pub static COORDINATOR_PORT: u16 = 52200;
 
 static REMOTE_ADDRESSES: OnceCell<Vec<SocketAddr>> = OnceCell::new();
 static COORDINATOR_ADDRESS: OnceCell<SocketAddr> = OnceCell::new();
+static CHAINED_STATE_VIEWS: OnceCell<bool> = OnceCell::new();
+static SECURITY_CONFIG: OnceCell<SecurityConfig> = OnceCell::new();
 
 pub fn set_remote_addresses(addresses: Vec<SocketAddr>) {
     REMOTE_ADDRESSES.set(addresses).ok();
@@ -55,6 +83,50 @@ pub fn get_coordinator_address() -> SocketAddr {
     }
 }
 
+/// Tell `REMOTE_SHARDED_BLOCK_EXECUTOR` that it executes the blocks of a chain one after the
+/// other, see `RemoteExecutorClient::set_chained_state_views`.
+pub fn set_chained_state_views(chained_state_views: bool) {
+    CHAINED_STATE_VIEWS.set(chained_state_views).ok();
+}
+
+pub fn get_chained_state_views() -> bool {
+    CHAINED_STATE_VIEWS.get().copied().unwrap_or(false)
+}
+
+/// How the coordinator authenticates the shards, which must be set up the same way. Plaintext if
+/// not set.
+pub fn set_security_config(security: SecurityConfig) {
+    SECURITY_CONFIG.set(security).ok();
+}
+
+pub fn get_security_config() -> Option<SecurityConfig> {
+    SECURITY_CONFIG.get().cloned()
+}
+
+fn coordinator_network_controller(
+    coordinator_address: SocketAddr,
+) -> Result<NetworkController, Error> {
+    let mut config = RemoteExecutorConfig::default();
+    if let Some(security) = get_security_config() {
+        config = config.security(security);
+    }
+    config.network_controller(
+        "remote-executor-coordinator".to_string(),
+        coordinator_address,
+    )
+}
+
+/// How many state values the shards fetched from the coordinator.
+pub fn get_num_served_state_values() -> u64 {
+    (0..get_remote_addresses().len())
+        .map(|shard_id| {
+            REMOTE_EXECUTOR_REMOTE_KV_COUNT
+                .with_label_values(&[&shard_id.to_string(), "served_kv"])
+                .get()
+        })
+        .sum()
+}
+
 pub static REMOTE_SHARDED_BLOCK_EXECUTOR: Lazy<
     Arc<
         aptos_infallible::Mutex<
@@ -72,6 +144,129 @@ pub static REMOTE_SHARDED_BLOCK_EXECUTOR: Lazy<
     ))
 });
 
+/// How the coordinator deals with a shard that does not send back its results, e.g. because the
+/// command or the results were lost on the way.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandRetryPolicy {
+    /// How long to wait for the results of a shard before re-sending it the command.
+    pub timeout: Duration,
+    /// How many times the command is re-sent before giving up on the block.
+    pub max_retries: usize,
+}
+
+impl Default for CommandRetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+        }
+    }
+}
+
+// What the coordinator got of the results of a shard for a command.
+struct ShardResultsProgress {
+    // The sequence number of the next result expected from the shard.
+    next_seq: u64,
+    // How many times the command was sent to the shard.
+    num_attempts: usize,
+    // When to re-send the command if no result comes in the meantime. Only relevant once the shard
+    // is done with the previous blocks, as the command waits behind them until then.
+    deadline: Instant,
+}
+
+impl ShardResultsProgress {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            next_seq: 0,
+            num_attempts: 1,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+// Where a shard is at with a block.
+enum ShardBlockStatus {
+    // The block is not sent to the shard yet, as the shard is busy with previous blocks.
+    Waiting,
+    Sent(ShardResultsProgress),
+    Done,
+}
+
+// A block sent, or to be sent, to the shards.
+struct InFlightBlock {
+    command_id: u64,
+    // When the block started, which is when the shards could have been sent it if they were not
+    // busy.
+    started_at: Instant,
+    // The serialized commands for the shards, kept in case they need to be re-sent.
+    commands: Vec<Message>,
+    // Cross-shard messages do not say which block they are for, so a block exchanging any is only
+    // sent once all the shards are done with the previous blocks.
+    has_cross_shard_messages: bool,
+    shards: Vec<ShardBlockStatus>,
+    assembler: RoundOutputAssembler,
+    shard_stats: Vec<ShardExecutionStats>,
+    // The first error of the block, which ends its execution.
+    error: Option<ShardedExecutionError>,
+    // Whether the block is aborted. The shards are still waited for, to be ready for the next
+    // block, but the outputs they send are discarded.
+    aborted: bool,
+    // The span the block is traced under until it is done, if it is traced.
+    span: tracing::Span,
+}
+
+impl InFlightBlock {
+    fn is_done(&self) -> bool {
+        self.error.is_some()
+            || self
+                .shards
+                .iter()
+                .all(|status| matches!(status, ShardBlockStatus::Done))
+    }
+
+    // Whether the shard has nothing left to do for the block.
+    fn is_done_on(&self, shard_id: ShardId) -> bool {
+        self.error.is_some() || matches!(self.shards[shard_id], ShardBlockStatus::Done)
+    }
+
+    fn progress(&self, shard_id: ShardId) -> &ShardResultsProgress {
+        match &self.shards[shard_id] {
+            ShardBlockStatus::Sent(progress) => progress,
+            _ => panic!("Block is not being executed by shard {}", shard_id),
+        }
+    }
+
+    fn into_output(self) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
+        match self.error {
+            Some(error) => Err(error),
+            None if self.aborted => Err(ShardedExecutionError::Aborted),
+            None => Ok(ShardedExecutionOutput::new(
+                self.assembler.into_output(),
+                vec![],
+                self.shard_stats,
+            )),
+        }
+    }
+}
+
+// A warm-up the shards were sent, and not all answered yet.
+struct PendingWarmUp {
+    command_id: u64,
+    started_at: Instant,
+    warm_shards: Vec<bool>,
+}
+
+// The state view read by the last block started, which the shards may have cached values of.
+struct LastState<S> {
+    // How the shards know the state, which is the id of the first command reading it.
+    version: u64,
+    state_view: Arc<S>,
+    // The last block started, which reads the state.
+    command_id: u64,
+    // The values the block wrote, once it is executed, if the state views are chained.
+    writes: Option<Vec<(StateKey, Option<StateValue>)>>,
+}
+
 #[allow(dead_code)]
 pub struct RemoteExecutorClient<S: StateView + Sync + Send + 'static> {
     // The network controller used to create channels to send and receive messages. We want the
@@ -83,11 +278,40 @@ pub struct RemoteExecutorClient<S: StateView + Sync + Send + 'static> {
     command_txs: Arc<Vec<Mutex<Sender<Message>>>>,
     // Channels to receive execution results from the executor shards.
     result_rxs: Vec<Receiver<Message>>,
+    // Channels to tell the executor shards to abort a block.
+    abort_txs: Vec<Sender<Message>>,
+    // Channel to receive the registrations of the executor shards, once they are up.
+    registration_rx: Receiver<Message>,
+    // The id of the next execute block command.
+    next_command_id: AtomicU64,
+    command_retry_policy: CommandRetryPolicy,
+    // How many blocks a shard is sent before it is done with them.
+    max_queued_commands_per_shard: usize,
+    // Whether the state view of a block is the one of the previous block with its writes.
+    chained_state_views: bool,
+    // One command in this many is traced, if any.
+    trace_sampling_interval: Option<u64>,
+    // What the shards fetch before the first block, if anything.
+    warm_up: Option<WarmUpConfig>,
+    // Whether the shards are to warm up before the next block.
+    warm_up_requested: AtomicBool,
+    pending_warm_up: Mutex<Option<PendingWarmUp>>,
+    last_state: Mutex<Option<LastState<S>>>,
+    // Records the messages exchanged with the shards for every block, if configured to.
+    message_recorder: Option<Arc<MessageRecorder>>,
+    // The protocol agreed on with the shards when they registered. Until then, the shards are
+    // assumed to run the same version as the coordinator.
+    protocol: RwLock<NegotiatedProtocol>,
     // Thread pool used to pre-fetch the state values for the block in parallel and create an in-memory state view.
     thread_pool: Arc<rayon::ThreadPool>,
+    // What the shards answer to the health checks.
+    health_monitor: Arc<ShardHealthMonitor>,
 
     phantom: std::marker::PhantomData<S>,
-    _join_handle: Option<thread::JoinHandle<()>>,
+    // The thread of the state view service, which stops once the network controller is shutdown.
+    join_handle: Option<thread::JoinHandle<()>>,
+    // The thread pinging the shards, which stops once the network controller is shutdown too.
+    health_check_join_handle: Option<thread::JoinHandle<()>>,
 }
 
 #[allow(dead_code)]
@@ -96,7 +320,7 @@ impl<S: StateView + Sync + Send + 'static> RemoteExecutorClient<S> {
         remote_shard_addresses: Vec<SocketAddr>,
         mut controller: NetworkController,
         num_threads: Option<usize>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let num_threads = num_threads.unwrap_or_else(num_cpus::get);
         let thread_pool = Arc::new(
             rayon::ThreadPoolBuilder::new()
@@ -118,6 +342,26 @@ impl<S: StateView + Sync + Send + 'static> RemoteExecutorClient<S> {
                 (command_tx, result_rx)
             })
             .unzip();
+        let abort_txs = remote_shard_addresses
+            .iter()
+            .enumerate()
+            .map(|(shard_id, address)| {
+                controller_mut_ref
+                    .create_outbound_channel(*address, format!("abort_command_{}", shard_id))
+            })
+            .collect();
+        let registration_rx =
+            controller_mut_ref.create_inbound_channel("shard_registration".to_string());
+        let health_check_txs = remote_shard_addresses
+            .iter()
+            .map(|address| {
+                controller_mut_ref
+                    .create_outbound_channel(*address, "health_check_request".to_string())
+            })
+            .collect();
+        let health_check_rx =
+            controller_mut_ref.create_inbound_channel("health_check_response".to_string());
+        let health_monitor = Arc::new(ShardHealthMonitor::new(remote_shard_addresses.len()));
 
         let state_view_service = Arc::new(RemoteStateViewService::new(
             controller_mut_ref,
@@ -125,24 +369,54 @@ impl<S: StateView + Sync + Send + 'static> RemoteExecutorClient<S> {
             None,
         ));
 
+        // Started before the threads, which would otherwise be left running if it failed. The
+        // messages arriving in the meantime wait in the channels.
+        controller.start()?;
+
         let state_view_service_clone = state_view_service.clone();
 
         let join_handle = thread::Builder::new()
             .name("remote-state_view-service".to_string())
             .spawn(move || state_view_service_clone.start())
             .unwrap();
+        let health_monitor_clone = health_monitor.clone();
+        let health_check_join_handle = thread::Builder::new()
+            .name("remote-executor-health-checks".to_string())
+            .spawn(move || health_monitor_clone.run(health_check_txs, health_check_rx))
+            .unwrap();
 
-        controller.start();
-
-        Self {
+        Ok(Self {
             network_controller: controller,
             state_view_service,
-            _join_handle: Some(join_handle),
+            join_handle: Some(join_handle),
+            health_check_join_handle: Some(health_check_join_handle),
             command_txs: Arc::new(command_txs),
             result_rxs,
+            abort_txs,
+            registration_rx,
+            // Not starting from 0, so that the ids do not repeat those of a previous coordinator
+            // talking to the same shards.
+            next_command_id: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+            ),
+            command_retry_policy: CommandRetryPolicy::default(),
+            max_queued_commands_per_shard: RemoteExecutorConfig::default()
+                .max_queued_commands_per_shard,
+            chained_state_views: false,
+            trace_sampling_interval: None,
+            warm_up: None,
+            warm_up_requested: AtomicBool::new(false),
+            pending_warm_up: Mutex::new(None),
+            last_state: Mutex::new(None),
+            message_recorder: None,
+            protocol: RwLock::new(NegotiatedProtocol::default()),
             thread_pool,
+            health_monitor,
             phantom: std::marker::PhantomData,
-        }
+        })
     }
 
     pub fn create_remote_sharded_block_executor(
@@ -150,69 +424,1112 @@ impl<S: StateView + Sync + Send + 'static> RemoteExecutorClient<S> {
         remote_shard_addresses: Vec<SocketAddr>,
         num_threads: Option<usize>,
     ) -> ShardedBlockExecutor<S, RemoteExecutorClient<S>> {
-        ShardedBlockExecutor::new(RemoteExecutorClient::new(
+        let mut executor_client = coordinator_network_controller(coordinator_address)
+            .and_then(|controller| {
+                RemoteExecutorClient::new(remote_shard_addresses, controller, num_threads)
+            })
+            .unwrap_or_else(|e| panic!("Failed to set up the coordinator network: {}", e));
+        executor_client.set_chained_state_views(get_chained_state_views());
+        ShardedBlockExecutor::new(executor_client)
+    }
+
+    /// Create the coordinator of executor shards running on other processes or machines (see
+    /// `ProcessExecutorService`), and wait up to `connect_timeout` for all of them to register.
+    pub fn create_network_remote_executor_shards(
+        coordinator_address: SocketAddr,
+        remote_shard_addresses: Vec<SocketAddr>,
+        num_threads: Option<usize>,
+        connect_timeout: Duration,
+    ) -> Result<ShardedBlockExecutor<S, RemoteExecutorClient<S>>, Error> {
+        let executor_client = RemoteExecutorClient::new(
             remote_shard_addresses,
-            NetworkController::new(
-                "remote-executor-coordinator".to_string(),
-                coordinator_address,
-                5000,
-            ),
+            coordinator_network_controller(coordinator_address)?,
             num_threads,
-        ))
+        )?;
+        executor_client.wait_for_shards(connect_timeout)?;
+        Ok(ShardedBlockExecutor::new(executor_client))
     }
 
-    fn get_output_from_shards(&self) -> Result<Vec<Vec<Vec<TransactionOutput>>>, VMStatus> {
-        trace!("RemoteExecutorClient Waiting for results");
-        let mut results = vec![];
-        for rx in self.result_rxs.iter() {
-            let received_bytes = rx.recv().unwrap().to_bytes();
-            let result: RemoteExecutionResult = bcs::from_bytes(&received_bytes).unwrap();
-            results.push(result.inner?);
+    /// Wait until every shard has registered, i.e. has started its executor service and reached
+    /// the coordinator, and agree with them on the protocol: the highest version and the features
+    /// they all support. Fails if a shard has no version in common with the coordinator and the
+    /// shards registered before it, or if a registration is for a shard that the coordinator does
+    /// not have or that already registered.
+    pub fn wait_for_shards(&self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut protocol_support = ProtocolSupport::current();
+        let mut registered = vec![false; self.command_txs.len()];
+        while registered.contains(&false) {
+            let message = self.registration_rx.recv_deadline(deadline).map_err(|_| {
+                let unregistered: Vec<_> = registered
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, registered)| !**registered)
+                    .map(|(shard_id, _)| shard_id)
+                    .collect();
+                Error::InternalError(format!(
+                    "Shards {:?} did not register within {:?}",
+                    unregistered, timeout
+                ))
+            })?;
+            let registration: ShardRegistration = protocol::decode_handshake(&message)?;
+            // The shard id comes off the network, so it is checked before being used as an index.
+            let shard_id = registration.shard_id;
+            match registered.get(shard_id) {
+                None => {
+                    return Err(Error::IncompatibleShard {
+                        shard_id,
+                        reason: format!("the coordinator only has {} shards", registered.len()),
+                    })
+                },
+                Some(true) => {
+                    return Err(Error::IncompatibleShard {
+                        shard_id,
+                        reason: "another shard already registered with its id".to_string(),
+                    })
+                },
+                Some(false) => {},
+            }
+            let shard_support = registration.protocol_support;
+            protocol_support = protocol_support.intersection(&shard_support).ok_or_else(|| {
+                Error::IncompatibleShard {
+                    shard_id,
+                    reason: format!(
+                        "it supports protocol versions {} to {}, the coordinator and the other shards {} to {}",
+                        shard_support.min_version,
+                        shard_support.max_version,
+                        protocol_support.min_version,
+                        protocol_support.max_version
+                    ),
+                }
+            })?;
+            info!(
+                "Executor shard {} registered, supporting {:?}",
+                shard_id, shard_support
+            );
+            registered[shard_id] = true;
         }
-        Ok(results)
+        let protocol = protocol_support.negotiated();
+        info!("Executor shards use {:?}", protocol);
+        *self.protocol.write().unwrap() = protocol;
+        Ok(())
     }
-}
 
-impl<S: StateView + Sync + Send + 'static> ExecutorClient<S> for RemoteExecutorClient<S> {
-    fn num_shards(&self) -> usize {
-        self.command_txs.len()
+    /// The protocol used with the shards.
+    pub fn protocol(&self) -> NegotiatedProtocol {
+        *self.protocol.read().unwrap()
     }
 
-    fn execute_block(
+    pub fn set_command_retry_policy(&mut self, command_retry_policy: CommandRetryPolicy) {
+        self.command_retry_policy = command_retry_policy;
+    }
+
+    pub fn set_health_check_policy(&mut self, health_check_policy: HealthCheckPolicy) {
+        self.health_monitor.set_policy(health_check_policy);
+    }
+
+    /// Whether every shard answered the last health checks, and takes blocks.
+    pub fn all_shards_ready(&self) -> bool {
+        self.health_monitor.all_ready()
+    }
+
+    /// The status the shard reported to the last health check, if it answered in time.
+    pub fn shard_status(&self, shard_id: ShardId) -> Option<ShardStatus> {
+        self.health_monitor.status(shard_id)
+    }
+
+    /// Set how many blocks a shard is sent before it is done with them, see
+    /// `RemoteExecutorConfig::max_queued_commands_per_shard`.
+    pub fn set_max_queued_commands_per_shard(&mut self, max_queued_commands_per_shard: usize) {
+        assert!(
+            max_queued_commands_per_shard > 0,
+            "A shard must be able to queue at least 1 command"
+        );
+        self.max_queued_commands_per_shard = max_queued_commands_per_shard;
+    }
+
+    /// Tell whether the state view of each block is the state view of the previous block with the
+    /// values written by the previous block, as when executing the blocks of a chain one after the
+    /// other. The shards then keep the state values they cached that the previous block did not
+    /// write. Otherwise, they only keep them for consecutive blocks reading the same state view.
+    pub fn set_chained_state_views(&mut self, chained_state_views: bool) {
+        self.chained_state_views = chained_state_views;
+    }
+
+    /// Trace one block in `trace_sampling_interval`, see
+    /// `RemoteExecutorConfig::trace_sampling_interval`.
+    pub fn set_trace_sampling_interval(&mut self, trace_sampling_interval: u64) {
+        assert!(
+            trace_sampling_interval > 0,
+            "The trace sampling interval must be at least 1"
+        );
+        self.trace_sampling_interval = Some(trace_sampling_interval);
+    }
+
+    /// Record the messages exchanged with the shards for every block to `recording_dir`, keeping
+    /// the recordings of the last `max_recorded_blocks` blocks, see
+    /// `RemoteExecutorConfig::recording_dir`.
+    pub fn set_recording(&mut self, recording_dir: PathBuf, max_recorded_blocks: usize) {
+        let message_recorder = Arc::new(MessageRecorder::new(
+            Participant::Coordinator,
+            recording_dir,
+            max_recorded_blocks,
+        ));
+        self.state_view_service
+            .set_message_recorder(message_recorder.clone());
+        self.message_recorder = Some(message_recorder);
+    }
+
+    // Record a message exchanged for the block of the command, if configured to.
+    fn record_message(&self, command_id: u64, message: impl FnOnce() -> RecordedMessage) {
+        if let Some(message_recorder) = &self.message_recorder {
+            message_recorder.record(Some(command_id), message());
+        }
+    }
+
+    /// Have the shards fetch the state values of `warm_up` before the first block, see
+    /// `RemoteExecutorConfig::warm_up`.
+    pub fn set_warm_up(&mut self, warm_up: WarmUpConfig) {
+        self.warm_up = Some(warm_up);
+        self.warm_up_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Have the shards warm up again before the next block, e.g. after an epoch change, from which
+    /// the blocks read other values. Does nothing if no warm-up is set.
+    pub fn request_warm_up(&self) {
+        self.warm_up_requested
+            .store(self.warm_up.is_some(), Ordering::Relaxed);
+    }
+
+    // Send the shards the warm-up if requested, reading the state view of the next block, for the
+    // values fetched to be valid for it. If the warm-up has a timeout, wait for the shards to be
+    // warm, but no longer than that, the shards warming up before the block anyway.
+    fn warm_up_shards(&self, state_view: &Arc<S>) {
+        let Some(warm_up) = &self.warm_up else {
+            return;
+        };
+        if !self.warm_up_requested.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let protocol = self.protocol();
+        if !protocol.features.contains(ProtocolFeatures::WARM_UP)
+            || !protocol.features.contains(ProtocolFeatures::STATE_CACHE)
+        {
+            warn!("The shards do not support warming up, sending them the blocks right away");
+            return;
+        }
+        let mut pending_warm_up = self.pending_warm_up.lock().unwrap();
+        if pending_warm_up.is_some() {
+            // The shards may still be fetching the values of the previous warm-up.
+            warn!("The shards are still warming up, not warming them up again");
+            return;
+        }
+        let command_id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        let base_state = self.base_state(command_id, state_view);
+        self.state_view_service
+            .set_state_view(command_id, state_view.clone());
+        let state_keys = warm_up.all_state_keys();
+        info!(
+            "Warming up the shards with {} state values",
+            state_keys.len()
+        );
+        let command = protocol::encode(
+            protocol.version,
+            &RemoteExecutionRequest::WarmUp(WarmUpCommand {
+                command_id,
+                state_keys,
+                base_state,
+                features: protocol.features,
+            }),
+        );
+        *pending_warm_up = Some(PendingWarmUp {
+            command_id,
+            started_at: Instant::now(),
+            warm_shards: vec![false; self.command_txs.len()],
+        });
+        drop(pending_warm_up);
+        for command_tx in self.command_txs.iter() {
+            command_tx.lock().unwrap().send(command.clone()).unwrap();
+        }
+        if let Some(timeout) = warm_up.timeout() {
+            self.wait_for_warm_up(timeout);
+        }
+    }
+
+    fn wait_for_warm_up(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut select = Select::new();
+        for result_rx in self.result_rxs.iter() {
+            select.recv(result_rx);
+        }
+        while self.pending_warm_up.lock().unwrap().is_some() {
+            let Ok(operation) = select.select_deadline(deadline) else {
+                warn!(
+                    "The shards are not warm after {:?}, sending them the blocks anyway",
+                    timeout
+                );
+                return;
+            };
+            let shard_id = operation.index();
+            // The network controller is shutdown otherwise.
+            let Ok(message) = operation.recv(&self.result_rxs[shard_id]) else {
+                return;
+            };
+            let Ok((_, result)) = protocol::decode::<RemoteExecutionResult>(&message) else {
+                continue;
+            };
+            if !self.record_warm_up_result(shard_id, &result) {
+                REMOTE_EXECUTOR_COMMAND_COUNT
+                    .with_label_values(&[&shard_id.to_string(), "stale_results"])
+                    .inc();
+            }
+        }
+    }
+
+    // Take the result if it is the answer of a shard to the pending warm-up. The state view of the
+    // warm-up is only dropped once all the shards answered, as they wait for the values they fetch.
+    fn record_warm_up_result(&self, shard_id: ShardId, result: &RemoteExecutionResult) -> bool {
+        let mut pending_warm_up = self.pending_warm_up.lock().unwrap();
+        let Some(warm_up) = pending_warm_up
+            .as_mut()
+            .filter(|warm_up| warm_up.command_id == result.command_id)
+        else {
+            return false;
+        };
+        if !warm_up.warm_shards[shard_id] {
+            warm_up.warm_shards[shard_id] = true;
+            REMOTE_EXECUTOR_WARM_UP_SECONDS
+                .with_label_values(&[&shard_id.to_string()])
+                .observe(warm_up.started_at.elapsed().as_secs_f64());
+        }
+        if warm_up.warm_shards.iter().all(|warm| *warm) {
+            info!("Shards warm after {:?}", warm_up.started_at.elapsed());
+            self.state_view_service.drop_state_view(warm_up.command_id);
+            *pending_warm_up = None;
+        }
+        true
+    }
+
+    // Whether the command is sampled to be traced, which needs the shards to support it.
+    fn is_traced(&self, command_id: u64) -> bool {
+        self.trace_sampling_interval
+            .map_or(false, |interval| command_id % interval == 0)
+            && self.protocol().features.contains(ProtocolFeatures::TRACING)
+    }
+
+    // Tell the shards where the command is traced, if it is.
+    fn trace_request(
+        request: RemoteExecutionRequest,
+        command_id: u64,
+        span: &tracing::Span,
+    ) -> RemoteExecutionRequest {
+        match span.id() {
+            Some(span_id) => RemoteExecutionRequest::Traced(
+                TraceContext {
+                    block_id: command_id,
+                    span_id: span_id.into_u64(),
+                },
+                Box::new(request),
+            ),
+            None => request,
+        }
+    }
+
+    // Execute the blocks with up to `pipeline_depth` of them in flight, a shard being sent a block
+    // as soon as it has fewer than `max_queued_commands_per_shard` blocks to do. The outputs of the
+    // rounds of the blocks are passed to `on_round_output` as they come, and the output of each
+    // block to `on_block_output` in the block order. Once `abort` is aborted, so are the blocks in
+    // flight.
+    fn execute_pipelined(
+        &self,
+        blocks: &mut dyn Iterator<Item = (Arc<S>, PartitionedTransactions)>,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        pipeline_depth: usize,
+        on_round_output: &mut RoundOutputCallback,
+        abort: &BlockAbort,
+        on_block_output: &mut dyn FnMut(Result<ShardedExecutionOutput, ShardedExecutionError>),
+    ) {
+        if let Err(error) = self.wait_until_ready() {
+            for _ in blocks {
+                on_block_output(Err(error.clone()));
+            }
+            return;
+        }
+        let mut blocks = blocks.fuse().peekable();
+        if let Some((state_view, _)) = blocks.peek() {
+            self.warm_up_shards(state_view);
+        }
+        let mut in_flight_blocks = VecDeque::new();
+        loop {
+            while in_flight_blocks
+                .front()
+                .map_or(false, InFlightBlock::is_done)
+            {
+                let block = in_flight_blocks.pop_front().unwrap();
+                let command_id = block.command_id;
+                self.state_view_service.drop_state_view(command_id);
+                if let Some(message_recorder) = &self.message_recorder {
+                    message_recorder.finish_block(command_id);
+                }
+                let output = block.into_output();
+                self.record_writes(command_id, &output);
+                on_block_output(output);
+            }
+            while in_flight_blocks.len() < pipeline_depth {
+                let Some((state_view, transactions)) = blocks.next() else {
+                    break;
+                };
+                in_flight_blocks.push_back(self.start_block(
+                    state_view,
+                    transactions,
+                    concurrency_level_per_shard,
+                    &onchain_config,
+                ));
+            }
+            if in_flight_blocks.is_empty() {
+                break;
+            }
+            if abort.is_aborted() {
+                self.abort_blocks(in_flight_blocks.make_contiguous());
+            }
+            self.send_commands(in_flight_blocks.make_contiguous());
+            self.update_queue_depths(in_flight_blocks.make_contiguous());
+            self.receive_result(in_flight_blocks.make_contiguous(), on_round_output, abort);
+        }
+        self.update_queue_depths(&[]);
+    }
+
+    // Wait for all the shards to answer the health checks and take blocks, which they do not until
+    // they are up, or once they are draining.
+    fn wait_until_ready(&self) -> Result<(), ShardedExecutionError> {
+        self.health_monitor
+            .wait_until_ready()
+            .map_err(|(shard_id, status)| {
+                let timeout = self.health_monitor.policy().readiness_timeout;
+                ShardedExecutionError::ShardFailure {
+                    shard_id,
+                    reason: match status {
+                        Some(status) => format!("Still {} after {:?}", status, timeout),
+                        None => format!("No answer to the health checks within {:?}", timeout),
+                    },
+                }
+            })
+    }
+
+    // Report how many of the blocks each shard was sent it is not done with yet.
+    fn update_queue_depths(&self, blocks: &[InFlightBlock]) {
+        for shard_id in 0..self.command_txs.len() {
+            let queue_depth = blocks
+                .iter()
+                .filter(|block| {
+                    !block.is_done_on(shard_id)
+                        && matches!(block.shards[shard_id], ShardBlockStatus::Sent(_))
+                })
+                .count();
+            REMOTE_EXECUTOR_SHARD_QUEUE_DEPTH
+                .with_label_values(&[&shard_id.to_string()])
+                .set(queue_depth as i64);
+        }
+    }
+
+    // Create the commands of the block and make its state view available to the shards.
+    fn start_block(
         &self,
         state_view: Arc<S>,
         transactions: PartitionedTransactions,
         concurrency_level_per_shard: usize,
-        onchain_config: BlockExecutorConfigFromOnchain,
-    ) -> Result<ShardedExecutionOutput, VMStatus> {
+        onchain_config: &BlockExecutorConfigFromOnchain,
+    ) -> InFlightBlock {
         trace!("RemoteExecutorClient Sending block to shards");
-        self.state_view_service.set_state_view(state_view);
+        let has_cross_shard_messages = transactions
+            .cross_shard_message_volume()
+            .total()
+            .num_messages
+            > 0;
         let (sub_blocks, global_txns) = transactions.into();
         if !global_txns.is_empty() {
             panic!("Global transactions are not supported yet");
         }
-        for (shard_id, sub_blocks) in sub_blocks.into_iter().enumerate() {
-            let senders = self.command_txs.clone();
-            let execution_request = RemoteExecutionRequest::ExecuteBlock(ExecuteBlockCommand {
-                sub_blocks,
-                concurrency_level: concurrency_level_per_shard,
-                onchain_config: onchain_config.clone(),
+        let num_shards = sub_blocks.len();
+        let num_rounds = sub_blocks[0].num_sub_blocks();
+        let command_id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        let base_state = self.base_state(command_id, &state_view);
+        self.state_view_service
+            .set_state_view(command_id, state_view);
+        let protocol = self.protocol();
+        let span = if self.is_traced(command_id) {
+            tracing::info_span!("remote_execute_block", block_id = command_id, num_shards)
+        } else {
+            tracing::Span::none()
+        };
+        let commands = sub_blocks
+            .into_iter()
+            .map(|sub_blocks| {
+                let command = ExecuteBlockCommand {
+                    command_id,
+                    sub_blocks,
+                    concurrency_level: concurrency_level_per_shard,
+                    onchain_config: onchain_config.clone(),
+                    features: protocol.features,
+                };
+                let execution_request = if protocol.features.contains(ProtocolFeatures::STATE_CACHE)
+                {
+                    RemoteExecutionRequest::ExecuteBlockWithBaseState(command, base_state.clone())
+                } else {
+                    RemoteExecutionRequest::ExecuteBlock(command)
+                };
+                let execution_request = Self::trace_request(execution_request, command_id, &span);
+                protocol::encode(protocol.version, &execution_request)
+            })
+            .collect();
+        if let Some(message_recorder) = &self.message_recorder {
+            message_recorder.start_block(command_id, protocol);
+        }
+        InFlightBlock {
+            command_id,
+            started_at: Instant::now(),
+            commands,
+            has_cross_shard_messages,
+            shards: (0..num_shards).map(|_| ShardBlockStatus::Waiting).collect(),
+            assembler: RoundOutputAssembler::new(num_shards, num_rounds),
+            shard_stats: vec![ShardExecutionStats::default(); num_shards],
+            error: None,
+            aborted: false,
+            span,
+        }
+    }
+
+    // The state the block reads, as the shards know it.
+    fn base_state(&self, command_id: u64, state_view: &Arc<S>) -> BaseState {
+        let mut last_state = self.last_state.lock().unwrap();
+        let base_state = match last_state.take() {
+            Some(last_state) if Arc::ptr_eq(&last_state.state_view, state_view) => BaseState {
+                version: last_state.version,
+                parent: None,
+            },
+            Some(LastState {
+                version,
+                writes: Some(writes),
+                ..
+            }) => BaseState {
+                version: command_id,
+                parent: Some(ParentState { version, writes }),
+            },
+            _ => BaseState {
+                version: command_id,
+                parent: None,
+            },
+        };
+        *last_state = Some(LastState {
+            version: base_state.version,
+            state_view: state_view.clone(),
+            command_id,
+            writes: None,
+        });
+        base_state
+    }
+
+    // Keep the values written by the block if it is the last one started, which is what the next
+    // state view differs by when the state views are chained.
+    fn record_writes(
+        &self,
+        command_id: u64,
+        output: &Result<ShardedExecutionOutput, ShardedExecutionError>,
+    ) {
+        if !self.chained_state_views {
+            return;
+        }
+        let mut last_state = self.last_state.lock().unwrap();
+        let (Some(last_state), Ok(output)) = (last_state.as_mut(), output) else {
+            return;
+        };
+        if last_state.command_id != command_id {
+            return;
+        }
+        // In the block order, for the last write of a key to win.
+        let num_rounds = output.sharded_output.first().map_or(0, Vec::len);
+        let writes = (0..num_rounds)
+            .flat_map(|round| {
+                output
+                    .sharded_output
+                    .iter()
+                    .flat_map(move |rounds| rounds[round].iter())
+            })
+            .filter(|txn_output| matches!(txn_output.status(), TransactionStatus::Keep(_)))
+            .flat_map(|txn_output| {
+                txn_output
+                    .write_set()
+                    .iter()
+                    .map(|(state_key, write_op)| (state_key.clone(), write_op.as_state_value()))
+            })
+            .collect();
+        last_state.writes = Some(writes);
+    }
+
+    // Abort the blocks not done yet. The shards not sent a block yet are done with it, and the
+    // others are told to stop at their next round boundary, if they support it, or else waited for
+    // to be done with the whole block.
+    fn abort_blocks(&self, blocks: &mut [InFlightBlock]) {
+        let protocol = self.protocol();
+        for block in blocks
+            .iter_mut()
+            .filter(|block| !block.is_done() && !block.aborted)
+        {
+            info!("Aborting command {}", block.command_id);
+            block.aborted = true;
+            let abort_command = protocol::encode(protocol.version, &AbortCommand {
+                command_id: block.command_id,
             });
+            for (shard_id, status) in block.shards.iter_mut().enumerate() {
+                match status {
+                    ShardBlockStatus::Waiting => *status = ShardBlockStatus::Done,
+                    ShardBlockStatus::Sent(_)
+                        if protocol.features.contains(ProtocolFeatures::ABORT) =>
+                    {
+                        REMOTE_EXECUTOR_COMMAND_COUNT
+                            .with_label_values(&[&shard_id.to_string(), "aborts"])
+                            .inc();
+                        self.record_message(block.command_id, || {
+                            RecordedMessage::sent(
+                                Participant::Shard(shard_id),
+                                &format!("abort_command_{}", shard_id),
+                                &abort_command,
+                            )
+                        });
+                        self.abort_txs[shard_id]
+                            .send(abort_command.clone())
+                            .unwrap();
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
 
-            senders[shard_id]
-                .lock()
-                .unwrap()
-                .send(Message::new(bcs::to_bytes(&execution_request).unwrap()))
-                .unwrap();
+    // Send the blocks to the shards that were sent all the previous blocks and have room for more,
+    // in the block order.
+    fn send_commands(&self, blocks: &mut [InFlightBlock]) {
+        for index in 0..blocks.len() {
+            let (previous_blocks, next_blocks) = blocks.split_at_mut(index);
+            let block = &mut next_blocks[0];
+            if block.is_done()
+                || (block.has_cross_shard_messages
+                    && !previous_blocks.iter().all(InFlightBlock::is_done))
+            {
+                continue;
+            }
+            for (shard_id, status) in block.shards.iter_mut().enumerate() {
+                if !matches!(status, ShardBlockStatus::Waiting) {
+                    continue;
+                }
+                let queued_blocks: Vec<_> = previous_blocks
+                    .iter()
+                    .filter(|previous_block| !previous_block.is_done_on(shard_id))
+                    .collect();
+                if queued_blocks.len() < self.max_queued_commands_per_shard
+                    && queued_blocks.iter().all(|queued_block| {
+                        matches!(queued_block.shards[shard_id], ShardBlockStatus::Sent(_))
+                    })
+                {
+                    REMOTE_EXECUTOR_BLOCKED_ON_SHARD_SECONDS
+                        .with_label_values(&[&shard_id.to_string()])
+                        .observe(block.started_at.elapsed().as_secs_f64());
+                    self.record_message(block.command_id, || {
+                        RecordedMessage::sent(
+                            Participant::Shard(shard_id),
+                            &format!("execute_command_{}", shard_id),
+                            &block.commands[shard_id],
+                        )
+                    });
+                    self.command_txs[shard_id]
+                        .lock()
+                        .unwrap()
+                        .send(block.commands[shard_id].clone())
+                        .unwrap();
+                    *status = ShardBlockStatus::Sent(ShardResultsProgress::new(
+                        self.command_retry_policy.timeout,
+                    ));
+                }
+            }
         }
+    }
 
-        let execution_results = self.get_output_from_shards()?;
+    // Wait for the next result from a shard executing one of the blocks, and add it to the block.
+    // A shard is re-sent its command when no result came from it for a while, as allowed by the
+    // retry policy. It does not execute a re-sent command twice, but sends all its results for the
+    // command again. A shard that stopped answering the health checks is not waited for though,
+    // and fails its block right away. Returns early once `abort` is aborted, for the blocks to be
+    // aborted.
+    fn receive_result(
+        &self,
+        blocks: &mut [InFlightBlock],
+        on_round_output: &mut RoundOutputCallback,
+        abort: &BlockAbort,
+    ) {
+        // The shards that are executing a block, with the index of the block. A shard executes
+        // one block at a time, the blocks it is sent after it waiting in its queue.
+        let executing_shards: Vec<(ShardId, usize)> = (0..self.command_txs.len())
+            .filter_map(|shard_id| {
+                blocks
+                    .iter()
+                    .position(|block| !block.is_done_on(shard_id))
+                    .filter(|index| {
+                        matches!(blocks[*index].shards[shard_id], ShardBlockStatus::Sent(_))
+                    })
+                    .map(|index| (shard_id, index))
+            })
+            .collect();
+        let mut select = Select::new();
+        for (shard_id, _) in executing_shards.iter() {
+            select.recv(&self.result_rxs[*shard_id]);
+        }
+        let abort_index = (!abort.is_aborted()).then(|| select.recv(abort.receiver()));
+        let health_check_deadline = Instant::now() + self.health_monitor.policy().interval;
+        let deadline = executing_shards
+            .iter()
+            .map(|(shard_id, index)| blocks[*index].progress(*shard_id).deadline)
+            .min()
+            .expect("No shard is executing a block")
+            .min(health_check_deadline);
+        let operation = match select.select_deadline(deadline) {
+            Ok(operation) => operation,
+            Err(_) => {
+                let now = Instant::now();
+                for (shard_id, index) in executing_shards {
+                    if blocks[index].is_done() {
+                        continue;
+                    }
+                    if self.health_monitor.stopped_responding(shard_id) {
+                        warn!(
+                            "Shard {} stopped answering the health checks while executing command {}",
+                            shard_id, blocks[index].command_id
+                        );
+                        let num_attempts = blocks[index].progress(shard_id).num_attempts;
+                        self.fail_block(blocks, index, ShardedExecutionError::ShardUnavailable {
+                            shard_id,
+                            num_attempts,
+                        });
+                        continue;
+                    }
+                    let block = &mut blocks[index];
+                    if block.progress(shard_id).deadline > now {
+                        continue;
+                    }
+                    let ShardBlockStatus::Sent(progress) = &mut block.shards[shard_id] else {
+                        unreachable!()
+                    };
+                    if let Err(error) = self.retry_command(
+                        shard_id,
+                        block.command_id,
+                        &block.commands[shard_id],
+                        progress,
+                    ) {
+                        self.fail_block(blocks, index, error);
+                    }
+                }
+                return;
+            },
+        };
+        if Some(operation.index()) == abort_index {
+            let _ = operation.recv(abort.receiver());
+            return;
+        }
+        let (shard_id, executing_index) = executing_shards[operation.index()];
+        let message = match operation.recv(&self.result_rxs[shard_id]) {
+            Ok(message) => message,
+            // The network controller is shutdown.
+            Err(_) => {
+                let num_attempts = blocks[executing_index].progress(shard_id).num_attempts;
+                self.fail_block(
+                    blocks,
+                    executing_index,
+                    ShardedExecutionError::ShardUnavailable {
+                        shard_id,
+                        num_attempts,
+                    },
+                );
+                return;
+            },
+        };
+        let result: RemoteExecutionResult = match protocol::decode(&message) {
+            Ok((_, result)) => result,
+            Err(error) => {
+                self.fail_block(
+                    blocks,
+                    executing_index,
+                    ShardedExecutionError::ShardFailure {
+                        shard_id,
+                        reason: format!("Cannot decode the result: {}", error),
+                    },
+                );
+                return;
+            },
+        };
+        if self.record_warm_up_result(shard_id, &result) {
+            return;
+        }
+        self.record_message(result.command_id, || {
+            RecordedMessage::received(
+                Some(Participant::Shard(shard_id)),
+                &format!("execute_result_{}", shard_id),
+                &message,
+            )
+        });
+        // The result is usually for the block the shard executes, but can be for one of the blocks
+        // queued after it if the last results of the block were lost.
+        let Some(index) = blocks.iter().position(|block| {
+            block.command_id == result.command_id
+                && !block.is_done_on(shard_id)
+                && matches!(block.shards[shard_id], ShardBlockStatus::Sent(_))
+        }) else {
+            // A result that was sent again for a previous block.
+            REMOTE_EXECUTOR_COMMAND_COUNT
+                .with_label_values(&[&shard_id.to_string(), "stale_results"])
+                .inc();
+            return;
+        };
+        let block = &mut blocks[index];
+        let ShardBlockStatus::Sent(progress) = &mut block.shards[shard_id] else {
+            unreachable!()
+        };
+        if result.seq != progress.next_seq {
+            // Either a result that was sent again, or one following a lost result, which comes
+            // again once the command is re-sent.
+            REMOTE_EXECUTOR_COMMAND_COUNT
+                .with_label_values(&[&shard_id.to_string(), "out_of_sequence_results"])
+                .inc();
+            return;
+        }
+        progress.next_seq += 1;
+        progress.deadline = Instant::now() + self.command_retry_policy.timeout;
+        match result.inner {
+            ShardExecutionMsg::RoundOutput(round, output) => {
+                if !block.aborted {
+                    block
+                        .assembler
+                        .add(shard_id, round, output, on_round_output);
+                }
+            },
+            ShardExecutionMsg::Done(result, stats) => {
+                block.shards[shard_id] = ShardBlockStatus::Done;
+                block.shard_stats[shard_id] = stats;
+                let aborted = block.aborted;
+                self.restart_deadline_after(blocks, index, shard_id);
+                match result {
+                    // The other shards stop too, and are waited for.
+                    Err(ShardedExecutionError::Aborted) if aborted => {},
+                    Err(error) => self.fail_block(blocks, index, error),
+                    Ok(()) => {},
+                }
+            },
+        }
+    }
 
-        self.state_view_service.drop_state_view();
-        Ok(ShardedExecutionOutput::new(execution_results, vec![]))
+    // End the block at `index` with the error, so the shards still executing it are waited for on
+    // their next blocks instead.
+    fn fail_block(&self, blocks: &mut [InFlightBlock], index: usize, error: ShardedExecutionError) {
+        let executing_shards: Vec<_> = (0..self.command_txs.len())
+            .filter(|shard_id| {
+                !blocks[index].is_done_on(*shard_id)
+                    && matches!(blocks[index].shards[*shard_id], ShardBlockStatus::Sent(_))
+            })
+            .collect();
+        blocks[index].error = Some(error);
+        for shard_id in executing_shards {
+            self.restart_deadline_after(blocks, index, shard_id);
+        }
+    }
+
+    // The shard is done with the block at `index`, and starts executing the next block it was sent,
+    // if any, whose results are only expected from now on.
+    fn restart_deadline_after(
+        &self,
+        blocks: &mut [InFlightBlock],
+        index: usize,
+        shard_id: ShardId,
+    ) {
+        let next_block = blocks[index + 1..]
+            .iter_mut()
+            .find(|block| !block.is_done_on(shard_id));
+        if let Some(ShardBlockStatus::Sent(progress)) =
+            next_block.map(|block| &mut block.shards[shard_id])
+        {
+            progress.deadline = Instant::now() + self.command_retry_policy.timeout;
+        }
+    }
+
+    // Re-send the command to a shard that did not send any result within the timeout, unless it
+    // already had all the attempts allowed by the retry policy.
+    fn retry_command(
+        &self,
+        shard_id: ShardId,
+        command_id: u64,
+        command: &Message,
+        progress: &mut ShardResultsProgress,
+    ) -> Result<(), ShardedExecutionError> {
+        let shard_label = shard_id.to_string();
+        let CommandRetryPolicy {
+            timeout,
+            max_retries,
+        } = self.command_retry_policy;
+        REMOTE_EXECUTOR_COMMAND_COUNT
+            .with_label_values(&[&shard_label, "timeouts"])
+            .inc();
+        if progress.num_attempts > max_retries {
+            return Err(ShardedExecutionError::ShardUnavailable {
+                shard_id,
+                num_attempts: progress.num_attempts,
+            });
+        }
+        warn!(
+            "No result from shard {} within {:?}, re-sending command {} (attempt {})",
+            shard_id,
+            timeout,
+            command_id,
+            progress.num_attempts + 1
+        );
+        REMOTE_EXECUTOR_COMMAND_COUNT
+            .with_label_values(&[&shard_label, "retries"])
+            .inc();
+        self.record_message(command_id, || {
+            RecordedMessage::sent(
+                Participant::Shard(shard_id),
+                &format!("execute_command_{}", shard_id),
+                command,
+            )
+        });
+        self.command_txs[shard_id]
+            .lock()
+            .unwrap()
+            .send(command.clone())
+            .unwrap();
+        progress.num_attempts += 1;
+        progress.deadline = Instant::now() + timeout;
+        Ok(())
+    }
+
+    /// Execute the sub-block of `round` on the shard on its own, reading `state_view` and the
+    /// writes of the other shards in `inputs` instead of exchanging any with them. The inputs are
+    /// usually the ones a shard captured (see `capture::SubBlockCapture`), to reproduce its output.
+    ///
+    /// Not to be called while blocks are executing, as the results of both come on the same
+    /// channels.
+    pub fn execute_sub_block(
+        &self,
+        shard_id: ShardId,
+        round: RoundId,
+        inputs: SubBlockInputs,
+        state_view: Arc<S>,
+    ) -> Result<SubBlockOutput, ShardedExecutionError> {
+        self.wait_until_ready()?;
+        let protocol = self.protocol();
+        if !protocol
+            .features
+            .contains(ProtocolFeatures::SUB_BLOCK_EXECUTION)
+        {
+            return Err(ShardedExecutionError::ShardFailure {
+                shard_id,
+                reason: "The shards do not support executing a sub-block on its own".to_string(),
+            });
+        }
+        let command_id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        self.state_view_service
+            .set_state_view(command_id, state_view);
+        let span = if self.is_traced(command_id) {
+            tracing::info_span!(
+                "remote_execute_sub_block",
+                block_id = command_id,
+                shard_id,
+                round
+            )
+        } else {
+            tracing::Span::none()
+        };
+        let request = RemoteExecutionRequest::ExecuteSubBlock(ExecuteSubBlockCommand {
+            command_id,
+            round,
+            inputs,
+            features: protocol.features,
+        });
+        let command = protocol::encode(
+            protocol.version,
+            &Self::trace_request(request, command_id, &span),
+        );
+        let output = self.receive_sub_block_output(shard_id, command_id, command);
+        self.state_view_service.drop_state_view(command_id);
+        output
+    }
+
+    // Send the command to execute a sub-block to the shard and wait for its output, re-sending the
+    // command as allowed by the retry policy.
+    fn receive_sub_block_output(
+        &self,
+        shard_id: ShardId,
+        command_id: u64,
+        command: Message,
+    ) -> Result<SubBlockOutput, ShardedExecutionError> {
+        self.command_txs[shard_id]
+            .lock()
+            .unwrap()
+            .send(command.clone())
+            .unwrap();
+        let mut progress = ShardResultsProgress::new(self.command_retry_policy.timeout);
+        let mut outputs = None;
+        loop {
+            let message = match self.result_rxs[shard_id].recv_deadline(progress.deadline) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.retry_command(shard_id, command_id, &command, &mut progress)?;
+                    continue;
+                },
+                // The network controller is shutdown.
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(ShardedExecutionError::ShardUnavailable {
+                        shard_id,
+                        num_attempts: progress.num_attempts,
+                    });
+                },
+            };
+            let (_, result): (_, RemoteExecutionResult) =
+                protocol::decode(&message).map_err(|error| {
+                    ShardedExecutionError::ShardFailure {
+                        shard_id,
+                        reason: format!("Cannot decode the result: {}", error),
+                    }
+                })?;
+            if result.command_id != command_id {
+                if !self.record_warm_up_result(shard_id, &result) {
+                    REMOTE_EXECUTOR_COMMAND_COUNT
+                        .with_label_values(&[&shard_id.to_string(), "stale_results"])
+                        .inc();
+                }
+                continue;
+            }
+            if result.seq != progress.next_seq {
+                REMOTE_EXECUTOR_COMMAND_COUNT
+                    .with_label_values(&[&shard_id.to_string(), "out_of_sequence_results"])
+                    .inc();
+                continue;
+            }
+            progress.next_seq += 1;
+            progress.deadline = Instant::now() + self.command_retry_policy.timeout;
+            match result.inner {
+                ShardExecutionMsg::RoundOutput(_, output) => outputs = Some(output),
+                ShardExecutionMsg::Done(result, stats) => {
+                    result?;
+                    let outputs = outputs.ok_or_else(|| ShardedExecutionError::ShardFailure {
+                        shard_id,
+                        reason: "No output for the sub-block".to_string(),
+                    })?;
+                    return Ok(SubBlockOutput { outputs, stats });
+                },
+            }
+        }
+    }
+
+    /// Replace the channel used to send commands to the shard with `wrap(channel)`.
+    #[cfg(test)]
+    pub(crate) fn wrap_command_tx(
+        &self,
+        shard_id: ShardId,
+        wrap: impl FnOnce(Sender<Message>) -> Sender<Message>,
+    ) {
+        let mut command_tx = self.command_txs[shard_id].lock().unwrap();
+        *command_tx = wrap(command_tx.clone());
+    }
+
+    /// Replace the channel used to receive results from the shard with `wrap(channel)`.
+    #[cfg(test)]
+    pub(crate) fn wrap_result_rx(
+        &mut self,
+        shard_id: ShardId,
+        wrap: impl FnOnce(Receiver<Message>) -> Receiver<Message>,
+    ) {
+        self.result_rxs[shard_id] = wrap(self.result_rxs[shard_id].clone());
+    }
+}
+
+impl<S: StateView + Sync + Send + 'static> ExecutorClient<S> for RemoteExecutorClient<S> {
+    fn num_shards(&self) -> usize {
+        self.command_txs.len()
+    }
+
+    fn execute_block(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        on_round_output: &mut RoundOutputCallback,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
+        self.execute_abortable_block(
+            state_view,
+            transactions,
+            concurrency_level_per_shard,
+            onchain_config,
+            on_round_output,
+            &BlockAbort::default(),
+        )
+    }
+
+    fn execute_abortable_block(
+        &self,
+        state_view: Arc<S>,
+        transactions: PartitionedTransactions,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        on_round_output: &mut RoundOutputCallback,
+        abort: &BlockAbort,
+    ) -> Result<ShardedExecutionOutput, ShardedExecutionError> {
+        let mut block_output = None;
+        self.execute_pipelined(
+            &mut iter::once((state_view, transactions)),
+            concurrency_level_per_shard,
+            onchain_config,
+            1,
+            on_round_output,
+            abort,
+            &mut |output| block_output = Some(output),
+        );
+        block_output.expect("The block is not executed")
+    }
+
+    fn execute_blocks(
+        &self,
+        blocks: &mut dyn Iterator<Item = (Arc<S>, PartitionedTransactions)>,
+        concurrency_level_per_shard: usize,
+        onchain_config: BlockExecutorConfigFromOnchain,
+        pipeline_depth: usize,
+        on_block_output: &mut dyn FnMut(Result<ShardedExecutionOutput, ShardedExecutionError>),
+    ) {
+        self.execute_pipelined(
+            blocks,
+            concurrency_level_per_shard,
+            onchain_config,
+            pipeline_depth,
+            &mut |_, _, _| {},
+            &BlockAbort::default(),
+            on_block_output,
+        );
     }
 
     fn shutdown(&mut self) {
-        self.network_controller.shutdown();
+        let controller_stopped = self.network_controller.shutdown();
+        let state_view_service_stopped = self.join_handle.take().map_or(true, |join_handle| {
+            join_with_timeout(join_handle, SHUTDOWN_TIMEOUT)
+        });
+        let health_checks_stopped = self
+            .health_check_join_handle
+            .take()
+            .map_or(true, |join_handle| {
+                join_with_timeout(join_handle, SHUTDOWN_TIMEOUT)
+            });
+        if !controller_stopped || !state_view_service_stopped || !health_checks_stopped {
+            warn!(
+                "Remote executor client did not shutdown cleanly (network controller stopped: {}, state view service stopped: {}, health checks stopped: {})",
+                controller_stopped, state_view_service_stopped, health_checks_stopped
+            );
+        }
     }
 }
@@ -2,13 +2,28 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
+    capture::SubBlockRecorder,
+    config::RemoteExecutorConfig,
+    error::Error,
+    health::{self, ShardStatusTracker},
+    protocol::{self, ProtocolSupport},
+    recording::{MessageRecorder, Participant},
     remote_cordinator_client::RemoteCoordinatorClient,
-    remote_cross_shard_client::RemoteCrossShardClient, remote_state_view::RemoteStateViewClient,
+    remote_cross_shard_client::RemoteCrossShardClient,
+    remote_state_view::RemoteStateViewClient,
+    ShardRegistration,
 };
-use aptos_secure_net::network_controller::NetworkController;
+use aptos_logger::warn;
+use aptos_secure_net::network_controller::{Message, NetworkController, SHUTDOWN_TIMEOUT};
 use aptos_types::block_executor::partitioner::ShardId;
 use aptos_vm::sharded_block_executor::sharded_executor_service::ShardedExecutorService;
-use std::{net::SocketAddr, sync::Arc, thread};
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
 
 /// A service that provides support for remote execution. Essentially, it reads a request from
 /// the remote executor client and executes the block locally and returns the result.
@@ -16,57 +31,234 @@ pub struct ExecutorService {
     shard_id: ShardId,
     controller: NetworkController,
     executor_service: Arc<ShardedExecutorService<RemoteStateViewClient>>,
+    // For the tests to look into the state cache of the shard.
+    #[cfg(test)]
+    coordinator_client: Arc<RemoteCoordinatorClient>,
+    #[cfg(test)]
+    cross_shard_client: Arc<RemoteCrossShardClient>,
+    // Channel to tell the coordinator that the shard is up.
+    registration_tx: Sender<Message>,
+    // What the shard tells the coordinator it supports of the protocol.
+    protocol_support: ProtocolSupport,
+    // What the shard is doing, and the channels it answers the health checks of the coordinator
+    // with.
+    status: Arc<ShardStatusTracker>,
+    health_check_rx: Receiver<Message>,
+    health_check_tx: Sender<Message>,
+    join_handle: Option<thread::JoinHandle<()>>,
+    health_check_join_handle: Option<thread::JoinHandle<()>>,
 }
 
 impl ExecutorService {
     pub fn new(
         shard_id: ShardId,
-        num_shards: usize,
-        num_threads: usize,
+        config: &RemoteExecutorConfig,
         self_address: SocketAddr,
         coordinator_address: SocketAddr,
         remote_shard_addresses: Vec<SocketAddr>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
+        Self::with_protocol_support(
+            shard_id,
+            config,
+            self_address,
+            coordinator_address,
+            remote_shard_addresses,
+            ProtocolSupport::current(),
+        )
+    }
+
+    /// Same as `new()`, but the shard tells the coordinator it only supports `protocol_support`,
+    /// e.g. to behave like a shard of an older version.
+    pub fn with_protocol_support(
+        shard_id: ShardId,
+        config: &RemoteExecutorConfig,
+        self_address: SocketAddr,
+        coordinator_address: SocketAddr,
+        remote_shard_addresses: Vec<SocketAddr>,
+        protocol_support: ProtocolSupport,
+    ) -> Result<Self, Error> {
+        config.validate_for_addresses(remote_shard_addresses.len())?;
         let service_name = format!("executor_service-{}", shard_id);
-        let mut controller = NetworkController::new(service_name, self_address, 5000);
+        let mut controller = config.network_controller(service_name, self_address)?;
+        let protocol = Arc::new(RwLock::new(protocol_support.negotiated()));
+        let status = Arc::new(ShardStatusTracker::default());
+        let recorder = config
+            .capture_dir
+            .clone()
+            .map(|dir| Arc::new(SubBlockRecorder::new(shard_id, dir)));
+        let message_recorder = config.recording_dir.clone().map(|dir| {
+            Arc::new(MessageRecorder::new(
+                Participant::Shard(shard_id),
+                dir,
+                config.max_recorded_blocks,
+            ))
+        });
         let coordinator_client = Arc::new(RemoteCoordinatorClient::new(
             shard_id,
             &mut controller,
             coordinator_address,
+            protocol_support,
+            protocol.clone(),
+            config.state_cache_size,
+            status.clone(),
+            recorder.clone(),
+            message_recorder.clone(),
         ));
-        let cross_shard_client = Arc::new(RemoteCrossShardClient::new(
+        let registration_tx = controller
+            .create_outbound_channel(coordinator_address, "shard_registration".to_string());
+        let health_check_rx = controller.create_inbound_channel("health_check_request".to_string());
+        let health_check_tx = controller
+            .create_outbound_channel(coordinator_address, "health_check_response".to_string());
+        let cross_shard_client = RemoteCrossShardClient::new(
+            shard_id,
+            config,
             &mut controller,
             remote_shard_addresses,
-        ));
+            protocol,
+            recorder,
+            message_recorder,
+            status.clone(),
+        );
 
         let executor_service = Arc::new(ShardedExecutorService::new(
             shard_id,
-            num_shards,
-            num_threads,
-            coordinator_client,
-            cross_shard_client,
+            config.num_shards,
+            config.num_threads_per_shard(),
+            coordinator_client.clone(),
+            cross_shard_client.clone(),
         ));
 
-        Self {
+        Ok(Self {
             shard_id,
             controller,
             executor_service,
-        }
+            #[cfg(test)]
+            coordinator_client,
+            #[cfg(test)]
+            cross_shard_client,
+            registration_tx,
+            protocol_support,
+            status,
+            health_check_rx,
+            health_check_tx,
+            join_handle: None,
+            health_check_join_handle: None,
+        })
     }
 
-    pub fn start(&mut self) {
-        self.controller.start();
+    /// Fails if the network cannot be started, e.g. as its TLS config is invalid.
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.controller.start()?;
         let thread_name = format!("ExecutorService-{}", self.shard_id);
         let builder = thread::Builder::new().name(thread_name);
         let executor_service_clone = self.executor_service.clone();
-        builder
-            .spawn(move || {
-                executor_service_clone.start();
-            })
-            .expect("Failed to spawn thread");
+        self.join_handle = Some(
+            builder
+                .spawn(move || {
+                    executor_service_clone.start();
+                })
+                .expect("Failed to spawn thread"),
+        );
+        let shard_id = self.shard_id;
+        let health_check_rx = self.health_check_rx.clone();
+        let health_check_tx = self.health_check_tx.clone();
+        let status = self.status.clone();
+        self.health_check_join_handle = Some(
+            thread::Builder::new()
+                .name(format!("ExecutorService-{}-health", self.shard_id))
+                .spawn(move || {
+                    health::answer_health_checks(
+                        shard_id,
+                        health_check_rx,
+                        health_check_tx,
+                        &status,
+                    )
+                })
+                .expect("Failed to spawn thread"),
+        );
+        let registration = ShardRegistration::new(self.shard_id, self.protocol_support);
+        self.registration_tx
+            .send(protocol::encode_handshake(&registration))
+            .unwrap();
+        Ok(())
     }
 
-    pub fn shutdown(&mut self) {
-        self.controller.shutdown();
+    /// What the shard is doing.
+    pub fn status(&self) -> Arc<ShardStatusTracker> {
+        self.status.clone()
+    }
+
+    /// The value the shard keeps of the key across blocks, if any.
+    #[cfg(test)]
+    pub(crate) fn cached_state_value(
+        &self,
+        state_key: &aptos_types::state_store::state_key::StateKey,
+    ) -> Option<Option<aptos_types::state_store::state_value::StateValue>> {
+        self.coordinator_client
+            .state_view_client()
+            .cached_state_value(state_key)
+    }
+
+    /// Replace the channels the shard sends cross-shard messages on with `wrap(channel)`.
+    #[cfg(test)]
+    pub(crate) fn wrap_cross_shard_txs(&self, wrap: impl Fn(Sender<Message>) -> Sender<Message>) {
+        self.cross_shard_client.wrap_message_txs(wrap);
+    }
+
+    /// Tell the coordinator the shard is about to shut down, and wait up to `timeout` for the shard
+    /// to be done with the block it executes, if any. Returns false if it is still executing one.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.status.start_draining();
+        let deadline = Instant::now() + timeout;
+        while self.status.is_executing() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Shutdown the network controller, which closes the command and health check channels of the
+    /// shard so that it leaves its loops, and wait for the shard threads.
+    ///
+    /// Returns false if the shard did not stop cleanly, i.e. in time and without panicking.
+    pub fn shutdown(&mut self) -> bool {
+        let controller_stopped = self.controller.shutdown();
+        let shard_stopped = [
+            self.join_handle.take(),
+            self.health_check_join_handle.take(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(true, |stopped, join_handle| {
+            join_with_timeout(join_handle, SHUTDOWN_TIMEOUT) && stopped
+        });
+        if !shard_stopped {
+            warn!(
+                "Executor shard {} did not stop within {:?}, or panicked",
+                self.shard_id, SHUTDOWN_TIMEOUT
+            );
+        }
+        controller_stopped && shard_stopped
+    }
+}
+
+impl Drop for ExecutorService {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Join the thread, unless it is still running after `timeout`.
+/// Returns false if the thread did not finish in time or panicked.
+pub(crate) fn join_with_timeout(join_handle: thread::JoinHandle<()>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !join_handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
     }
+    join_handle.join().is_ok()
 }
@@ -1,13 +1,18 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
-use crate::{RemoteKVRequest, RemoteKVResponse};
+use crate::{
+    protocol::{self, NegotiatedProtocol},
+    recording::{MessageRecorder, Participant, RecordedMessage},
+    state_cache::StateValueCache,
+    BaseState, RemoteKVRequest, RemoteKVResponse,
+};
 use aptos_secure_net::network_controller::{Message, NetworkController};
 use aptos_types::state_store::state_key::StateKey;
 use aptos_vm::sharded_block_executor::remote_state_value::RemoteStateValue;
 use crossbeam_channel::{Receiver, Sender};
 use std::{
     net::SocketAddr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
     thread,
 };
 
@@ -26,16 +31,31 @@ use rayon::ThreadPool;
 pub static REMOTE_STATE_KEY_BATCH_SIZE: usize = 200;
 
 pub struct RemoteStateView {
+    // The command the state values are fetched for, if any.
+    command_id: Option<u64>,
+    // The version of the state the values are read at, which the values fetched are cached with,
+    // if known.
+    base_version: Option<u64>,
     state_values: DashMap<StateKey, RemoteStateValue>,
 }
 
 impl RemoteStateView {
-    pub fn new() -> Self {
+    pub fn new(command_id: Option<u64>, base_version: Option<u64>) -> Self {
         Self {
+            command_id,
+            base_version,
             state_values: DashMap::new(),
         }
     }
 
+    pub fn command_id(&self) -> Option<u64> {
+        self.command_id
+    }
+
+    pub fn base_version(&self) -> Option<u64> {
+        self.base_version
+    }
+
     pub fn has_state_key(&self, state_key: &StateKey) -> bool {
         self.state_values.contains_key(state_key)
     }
@@ -53,6 +73,23 @@ impl RemoteStateView {
             .or_insert(RemoteStateValue::waiting());
     }
 
+    // Add a value that is already known, e.g. kept from a previous block. The value may also be
+    // fetched in the meantime, which is waited for on the same entry.
+    pub fn insert_state_value(&self, state_key: StateKey, state_value: Option<StateValue>) {
+        self.state_values
+            .entry(state_key)
+            .or_insert(RemoteStateValue::waiting())
+            .set_value(state_value);
+    }
+
+    // The value of the key, if it is known already.
+    fn ready_state_value(&self, state_key: &StateKey) -> Option<Option<StateValue>> {
+        self.state_values
+            .get(state_key)
+            .filter(|value| value.is_ready())
+            .map(|value| value.get_value())
+    }
+
     pub fn get_state_value(&self, state_key: &StateKey) -> Result<Option<StateValue>> {
         if let Some(value) = self.state_values.get(state_key) {
             let value_clone = value.clone();
@@ -70,7 +107,13 @@ pub struct RemoteStateViewClient {
     shard_id: ShardId,
     kv_tx: Arc<Sender<Message>>,
     state_view: Arc<RwLock<RemoteStateView>>,
+    // The state values kept across blocks.
+    state_cache: Arc<Mutex<StateValueCache>>,
     thread_pool: Arc<rayon::ThreadPool>,
+    // The protocol of the block being executed, which the requests are encoded with.
+    protocol: Arc<RwLock<NegotiatedProtocol>>,
+    // Records the requests and responses, if configured to.
+    message_recorder: Option<Arc<MessageRecorder>>,
     _join_handle: Option<thread::JoinHandle<()>>,
 }
 
@@ -79,6 +122,9 @@ impl RemoteStateViewClient {
         shard_id: ShardId,
         controller: &mut NetworkController,
         coordinator_address: SocketAddr,
+        protocol: Arc<RwLock<NegotiatedProtocol>>,
+        state_cache_size: Option<usize>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) -> Self {
         let thread_pool = Arc::new(
             rayon::ThreadPoolBuilder::new()
@@ -92,12 +138,17 @@ impl RemoteStateViewClient {
         let result_rx = controller.create_inbound_channel(kv_response_type.to_string());
         let command_tx =
             controller.create_outbound_channel(coordinator_address, kv_request_type.to_string());
-        let state_view = Arc::new(RwLock::new(RemoteStateView::new()));
+        let state_view = Arc::new(RwLock::new(RemoteStateView::new(None, None)));
+        let state_cache = Arc::new(Mutex::new(StateValueCache::new(
+            state_cache_size.unwrap_or(0),
+        )));
         let state_value_receiver = RemoteStateValueReceiver::new(
             shard_id,
             state_view.clone(),
+            state_cache.clone(),
             result_rx,
             thread_pool.clone(),
+            message_recorder.clone(),
         );
 
         let join_handle = thread::Builder::new()
@@ -109,17 +160,55 @@ impl RemoteStateViewClient {
             shard_id,
             kv_tx: Arc::new(command_tx),
             state_view,
+            state_cache,
             thread_pool,
+            protocol,
+            message_recorder,
             _join_handle: Some(join_handle),
         }
     }
 
-    pub fn init_for_block(&self, state_keys: Vec<StateKey>) {
-        *self.state_view.write().unwrap() = RemoteStateView::new();
+    pub fn init_for_block(
+        &self,
+        command_id: u64,
+        state_keys: Vec<StateKey>,
+        base_state: Option<&BaseState>,
+    ) {
+        let mut state_cache = self.state_cache.lock().unwrap();
+        state_cache.set_base_state(base_state);
+        let state_view = RemoteStateView::new(Some(command_id), state_cache.version());
+        let (cached_keys, state_keys): (Vec<_>, Vec<_>) = state_keys
+            .into_iter()
+            .partition(|state_key| state_cache.get(state_key).is_some());
+        for state_key in cached_keys.iter() {
+            let state_value = state_cache.get(state_key).unwrap().clone();
+            state_view.insert_state_value(state_key.clone(), state_value);
+        }
+        drop(state_cache);
+        *self.state_view.write().unwrap() = state_view;
+
+        let shard_label = self.shard_id.to_string();
         REMOTE_EXECUTOR_REMOTE_KV_COUNT
-            .with_label_values(&[&self.shard_id.to_string(), "prefetch_kv"])
+            .with_label_values(&[&shard_label, "prefetch_kv"])
             .inc_by(state_keys.len() as u64);
-        self.pre_fetch_state_values(state_keys, false);
+        REMOTE_EXECUTOR_REMOTE_KV_COUNT
+            .with_label_values(&[&shard_label, "cached_kv"])
+            .inc_by(cached_keys.len() as u64);
+        self.pre_fetch_state_values(command_id, state_keys, false);
+        if cfg!(debug_assertions) && !cached_keys.is_empty() {
+            // Fetched anyway, for the receiver to check the values kept are the ones the
+            // coordinator has.
+            REMOTE_EXECUTOR_REMOTE_KV_COUNT
+                .with_label_values(&[&shard_label, "verified_kv"])
+                .inc_by(cached_keys.len() as u64);
+            self.pre_fetch_state_values(command_id, cached_keys, false);
+        }
+    }
+
+    /// The value the shard keeps of the key across blocks, if any.
+    #[cfg(test)]
+    pub(crate) fn cached_state_value(&self, state_key: &StateKey) -> Option<Option<StateValue>> {
+        self.state_cache.lock().unwrap().get(state_key).cloned()
     }
 
     fn insert_keys_and_fetch_values(
@@ -127,7 +216,10 @@ impl RemoteStateViewClient {
         thread_pool: Arc<ThreadPool>,
         kv_tx: Arc<Sender<Message>>,
         shard_id: ShardId,
+        version: u8,
+        command_id: u64,
         state_keys: Vec<StateKey>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) {
         state_keys.clone().into_iter().for_each(|state_key| {
             state_view_clone.read().unwrap().insert_state_key(state_key);
@@ -137,17 +229,32 @@ impl RemoteStateViewClient {
             .map(|state_keys_chunk| state_keys_chunk.to_vec())
             .for_each(|state_keys| {
                 let sender = kv_tx.clone();
+                let message_recorder = message_recorder.clone();
                 thread_pool.spawn(move || {
-                    Self::send_state_value_request(shard_id, sender, state_keys);
+                    Self::send_state_value_request(
+                        shard_id,
+                        version,
+                        command_id,
+                        sender,
+                        state_keys,
+                        message_recorder,
+                    );
                 });
             });
     }
 
-    fn pre_fetch_state_values(&self, state_keys: Vec<StateKey>, sync_insert_keys: bool) {
+    fn pre_fetch_state_values(
+        &self,
+        command_id: u64,
+        state_keys: Vec<StateKey>,
+        sync_insert_keys: bool,
+    ) {
         let state_view_clone = self.state_view.clone();
         let thread_pool_clone = self.thread_pool.clone();
         let kv_tx_clone = self.kv_tx.clone();
         let shard_id = self.shard_id;
+        let version = self.protocol.read().unwrap().version;
+        let message_recorder = self.message_recorder.clone();
 
         let insert_and_fetch = move || {
             Self::insert_keys_and_fetch_values(
@@ -155,7 +262,10 @@ impl RemoteStateViewClient {
                 thread_pool_clone,
                 kv_tx_clone,
                 shard_id,
+                version,
+                command_id,
                 state_keys,
+                message_recorder,
             );
         };
         if sync_insert_keys {
@@ -170,12 +280,21 @@ impl RemoteStateViewClient {
 
     fn send_state_value_request(
         shard_id: ShardId,
+        version: u8,
+        command_id: u64,
         sender: Arc<Sender<Message>>,
         state_keys: Vec<StateKey>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) {
-        let request = RemoteKVRequest::new(shard_id, state_keys);
-        let request_message = bcs::to_bytes(&request).unwrap();
-        sender.send(Message::new(request_message)).unwrap();
+        let request = RemoteKVRequest::new(shard_id, command_id, state_keys);
+        let message = protocol::encode(version, &request);
+        if let Some(message_recorder) = message_recorder {
+            message_recorder.record(
+                Some(command_id),
+                RecordedMessage::sent(Participant::Coordinator, "remote_kv_request", &message),
+            );
+        }
+        sender.send(message).unwrap();
     }
 }
 
@@ -191,6 +310,26 @@ impl TStateView for RemoteStateViewClient {
                 .start_timer();
             return state_view_reader.get_state_value(state_key);
         }
+        let command_id = state_view_reader
+            .command_id()
+            .expect("State values are read before any block");
+        // The key may not be in the read and write hints, but still be kept from a previous block.
+        let cached_value = state_view_reader.base_version().and_then(|base_version| {
+            let state_cache = self.state_cache.lock().unwrap();
+            (state_cache.version() == Some(base_version))
+                .then(|| state_cache.get(state_key).cloned())
+                .flatten()
+        });
+        if let Some(state_value) = cached_value {
+            REMOTE_EXECUTOR_REMOTE_KV_COUNT
+                .with_label_values(&[&self.shard_id.to_string(), "cached_kv"])
+                .inc();
+            state_view_reader.insert_state_value(state_key.clone(), state_value.clone());
+            if cfg!(debug_assertions) {
+                self.pre_fetch_state_values(command_id, vec![state_key.clone()], false);
+            }
+            return Ok(state_value);
+        }
         // If the value is not already in the cache then we pre-fetch it and wait for it to arrive.
         let _timer = REMOTE_EXECUTOR_TIMER
             .with_label_values(&[&self.shard_id.to_string(), "non_prefetch_wait"])
@@ -198,7 +337,7 @@ impl TStateView for RemoteStateViewClient {
         REMOTE_EXECUTOR_REMOTE_KV_COUNT
             .with_label_values(&[&self.shard_id.to_string(), "non_prefetch_kv"])
             .inc();
-        self.pre_fetch_state_values(vec![state_key.clone()], true);
+        self.pre_fetch_state_values(command_id, vec![state_key.clone()], true);
         state_view_reader.get_state_value(state_key)
     }
 
@@ -210,31 +349,39 @@ impl TStateView for RemoteStateViewClient {
 struct RemoteStateValueReceiver {
     shard_id: ShardId,
     state_view: Arc<RwLock<RemoteStateView>>,
+    state_cache: Arc<Mutex<StateValueCache>>,
     kv_rx: Receiver<Message>,
     thread_pool: Arc<rayon::ThreadPool>,
+    message_recorder: Option<Arc<MessageRecorder>>,
 }
 
 impl RemoteStateValueReceiver {
     fn new(
         shard_id: ShardId,
         state_view: Arc<RwLock<RemoteStateView>>,
+        state_cache: Arc<Mutex<StateValueCache>>,
         kv_rx: Receiver<Message>,
         thread_pool: Arc<rayon::ThreadPool>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) -> Self {
         Self {
             shard_id,
             state_view,
+            state_cache,
             kv_rx,
             thread_pool,
+            message_recorder,
         }
     }
 
     fn start(&self) {
         while let Ok(message) = self.kv_rx.recv() {
             let state_view = self.state_view.clone();
+            let state_cache = self.state_cache.clone();
             let shard_id = self.shard_id;
+            let message_recorder = self.message_recorder.clone();
             self.thread_pool.spawn(move || {
-                Self::handle_message(shard_id, message, state_view);
+                Self::handle_message(shard_id, message, state_view, state_cache, message_recorder);
             });
         }
     }
@@ -243,6 +390,8 @@ impl RemoteStateValueReceiver {
         shard_id: ShardId,
         message: Message,
         state_view: Arc<RwLock<RemoteStateView>>,
+        state_cache: Arc<Mutex<StateValueCache>>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) {
         let _timer = REMOTE_EXECUTOR_TIMER
             .with_label_values(&[&shard_id.to_string(), "kv_responses"])
@@ -250,18 +399,55 @@ impl RemoteStateValueReceiver {
         let bcs_deser_timer = REMOTE_EXECUTOR_TIMER
             .with_label_values(&[&shard_id.to_string(), "kv_resp_deser"])
             .start_timer();
-        let response: RemoteKVResponse = bcs::from_bytes(&message.data).unwrap();
+        let (_, response): (u8, RemoteKVResponse) =
+            protocol::decode(&message).unwrap_or_else(|error| {
+                panic!("Shard {} cannot decode state values: {}", shard_id, error)
+            });
         drop(bcs_deser_timer);
+        if let Some(message_recorder) = message_recorder {
+            message_recorder.record(
+                Some(response.command_id),
+                RecordedMessage::received(
+                    Some(Participant::Coordinator),
+                    "remote_kv_response",
+                    &message,
+                ),
+            );
+        }
 
         REMOTE_EXECUTOR_REMOTE_KV_COUNT
             .with_label_values(&[&shard_id.to_string(), "kv_responses"])
             .inc();
         let state_view_lock = state_view.read().unwrap();
+        if state_view_lock.command_id() != Some(response.command_id) {
+            // The response to a pre-fetch for a previous block, that block did not wait for.
+            REMOTE_EXECUTOR_REMOTE_KV_COUNT
+                .with_label_values(&[&shard_id.to_string(), "stale_kv_responses"])
+                .inc();
+            return;
+        }
         trace!(
             "Received state values for shard {} with size {}",
             shard_id,
             response.inner.len()
         );
+        if cfg!(debug_assertions) {
+            for (state_key, state_value) in response.inner.iter() {
+                if let Some(known_value) = state_view_lock.ready_state_value(state_key) {
+                    assert_eq!(
+                        &known_value, state_value,
+                        "Shard {} kept a stale value of {:?} for command {}",
+                        shard_id, state_key, response.command_id
+                    );
+                }
+            }
+        }
+        if let Some(base_version) = state_view_lock.base_version() {
+            let mut state_cache = state_cache.lock().unwrap();
+            for (state_key, state_value) in response.inner.iter() {
+                state_cache.insert(base_version, state_key.clone(), state_value.clone());
+            }
+        }
         response
             .inner
             .into_iter()
@@ -1,16 +1,21 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
-use crate::{RemoteKVRequest, RemoteKVResponse};
+use crate::{
+    protocol,
+    recording::{MessageRecorder, Participant, RecordedMessage},
+    RemoteKVRequest, RemoteKVResponse,
+};
 use aptos_secure_net::network_controller::{Message, NetworkController};
 use crossbeam_channel::{Receiver, Sender};
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, RwLock},
 };
 
 extern crate itertools;
-use crate::metrics::REMOTE_EXECUTOR_TIMER;
-use aptos_logger::trace;
+use crate::metrics::{REMOTE_EXECUTOR_REMOTE_KV_COUNT, REMOTE_EXECUTOR_TIMER};
+use aptos_logger::{trace, warn};
 use aptos_types::state_store::{StateView, TStateView};
 use itertools::Itertools;
 
@@ -18,7 +23,10 @@ pub struct RemoteStateViewService<S: StateView + Sync + Send + 'static> {
     kv_rx: Receiver<Message>,
     kv_tx: Arc<Vec<Sender<Message>>>,
     thread_pool: Arc<rayon::ThreadPool>,
-    state_view: Arc<RwLock<Option<Arc<S>>>>,
+    // The state views of the blocks being executed, by command id.
+    state_views: Arc<RwLock<HashMap<u64, Arc<S>>>>,
+    // Records the requests and responses, if configured to.
+    message_recorder: RwLock<Option<Arc<MessageRecorder>>>,
 }
 
 impl<S: StateView + Sync + Send + 'static> RemoteStateViewService<S> {
@@ -47,34 +55,41 @@ impl<S: StateView + Sync + Send + 'static> RemoteStateViewService<S> {
             kv_rx: result_rx,
             kv_tx: Arc::new(command_txs),
             thread_pool,
-            state_view: Arc::new(RwLock::new(None)),
+            state_views: Arc::new(RwLock::new(HashMap::new())),
+            message_recorder: RwLock::new(None),
         }
     }
 
-    pub fn set_state_view(&self, state_view: Arc<S>) {
-        let mut state_view_lock = self.state_view.write().unwrap();
-        *state_view_lock = Some(state_view);
+    pub fn set_message_recorder(&self, message_recorder: Arc<MessageRecorder>) {
+        *self.message_recorder.write().unwrap() = Some(message_recorder);
+    }
+
+    pub fn set_state_view(&self, command_id: u64, state_view: Arc<S>) {
+        let mut state_views_lock = self.state_views.write().unwrap();
+        state_views_lock.insert(command_id, state_view);
     }
 
-    pub fn drop_state_view(&self) {
-        let mut state_view_lock = self.state_view.write().unwrap();
-        *state_view_lock = None;
+    pub fn drop_state_view(&self, command_id: u64) {
+        let mut state_views_lock = self.state_views.write().unwrap();
+        state_views_lock.remove(&command_id);
     }
 
     pub fn start(&self) {
         while let Ok(message) = self.kv_rx.recv() {
-            let state_view = self.state_view.clone();
+            let state_views = self.state_views.clone();
             let kv_txs = self.kv_tx.clone();
+            let message_recorder = self.message_recorder.read().unwrap().clone();
             self.thread_pool.spawn(move || {
-                Self::handle_message(message, state_view, kv_txs);
+                Self::handle_message(message, state_views, kv_txs, message_recorder);
             });
         }
     }
 
     pub fn handle_message(
         message: Message,
-        state_view: Arc<RwLock<Option<Arc<S>>>>,
+        state_views: Arc<RwLock<HashMap<u64, Arc<S>>>>,
         kv_tx: Arc<Vec<Sender<Message>>>,
+        message_recorder: Option<Arc<MessageRecorder>>,
     ) {
         // we don't know the shard id until we deserialize the message, so lets default it to 0
         let _timer = REMOTE_EXECUTOR_TIMER
@@ -83,41 +98,69 @@ impl<S: StateView + Sync + Send + 'static> RemoteStateViewService<S> {
         let bcs_deser_timer = REMOTE_EXECUTOR_TIMER
             .with_label_values(&["0", "kv_req_deser"])
             .start_timer();
-        let req: RemoteKVRequest = bcs::from_bytes(&message.data).unwrap();
+        let (version, req): (u8, RemoteKVRequest) = match protocol::decode(&message) {
+            Ok(request) => request,
+            Err(error) => {
+                warn!("remote state view service - dropping request: {}", error);
+                return;
+            },
+        };
         drop(bcs_deser_timer);
 
-        let (shard_id, state_keys) = req.into();
+        let (shard_id, command_id, state_keys) = req.into();
+        if let Some(message_recorder) = &message_recorder {
+            message_recorder.record(
+                Some(command_id),
+                RecordedMessage::received(
+                    Some(Participant::Shard(shard_id)),
+                    "remote_kv_request",
+                    &message,
+                ),
+            );
+        }
         trace!(
             "remote state view service - received request for shard {} with {} keys",
             shard_id,
             state_keys.len()
         );
+        let Some(state_view) = state_views.read().unwrap().get(&command_id).cloned() else {
+            // A pre-fetch the shard did not wait for, as the block is already executed.
+            trace!(
+                "remote state view service - dropping request for shard {} of done command {}",
+                shard_id,
+                command_id
+            );
+            return;
+        };
         let resp = state_keys
             .into_iter()
             .map(|state_key| {
-                let state_value = state_view
-                    .read()
-                    .unwrap()
-                    .as_ref()
-                    .unwrap()
-                    .get_state_value(&state_key)
-                    .unwrap();
+                let state_value = state_view.get_state_value(&state_key).unwrap();
                 (state_key, state_value)
             })
             .collect_vec();
         let len = resp.len();
-        let resp = RemoteKVResponse::new(resp);
+        REMOTE_EXECUTOR_REMOTE_KV_COUNT
+            .with_label_values(&[&shard_id.to_string(), "served_kv"])
+            .inc_by(len as u64);
+        let resp = RemoteKVResponse::new(command_id, resp);
         let bcs_ser_timer = REMOTE_EXECUTOR_TIMER
             .with_label_values(&["0", "kv_resp_ser"])
             .start_timer();
-        let resp = bcs::to_bytes(&resp).unwrap();
+        // Answered with the version of the request, which the shard supports.
+        let message = protocol::encode(version, &resp);
         drop(bcs_ser_timer);
         trace!(
             "remote state view service - sending response for shard {} with {} keys",
             shard_id,
             len
         );
-        let message = Message::new(resp);
+        if let Some(message_recorder) = &message_recorder {
+            message_recorder.record(
+                Some(command_id),
+                RecordedMessage::sent(Participant::Shard(shard_id), "remote_kv_response", &message),
+            );
+        }
         kv_tx[shard_id].send(message).unwrap();
     }
 }
@@ -0,0 +1,138 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::BaseState;
+use aptos_types::{
+    state_store::{state_key::StateKey, state_value::StateValue},
+    write_set::TOTAL_SUPPLY_STATE_KEY,
+};
+use std::collections::HashMap;
+
+/// The state values a shard keeps across blocks, so that it only fetches from the coordinator the
+/// values it did not read for the previous blocks, or that the previous blocks overwrote.
+pub struct StateValueCache {
+    max_size: usize,
+    // The version of the state the values are of, if any.
+    version: Option<u64>,
+    values: HashMap<StateKey, Option<StateValue>>,
+}
+
+impl StateValueCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            version: None,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Get ready for a block reading `base_state`, keeping the values still valid at its version,
+    /// or for a block reading an unknown state if `None`.
+    pub fn set_base_state(&mut self, base_state: Option<&BaseState>) {
+        let Some(base_state) = base_state else {
+            self.version = None;
+            self.values.clear();
+            return;
+        };
+        if self.version == Some(base_state.version) {
+            return;
+        }
+        match &base_state.parent {
+            Some(parent) if self.version == Some(parent.version) => {
+                for (state_key, state_value) in parent.writes.iter() {
+                    if let Some(value) = self.values.get_mut(state_key) {
+                        *value = state_value.clone();
+                    }
+                }
+            },
+            _ => self.values.clear(),
+        }
+        self.version = Some(base_state.version);
+    }
+
+    pub fn version(&self) -> Option<u64> {
+        self.version
+    }
+
+    pub fn get(&self, state_key: &StateKey) -> Option<&Option<StateValue>> {
+        self.values.get(state_key)
+    }
+
+    /// Keep the value read at `version`, unless the cache moved on to another version since, or
+    /// is full.
+    pub fn insert(&mut self, version: u64, state_key: StateKey, state_value: Option<StateValue>) {
+        // The shards write the total supply relative to a base value, and only the coordinator
+        // knows the actual value written, so the writes of the previous blocks cannot update it.
+        if self.version != Some(version)
+            || state_key == *TOTAL_SUPPLY_STATE_KEY
+            || (self.values.len() >= self.max_size && !self.values.contains_key(&state_key))
+        {
+            return;
+        }
+        self.values.insert(state_key, state_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParentState;
+
+    fn state_value(value: &[u8]) -> Option<StateValue> {
+        Some(StateValue::new_legacy(value.to_vec().into()))
+    }
+
+    fn base_state(version: u64, parent: Option<ParentState>) -> BaseState {
+        BaseState { version, parent }
+    }
+
+    #[test]
+    fn test_state_value_cache_follows_versions() {
+        let [key_1, key_2, key_3] = ["1", "2", "3"].map(|key| StateKey::raw(key.as_bytes()));
+        let mut cache = StateValueCache::new(2);
+        cache.set_base_state(Some(&base_state(10, None)));
+        cache.insert(10, key_1.clone(), state_value(b"a"));
+        cache.insert(10, key_2.clone(), None);
+        cache.insert(10, TOTAL_SUPPLY_STATE_KEY.clone(), state_value(b"t"));
+        assert_eq!(cache.get(&TOTAL_SUPPLY_STATE_KEY), None);
+        // Full.
+        cache.insert(10, key_3.clone(), state_value(b"c"));
+        assert_eq!(cache.get(&key_3), None);
+        // Read at a version the cache moved on from.
+        cache.insert(9, key_1.clone(), state_value(b"z"));
+        assert_eq!(cache.get(&key_1), Some(&state_value(b"a")));
+
+        // The same state.
+        cache.set_base_state(Some(&base_state(10, None)));
+        assert_eq!(cache.get(&key_2), Some(&None));
+
+        // The state plus the writes of the block that read it.
+        cache.set_base_state(Some(&base_state(
+            11,
+            Some(ParentState {
+                version: 10,
+                writes: vec![(key_1.clone(), None), (key_3.clone(), state_value(b"c"))],
+            }),
+        )));
+        assert_eq!(cache.version(), Some(11));
+        assert_eq!(cache.get(&key_1), Some(&None));
+        assert_eq!(cache.get(&key_2), Some(&None));
+        assert_eq!(cache.get(&key_3), None);
+
+        // A state following another one than the cached one.
+        cache.set_base_state(Some(&base_state(
+            13,
+            Some(ParentState {
+                version: 12,
+                writes: vec![],
+            }),
+        )));
+        assert_eq!(cache.version(), Some(13));
+        assert_eq!(cache.get(&key_2), None);
+
+        cache.insert(13, key_2.clone(), state_value(b"b"));
+        cache.set_base_state(None);
+        assert_eq!(cache.version(), None);
+        assert_eq!(cache.get(&key_2), None);
+    }
+}
@@ -1,30 +1,52 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use aptos_block_partitioner::{v2::config::PartitionerV2Config, PartitionerConfig};
+use aptos_block_partitioner::{
+    test_utils::TxnParams, v2::config::PartitionerV2Config, workloads::WorkloadGenerator,
+    PartitionerConfig,
+};
+use aptos_crypto::PrivateKey;
 use aptos_language_e2e_tests::{
-    account::AccountData, common_transactions::peer_to_peer_txn, data_store::FakeDataStore,
+    account::{Account, AccountData, AccountPublicKey, DEFAULT_EXPIRATION_TIME},
+    common_transactions::peer_to_peer_txn,
+    data_store::FakeDataStore,
     executor::FakeExecutor,
+    gas_costs::TXN_RESERVED,
 };
+use aptos_secure_net::network_controller::Message;
 use aptos_types::{
     account_address::AccountAddress,
+    account_config::CoinStoreResource,
     block_executor::{
         config::BlockExecutorConfigFromOnchain, partitioner::PartitionedTransactions,
     },
-    state_store::state_key::inner::StateKeyInner,
+    chain_id::ChainId,
+    state_store::{
+        errors::StateviewError,
+        state_key::{inner::StateKeyInner, StateKey},
+        state_storage_usage::StateStorageUsage,
+        state_value::StateValue,
+        MoveResourceExt, TStateView,
+    },
     transaction::{
         analyzed_transaction::AnalyzedTransaction,
-        signature_verified_transaction::SignatureVerifiedTransaction, Transaction,
-        TransactionOutput,
+        signature_verified_transaction::SignatureVerifiedTransaction, ExecutionStatus, Transaction,
+        TransactionOutput, TransactionStatus,
     },
 };
 use aptos_vm::{
     sharded_block_executor::{executor_client::ExecutorClient, ShardedBlockExecutor},
     AptosVM, VMExecutor,
 };
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
 pub fn generate_account_at(executor: &mut FakeExecutor, address: AccountAddress) -> AccountData {
@@ -51,17 +73,28 @@ pub fn generate_non_conflicting_p2p(
     (txn, sender, receiver)
 }
 
+const GAS_UNIT_PRICE: u64 = 100;
+
 pub fn generate_p2p_txn(
     sender: &mut AccountData,
     receiver: &AccountData,
     transfer_amount: u64,
+) -> AnalyzedTransaction {
+    generate_p2p_txn_with_gas_unit_price(sender, receiver, transfer_amount, GAS_UNIT_PRICE)
+}
+
+fn generate_p2p_txn_with_gas_unit_price(
+    sender: &mut AccountData,
+    receiver: &AccountData,
+    transfer_amount: u64,
+    gas_unit_price: u64,
 ) -> AnalyzedTransaction {
     let txn = Transaction::UserTransaction(peer_to_peer_txn(
         sender.account(),
         receiver.account(),
         sender.sequence_number(),
         transfer_amount,
-        100,
+        gas_unit_price,
     ))
     .into();
     sender.increment_sequence_number();
@@ -107,91 +140,513 @@ pub fn compare_txn_outputs(
     }
 }
 
-pub fn test_sharded_block_executor_no_conflict<E: ExecutorClient<FakeDataStore>>(
-    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
-) {
-    let num_txns = 400;
-    let num_shards = sharded_block_executor.num_shards();
-    let mut executor = FakeExecutor::from_head_genesis();
-    let mut transactions = Vec::new();
-    for _ in 0..num_txns {
-        transactions.push(generate_non_conflicting_p2p(&mut executor).0)
+/// Params that make the txns of a `WorkloadGenerator` executable by `FakeExecutor::from_head_genesis()`.
+pub fn executable_txn_params() -> TxnParams {
+    TxnParams {
+        max_gas_amount: TXN_RESERVED,
+        gas_unit_price: 100,
+        expiration_timestamp_secs: DEFAULT_EXPIRATION_TIME,
+        chain_id: ChainId::test(),
     }
-    let partitioner = PartitionerV2Config::default()
-        .max_partitioning_rounds(2)
-        .cross_shard_dep_avoid_threshold(0.9)
-        .partition_last_round(true)
-        .build();
-    let partitioned_txns = partitioner.partition(transactions.clone(), num_shards);
+}
+
+/// Create the accounts of the generator in the state of the executor, with enough balance to pay for their txns.
+/// Has to be called before the generator signs any txn.
+pub fn add_workload_accounts(executor: &mut FakeExecutor, generator: &WorkloadGenerator) {
+    for test_account in generator.accounts() {
+        let account = Account::new_from_addr(
+            test_account.account_address,
+            AccountPublicKey::Ed25519(test_account.private_key.public_key()),
+        );
+        executor.add_account_data(&AccountData::with_account(
+            account,
+            3_000_000_000,
+            test_account.sequence_number,
+        ));
+    }
+    generate_account_at(executor, generator.collection_creator());
+}
+
+/// Execute the partitioned txns with the sharded executor and, in the order given by the partitioner,
+/// with the unsharded one, then compare the outputs. Returns the outputs of the sharded executor.
+pub fn execute_and_compare<E: ExecutorClient<FakeDataStore>>(
+    sharded_block_executor: &ShardedBlockExecutor<FakeDataStore, E>,
+    data_store: &FakeDataStore,
+    partitioned_txns: PartitionedTransactions,
+    concurrency: usize,
+) -> Vec<TransactionOutput> {
+    let execution_ordered_txns: Vec<SignatureVerifiedTransaction> =
+        PartitionedTransactions::flatten(partitioned_txns.clone())
+            .into_iter()
+            .map(|t| t.into_txn())
+            .collect();
     let sharded_txn_output = sharded_block_executor
         .execute_block(
-            Arc::new(executor.data_store().clone()),
-            partitioned_txns.clone(),
-            2,
+            Arc::new(data_store.clone()),
+            partitioned_txns,
+            concurrency,
             BlockExecutorConfigFromOnchain::new_no_block_limit(),
         )
         .unwrap();
+    let unsharded_txn_output =
+        AptosVM::execute_block_no_limit(&execution_ordered_txns, data_store).unwrap();
+    compare_txn_outputs(unsharded_txn_output, sharded_txn_output.clone());
+    sharded_txn_output
+}
+
+/// Execute the partitioned txns with the unsharded executor and apply their writes, for the data
+/// store of the executor to be the state of the next block.
+pub fn apply_partitioned_txns(
+    executor: &mut FakeExecutor,
+    partitioned_txns: PartitionedTransactions,
+) {
     let txns: Vec<SignatureVerifiedTransaction> =
         PartitionedTransactions::flatten(partitioned_txns)
             .into_iter()
             .map(|t| t.into_txn())
             .collect();
-    let unsharded_txn_output =
-        AptosVM::execute_block_no_limit(&txns, executor.data_store()).unwrap();
-    compare_txn_outputs(unsharded_txn_output, sharded_txn_output);
-    sharded_block_executor.shutdown();
+    for output in AptosVM::execute_block_no_limit(&txns, executor.data_store()).unwrap() {
+        if matches!(output.status(), TransactionStatus::Keep(_)) {
+            executor.apply_write_set(output.write_set());
+        }
+    }
 }
 
-pub fn sharded_block_executor_with_conflict<E: ExecutorClient<FakeDataStore>>(
-    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
-    concurrency: usize,
+pub fn test_sharded_block_executor_no_conflict<E: ExecutorClient<FakeDataStore>>(
+    sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
 ) {
-    let num_txns = 800;
-    let num_shards = sharded_block_executor.num_shards();
-    let num_accounts = 80;
-    let mut executor = FakeExecutor::from_head_genesis();
-    let mut transactions = Vec::new();
-    let mut accounts = Vec::new();
-    let mut txn_hash_to_account = HashMap::new();
-    for _ in 0..num_accounts {
-        let account = generate_account_at(&mut executor, AccountAddress::random());
-        accounts.push(Mutex::new(account));
+    sharded_block_executor_with_workload(sharded_block_executor, |executor| {
+        generate_hot_spot_workload(executor, 400, 0.0)
+    });
+}
+
+/// The txns of a workload of transfers, along with the balances they leave the accounts with, so
+/// that the tests check the outputs the same way whatever the workload.
+pub struct TransferWorkload {
+    pub transactions: Vec<AnalyzedTransaction>,
+    // The balance of each account once all the txns are executed, before the gas fees.
+    pub expected_balances: BTreeMap<AccountAddress, u64>,
+}
+
+impl TransferWorkload {
+    fn new() -> Self {
+        Self {
+            transactions: vec![],
+            expected_balances: BTreeMap::new(),
+        }
+    }
+
+    fn new_account(&mut self, executor: &mut FakeExecutor) -> AccountData {
+        let account = generate_account_at(executor, AccountAddress::random());
+        self.expected_balances
+            .insert(*account.address(), account.balance());
+        account
+    }
+
+    fn new_accounts(
+        &mut self,
+        executor: &mut FakeExecutor,
+        num_accounts: usize,
+    ) -> Vec<AccountData> {
+        (0..num_accounts)
+            .map(|_| self.new_account(executor))
+            .collect()
+    }
+
+    fn add_transfer(
+        &mut self,
+        sender: &mut AccountData,
+        receiver: &AccountData,
+        transfer_amount: u64,
+        gas_unit_price: u64,
+    ) {
+        self.transactions.push(generate_p2p_txn_with_gas_unit_price(
+            sender,
+            receiver,
+            transfer_amount,
+            gas_unit_price,
+        ));
+        *self.expected_balances.get_mut(sender.address()).unwrap() -= transfer_amount;
+        *self.expected_balances.get_mut(receiver.address()).unwrap() += transfer_amount;
+    }
+
+    /// Check that the txns, executed in the order of `txns` with `outputs` on top of `data_store`,
+    /// all succeeded and left the accounts with the expected balances, minus the gas fees.
+    pub fn assert_balances(
+        &self,
+        data_store: &FakeDataStore,
+        txns: &[SignatureVerifiedTransaction],
+        outputs: &[TransactionOutput],
+    ) {
+        assert_eq!(txns.len(), outputs.len());
+        let mut data_store = data_store.clone();
+        let mut expected_balances = self.expected_balances.clone();
+        for (txn, output) in txns.iter().zip(outputs) {
+            assert_eq!(
+                output.status(),
+                &TransactionStatus::Keep(ExecutionStatus::Success)
+            );
+            data_store.add_write_set(output.write_set());
+            let Transaction::UserTransaction(signed_txn) = txn.expect_valid() else {
+                continue;
+            };
+            if let Some(balance) = expected_balances.get_mut(&signed_txn.sender()) {
+                *balance -= output.gas_used() * signed_txn.gas_unit_price();
+            }
+        }
+        for (address, expected_balance) in expected_balances {
+            let balance = CoinStoreResource::fetch_move_resource(&data_store, &address)
+                .unwrap()
+                .map_or(0, |coin_store| coin_store.coin());
+            assert_eq!(balance, expected_balance, "Balance of {}", address);
+        }
     }
+
+    /// Same as `execute_and_compare()`, and then check the balances the txns left the accounts
+    /// with.
+    pub fn execute_and_check<E: ExecutorClient<FakeDataStore>>(
+        &self,
+        sharded_block_executor: &ShardedBlockExecutor<FakeDataStore, E>,
+        data_store: &FakeDataStore,
+        partitioned_txns: PartitionedTransactions,
+        concurrency: usize,
+    ) {
+        let execution_ordered_txns: Vec<SignatureVerifiedTransaction> =
+            PartitionedTransactions::flatten(partitioned_txns.clone())
+                .into_iter()
+                .map(|t| t.into_txn())
+                .collect();
+        let sharded_txn_output = execute_and_compare(
+            sharded_block_executor,
+            data_store,
+            partitioned_txns,
+            concurrency,
+        );
+        self.assert_balances(data_store, &execution_ordered_txns, &sharded_txn_output);
+    }
+}
+
+/// Transfers from `num_senders` accounts, of which a `hot_ratio` share all pay the same receiver
+/// and the others pay a receiver of their own. With a ratio of 0, no txn depends on another.
+pub fn generate_hot_spot_workload(
+    executor: &mut FakeExecutor,
+    num_senders: usize,
+    hot_ratio: f64,
+) -> TransferWorkload {
+    let mut workload = TransferWorkload::new();
+    let hot_receiver = workload.new_account(executor);
+    let num_hot_senders = (num_senders as f64 * hot_ratio).round() as usize;
+    for index in 0..num_senders {
+        let mut sender = workload.new_account(executor);
+        let receiver = if index < num_hot_senders {
+            hot_receiver.clone()
+        } else {
+            workload.new_account(executor)
+        };
+        workload.add_transfer(&mut sender, &receiver, 1_000 + index as u64, GAS_UNIT_PRICE);
+    }
+    workload
+}
+
+/// `num_chains` chains of `chain_length` transfers: the first account of a chain pays the second
+/// one, which then pays the third one, and so on, each txn depending on the previous one.
+pub fn generate_chained_workload(
+    executor: &mut FakeExecutor,
+    num_chains: usize,
+    chain_length: usize,
+) -> TransferWorkload {
+    let mut workload = TransferWorkload::new();
+    let mut chains: Vec<_> = (0..num_chains)
+        .map(|_| workload.new_accounts(executor, chain_length + 1))
+        .collect();
+    // The chains are interleaved, so that the partitioner cannot keep a chain on a shard just by
+    // splitting the block in consecutive parts.
+    for link in 0..chain_length {
+        for chain in chains.iter_mut() {
+            let (senders, receivers) = chain.split_at_mut(link + 1);
+            workload.add_transfer(
+                &mut senders[link],
+                &receivers[0],
+                1_000 * (chain_length - link) as u64,
+                GAS_UNIT_PRICE,
+            );
+        }
+    }
+    workload
+}
+
+/// For each of `num_hubs` hubs, `fan_width` accounts pay the hub, which then pays `fan_width` other
+/// accounts, the payments of the hub depending on all the payments to it.
+pub fn generate_fan_in_fan_out_workload(
+    executor: &mut FakeExecutor,
+    num_hubs: usize,
+    fan_width: usize,
+) -> TransferWorkload {
+    let mut workload = TransferWorkload::new();
+    for _ in 0..num_hubs {
+        let mut hub = workload.new_account(executor);
+        for index in 0..fan_width {
+            let mut sender = workload.new_account(executor);
+            workload.add_transfer(&mut sender, &hub, 2_000 + index as u64, GAS_UNIT_PRICE);
+        }
+        for index in 0..fan_width {
+            let receiver = workload.new_account(executor);
+            workload.add_transfer(&mut hub, &receiver, 1_000 + index as u64, GAS_UNIT_PRICE);
+        }
+    }
+    workload
+}
+
+/// Independent transfers between `num_txns` pairs of accounts, of which an `expensive_ratio` share
+/// pays 10 times the gas unit price of the others.
+pub fn generate_mixed_gas_workload(
+    executor: &mut FakeExecutor,
+    num_txns: usize,
+    expensive_ratio: f64,
+) -> TransferWorkload {
+    let mut workload = TransferWorkload::new();
+    let num_expensive_txns = (num_txns as f64 * expensive_ratio).round() as usize;
+    for index in 0..num_txns {
+        let mut sender = workload.new_account(executor);
+        let receiver = workload.new_account(executor);
+        // Spread the expensive txns over the block.
+        let gas_unit_price = if index * num_expensive_txns % num_txns < num_expensive_txns {
+            GAS_UNIT_PRICE * 10
+        } else {
+            GAS_UNIT_PRICE
+        };
+        workload.add_transfer(&mut sender, &receiver, 1_000 + index as u64, gas_unit_price);
+    }
+    workload
+}
+
+/// Transfers between `num_accounts` accounts, each of them sending to all the others in turn.
+pub fn generate_all_to_all_workload(
+    executor: &mut FakeExecutor,
+    num_accounts: usize,
+    num_txns: usize,
+) -> TransferWorkload {
+    let mut workload = TransferWorkload::new();
+    let mut accounts = workload.new_accounts(executor, num_accounts);
     for i in 1..num_txns / num_accounts {
         for j in 0..num_accounts {
-            let sender = &mut accounts[j].lock().unwrap();
-            let sender_addr = *sender.address();
-            let receiver = &accounts[(j + i) % num_accounts].lock().unwrap();
-            let transfer_amount = 1_000;
-            let txn = generate_p2p_txn(sender, receiver, transfer_amount);
-            txn_hash_to_account.insert(txn.transaction().hash(), sender_addr);
-            transactions.push(txn)
+            let receiver = accounts[(j + i) % num_accounts].clone();
+            workload.add_transfer(&mut accounts[j], &receiver, 1_000, GAS_UNIT_PRICE);
         }
     }
+    workload
+}
 
+/// Execute the workload generated on top of the genesis with the sharded executor and the
+/// unsharded one, see `TransferWorkload::execute_and_check()`.
+pub fn sharded_block_executor_with_workload<E: ExecutorClient<FakeDataStore>>(
+    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+    generate_workload: impl FnOnce(&mut FakeExecutor) -> TransferWorkload,
+) {
+    let num_shards = sharded_block_executor.num_shards();
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = generate_workload(&mut executor);
     let partitioner = PartitionerV2Config::default()
         .max_partitioning_rounds(2)
         .cross_shard_dep_avoid_threshold(0.9)
         .partition_last_round(true)
         .build();
-    let partitioned_txns = partitioner.partition(transactions.clone(), num_shards);
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+    sharded_block_executor.shutdown();
+}
 
-    let execution_ordered_txns: Vec<SignatureVerifiedTransaction> =
-        PartitionedTransactions::flatten(partitioned_txns.clone())
-            .into_iter()
-            .map(|t| t.into_txn())
-            .collect();
+// The round outputs are passed in the order of the final output, which they only differ from by the
+// total supply.
+pub fn sharded_block_executor_streams_round_outputs<E: ExecutorClient<FakeDataStore>>(
+    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+) {
+    let num_shards = sharded_block_executor.num_shards();
+    let mut executor = FakeExecutor::from_head_genesis();
+    let transactions = generate_all_to_all_workload(&mut executor, 80, 400).transactions;
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(transactions, num_shards);
+    let num_rounds = partitioned_txns.sharded_txns()[0].num_sub_blocks();
+
+    let mut streamed_rounds = vec![];
+    let mut streamed_txn_output = vec![];
     let sharded_txn_output = sharded_block_executor
-        .execute_block(
+        .execute_block_streaming(
             Arc::new(executor.data_store().clone()),
             partitioned_txns,
-            concurrency,
+            2,
             BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            |shard_id, round, output| {
+                streamed_rounds.push((round, shard_id));
+                streamed_txn_output.extend_from_slice(output);
+            },
         )
         .unwrap();
 
-    let unsharded_txn_output =
-        AptosVM::execute_block_no_limit(&execution_ordered_txns, executor.data_store()).unwrap();
-    compare_txn_outputs(unsharded_txn_output, sharded_txn_output);
+    let expected_rounds: Vec<_> = (0..num_rounds)
+        .flat_map(|round| (0..num_shards).map(move |shard_id| (round, shard_id)))
+        .collect();
+    assert_eq!(streamed_rounds, expected_rounds);
+    assert_eq!(streamed_txn_output.len(), sharded_txn_output.len());
+    for (streamed, output) in streamed_txn_output.iter().zip(sharded_txn_output.iter()) {
+        assert_eq!(streamed.status(), output.status());
+    }
     sharded_block_executor.shutdown();
 }
+
+pub fn sharded_block_executor_with_conflict<E: ExecutorClient<FakeDataStore>>(
+    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+    concurrency: usize,
+) {
+    let num_shards = sharded_block_executor.num_shards();
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = generate_all_to_all_workload(&mut executor, 80, 800);
+
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        concurrency,
+    );
+    sharded_block_executor.shutdown();
+}
+
+/// A channel to send messages to `tx`, which delivers every message sent to it twice.
+pub fn duplicate_sent_messages(tx: Sender<Message>) -> Sender<Message> {
+    let (duplicating_tx, duplicating_rx) = unbounded();
+    thread::spawn(move || {
+        for message in duplicating_rx.iter() {
+            if tx.send(message.clone()).is_err() || tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    duplicating_tx
+}
+
+// Forward the messages from `rx` to `tx` on a separate thread, except for the first `num_dropped`
+// ones, which are lost.
+fn forward_dropping_first_messages(rx: Receiver<Message>, tx: Sender<Message>, num_dropped: usize) {
+    thread::spawn(move || {
+        for message in rx.iter().skip(num_dropped) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// A channel to send messages to `tx`, which loses the first `num_dropped` messages sent to it.
+pub fn drop_first_sent_messages(tx: Sender<Message>, num_dropped: usize) -> Sender<Message> {
+    let (faulty_tx, faulty_rx) = unbounded();
+    forward_dropping_first_messages(faulty_rx, tx, num_dropped);
+    faulty_tx
+}
+
+/// A channel to receive the messages of `rx`, which loses the first `num_dropped` of them.
+pub fn drop_first_received_messages(
+    rx: Receiver<Message>,
+    num_dropped: usize,
+) -> Receiver<Message> {
+    let (faulty_tx, faulty_rx) = unbounded();
+    forward_dropping_first_messages(rx, faulty_tx, num_dropped);
+    faulty_rx
+}
+
+/// A channel to receive the messages of `rx`, which loses all of them once `dropping` is set.
+pub fn drop_received_messages_when(
+    rx: Receiver<Message>,
+    dropping: Arc<AtomicBool>,
+) -> Receiver<Message> {
+    let (faulty_tx, faulty_rx) = unbounded();
+    thread::spawn(move || {
+        for message in rx.iter() {
+            if !dropping.load(Ordering::Relaxed) && faulty_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    faulty_rx
+}
+
+/// A state view over `inner` that panics when `trigger` is read, to make the shard executing the
+/// txn that reads it panic.
+pub struct PanickingStateView {
+    inner: FakeDataStore,
+    trigger: Option<StateKey>,
+}
+
+impl PanickingStateView {
+    pub fn new(inner: FakeDataStore, trigger: Option<StateKey>) -> Self {
+        Self { inner, trigger }
+    }
+}
+
+impl TStateView for PanickingStateView {
+    type Key = StateKey;
+
+    fn get_state_value(&self, state_key: &StateKey) -> Result<Option<StateValue>, StateviewError> {
+        if self.trigger.as_ref() == Some(state_key) {
+            panic!("Read the trigger key {:?}", state_key);
+        }
+        self.inner.get_state_value(state_key)
+    }
+
+    fn get_usage(&self) -> Result<StateStorageUsage, StateviewError> {
+        self.inner.get_usage()
+    }
+}
+
+/// A state view over `inner` that takes some time to read some of the keys, to slow down the
+/// shards executing the txns that read them.
+pub struct DelayedStateView {
+    inner: FakeDataStore,
+    delays: HashMap<StateKey, Duration>,
+}
+
+impl DelayedStateView {
+    /// Takes `delay` to read any of the `delayed` keys.
+    pub fn new(inner: FakeDataStore, delayed: HashSet<StateKey>, delay: Duration) -> Self {
+        Self::with_delays(
+            inner,
+            delayed
+                .into_iter()
+                .map(|state_key| (state_key, delay))
+                .collect(),
+        )
+    }
+
+    pub fn with_delays(inner: FakeDataStore, delays: HashMap<StateKey, Duration>) -> Self {
+        Self { inner, delays }
+    }
+}
+
+impl TStateView for DelayedStateView {
+    type Key = StateKey;
+
+    fn get_state_value(&self, state_key: &StateKey) -> Result<Option<StateValue>, StateviewError> {
+        if let Some(delay) = self.delays.get(state_key) {
+            thread::sleep(*delay);
+        }
+        self.inner.get_state_value(state_key)
+    }
+
+    fn get_usage(&self) -> Result<StateStorageUsage, StateviewError> {
+        self.inner.get_usage()
+    }
+}
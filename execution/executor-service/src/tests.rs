@@ -2,64 +2,172 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
-    remote_executor_client::RemoteExecutorClient, test_utils,
+    capture::SubBlockCapture,
+    config::{RemoteExecutorConfig, SecurityConfig, WarmUpConfig},
+    error::Error,
+    health::{HealthCheckPolicy, ShardStatusTracker},
+    metrics::{
+        REMOTE_EXECUTOR_BLOCKED_ON_SHARD_SECONDS, REMOTE_EXECUTOR_COMMAND_COUNT,
+        REMOTE_EXECUTOR_CROSS_SHARD_COUNT, REMOTE_EXECUTOR_REMOTE_KV_COUNT,
+        REMOTE_EXECUTOR_SHARD_QUEUE_DEPTH,
+    },
+    mock_executor_shard::{self, MockBlockScript, MockExecutorShard},
+    protocol::{self, NegotiatedProtocol, ProtocolFeatures, ProtocolSupport, PROTOCOL_VERSION},
+    recording::{self, BlockRecording, MessageDirection, Participant},
+    remote_cross_shard_client::RemoteCrossShardClient,
+    remote_executor_client::{CommandRetryPolicy, RemoteExecutorClient},
+    test_utils,
     thread_executor_service::ThreadExecutorService,
+    ShardRegistration,
 };
+use aptos_block_partitioner::{v2::config::PartitionerV2Config, PartitionerConfig};
 use aptos_config::utils;
-use aptos_language_e2e_tests::data_store::FakeDataStore;
+use aptos_language_e2e_tests::{data_store::FakeDataStore, executor::FakeExecutor};
 use aptos_secure_net::network_controller::NetworkController;
-use aptos_vm::sharded_block_executor::ShardedBlockExecutor;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use aptos_temppath::TempPath;
+use aptos_types::{
+    account_address::AccountAddress,
+    account_config::AccountResource,
+    block_executor::{
+        config::BlockExecutorConfigFromOnchain, partitioner::PartitionedTransactions,
+    },
+    state_store::{state_key::StateKey, StateView},
+    transaction::{
+        signature_verified_transaction::SignatureVerifiedTransaction, TransactionOutput,
+    },
+};
+use aptos_vm::sharded_block_executor::{
+    cross_shard_client::CrossShardClient,
+    executor_client::{ExecutorClient, ShardedExecutionError},
+    local_executor_shard::LocalExecutorClient,
+    messages::{CrossShardMsg, RemoteTxnWrite},
+    ShardedBlockExecutor,
+};
+use futures::{executor::block_on, FutureExt};
+use move_core_types::{identifier::Identifier, language_storage::ModuleId};
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{mpsc, Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
 
-pub fn create_thread_remote_executor_shards(
-    num_shards: usize,
-    num_threads: Option<usize>,
-) -> (
-    RemoteExecutorClient<FakeDataStore>,
-    Vec<ThreadExecutorService>,
-) {
-    // First create the coordinator.
-    let listen_port = utils::get_available_port();
-    let coordinator_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), listen_port);
-    let controller = NetworkController::new(
-        "remote-executor-coordinator".to_string(),
-        coordinator_address,
-        5000,
-    );
-    let remote_shard_addresses = (0..num_shards)
+fn get_available_addresses(num_addresses: usize) -> Vec<SocketAddr> {
+    (0..num_addresses)
         .map(|_| {
             let listen_port = utils::get_available_port();
             SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), listen_port)
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+pub fn create_thread_remote_executor_shards<S: StateView + Sync + Send + 'static>(
+    config: &RemoteExecutorConfig,
+) -> (RemoteExecutorClient<S>, Vec<ThreadExecutorService>) {
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(config.num_shards);
+    create_thread_remote_executor_shards_at(config, coordinator_address, remote_shard_addresses)
+}
 
-    let num_threads =
-        num_threads.unwrap_or_else(|| (num_cpus::get() as f64 / num_shards as f64).ceil() as usize);
+fn create_thread_remote_executor_shards_at<S: StateView + Sync + Send + 'static>(
+    config: &RemoteExecutorConfig,
+    coordinator_address: SocketAddr,
+    remote_shard_addresses: Vec<SocketAddr>,
+) -> (RemoteExecutorClient<S>, Vec<ThreadExecutorService>) {
+    // First create the coordinator.
+    let controller = config
+        .network_controller(
+            "remote-executor-coordinator".to_string(),
+            coordinator_address,
+        )
+        .unwrap();
 
-    let remote_executor_services = (0..num_shards)
+    let remote_executor_services = (0..config.num_shards)
         .map(|shard_id| {
             ThreadExecutorService::new(
                 shard_id,
-                num_shards,
-                num_threads,
+                config,
                 coordinator_address,
                 remote_shard_addresses.clone(),
             )
+            .unwrap()
         })
         .collect::<Vec<_>>();
 
-    let remote_executor_client =
-        RemoteExecutorClient::new(remote_shard_addresses, controller, None);
+    let mut remote_executor_client =
+        RemoteExecutorClient::new(remote_shard_addresses, controller, None).unwrap();
+    remote_executor_client.set_max_queued_commands_per_shard(config.max_queued_commands_per_shard);
+    if let Some(trace_sampling_interval) = config.trace_sampling_interval {
+        remote_executor_client.set_trace_sampling_interval(trace_sampling_interval);
+    }
+    if let Some(warm_up) = &config.warm_up {
+        remote_executor_client.set_warm_up(warm_up.clone());
+    }
+    if let Some(recording_dir) = &config.recording_dir {
+        remote_executor_client.set_recording(recording_dir.clone(), config.max_recorded_blocks);
+    }
     (remote_executor_client, remote_executor_services)
 }
 
+// Mock shards executing the blocks as scripted by `scripts`, indexed by block then by shard.
+fn create_mock_executor_shards(
+    num_shards: usize,
+    scripts: Vec<Vec<MockBlockScript>>,
+) -> (RemoteExecutorClient<FakeDataStore>, Vec<MockExecutorShard>) {
+    let network_timeout_ms = RemoteExecutorConfig::new(num_shards).network_timeout_ms;
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(num_shards);
+    let controller = NetworkController::new(
+        "remote-executor-coordinator".to_string(),
+        coordinator_address,
+        network_timeout_ms,
+    );
+    let executor_client =
+        RemoteExecutorClient::new(remote_shard_addresses.clone(), controller, None).unwrap();
+
+    let mut shard_scripts = vec![vec![]; num_shards];
+    for block_scripts in scripts {
+        for (shard_id, script) in block_scripts.into_iter().enumerate() {
+            shard_scripts[shard_id].push(script);
+        }
+    }
+    let mock_shards = shard_scripts
+        .into_iter()
+        .enumerate()
+        .map(|(shard_id, scripts)| {
+            MockExecutorShard::new(
+                shard_id,
+                scripts,
+                coordinator_address,
+                &remote_shard_addresses,
+                network_timeout_ms,
+            )
+        })
+        .collect();
+    executor_client
+        .wait_for_shards(Duration::from_secs(10))
+        .unwrap();
+    (executor_client, mock_shards)
+}
+
+// The gas used of the outputs of the mock shards is the index of their txn in the block.
+fn assert_mock_outputs_in_order(outputs: &[TransactionOutput], num_txns: usize) {
+    assert_eq!(outputs.len(), num_txns);
+    for (index, output) in outputs.iter().enumerate() {
+        assert_eq!(output.gas_used(), index as u64);
+    }
+}
+
 #[test]
 fn test_sharded_block_executor_no_conflict() {
     use std::thread;
 
     let num_shards = 8;
-    let (executor_client, mut executor_services) =
-        create_thread_remote_executor_shards(num_shards, Some(2));
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
     let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
 
     // wait for the servers to be ready before sending messages
@@ -78,8 +186,9 @@ fn test_sharded_block_executor_with_conflict() {
     use std::thread;
 
     let num_shards = 8;
-    let (executor_client, mut executor_services) =
-        create_thread_remote_executor_shards(num_shards, Some(2));
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
     let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
 
     // wait for the servers to be ready before sending messages
@@ -92,3 +201,2046 @@ fn test_sharded_block_executor_with_conflict() {
         executor_service.shutdown();
     });
 }
+
+// The shards are started after the coordinator, so the coordinator has to wait for them, and
+// their first messages have to be retried until the remote servers are up.
+fn create_network_remote_executor_shards_started_late(
+    num_shards: usize,
+) -> (
+    ShardedBlockExecutor<FakeDataStore, RemoteExecutorClient<FakeDataStore>>,
+    Vec<ThreadExecutorService>,
+) {
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(num_shards);
+    let coordinator = {
+        let remote_shard_addresses = remote_shard_addresses.clone();
+        std::thread::spawn(move || {
+            RemoteExecutorClient::create_network_remote_executor_shards(
+                coordinator_address,
+                remote_shard_addresses,
+                None,
+                Duration::from_secs(30),
+            )
+        })
+    };
+    std::thread::sleep(Duration::from_millis(200));
+    let executor_services = (0..num_shards)
+        .map(|shard_id| {
+            ThreadExecutorService::new(
+                shard_id,
+                &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+                coordinator_address,
+                remote_shard_addresses.clone(),
+            )
+            .unwrap()
+        })
+        .collect();
+    let sharded_block_executor = coordinator.join().unwrap().unwrap();
+    (sharded_block_executor, executor_services)
+}
+
+#[test]
+fn test_network_sharded_block_executor_no_conflict() {
+    let (sharded_block_executor, executor_services) =
+        create_network_remote_executor_shards_started_late(4);
+    test_utils::test_sharded_block_executor_no_conflict(sharded_block_executor);
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_network_sharded_block_executor_with_conflict() {
+    let (sharded_block_executor, executor_services) =
+        create_network_remote_executor_shards_started_late(4);
+    test_utils::sharded_block_executor_with_conflict(sharded_block_executor, 2);
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_network_remote_executor_shards_connect_timeout() {
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(2);
+    let result = RemoteExecutorClient::<FakeDataStore>::create_network_remote_executor_shards(
+        coordinator_address,
+        remote_shard_addresses,
+        None,
+        Duration::from_millis(200),
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_remote_executor_client_retries_lost_command_and_result() {
+    let num_shards = 4;
+    let (mut executor_client, executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    executor_client.set_command_retry_policy(CommandRetryPolicy {
+        timeout: Duration::from_millis(500),
+        max_retries: 20,
+    });
+    // The first command to shard 0 and the first result of shard 1 are lost.
+    executor_client.wrap_command_tx(0, |tx| test_utils::drop_first_sent_messages(tx, 1));
+    executor_client.wrap_result_rx(1, |rx| test_utils::drop_first_received_messages(rx, 1));
+    let num_retries = |shard_id: usize| {
+        REMOTE_EXECUTOR_COMMAND_COUNT
+            .with_label_values(&[&shard_id.to_string(), "retries"])
+            .get()
+    };
+    let num_retries_before = [num_retries(0), num_retries(1)];
+
+    test_utils::test_sharded_block_executor_no_conflict(ShardedBlockExecutor::new(executor_client));
+    assert!(num_retries(0) > num_retries_before[0]);
+    assert!(num_retries(1) > num_retries_before[1]);
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_sharded_block_executor_ignores_redelivered_commands_and_batches() {
+    let num_shards = 4;
+    let (executor_client, executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    // Every command and every cross-shard batch is delivered twice.
+    for shard_id in 0..num_shards {
+        executor_client.wrap_command_tx(shard_id, test_utils::duplicate_sent_messages);
+    }
+    for executor_service in &executor_services {
+        executor_service.wrap_cross_shard_txs(test_utils::duplicate_sent_messages);
+    }
+    executor_client
+        .wait_for_shards(Duration::from_secs(10))
+        .unwrap();
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    let num_sub_blocks: usize = partitioned_txns
+        .sharded_txns()
+        .iter()
+        .map(|sub_blocks| sub_blocks.num_sub_blocks())
+        .sum();
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+
+    // Relies on every test running in its own process for the metrics, which is what nextest
+    // does.
+    let num_executed_sub_blocks: u64 = aptos_metrics_core::gather()
+        .into_iter()
+        .find(|family| family.get_name() == "sharded_block_executor_txn_count")
+        .unwrap()
+        .get_metric()
+        .iter()
+        .map(|metric| metric.get_histogram().get_sample_count())
+        .sum();
+    assert_eq!(num_executed_sub_blocks, num_sub_blocks as u64);
+    let count = |name: &str| -> u64 {
+        (0..num_shards)
+            .map(|shard_id| {
+                let shard_label = shard_id.to_string();
+                REMOTE_EXECUTOR_COMMAND_COUNT
+                    .with_label_values(&[&shard_label, name])
+                    .get()
+                    + REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+                        .with_label_values(&[&shard_label, name])
+                        .get()
+            })
+            .sum()
+    };
+    assert_eq!(count("redelivered_commands"), num_shards as u64);
+    assert!(count("duplicate_batches") > 0);
+
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_remote_executor_client_gives_up_on_unavailable_shard() {
+    let num_shards = 2;
+    let (mut executor_client, executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    executor_client.set_command_retry_policy(CommandRetryPolicy {
+        timeout: Duration::from_millis(100),
+        max_retries: 2,
+    });
+    // Shard 1 never gets any command.
+    executor_client.wrap_command_tx(1, |tx| test_utils::drop_first_sent_messages(tx, usize::MAX));
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let transactions = (0..20)
+        .map(|_| test_utils::generate_non_conflicting_p2p(&mut executor).0)
+        .collect();
+    let partitioned_txns = PartitionerV2Config::default()
+        .build()
+        .partition(transactions, num_shards);
+    let result = sharded_block_executor.execute_block(
+        Arc::new(executor.data_store().clone()),
+        partitioned_txns,
+        2,
+        BlockExecutorConfigFromOnchain::new_no_block_limit(),
+    );
+    assert_eq!(
+        result,
+        Err(ShardedExecutionError::ShardUnavailable {
+            shard_id: 1,
+            num_attempts: 3,
+        })
+    );
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+// Counts all the threads of the process, so it relies on the test running in its own process,
+// which is what nextest does.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_remote_executor_shards_shutdown_without_leaks() {
+    use std::{fs, thread};
+
+    fn num_threads() -> usize {
+        fs::read_dir("/proc/self/task").unwrap().count()
+    }
+
+    fn run_assembly(coordinator_address: SocketAddr, remote_shard_addresses: Vec<SocketAddr>) {
+        let (executor_client, executor_services) = create_thread_remote_executor_shards_at(
+            &RemoteExecutorConfig::new(remote_shard_addresses.len()).threads_per_shard(2),
+            coordinator_address,
+            remote_shard_addresses,
+        );
+        let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+        // wait for the servers to be ready before sending messages
+        thread::sleep(Duration::from_millis(10));
+        test_utils::test_sharded_block_executor_no_conflict(sharded_block_executor);
+        for executor_service in executor_services {
+            assert!(executor_service.close());
+        }
+    }
+
+    // Rayon pools let their threads exit asynchronously after being dropped.
+    fn wait_for_num_threads_at_most(max_num_threads: usize) -> usize {
+        for _ in 0..100 {
+            if num_threads() <= max_num_threads {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        num_threads()
+    }
+
+    // The same addresses every time, which only works if the sockets of the previous run were released.
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(2);
+    // The first run initializes process-wide state, such as the global rayon pool used by the VM.
+    run_assembly(coordinator_address, remote_shard_addresses.clone());
+    thread::sleep(Duration::from_millis(500));
+    let num_threads_before = num_threads();
+    for _ in 0..3 {
+        run_assembly(coordinator_address, remote_shard_addresses.clone());
+    }
+    let num_threads_after = wait_for_num_threads_at_most(num_threads_before);
+    assert!(
+        num_threads_after <= num_threads_before,
+        "{} threads before, {} after",
+        num_threads_before,
+        num_threads_after
+    );
+}
+
+#[test]
+fn test_sharded_block_executor_reports_per_shard_stats() {
+    let num_shards = 4;
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    assert!(sharded_block_executor.last_block_breakdown().is_none());
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 400);
+    let num_txns = workload.transactions.len();
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+
+    let breakdown = sharded_block_executor.last_block_breakdown().unwrap();
+    assert_eq!(breakdown.shard_stats.len(), num_shards);
+    let num_executed_txns: usize = breakdown
+        .shard_stats
+        .iter()
+        .flat_map(|stats| stats.rounds.iter())
+        .map(|round_stats| round_stats.num_txns)
+        .sum();
+    assert_eq!(num_executed_txns, num_txns);
+    assert!(breakdown.slowest_shard().is_some());
+    assert!(breakdown.slowest_round().is_some());
+
+    let metric_families = aptos_metrics_core::gather();
+    for name in [
+        "sharded_block_executor_txn_count",
+        "sharded_block_execution_by_rounds_seconds",
+        "sharded_executor_cross_shard_wait_seconds",
+        "sharded_executor_execute_block_seconds",
+    ] {
+        let family = metric_families
+            .iter()
+            .find(|family| family.get_name() == name)
+            .unwrap_or_else(|| panic!("Metric {} is not registered", name));
+        for shard_id in 0..num_shards {
+            let shard_label = shard_id.to_string();
+            assert!(
+                family
+                    .get_metric()
+                    .iter()
+                    .any(|metric| metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_name() == "shard_id"
+                            && label.get_value() == shard_label)),
+                "Metric {} has no sample for shard {}",
+                name,
+                shard_id
+            );
+        }
+    }
+
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_sharded_block_executor_attributes_dependency_waits() {
+    let num_shards = 4;
+    let (executor_client, executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    executor_client
+        .wait_for_shards(Duration::from_secs(10))
+        .unwrap();
+    let mut sharded_block_executor: ShardedBlockExecutor<
+        test_utils::DelayedStateView,
+        RemoteExecutorClient<test_utils::DelayedStateView>,
+    > = ShardedBlockExecutor::new(executor_client);
+    // Every block is slow enough for its longest waits to be logged.
+    sharded_block_executor.set_slow_block_threshold(Some(Duration::ZERO));
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_hot_spot_workload(&mut executor, 200, 0.5);
+    // The coin store of the receiver half of the senders pay, the key the most txns write.
+    let mut num_writes = HashMap::new();
+    for txn in &workload.transactions {
+        for location in txn.write_hints() {
+            *num_writes.entry(location.state_key().clone()).or_insert(0) += 1;
+        }
+    }
+    let (hot_key, _) = num_writes
+        .into_iter()
+        .max_by_key(|(_, num_writes)| *num_writes)
+        .unwrap();
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    // The shards reading the hot key from the state view are slow to, which the shards waiting for
+    // their writes of it wait for.
+    let state_view = Arc::new(test_utils::DelayedStateView::new(
+        executor.data_store().clone(),
+        HashSet::from([hot_key.clone()]),
+        Duration::from_millis(20),
+    ));
+    let outputs = sharded_block_executor
+        .execute_block(
+            state_view,
+            partitioned_txns,
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+        .unwrap();
+    assert_eq!(outputs.len(), workload.transactions.len());
+
+    let breakdown = sharded_block_executor.last_block_breakdown().unwrap();
+    let longest_waits = breakdown.longest_dependency_waits(1);
+    let (shard_id, longest_wait) = longest_waits[0];
+    assert_eq!(longest_wait.state_key, hot_key);
+    assert!(longest_wait.wait_time > Duration::ZERO);
+    assert_ne!(longest_wait.source_shard, shard_id);
+    assert!(breakdown.to_string().contains(&format!("{:?}", hot_key)));
+
+    // Relies on every test running in its own process for the metric, which is what nextest does.
+    let num_waits: u64 = aptos_metrics_core::gather()
+        .into_iter()
+        .find(|family| family.get_name() == "sharded_executor_dependency_wait_seconds")
+        .unwrap()
+        .get_metric()
+        .iter()
+        .map(|metric| metric.get_histogram().get_sample_count())
+        .sum();
+    assert!(num_waits > 0);
+
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_remote_executor_shards_use_configured_threads() {
+    let num_shards = 2;
+    for (threads_per_shard, expected_concurrency_level) in [(1, 1), (3, 3), (8, 4)] {
+        let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+            &RemoteExecutorConfig::new(num_shards).threads_per_shard(threads_per_shard),
+        );
+        let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+        let mut executor = FakeExecutor::from_head_genesis();
+        let workload = test_utils::generate_all_to_all_workload(&mut executor, 40, 200);
+        let partitioner = PartitionerV2Config::default()
+            .max_partitioning_rounds(2)
+            .cross_shard_dep_avoid_threshold(0.9)
+            .partition_last_round(true)
+            .build();
+        let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+        // The coordinator asks for a concurrency level of 4, more than some shards have threads
+        // for.
+        workload.execute_and_check(
+            &sharded_block_executor,
+            executor.data_store(),
+            partitioned_txns,
+            4,
+        );
+
+        let breakdown = sharded_block_executor.last_block_breakdown().unwrap();
+        assert_eq!(breakdown.shard_stats.len(), num_shards);
+        for round_stats in breakdown
+            .shard_stats
+            .iter()
+            .flat_map(|stats| stats.rounds.iter())
+        {
+            assert_eq!(
+                round_stats.concurrency_level, expected_concurrency_level,
+                "Wrong concurrency level with {} threads per shard",
+                threads_per_shard
+            );
+        }
+
+        sharded_block_executor.shutdown();
+        executor_services.iter_mut().for_each(|executor_service| {
+            executor_service.shutdown();
+        });
+    }
+}
+
+#[test]
+fn test_sharded_block_executor_reports_shard_panic() {
+    let num_shards = 4;
+    let sharded_block_executor = Arc::new(
+        LocalExecutorClient::<test_utils::PanickingStateView>::create_local_sharded_block_executor(
+            num_shards,
+            Some(2),
+        ),
+    );
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_chained_workload(&mut executor, 4, 10);
+    let num_txns = workload.transactions.len();
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    // A shard panics when a txn reads the account of the sender of a txn another shard waits on,
+    // which is read by that txn or the one paying its sender before. The other shards wait on the
+    // panicking shard, and only go on if it releases them.
+    let trigger = partitioned_txns
+        .sharded_txns()
+        .iter()
+        .enumerate()
+        .flat_map(|(shard_id, sub_blocks)| sub_blocks.iter().map(move |txn| (shard_id, txn)))
+        .find(|(shard_id, txn)| {
+            txn.cross_shard_dependencies()
+                .dependent_edges()
+                .iter()
+                .any(|(dependent, _)| dependent.shard_id != *shard_id)
+        })
+        .map(|(_, txn)| {
+            StateKey::resource_typed::<AccountResource>(&txn.txn().sender().unwrap()).unwrap()
+        })
+        .expect("No txn has a dependent on another shard");
+
+    let execute_block = |trigger: Option<StateKey>| {
+        let sharded_block_executor = sharded_block_executor.clone();
+        let state_view = Arc::new(test_utils::PanickingStateView::new(
+            executor.data_store().clone(),
+            trigger,
+        ));
+        let partitioned_txns = partitioned_txns.clone();
+        let (result_tx, result_rx) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            // Sequential execution, so that the panic is not caught by parallel execution.
+            let result = sharded_block_executor.execute_block(
+                state_view,
+                partitioned_txns,
+                1,
+                BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            );
+            result_tx.send(result).unwrap();
+        });
+        result_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("The coordinator did not return within the timeout")
+    };
+
+    match execute_block(Some(trigger)) {
+        Err(ShardedExecutionError::ShardFailure { shard_id, reason }) => {
+            assert!(shard_id < num_shards);
+            assert!(reason.contains("Read the trigger key"), "{}", reason);
+        },
+        result => panic!("Expected a shard failure, got {:?}", result),
+    }
+    // The shard survives the panic and executes the next block.
+    assert_eq!(execute_block(None).unwrap().len(), num_txns);
+}
+
+#[test]
+fn test_local_cross_shard_channels_use_hinted_capacities() {
+    let num_shards = 4;
+    let sharded_block_executor =
+        LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(
+            num_shards,
+            Some(2),
+        );
+    // Nothing is allocated upfront before the first block.
+    for capacities in sharded_block_executor
+        .executor_client()
+        .cross_shard_channel_capacities()
+    {
+        assert!(capacities.iter().all(|capacity| *capacity == 0));
+    }
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    let volume = partitioned_txns.cross_shard_message_volume().clone();
+    assert!(volume.total().num_messages > 0);
+
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+    let capacities = sharded_block_executor
+        .executor_client()
+        .cross_shard_channel_capacities();
+    assert_eq!(capacities.len(), num_shards);
+    for (shard_id, capacities) in capacities.iter().enumerate() {
+        for (round, capacity) in capacities.iter().enumerate() {
+            // Room for the messages of the round and the stop message.
+            assert!(*capacity > volume.inbound(shard_id, round).num_messages);
+        }
+    }
+}
+
+#[test]
+fn test_local_sharded_block_executor_streams_round_outputs() {
+    let sharded_block_executor =
+        LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(4, Some(2));
+    test_utils::sharded_block_executor_streams_round_outputs(sharded_block_executor);
+}
+
+#[test]
+fn test_local_sharded_block_executor_updates_shards_between_blocks() {
+    let mut sharded_block_executor =
+        LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(2, Some(2));
+    let mut executor = FakeExecutor::from_head_genesis();
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    // Each block reads the state the previous blocks left.
+    for num_shards in [2, 4, 4, 3, 2] {
+        sharded_block_executor.update_shards(num_shards).unwrap();
+        assert_eq!(sharded_block_executor.num_shards(), num_shards);
+        let workload = test_utils::generate_all_to_all_workload(&mut executor, 40, 200);
+        let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+        workload.execute_and_check(
+            &sharded_block_executor,
+            executor.data_store(),
+            partitioned_txns.clone(),
+            2,
+        );
+        test_utils::apply_partitioned_txns(&mut executor, partitioned_txns);
+    }
+
+    // A block partitioned for the shards before the update.
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 40, 200);
+    let partitioned_txns = partitioner.partition(workload.transactions, 4);
+    assert_eq!(
+        sharded_block_executor.execute_block(
+            Arc::new(executor.data_store().clone()),
+            partitioned_txns,
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        ),
+        Err(ShardedExecutionError::PartitionMismatch {
+            num_shards: 2,
+            num_partitions: 4,
+        })
+    );
+    assert!(matches!(
+        sharded_block_executor.update_shards(0),
+        Err(ShardedExecutionError::ShardUpdateFailed(_))
+    ));
+    assert_eq!(sharded_block_executor.num_shards(), 2);
+}
+
+#[test]
+fn test_remote_executor_client_cannot_update_shards() {
+    let (executor_client, mut executor_services) =
+        create_thread_remote_executor_shards::<FakeDataStore>(&RemoteExecutorConfig::new(2));
+    let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    assert!(matches!(
+        sharded_block_executor.update_shards(4),
+        Err(ShardedExecutionError::ShardUpdateFailed(_))
+    ));
+    assert_eq!(sharded_block_executor.num_shards(), 2);
+    test_utils::test_sharded_block_executor_no_conflict(sharded_block_executor);
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_sharded_block_executor_streams_round_outputs() {
+    let (executor_client, mut executor_services) =
+        create_thread_remote_executor_shards(&RemoteExecutorConfig::new(4).threads_per_shard(2));
+    test_utils::sharded_block_executor_streams_round_outputs(ShardedBlockExecutor::new(
+        executor_client,
+    ));
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_sharded_block_executor_chained_transfers() {
+    for num_shards in [2, 4, 8] {
+        test_utils::sharded_block_executor_with_workload(
+            LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(
+                num_shards,
+                Some(2),
+            ),
+            |executor| test_utils::generate_chained_workload(executor, 20, 10),
+        );
+    }
+}
+
+#[test]
+fn test_sharded_block_executor_fan_in_fan_out_transfers() {
+    for num_shards in [2, 4, 8] {
+        test_utils::sharded_block_executor_with_workload(
+            LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(
+                num_shards,
+                Some(2),
+            ),
+            |executor| test_utils::generate_fan_in_fan_out_workload(executor, 8, 25),
+        );
+    }
+}
+
+#[test]
+fn test_remote_sharded_block_executor_chained_transfers() {
+    let (executor_client, mut executor_services) =
+        create_thread_remote_executor_shards(&RemoteExecutorConfig::new(4).threads_per_shard(2));
+    test_utils::sharded_block_executor_with_workload(
+        ShardedBlockExecutor::new(executor_client),
+        |executor| test_utils::generate_chained_workload(executor, 20, 10),
+    );
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_sharded_block_executor_with_shared_secret() {
+    let secret_file = TempPath::new();
+    secret_file.create_as_file().unwrap();
+    std::fs::write(secret_file.path(), b"executor service secret").unwrap();
+    let config = RemoteExecutorConfig::new(2).security(SecurityConfig::SharedSecret {
+        secret_path: secret_file.path().to_path_buf(),
+    });
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(&config);
+    test_utils::sharded_block_executor_with_workload(
+        ShardedBlockExecutor::new(executor_client),
+        |executor| test_utils::generate_chained_workload(executor, 10, 5),
+    );
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_sharded_block_executor_hot_spot_transfers() {
+    for hot_ratio in [0.1, 0.5, 1.0] {
+        test_utils::sharded_block_executor_with_workload(
+            LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(4, Some(2)),
+            |executor| test_utils::generate_hot_spot_workload(executor, 200, hot_ratio),
+        );
+    }
+}
+
+#[test]
+fn test_sharded_block_executor_mixed_gas_transfers() {
+    test_utils::sharded_block_executor_with_workload(
+        LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(4, Some(2)),
+        |executor| test_utils::generate_mixed_gas_workload(executor, 200, 0.25),
+    );
+}
+
+// Relies on every test running in its own process for the metric, which is what nextest does.
+#[test]
+fn test_remote_executor_client_pipelines_blocks() {
+    let num_shards = 2;
+    let delay = Duration::from_secs(1);
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let partitioner = PartitionerV2Config::default().build();
+    let blocks: Vec<_> = (0..num_shards)
+        .map(|_| {
+            let transactions = (0..20)
+                .map(|_| test_utils::generate_non_conflicting_p2p(&mut executor).0)
+                .collect();
+            partitioner.partition(transactions, num_shards)
+        })
+        .collect();
+    // Shard `i` is slow on block `i` only, so that when the blocks overlap, each shard executes
+    // its slow block while the other shard executes its own.
+    let delayed_keys: HashSet<StateKey> = blocks
+        .iter()
+        .enumerate()
+        .map(|(shard_id, partitioned_txns)| {
+            let txn = partitioned_txns.sharded_txns()[shard_id]
+                .iter()
+                .next()
+                .unwrap()
+                .txn();
+            StateKey::resource_typed::<AccountResource>(&txn.sender().unwrap()).unwrap()
+        })
+        .collect();
+    let state_view = Arc::new(test_utils::DelayedStateView::new(
+        executor.data_store().clone(),
+        delayed_keys,
+        delay,
+    ));
+    let num_in_flight_blocks = || {
+        aptos_metrics_core::gather()
+            .into_iter()
+            .find(|family| family.get_name() == "sharded_executor_in_flight_blocks")
+            .unwrap()
+            .get_metric()[0]
+            .get_gauge()
+            .get_value() as usize
+    };
+
+    let mut execute_blocks = |pipeline_depth: usize| {
+        sharded_block_executor.set_pipeline_depth(pipeline_depth);
+        let started_at = Instant::now();
+        let mut outputs = vec![];
+        sharded_block_executor.execute_blocks(
+            blocks
+                .iter()
+                .map(|partitioned_txns| (state_view.clone(), partitioned_txns.clone())),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            |output| {
+                // The blocks after this one that are still in flight.
+                let num_next_blocks = blocks.len() - outputs.len() - 1;
+                assert_eq!(
+                    num_in_flight_blocks(),
+                    num_next_blocks.min(pipeline_depth - 1)
+                );
+                outputs.push(output.unwrap());
+            },
+        );
+        (outputs, started_at.elapsed())
+    };
+
+    let (serial_outputs, serial_time) = execute_blocks(1);
+    let (pipelined_outputs, pipelined_time) = execute_blocks(2);
+    assert!(serial_time >= delay * 2, "{:?}", serial_time);
+    assert!(
+        pipelined_time < serial_time - delay / 2,
+        "Pipelined execution took {:?}, serial execution {:?}",
+        pipelined_time,
+        serial_time
+    );
+    assert_eq!(pipelined_outputs.len(), serial_outputs.len());
+    for (pipelined_output, serial_output) in pipelined_outputs.into_iter().zip(serial_outputs) {
+        test_utils::compare_txn_outputs(serial_output, pipelined_output);
+    }
+
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_executor_output_is_independent_of_shard_timing() {
+    let num_shards = 4;
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    sharded_block_executor.set_verify_output_order(true);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 40, 400);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    // The account of the sender of the first txn of each shard and round, whose read delays the
+    // sub-block.
+    let first_senders: Vec<StateKey> = partitioned_txns
+        .sharded_txns()
+        .iter()
+        .flat_map(|sub_blocks| sub_blocks.sub_block_iter())
+        .filter_map(|sub_block| sub_block.iter().next())
+        .map(|txn| {
+            StateKey::resource_typed::<AccountResource>(&txn.txn().sender().unwrap()).unwrap()
+        })
+        .collect();
+
+    let mut rng = rand::thread_rng();
+    let mut outputs = vec![];
+    for _ in 0..5 {
+        let delays: HashMap<_, _> = first_senders
+            .iter()
+            .map(|state_key| {
+                let delay = Duration::from_millis(rng.gen_range(0, 50));
+                (state_key.clone(), delay)
+            })
+            .collect();
+        let state_view = Arc::new(test_utils::DelayedStateView::with_delays(
+            executor.data_store().clone(),
+            delays,
+        ));
+        let output = sharded_block_executor
+            .execute_block(
+                state_view,
+                partitioned_txns.clone(),
+                2,
+                BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            )
+            .unwrap();
+        outputs.push(bcs::to_bytes(&output).unwrap());
+    }
+    assert!(outputs.iter().all(|output| *output == outputs[0]));
+
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_executor_reexecutes_captured_sub_block() {
+    let num_shards = 2;
+    let capture_dir = TempPath::new();
+    capture_dir.create_as_dir().unwrap();
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards)
+            .threads_per_shard(2)
+            .capture_dir(capture_dir.path().to_path_buf()),
+    );
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    let block_output = test_utils::execute_and_compare(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+
+    let captures = SubBlockCapture::load_all(capture_dir.path()).unwrap();
+    let state_view = Arc::new(executor.data_store().clone());
+    // Shard 1 round 0, along with the other rounds of the shard, which read the writes of the
+    // previous rounds replayed from the capture.
+    let shard_captures: Vec<_> = captures
+        .into_iter()
+        .filter(|capture| capture.shard_id == 1)
+        .collect();
+    assert_eq!(shard_captures[0].round, 0);
+    for capture in shard_captures {
+        let start_index = capture.inputs.sub_block.start_index;
+        let num_txns = capture.inputs.sub_block.num_txns();
+        let output = sharded_block_executor
+            .executor_client()
+            .execute_sub_block(1, capture.round, capture.inputs, state_view.clone())
+            .unwrap();
+        assert_eq!(
+            bcs::to_bytes(&output.outputs).unwrap(),
+            bcs::to_bytes(&block_output[start_index..start_index + num_txns]).unwrap(),
+            "Round {}",
+            capture.round
+        );
+    }
+
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_executor_replays_recorded_shard() {
+    let num_shards = 2;
+    let recording_dir = TempPath::new();
+    recording_dir.create_as_dir().unwrap();
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards)
+            .threads_per_shard(2)
+            .recording_dir(recording_dir.path().to_path_buf()),
+    );
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut executor = FakeExecutor::from_head_genesis();
+    // Transfers between all the accounts, which conflict across the shards.
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    let sub_block_ranges: Vec<_> = partitioned_txns.sharded_txns()[1]
+        .sub_block_iter()
+        .map(|sub_block| sub_block.start_index..sub_block.start_index + sub_block.num_txns())
+        .collect();
+    let block_output = test_utils::execute_and_compare(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioned_txns,
+        2,
+    );
+    // The shards write their recording once they sent the coordinator all their results.
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+
+    let recordings = BlockRecording::load_all(recording_dir.path()).unwrap();
+    assert_eq!(
+        recordings
+            .iter()
+            .map(|recording| recording.participant)
+            .collect::<Vec<_>>(),
+        vec![
+            Participant::Coordinator,
+            Participant::Shard(0),
+            Participant::Shard(1)
+        ]
+    );
+    assert_eq!(
+        bcs::to_bytes(&recording::replay_coordinator(&recordings[0]).unwrap()).unwrap(),
+        bcs::to_bytes(&block_output).unwrap()
+    );
+    // Shard 1 read writes of shard 0, which are replayed from the recording.
+    let shard_recording = &recordings[2];
+    assert!(shard_recording.messages.iter().any(|message| {
+        message.direction == MessageDirection::Received
+            && message.channel.starts_with("cross_shard_")
+            && message.peer == Some(Participant::Shard(0))
+    }));
+    let replayed_outputs = recording::replay_shard(shard_recording, 2).unwrap();
+    assert_eq!(replayed_outputs.len(), sub_block_ranges.len());
+    for (round, (outputs, range)) in replayed_outputs.iter().zip(sub_block_ranges).enumerate() {
+        assert_eq!(
+            bcs::to_bytes(outputs).unwrap(),
+            bcs::to_bytes(&block_output[range]).unwrap(),
+            "Round {}",
+            round
+        );
+    }
+}
+
+#[test]
+fn test_remote_executor_warms_up_shards_before_first_block() {
+    let module_id = ModuleId::new(AccountAddress::ONE, Identifier::new("coin").unwrap());
+    // Read by no txn of the block, and absent from the state.
+    let state_key = StateKey::resource_typed::<AccountResource>(&AccountAddress::random()).unwrap();
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(2)
+            .threads_per_shard(2)
+            .state_cache_size(10_000)
+            .warm_up(
+                WarmUpConfig::new(vec![module_id.clone()], vec![state_key.clone()])
+                    .timeout_ms(10_000),
+            ),
+    );
+    test_utils::test_sharded_block_executor_no_conflict(ShardedBlockExecutor::new(executor_client));
+    // The block read the state view the shards warmed up with, so they kept the values.
+    for executor_service in executor_services.iter() {
+        assert!(matches!(
+            executor_service.cached_state_value(&StateKey::module_id(&module_id)),
+            Some(Some(_))
+        ));
+        assert_eq!(executor_service.cached_state_value(&state_key), Some(None));
+    }
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+#[test]
+fn test_remote_executor_sends_blocks_once_warm_up_times_out() {
+    let state_key = StateKey::resource_typed::<AccountResource>(&AccountAddress::random()).unwrap();
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(2)
+            .threads_per_shard(2)
+            .state_cache_size(10_000)
+            .warm_up(WarmUpConfig::new(vec![], vec![state_key.clone()]).timeout_ms(500)),
+    );
+    // Shard 0 never gets the warm-up, the first command it is sent, so it never answers it.
+    executor_client.wrap_command_tx(0, |tx| test_utils::drop_first_sent_messages(tx, 1));
+    test_utils::test_sharded_block_executor_no_conflict(ShardedBlockExecutor::new(executor_client));
+    assert_eq!(executor_services[0].cached_state_value(&state_key), None);
+    assert_eq!(
+        executor_services[1].cached_state_value(&state_key),
+        Some(None)
+    );
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+}
+
+// A span recorded by `SpanCapture`, with its fields formatted with `Debug`.
+#[derive(Clone, Debug)]
+struct CapturedSpan {
+    id: u64,
+    name: &'static str,
+    // The index of the parent span in the captured spans.
+    parent: Option<usize>,
+    fields: HashMap<String, String>,
+}
+
+impl tracing::field::Visit for CapturedSpan {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.fields
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+// Records all the spans, in the order they are created.
+#[derive(Clone, Default)]
+struct SpanCapture {
+    spans: Arc<Mutex<Vec<CapturedSpan>>>,
+    // The index of the spans not closed yet by id, as ids are reused once a span is closed.
+    open_spans: Arc<Mutex<HashMap<u64, usize>>>,
+}
+
+impl<S> tracing_subscriber::Layer<S> for SpanCapture
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut open_spans = self.open_spans.lock().unwrap();
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .and_then(|parent| open_spans.get(&parent.id().into_u64()).copied());
+        let mut span = CapturedSpan {
+            id: id.into_u64(),
+            name: attrs.metadata().name(),
+            parent,
+            fields: HashMap::new(),
+        };
+        attrs.record(&mut span);
+        let mut spans = self.spans.lock().unwrap();
+        open_spans.insert(id.into_u64(), spans.len());
+        spans.push(span);
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if let Some(&index) = self.open_spans.lock().unwrap().get(&id.into_u64()) {
+            values.record(&mut self.spans.lock().unwrap()[index]);
+        }
+    }
+
+    fn on_close(&self, id: tracing::span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        self.open_spans.lock().unwrap().remove(&id.into_u64());
+    }
+}
+
+#[test]
+fn test_remote_executor_traces_blocks_across_shards() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Global, as the spans of the shards are created on their own threads.
+    let span_capture = SpanCapture::default();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::Registry::default().with(span_capture.clone()),
+    )
+    .unwrap();
+
+    let num_shards = 2;
+    let (executor_client, mut executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards)
+            .threads_per_shard(2)
+            .trace_sampling_interval(1),
+    );
+    test_utils::test_sharded_block_executor_no_conflict(ShardedBlockExecutor::new(executor_client));
+    executor_services.iter_mut().for_each(|executor_service| {
+        executor_service.shutdown();
+    });
+
+    let spans = span_capture.spans.lock().unwrap().clone();
+    let children = |index: usize, name: &str| {
+        spans
+            .iter()
+            .filter(|span| span.parent == Some(index) && span.name == name)
+            .count()
+    };
+    let blocks: Vec<_> = spans
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| span.name == "remote_execute_block")
+        .collect();
+    assert!(!blocks.is_empty());
+    for (_, block) in blocks {
+        assert_eq!(block.fields["num_shards"], num_shards.to_string());
+        // The spans of the shards are tied to the one of the coordinator by their fields.
+        let shard_commands: Vec<_> = spans
+            .iter()
+            .enumerate()
+            .filter(|(_, span)| {
+                span.name == "shard_command"
+                    && span.fields["block_id"] == block.fields["block_id"]
+                    && span.fields["parent_span_id"] == block.id.to_string()
+            })
+            .collect();
+        let shard_ids: HashSet<_> = shard_commands
+            .iter()
+            .map(|(_, span)| span.fields["shard_id"].clone())
+            .collect();
+        assert_eq!(shard_ids.len(), num_shards);
+        for (index, _) in shard_commands {
+            assert_eq!(children(index, "fetch_state"), 1);
+            assert!(children(index, "execute_round") > 0);
+            assert!(children(index, "send_result") > children(index, "execute_round"));
+        }
+    }
+}
+
+#[test]
+fn test_remote_executor_shards_batch_cross_shard_messages() {
+    let num_shards = 4;
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    assert!(
+        partitioned_txns
+            .cross_shard_message_volume()
+            .total()
+            .num_messages
+            > 0
+    );
+
+    let cross_shard_count = |name: &str| -> u64 {
+        (0..num_shards)
+            .map(|shard_id| {
+                REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+                    .with_label_values(&[&shard_id.to_string(), name])
+                    .get()
+            })
+            .sum()
+    };
+    let execute = |config: RemoteExecutorConfig| {
+        let [messages, batches, raw_bytes, sent_bytes] =
+            ["messages", "batches", "raw_bytes", "sent_bytes"].map(cross_shard_count);
+        let (executor_client, mut executor_services) =
+            create_thread_remote_executor_shards(&config);
+        workload.execute_and_check(
+            &ShardedBlockExecutor::new(executor_client),
+            executor.data_store(),
+            partitioned_txns.clone(),
+            2,
+        );
+        executor_services.iter_mut().for_each(|executor_service| {
+            executor_service.shutdown();
+        });
+        (
+            cross_shard_count("messages") - messages,
+            cross_shard_count("batches") - batches,
+            cross_shard_count("raw_bytes") - raw_bytes,
+            cross_shard_count("sent_bytes") - sent_bytes,
+        )
+    };
+
+    let config = RemoteExecutorConfig::new(num_shards).threads_per_shard(2);
+    let (unbatched_messages, unbatched_batches, unbatched_raw_bytes, unbatched_sent_bytes) =
+        execute(config.clone());
+    assert!(unbatched_messages > 0);
+    assert_eq!(unbatched_batches, unbatched_messages);
+    assert_eq!(unbatched_sent_bytes, unbatched_raw_bytes);
+
+    let (batched_messages, batched_batches, batched_raw_bytes, batched_sent_bytes) = execute(
+        config
+            .cross_shard_batch_size(64)
+            .cross_shard_batch_flush_after_us(1_000_000)
+            .cross_shard_compression_threshold(256),
+    );
+    assert_eq!(batched_messages, unbatched_messages);
+    assert!(
+        batched_batches < unbatched_batches,
+        "{} batches batched, {} unbatched",
+        batched_batches,
+        unbatched_batches
+    );
+    assert!(
+        batched_sent_bytes < batched_raw_bytes,
+        "{} bytes sent for {} raw bytes",
+        batched_sent_bytes,
+        batched_raw_bytes
+    );
+}
+
+#[test]
+fn test_remote_cross_shard_client_flushes_pending_messages_after_window() {
+    let num_shards = 2;
+    let flush_after = Duration::from_millis(200);
+    let config = RemoteExecutorConfig::new(num_shards)
+        .cross_shard_batch_size(64)
+        .cross_shard_batch_flush_after_us(flush_after.as_micros() as u64);
+    let addresses = get_available_addresses(num_shards);
+    let mut controllers = vec![];
+    let clients: Vec<_> = (0..num_shards)
+        .map(|shard_id| {
+            let mut controller = config
+                .network_controller(
+                    format!("cross-shard-client-{}", shard_id),
+                    addresses[shard_id],
+                )
+                .unwrap();
+            let status = Arc::new(ShardStatusTracker::default());
+            status.start_block(0);
+            let client = RemoteCrossShardClient::new(
+                shard_id,
+                &config,
+                &mut controller,
+                addresses.clone(),
+                Arc::new(RwLock::new(NegotiatedProtocol::default())),
+                None,
+                None,
+                status,
+            );
+            controller.start().unwrap();
+            controllers.push(controller);
+            client
+        })
+        .collect();
+
+    // A single message, with no other message after it and the round not over, still goes out
+    // once it waited for the window.
+    let sent_at = Instant::now();
+    clients[0].send_cross_shard_msg(
+        1,
+        0,
+        CrossShardMsg::RemoteTxnWriteMsg(RemoteTxnWrite::new(StateKey::raw(b"key"), None)),
+    );
+    let (received_tx, received_rx) = mpsc::channel();
+    let receiver = clients[1].clone();
+    thread::spawn(move || {
+        received_tx
+            .send(receiver.receive_cross_shard_msg(0))
+            .unwrap();
+    });
+    match received_rx.recv_timeout(Duration::from_secs(10)) {
+        Ok(CrossShardMsg::RemoteTxnWriteMsg(write)) => {
+            assert_eq!(write.take(), (StateKey::raw(b"key"), None));
+        },
+        result => panic!("Expected the pending message, got {:?}", result),
+    }
+    assert!(sent_at.elapsed() >= flush_after);
+
+    for mut controller in controllers {
+        controller.shutdown();
+    }
+}
+
+#[test]
+fn test_mock_sharded_block_executor_reports_shard_failure() {
+    let num_shards = 4;
+    let (executor_client, mock_shards) = create_mock_executor_shards(num_shards, vec![
+        mock_executor_shard::failing_shard_scripts(num_shards, 2, 1),
+    ]);
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    let partitioned_txns = mock_executor_shard::mock_partitioned_txns(num_shards, 2, 5);
+    let execute_block = || {
+        sharded_block_executor.execute_block(
+            Arc::new(FakeDataStore::default()),
+            partitioned_txns.clone(),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+    };
+
+    match execute_block() {
+        Err(ShardedExecutionError::ShardFailure { shard_id, reason }) => {
+            assert_eq!(shard_id, 2);
+            assert!(reason.contains("Injected failure"), "{}", reason);
+        },
+        result => panic!("Expected a shard failure, got {:?}", result),
+    }
+    // The failure does not leak into the next block.
+    assert_mock_outputs_in_order(&execute_block().unwrap(), num_shards * 2 * 5);
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_remote_executor_client_gives_up_on_unavailable_shard() {
+    let num_shards = 2;
+    let (mut executor_client, mock_shards) = create_mock_executor_shards(num_shards, vec![vec![
+        MockBlockScript::default(),
+        MockBlockScript::default().unresponsive(),
+    ]]);
+    executor_client.set_command_retry_policy(CommandRetryPolicy {
+        timeout: Duration::from_millis(100),
+        max_retries: 2,
+    });
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let result = sharded_block_executor.execute_block(
+        Arc::new(FakeDataStore::default()),
+        mock_executor_shard::mock_partitioned_txns(num_shards, 1, 10),
+        2,
+        BlockExecutorConfigFromOnchain::new_no_block_limit(),
+    );
+    assert_eq!(
+        result,
+        Err(ShardedExecutionError::ShardUnavailable {
+            shard_id: 1,
+            num_attempts: 3,
+        })
+    );
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_sharded_block_executor_streams_out_of_order_rounds() {
+    let num_shards = 4;
+    let num_rounds = 3;
+    let (executor_client, mock_shards) = create_mock_executor_shards(num_shards, vec![
+        mock_executor_shard::out_of_order_rounds_scripts(num_shards, Duration::from_millis(20)),
+    ]);
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let mut streamed = vec![];
+    let outputs = sharded_block_executor
+        .execute_block_streaming(
+            Arc::new(FakeDataStore::default()),
+            mock_executor_shard::mock_partitioned_txns(num_shards, num_rounds, 3),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            |shard_id, round, outputs| {
+                streamed.push((round, shard_id));
+                assert_eq!(outputs.len(), 3);
+            },
+        )
+        .unwrap();
+    // The rounds are passed in order even though the last shards are done with them first.
+    let expected: Vec<_> = (0..num_rounds)
+        .flat_map(|round| (0..num_shards).map(move |shard_id| (round, shard_id)))
+        .collect();
+    assert_eq!(streamed, expected);
+    assert_mock_outputs_in_order(&outputs, num_shards * num_rounds * 3);
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_remote_executor_client_pipelines_blocks() {
+    let num_shards = 2;
+    let delay = Duration::from_millis(200);
+    // Shard `i` is slow on block `i` only.
+    let scripts = (0..num_shards)
+        .map(|shard_id| mock_executor_shard::slow_shard_scripts(num_shards, shard_id, 1, delay))
+        .collect::<Vec<_>>();
+    let blocks: Vec<_> = (0..num_shards)
+        .map(|_| mock_executor_shard::mock_partitioned_txns(num_shards, 1, 5))
+        .collect();
+
+    let execute_blocks = |pipeline_depth: usize| {
+        let (executor_client, mock_shards) =
+            create_mock_executor_shards(num_shards, scripts.clone());
+        let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+        sharded_block_executor.set_pipeline_depth(pipeline_depth);
+        let started_at = Instant::now();
+        let mut num_outputs = 0;
+        sharded_block_executor.execute_blocks(
+            blocks.iter().map(|partitioned_txns| {
+                (Arc::new(FakeDataStore::default()), partitioned_txns.clone())
+            }),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+            |output| {
+                assert_mock_outputs_in_order(&output.unwrap(), num_shards * 5);
+                num_outputs += 1;
+            },
+        );
+        let elapsed = started_at.elapsed();
+        assert_eq!(num_outputs, blocks.len());
+        for mock_shard in mock_shards {
+            assert!(mock_shard.close());
+        }
+        elapsed
+    };
+
+    let serial_time = execute_blocks(1);
+    let pipelined_time = execute_blocks(2);
+    assert!(serial_time >= delay * 2, "{:?}", serial_time);
+    assert!(
+        pipelined_time < serial_time - delay / 2,
+        "Pipelined execution took {:?}, serial execution {:?}",
+        pipelined_time,
+        serial_time
+    );
+}
+
+#[test]
+fn test_mock_remote_executor_client_reports_partition_mismatch_in_pipeline() {
+    let num_shards = 2;
+    let scripts = vec![vec![MockBlockScript::default(); num_shards]; 2];
+    let (executor_client, mock_shards) = create_mock_executor_shards(num_shards, scripts);
+    let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    sharded_block_executor.set_pipeline_depth(2);
+
+    // The block in the middle is partitioned for one shard more than there are.
+    let blocks = [num_shards, num_shards + 1, num_shards]
+        .map(|num_partitions| mock_executor_shard::mock_partitioned_txns(num_partitions, 1, 5));
+    let mut outputs = vec![];
+    sharded_block_executor.execute_blocks(
+        blocks
+            .into_iter()
+            .map(|partitioned_txns| (Arc::new(FakeDataStore::default()), partitioned_txns)),
+        2,
+        BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        |output| outputs.push(output),
+    );
+    assert_eq!(outputs.len(), 3);
+    assert_mock_outputs_in_order(outputs[0].as_ref().unwrap(), num_shards * 5);
+    assert_eq!(
+        outputs[1].as_ref().unwrap_err(),
+        &ShardedExecutionError::PartitionMismatch {
+            num_shards,
+            num_partitions: num_shards + 1,
+        }
+    );
+    assert_mock_outputs_in_order(outputs[2].as_ref().unwrap(), num_shards * 5);
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_remote_executor_client_bounds_commands_queued_on_slow_shard() {
+    let num_shards = 2;
+    let num_blocks = 6;
+    let max_queued_commands = 2;
+    let delay = Duration::from_millis(100);
+    // Shard 1 is slow on every block, so the coordinator runs ahead of it.
+    let scripts = (0..num_blocks)
+        .map(|_| mock_executor_shard::slow_shard_scripts(num_shards, 1, 1, delay))
+        .collect();
+    let (mut executor_client, mock_shards) = create_mock_executor_shards(num_shards, scripts);
+    executor_client.set_max_queued_commands_per_shard(max_queued_commands);
+    let mut sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    sharded_block_executor.set_pipeline_depth(num_blocks);
+
+    // Relies on every test running in its own process for the metric, which is what nextest does.
+    let queue_depth = |shard_id: usize| {
+        REMOTE_EXECUTOR_SHARD_QUEUE_DEPTH
+            .with_label_values(&[&shard_id.to_string()])
+            .get()
+    };
+    let blocked_on_shard = |shard_id: usize| {
+        let histogram =
+            REMOTE_EXECUTOR_BLOCKED_ON_SHARD_SECONDS.with_label_values(&[&shard_id.to_string()]);
+        (histogram.get_sample_count(), histogram.get_sample_sum())
+    };
+    let (blocked_count, blocked_secs) = blocked_on_shard(1);
+    let mut max_queue_depth = 0;
+    let mut num_outputs = 0;
+    sharded_block_executor.execute_blocks(
+        (0..num_blocks).map(|_| {
+            (
+                Arc::new(FakeDataStore::default()),
+                mock_executor_shard::mock_partitioned_txns(num_shards, 1, 5),
+            )
+        }),
+        2,
+        BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        |output| {
+            assert_mock_outputs_in_order(&output.unwrap(), num_shards * 5);
+            max_queue_depth = max_queue_depth.max(queue_depth(1));
+            num_outputs += 1;
+        },
+    );
+    assert_eq!(num_outputs, num_blocks);
+    // The slow shard was sent as many blocks as it can queue, and the next ones had to wait.
+    assert_eq!(max_queue_depth, max_queued_commands as i64);
+    assert_eq!(queue_depth(1), 0);
+    let (new_blocked_count, new_blocked_secs) = blocked_on_shard(1);
+    assert_eq!(new_blocked_count - blocked_count, num_blocks as u64);
+    assert!(
+        new_blocked_secs - blocked_secs >= delay.as_secs_f64(),
+        "Blocked on the slow shard for {}s",
+        new_blocked_secs - blocked_secs
+    );
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_remote_executor_client_waits_for_shard_started_late() {
+    let num_shards = 2;
+    let late_start = Duration::from_millis(500);
+    let network_timeout_ms = RemoteExecutorConfig::new(num_shards).network_timeout_ms;
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(num_shards);
+    let controller = NetworkController::new(
+        "remote-executor-coordinator".to_string(),
+        coordinator_address,
+        network_timeout_ms,
+    );
+    let mut executor_client =
+        RemoteExecutorClient::new(remote_shard_addresses.clone(), controller, None).unwrap();
+    executor_client.set_health_check_policy(HealthCheckPolicy {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_secs(1),
+        readiness_timeout: Duration::from_secs(10),
+    });
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    let mut mock_shards = vec![MockExecutorShard::new(
+        0,
+        vec![],
+        coordinator_address,
+        &remote_shard_addresses,
+        network_timeout_ms,
+    )];
+    let late_shard = {
+        let remote_shard_addresses = remote_shard_addresses.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(late_start);
+            MockExecutorShard::new(
+                1,
+                vec![],
+                coordinator_address,
+                &remote_shard_addresses,
+                network_timeout_ms,
+            )
+        })
+    };
+    assert!(!sharded_block_executor.executor_client().all_shards_ready());
+
+    // Dispatched once the late shard is up, without waiting for the shards to register.
+    let started_at = Instant::now();
+    let output = sharded_block_executor
+        .execute_block(
+            Arc::new(FakeDataStore::default()),
+            mock_executor_shard::mock_partitioned_txns(num_shards, 1, 5),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+        .unwrap();
+    assert_mock_outputs_in_order(&output, num_shards * 5);
+    assert!(
+        started_at.elapsed() >= late_start / 2,
+        "{:?}",
+        started_at.elapsed()
+    );
+    assert!(sharded_block_executor.executor_client().all_shards_ready());
+    mock_shards.push(late_shard.join().unwrap());
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+#[test]
+fn test_mock_remote_executor_client_fails_block_of_shard_that_stops_responding() {
+    let num_shards = 2;
+    // Shard 1 hangs on the second block.
+    let mut hanging_scripts = vec![MockBlockScript::default(); num_shards];
+    hanging_scripts[1] = MockBlockScript::default().hang();
+    let scripts = vec![
+        vec![MockBlockScript::default(); num_shards],
+        hanging_scripts,
+    ];
+    let (mut executor_client, mock_shards) = create_mock_executor_shards(num_shards, scripts);
+    executor_client.set_health_check_policy(HealthCheckPolicy {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(300),
+        readiness_timeout: Duration::from_secs(10),
+    });
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    let execute_block = || {
+        sharded_block_executor.execute_block(
+            Arc::new(FakeDataStore::default()),
+            mock_executor_shard::mock_partitioned_txns(num_shards, 1, 5),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+    };
+
+    assert_mock_outputs_in_order(&execute_block().unwrap(), num_shards * 5);
+    assert!(sharded_block_executor.executor_client().all_shards_ready());
+
+    let started_at = Instant::now();
+    assert_eq!(
+        execute_block(),
+        Err(ShardedExecutionError::ShardUnavailable {
+            shard_id: 1,
+            num_attempts: 1,
+        })
+    );
+    // Long before the command would have been re-sent.
+    assert!(started_at.elapsed() < CommandRetryPolicy::default().timeout);
+    let executor_client = sharded_block_executor.executor_client();
+    assert!(!executor_client.all_shards_ready());
+    assert_eq!(executor_client.shard_status(1), None);
+    assert!(executor_client.shard_status(0).is_some());
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
+
+// Thread shards where shard `i` supports `protocol_supports[i]` of the protocol, with a coordinator
+// that waited for them to register.
+fn create_thread_remote_executor_shards_supporting(
+    config: &RemoteExecutorConfig,
+    protocol_supports: Vec<ProtocolSupport>,
+) -> (
+    RemoteExecutorClient<FakeDataStore>,
+    Vec<ThreadExecutorService>,
+    Result<(), Error>,
+) {
+    let coordinator_address = get_available_addresses(1)[0];
+    let remote_shard_addresses = get_available_addresses(config.num_shards);
+    let controller = NetworkController::new(
+        "remote-executor-coordinator".to_string(),
+        coordinator_address,
+        config.network_timeout_ms,
+    );
+    let executor_client =
+        RemoteExecutorClient::new(remote_shard_addresses.clone(), controller, None).unwrap();
+    let executor_services = protocol_supports
+        .into_iter()
+        .enumerate()
+        .map(|(shard_id, protocol_support)| {
+            ThreadExecutorService::with_protocol_support(
+                shard_id,
+                config,
+                coordinator_address,
+                remote_shard_addresses.clone(),
+                protocol_support,
+            )
+            .unwrap()
+        })
+        .collect();
+    let registration = executor_client.wait_for_shards(Duration::from_secs(10));
+    (executor_client, executor_services, registration)
+}
+
+#[test]
+fn test_remote_executor_client_degrades_features_for_older_shard() {
+    let num_shards = 4;
+    let config = RemoteExecutorConfig::new(num_shards)
+        .threads_per_shard(2)
+        .cross_shard_batch_size(64)
+        .cross_shard_batch_flush_after_us(1_000_000)
+        .cross_shard_compression_threshold(256);
+    // Shard 2 is older, without any of the optional features.
+    let mut protocol_supports = vec![ProtocolSupport::current(); num_shards];
+    protocol_supports[2].features = ProtocolFeatures::empty();
+    let (executor_client, executor_services, registration) =
+        create_thread_remote_executor_shards_supporting(&config, protocol_supports);
+    registration.unwrap();
+    assert_eq!(executor_client.protocol(), NegotiatedProtocol {
+        version: PROTOCOL_VERSION,
+        features: ProtocolFeatures::empty(),
+    });
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    let cross_shard_count = |name: &str| -> u64 {
+        (0..num_shards)
+            .map(|shard_id| {
+                REMOTE_EXECUTOR_CROSS_SHARD_COUNT
+                    .with_label_values(&[&shard_id.to_string(), name])
+                    .get()
+            })
+            .sum()
+    };
+    let [messages, batches, raw_bytes, sent_bytes] =
+        ["messages", "batches", "raw_bytes", "sent_bytes"].map(cross_shard_count);
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 80, 800);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    workload.execute_and_check(
+        &sharded_block_executor,
+        executor.data_store(),
+        partitioner.partition(workload.transactions.clone(), num_shards),
+        2,
+    );
+    // The shards neither batch nor compress their cross-shard messages.
+    assert!(cross_shard_count("messages") > messages);
+    assert_eq!(
+        cross_shard_count("batches") - batches,
+        cross_shard_count("messages") - messages
+    );
+    assert_eq!(
+        cross_shard_count("sent_bytes") - sent_bytes,
+        cross_shard_count("raw_bytes") - raw_bytes
+    );
+    // The round outputs come at the end of the block, but still in order.
+    test_utils::sharded_block_executor_streams_round_outputs(sharded_block_executor);
+
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_remote_executor_client_refuses_incompatible_shard() {
+    let num_shards = 2;
+    // Shard 1 is newer, and dropped the support of the current version.
+    let mut protocol_supports = vec![ProtocolSupport::current(); num_shards];
+    protocol_supports[1].min_version = PROTOCOL_VERSION + 1;
+    protocol_supports[1].max_version = PROTOCOL_VERSION + 1;
+    let (_executor_client, executor_services, registration) =
+        create_thread_remote_executor_shards_supporting(
+            &RemoteExecutorConfig::new(num_shards).threads_per_shard(1),
+            protocol_supports,
+        );
+    match registration {
+        Err(Error::IncompatibleShard { shard_id, reason }) => {
+            assert_eq!(shard_id, 1);
+            assert!(reason.contains("protocol versions"), "{}", reason);
+        },
+        result => panic!("Expected an incompatible shard, got {:?}", result),
+    }
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+// Registers with the coordinator as the shards with the ids given, without running them.
+fn register_fake_shards(coordinator_address: SocketAddr, shard_ids: &[usize]) -> NetworkController {
+    let mut controller = NetworkController::new(
+        "fake-executor-shard".to_string(),
+        get_available_addresses(1)[0],
+        RemoteExecutorConfig::new(1).network_timeout_ms,
+    );
+    let registration_tx =
+        controller.create_outbound_channel(coordinator_address, "shard_registration".to_string());
+    controller.start().unwrap();
+    for shard_id in shard_ids {
+        let registration = ShardRegistration::new(*shard_id, ProtocolSupport::current());
+        registration_tx
+            .send(protocol::encode_handshake(&registration))
+            .unwrap();
+    }
+    controller
+}
+
+#[test]
+fn test_remote_executor_client_refuses_unknown_or_duplicate_shard_id() {
+    let num_shards = 2;
+    for (shard_ids, refused_shard_id, refusal) in [
+        (vec![0, 5], 5, "only has 2 shards"),
+        (vec![0, 0], 0, "already registered"),
+    ] {
+        let coordinator_address = get_available_addresses(1)[0];
+        let controller = NetworkController::new(
+            "remote-executor-coordinator".to_string(),
+            coordinator_address,
+            RemoteExecutorConfig::new(num_shards).network_timeout_ms,
+        );
+        let executor_client = RemoteExecutorClient::<FakeDataStore>::new(
+            get_available_addresses(num_shards),
+            controller,
+            None,
+        )
+        .unwrap();
+        let mut fake_shards = register_fake_shards(coordinator_address, &shard_ids);
+        match executor_client.wait_for_shards(Duration::from_secs(10)) {
+            Err(Error::IncompatibleShard { shard_id, reason }) => {
+                assert_eq!(shard_id, refused_shard_id);
+                assert!(reason.contains(refusal), "{}", reason);
+            },
+            result => panic!("Expected the shard to be refused, got {:?}", result),
+        }
+        fake_shards.shutdown();
+    }
+}
+
+#[test]
+fn test_remote_executor_shards_keep_state_values_across_blocks() {
+    let num_shards = 2;
+    let num_accounts = 40;
+    let num_blocks = 3;
+    let config = RemoteExecutorConfig::new(num_shards)
+        .threads_per_shard(2)
+        .state_cache_size(100_000);
+    let (mut executor_client, executor_services) = create_thread_remote_executor_shards(&config);
+    executor_client.set_chained_state_views(true);
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+
+    // Relies on every test running in its own process for the metric, which is what nextest does.
+    let kv_count = |name: &str| -> u64 {
+        (0..num_shards)
+            .map(|shard_id| {
+                REMOTE_EXECUTOR_REMOTE_KV_COUNT
+                    .with_label_values(&[&shard_id.to_string(), name])
+                    .get()
+            })
+            .sum()
+    };
+    let mut executor = FakeExecutor::from_head_genesis();
+    let mut accounts: Vec<_> = (0..num_accounts)
+        .map(|_| test_utils::generate_account_at(&mut executor, AccountAddress::random()))
+        .collect();
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(2)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let mut num_fetched_per_block = vec![];
+    for block in 0..num_blocks {
+        // The accounts send to each other in every block, which overwrites the balances and
+        // sequence numbers the previous block read.
+        let transactions = (0..num_accounts)
+            .map(|index| {
+                let receiver = accounts[(index + block + 1) % num_accounts].clone();
+                test_utils::generate_p2p_txn(&mut accounts[index], &receiver, 1_000)
+            })
+            .collect();
+        let partitioned_txns = partitioner.partition(transactions, num_shards);
+        let num_fetched = kv_count("prefetch_kv") + kv_count("non_prefetch_kv");
+        let num_cached = kv_count("cached_kv");
+        // The shards check the values they kept against the ones of the coordinator in debug
+        // builds, on top of the outputs being compared.
+        test_utils::execute_and_compare(
+            &sharded_block_executor,
+            executor.data_store(),
+            partitioned_txns.clone(),
+            2,
+        );
+        num_fetched_per_block
+            .push(kv_count("prefetch_kv") + kv_count("non_prefetch_kv") - num_fetched);
+        if block > 0 {
+            assert!(kv_count("cached_kv") > num_cached);
+        }
+        // The state view of the next block is the one of this block with its writes.
+        test_utils::apply_partitioned_txns(&mut executor, partitioned_txns);
+    }
+    assert!(
+        num_fetched_per_block[num_blocks - 1] < num_fetched_per_block[0],
+        "State values fetched per block: {:?}",
+        num_fetched_per_block
+    );
+
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+// Execute a block with `execute_block_async()`, then the same block again dropping its future at
+// different points of the execution, the shards executing the next block as usual all the same.
+fn sharded_block_executor_async<E: ExecutorClient<FakeDataStore>>(
+    mut sharded_block_executor: ShardedBlockExecutor<FakeDataStore, E>,
+) {
+    let num_shards = sharded_block_executor.num_shards();
+    let mut executor = FakeExecutor::from_head_genesis();
+    let workload = test_utils::generate_all_to_all_workload(&mut executor, 40, 400);
+    let partitioner = PartitionerV2Config::default()
+        .max_partitioning_rounds(4)
+        .cross_shard_dep_avoid_threshold(0.9)
+        .partition_last_round(true)
+        .build();
+    let partitioned_txns = partitioner.partition(workload.transactions.clone(), num_shards);
+    let execute_block = |sharded_block_executor: &ShardedBlockExecutor<FakeDataStore, E>| {
+        sharded_block_executor.execute_block_async(
+            Arc::new(executor.data_store().clone()),
+            partitioned_txns.clone(),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+    };
+
+    let execution_ordered_txns: Vec<SignatureVerifiedTransaction> =
+        PartitionedTransactions::flatten(partitioned_txns.clone())
+            .into_iter()
+            .map(|t| t.into_txn())
+            .collect();
+    let outputs = block_on(execute_block(&sharded_block_executor)).unwrap();
+    workload.assert_balances(executor.data_store(), &execution_ordered_txns, &outputs);
+
+    for abort_after in [
+        Duration::ZERO,
+        Duration::from_millis(5),
+        Duration::from_millis(50),
+    ] {
+        let mut aborted_block = Box::pin(execute_block(&sharded_block_executor));
+        // The first poll starts the execution, which can hardly be done already.
+        let _ = aborted_block.as_mut().now_or_never();
+        thread::sleep(abort_after);
+        drop(aborted_block);
+        // Nothing of the aborted block was committed, so the same txns are valid again.
+        workload.execute_and_check(
+            &sharded_block_executor,
+            executor.data_store(),
+            partitioned_txns.clone(),
+            2,
+        );
+    }
+    sharded_block_executor.shutdown();
+}
+
+#[test]
+fn test_local_sharded_block_executor_async() {
+    let sharded_block_executor =
+        LocalExecutorClient::<FakeDataStore>::create_local_sharded_block_executor(4, Some(2));
+    sharded_block_executor_async(sharded_block_executor);
+}
+
+#[test]
+fn test_sharded_block_executor_async() {
+    let num_shards = 4;
+    let (executor_client, executor_services) = create_thread_remote_executor_shards(
+        &RemoteExecutorConfig::new(num_shards).threads_per_shard(2),
+    );
+    executor_client
+        .wait_for_shards(Duration::from_secs(10))
+        .unwrap();
+    sharded_block_executor_async(ShardedBlockExecutor::new(executor_client));
+    for executor_service in executor_services {
+        assert!(executor_service.close());
+    }
+}
+
+#[test]
+fn test_mock_sharded_block_executor_aborts_dropped_block() {
+    let num_shards = 2;
+    let num_rounds = 10;
+    let delay = Duration::from_millis(100);
+    let (executor_client, mock_shards) = create_mock_executor_shards(num_shards, vec![
+        vec![MockBlockScript::default().slow(num_rounds, delay); num_shards],
+        vec![MockBlockScript::default(); num_shards],
+    ]);
+    let sharded_block_executor = ShardedBlockExecutor::new(executor_client);
+    let execute_block = |num_rounds| {
+        sharded_block_executor.execute_block_async(
+            Arc::new(FakeDataStore::default()),
+            mock_executor_shard::mock_partitioned_txns(num_shards, num_rounds, 5),
+            2,
+            BlockExecutorConfigFromOnchain::new_no_block_limit(),
+        )
+    };
+
+    let mut aborted_block = Box::pin(execute_block(num_rounds));
+    assert!(aborted_block.as_mut().now_or_never().is_none());
+    // In the middle of the third round.
+    thread::sleep(delay * 5 / 2);
+    let aborted_at = Instant::now();
+    drop(aborted_block);
+
+    // The shards stop at the end of the round they are in, rather than executing all the rounds
+    // of the aborted block before the next one.
+    let outputs = block_on(execute_block(2)).unwrap();
+    let elapsed = aborted_at.elapsed();
+    assert!(elapsed < delay * num_rounds as u32 / 2, "{:?}", elapsed);
+    assert_mock_outputs_in_order(&outputs, num_shards * 2 * 5);
+    // Relies on every test running in its own process for the metric, which is what nextest does.
+    for shard_id in 0..num_shards {
+        assert_eq!(
+            REMOTE_EXECUTOR_COMMAND_COUNT
+                .with_label_values(&[&shard_id.to_string(), "aborts"])
+                .get(),
+            1
+        );
+    }
+    for mock_shard in mock_shards {
+        assert!(mock_shard.close());
+    }
+}
@@ -1,11 +1,17 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
-use crate::remote_executor_service::ExecutorService;
-use aptos_types::block_executor::partitioner::ShardId;
+use crate::{
+    config::RemoteExecutorConfig, error::Error, protocol::ProtocolSupport,
+    remote_executor_service::ExecutorService,
+};
+use aptos_types::{
+    block_executor::partitioner::ShardId,
+    state_store::{state_key::StateKey, state_value::StateValue},
+};
 use std::net::SocketAddr;
 
 /// This is a simple implementation of RemoteExecutorService that runs the executor service in a
-/// separate thread. This should be used for testing only.
+/// separate thread. This should be used for testing and benchmarking only.
 pub struct ThreadExecutorService {
     _self_address: SocketAddr,
     executor_service: ExecutorService,
@@ -14,28 +20,66 @@ pub struct ThreadExecutorService {
 impl ThreadExecutorService {
     pub fn new(
         shard_id: ShardId,
-        num_shards: usize,
-        num_threads: usize,
+        config: &RemoteExecutorConfig,
         coordinator_address: SocketAddr,
         remote_shard_addresses: Vec<SocketAddr>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
+        Self::with_protocol_support(
+            shard_id,
+            config,
+            coordinator_address,
+            remote_shard_addresses,
+            ProtocolSupport::current(),
+        )
+    }
+
+    pub fn with_protocol_support(
+        shard_id: ShardId,
+        config: &RemoteExecutorConfig,
+        coordinator_address: SocketAddr,
+        remote_shard_addresses: Vec<SocketAddr>,
+        protocol_support: ProtocolSupport,
+    ) -> Result<Self, Error> {
         let self_address = remote_shard_addresses[shard_id];
-        let mut executor_service = ExecutorService::new(
+        let mut executor_service = ExecutorService::with_protocol_support(
             shard_id,
-            num_shards,
-            num_threads,
+            config,
             self_address,
             coordinator_address,
             remote_shard_addresses,
-        );
-        executor_service.start();
-        Self {
+            protocol_support,
+        )?;
+        executor_service.start()?;
+        Ok(Self {
             _self_address: self_address,
             executor_service,
-        }
+        })
+    }
+
+    /// The value the shard keeps of the key across blocks, if any.
+    #[cfg(test)]
+    pub fn cached_state_value(&self, state_key: &StateKey) -> Option<Option<StateValue>> {
+        self.executor_service.cached_state_value(state_key)
     }
 
-    pub fn shutdown(&mut self) {
+    /// Replace the channels the shard sends cross-shard messages on with `wrap(channel)`.
+    #[cfg(test)]
+    pub(crate) fn wrap_cross_shard_txs(
+        &self,
+        wrap: impl Fn(
+            crossbeam_channel::Sender<aptos_secure_net::network_controller::Message>,
+        )
+            -> crossbeam_channel::Sender<aptos_secure_net::network_controller::Message>,
+    ) {
+        self.executor_service.wrap_cross_shard_txs(wrap);
+    }
+
+    pub fn shutdown(&mut self) -> bool {
         self.executor_service.shutdown()
     }
+
+    /// Shutdown the shard and release it. Returns false if the shard did not stop cleanly.
+    pub fn close(mut self) -> bool {
+        self.shutdown()
+    }
 }
@@ -1,8 +1,13 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::network_controller::{metrics::NETWORK_HANDLER_TIMER, Message, MessageType};
-use aptos_logger::{error, info};
+use crate::network_controller::{
+    error::Error,
+    metrics::{NETWORK_HANDLER_TIMER, NETWORK_REJECTED_MESSAGES},
+    security::{NetworkSecurity, MESSAGE_TAG_METADATA_KEY},
+    Message, MessageType,
+};
+use aptos_logger::{error, info, warn};
 use aptos_protos::remote_executor::v1::{
     network_message_service_client::NetworkMessageServiceClient,
     network_message_service_server::{NetworkMessageService, NetworkMessageServiceServer},
@@ -13,31 +18,43 @@ use std::{
     collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{runtime::Runtime, sync::oneshot};
 use tonic::{
-    transport::{Channel, Server},
-    Request, Response, Status,
+    metadata::MetadataValue,
+    transport::{Certificate, Channel, ClientTlsConfig, Identity, Server, ServerTlsConfig},
+    Code, Request, Response, Status,
 };
 
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 80;
 
+/// How long the first message to a remote node is retried while the node is unreachable, e.g.
+/// because its server has not started yet.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
 pub struct GRPCNetworkMessageServiceServerWrapper {
     inbound_handlers: Arc<Mutex<HashMap<MessageType, Sender<Message>>>>,
     self_addr: SocketAddr,
+    security: NetworkSecurity,
 }
 
 impl GRPCNetworkMessageServiceServerWrapper {
     pub fn new(
         inbound_handlers: Arc<Mutex<HashMap<MessageType, Sender<Message>>>>,
         self_addr: SocketAddr,
+        security: NetworkSecurity,
     ) -> Self {
         Self {
             inbound_handlers,
             self_addr,
+            security,
         }
     }
 
+    /// Fails if the TLS config is invalid, which is checked before the server is spawned.
     // Note: The object is consumed here. That is once the server is started, we cannot/should not
     //       use the object anymore
     pub fn start(
@@ -47,15 +64,30 @@ impl GRPCNetworkMessageServiceServerWrapper {
         server_addr: SocketAddr,
         rpc_timeout_ms: u64,
         server_shutdown_rx: oneshot::Receiver<()>,
-    ) {
+    ) -> Result<(), Error> {
+        let mut server = Server::builder();
+        if let NetworkSecurity::Tls(tls_config) = &self.security {
+            tls_config.check()?;
+            // The clients without a certificate signed by the CA are rejected by the TLS
+            // handshake, before any of their messages is read.
+            server = server
+                .tls_config(
+                    ServerTlsConfig::new()
+                        .identity(Identity::from_pem(&tls_config.cert, &tls_config.key))
+                        .client_ca_root(Certificate::from_pem(&tls_config.ca_cert)),
+                )
+                .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?;
+        }
         rt.spawn(async move {
-            self.start_async(server_addr, rpc_timeout_ms, server_shutdown_rx)
+            self.start_async(server, server_addr, rpc_timeout_ms, server_shutdown_rx)
                 .await;
         });
+        Ok(())
     }
 
     async fn start_async(
         self,
+        mut server: Server,
         server_addr: SocketAddr,
         rpc_timeout_ms: u64,
         server_shutdown_rx: oneshot::Receiver<()>,
@@ -72,7 +104,7 @@ impl GRPCNetworkMessageServiceServerWrapper {
         //           the server
         //       (2) There is no easy way to know if/when the server has started successfully. Hence
         //           we may need to implement a healthcheck service to check if the server is up
-        Server::builder()
+        server
             .timeout(std::time::Duration::from_millis(rpc_timeout_ms))
             .add_service(
                 NetworkMessageServiceServer::new(self).max_decoding_message_size(MAX_MESSAGE_SIZE),
@@ -98,7 +130,31 @@ impl NetworkMessageService for GRPCNetworkMessageServiceServerWrapper {
             .with_label_values(&[&self.self_addr.to_string(), "inbound_msgs"])
             .start_timer();
         let remote_addr = request.remote_addr();
+        let tag = request
+            .metadata()
+            .get_bin(MESSAGE_TAG_METADATA_KEY)
+            .and_then(|tag| tag.to_bytes().ok());
         let network_message = request.into_inner();
+        if let NetworkSecurity::SharedSecret(secret) = &self.security {
+            if !tag.map_or(false, |tag| {
+                secret.verify(
+                    &network_message.message_type,
+                    &network_message.message,
+                    &tag,
+                )
+            }) {
+                NETWORK_REJECTED_MESSAGES
+                    .with_label_values(&[&self.self_addr.to_string()])
+                    .inc();
+                warn!(
+                    "Rejecting message of type {} from {:?}: not signed with the shared secret",
+                    network_message.message_type, remote_addr
+                );
+                return Err(Status::unauthenticated(
+                    "The message is not signed with the shared secret",
+                ));
+            }
+        }
         let msg = Message::new(network_message.message);
         let message_type = MessageType::new(network_message.message_type);
 
@@ -118,44 +174,114 @@ impl NetworkMessageService for GRPCNetworkMessageServiceServerWrapper {
 pub struct GRPCNetworkMessageServiceClientWrapper {
     remote_addr: String,
     remote_channel: NetworkMessageServiceClient<Channel>,
+    security: NetworkSecurity,
+    // Whether a message has already been delivered to the remote node.
+    connected: bool,
 }
 
 impl GRPCNetworkMessageServiceClientWrapper {
-    pub fn new(rt: &Runtime, remote_addr: SocketAddr) -> Self {
-        Self {
+    /// Fails if the TLS config is invalid, as the server does.
+    pub fn new(
+        rt: &Runtime,
+        remote_addr: SocketAddr,
+        security: NetworkSecurity,
+    ) -> Result<Self, Error> {
+        let remote_channel =
+            rt.block_on(async { Self::get_channel(remote_addr, &security).await })?;
+        Ok(Self {
             remote_addr: remote_addr.to_string(),
-            remote_channel: rt
-                .block_on(async { Self::get_channel(format!("http://{}", remote_addr)).await }),
-        }
+            remote_channel,
+            security,
+            connected: false,
+        })
     }
 
-    async fn get_channel(remote_addr: String) -> NetworkMessageServiceClient<Channel> {
+    async fn get_channel(
+        remote_addr: SocketAddr,
+        security: &NetworkSecurity,
+    ) -> Result<NetworkMessageServiceClient<Channel>, Error> {
         info!("Trying to connect to remote server at {:?}", remote_addr);
-        let conn = tonic::transport::Endpoint::new(remote_addr)
-            .unwrap()
-            .connect_lazy();
-        NetworkMessageServiceClient::new(conn).max_decoding_message_size(MAX_MESSAGE_SIZE)
+        let conn = match security {
+            NetworkSecurity::Tls(tls_config) => {
+                tls_config.check()?;
+                tonic::transport::Endpoint::new(format!("https://{}", remote_addr))
+                    .unwrap()
+                    .tls_config(
+                        ClientTlsConfig::new()
+                            .ca_certificate(Certificate::from_pem(&tls_config.ca_cert))
+                            .identity(Identity::from_pem(&tls_config.cert, &tls_config.key))
+                            .domain_name(tls_config.server_name.clone()),
+                    )
+                    .map_err(|e| Error::InvalidTlsConfig(e.to_string()))?
+            },
+            NetworkSecurity::Plaintext | NetworkSecurity::SharedSecret(_) => {
+                tonic::transport::Endpoint::new(format!("http://{}", remote_addr)).unwrap()
+            },
+        }
+        .connect_lazy();
+        Ok(NetworkMessageServiceClient::new(conn).max_decoding_message_size(MAX_MESSAGE_SIZE))
     }
 
+    /// Send the message. Until a first message gets through, the remote node being unreachable is
+    /// not a failure: the message is retried with exponential backoff for up to `CONNECT_TIMEOUT`.
+    /// A message the remote node does not authenticate, e.g. as it has another shared secret, fails
+    /// with `Error::Unauthenticated`, and is not retried.
     pub async fn send_message(
         &mut self,
         sender_addr: SocketAddr,
-        message: Message,
+        mut message: Message,
         mt: &MessageType,
-    ) {
-        let request = tonic::Request::new(NetworkMessage {
-            message: message.data,
-            message_type: mt.get_type(),
-        });
-        // TODO: Retry with exponential backoff on failures
-        match self.remote_channel.simple_msg_exchange(request).await {
-            Ok(_) => {},
-            Err(e) => {
-                panic!(
-                    "Error '{}' sending message to {} on node {:?}",
-                    e, self.remote_addr, sender_addr
-                );
-            },
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let mut backoff = INITIAL_CONNECT_BACKOFF;
+        loop {
+            // Keep a copy of the message to retry with, as long as we are not connected.
+            let data = if self.connected {
+                std::mem::take(&mut message.data)
+            } else {
+                message.data.clone()
+            };
+            let mut request = tonic::Request::new(NetworkMessage {
+                message: data,
+                message_type: mt.get_type(),
+            });
+            if let NetworkSecurity::SharedSecret(secret) = &self.security {
+                let network_message = request.get_ref();
+                let tag = secret.sign(&network_message.message_type, &network_message.message);
+                request
+                    .metadata_mut()
+                    .insert_bin(MESSAGE_TAG_METADATA_KEY, MetadataValue::from_bytes(&tag));
+            }
+            match self.remote_channel.simple_msg_exchange(request).await {
+                Ok(_) => {
+                    self.connected = true;
+                    return Ok(());
+                },
+                Err(e) if e.code() == Code::Unauthenticated => {
+                    return Err(Error::Unauthenticated {
+                        remote_addr: self.remote_addr.clone(),
+                        reason: e.message().to_string(),
+                    });
+                },
+                Err(e)
+                    if !self.connected
+                        && e.code() == Code::Unavailable
+                        && Instant::now() + backoff < deadline =>
+                {
+                    warn!(
+                        "{} is unreachable from node {:?} ('{}'), retrying in {:?}",
+                        self.remote_addr, sender_addr, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                },
+                Err(e) => {
+                    return Err(Error::InternalError(format!(
+                        "Error '{}' sending message to {} on node {:?}",
+                        e, self.remote_addr, sender_addr
+                    )));
+                },
+            }
         }
     }
 }
@@ -178,19 +304,27 @@ fn basic_test() {
         .lock()
         .unwrap()
         .insert(MessageType::new(message_type.clone()), msg_tx);
-    let server = GRPCNetworkMessageServiceServerWrapper::new(server_handlers, server_addr);
+    let server = GRPCNetworkMessageServiceServerWrapper::new(
+        server_handlers,
+        server_addr,
+        NetworkSecurity::Plaintext,
+    );
 
     let rt = Runtime::new().unwrap();
     let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel();
-    server.start(
-        &rt,
-        "unit tester".to_string(),
-        server_addr,
-        1000,
-        server_shutdown_rx,
-    );
+    server
+        .start(
+            &rt,
+            "unit tester".to_string(),
+            server_addr,
+            1000,
+            server_shutdown_rx,
+        )
+        .unwrap();
 
-    let mut grpc_client = GRPCNetworkMessageServiceClientWrapper::new(&rt, server_addr);
+    let mut grpc_client =
+        GRPCNetworkMessageServiceClientWrapper::new(&rt, server_addr, NetworkSecurity::Plaintext)
+            .unwrap();
 
     let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
     let test_message_content = "test1".as_bytes().to_vec();
@@ -208,7 +342,8 @@ fn basic_test() {
                     Message::new(test_message_content.clone()),
                     &MessageType::new(message_type.clone()),
                 )
-                .await;
+                .await
+                .unwrap();
         });
     }
 
@@ -218,3 +353,58 @@ fn basic_test() {
     }
     server_shutdown_tx.send(()).unwrap();
 }
+
+#[test]
+fn test_send_message_retries_until_server_starts() {
+    use aptos_config::utils;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        thread,
+        time::Duration,
+    };
+
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+    let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+    let message_type = MessageType::new("test_type".to_string());
+    let rt = Runtime::new().unwrap();
+
+    // Send before the server is listening.
+    let mut grpc_client =
+        GRPCNetworkMessageServiceClientWrapper::new(&rt, server_addr, NetworkSecurity::Plaintext)
+            .unwrap();
+    let send_task = rt.spawn({
+        let message_type = message_type.clone();
+        async move {
+            grpc_client
+                .send_message(client_addr, Message::new(vec![1, 2, 3]), &message_type)
+                .await
+                .unwrap();
+        }
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let server_handlers: Arc<Mutex<HashMap<MessageType, Sender<Message>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let (msg_tx, msg_rx) = crossbeam_channel::unbounded();
+    server_handlers.lock().unwrap().insert(message_type, msg_tx);
+    let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel();
+    GRPCNetworkMessageServiceServerWrapper::new(
+        server_handlers,
+        server_addr,
+        NetworkSecurity::Plaintext,
+    )
+    .start(
+        &rt,
+        "unit tester".to_string(),
+        server_addr,
+        1000,
+        server_shutdown_rx,
+    )
+    .unwrap();
+
+    assert_eq!(msg_rx.recv_timeout(CONNECT_TIMEOUT).unwrap().data, vec![
+        1, 2, 3
+    ]);
+    rt.block_on(send_task).unwrap();
+    server_shutdown_tx.send(()).unwrap();
+}
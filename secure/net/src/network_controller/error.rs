@@ -14,6 +14,10 @@ pub enum Error {
     InternalError(String),
     #[error("Serialization error: {0}")]
     SerializationError(String),
+    #[error("Invalid TLS config: {0}")]
+    InvalidTlsConfig(String),
+    #[error("Message rejected by {remote_addr} as not authenticated: {reason}")]
+    Unauthenticated { remote_addr: String, reason: String },
 }
 
 impl From<SendError<network_controller::Message>> for Error {
@@ -3,7 +3,7 @@
 
 use crate::{
     grpc_network_service::GRPCNetworkMessageServiceServerWrapper,
-    network_controller::{Message, MessageType},
+    network_controller::{error::Error, security::NetworkSecurity, Message, MessageType},
 };
 use aptos_logger::warn;
 use crossbeam_channel::Sender;
@@ -41,9 +41,13 @@ impl InboundHandler {
         inbound_handlers.insert(MessageType::new(message_type), sender);
     }
 
-    pub fn start(&self, rt: &Runtime) -> Option<oneshot::Sender<()>> {
+    pub fn start(
+        &self,
+        rt: &Runtime,
+        security: NetworkSecurity,
+    ) -> Result<Option<oneshot::Sender<()>>, Error> {
         if self.inbound_handlers.lock().unwrap().is_empty() {
-            return None;
+            return Ok(None);
         }
 
         let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel();
@@ -51,6 +55,7 @@ impl InboundHandler {
         GRPCNetworkMessageServiceServerWrapper::new(
             self.inbound_handlers.clone(),
             self.listen_addr,
+            security,
         )
         .start(
             rt,
@@ -58,8 +63,14 @@ impl InboundHandler {
             self.listen_addr,
             self.rpc_timeout_ms,
             server_shutdown_rx,
-        );
-        Some(server_shutdown_tx)
+        )?;
+        Ok(Some(server_shutdown_tx))
+    }
+
+    /// Drop the senders of all the inbound channels, so that their receivers get disconnected.
+    /// Messages arriving afterwards are discarded.
+    pub fn close(&self) {
+        self.inbound_handlers.lock().unwrap().clear();
     }
 
     // Helper function to short-circuit the network message not to be sent over the network for self messages
@@ -1,7 +1,10 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use aptos_metrics_core::{exponential_buckets, register_histogram_vec, HistogramVec};
+use aptos_metrics_core::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, HistogramVec,
+    IntCounterVec,
+};
 use once_cell::sync::Lazy;
 
 pub static NETWORK_HANDLER_TIMER: Lazy<HistogramVec> = Lazy::new(|| {
@@ -18,3 +21,28 @@ pub static NETWORK_HANDLER_TIMER: Lazy<HistogramVec> = Lazy::new(|| {
     )
     .unwrap()
 });
+
+pub static NETWORK_REJECTED_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        // metric name
+        "network_rejected_messages",
+        // metric description
+        "The number of inbound messages rejected because they failed authentication",
+        // metric labels (dimensions)
+        &["node_addr"],
+    )
+    .unwrap()
+});
+
+pub static NETWORK_UNAUTHENTICATED_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        // metric name
+        "network_unauthenticated_messages",
+        // metric description
+        "The number of outbound messages the remote node rejected because they failed \
+         authentication",
+        // metric labels (dimensions)
+        &["node_addr", "remote_addr"],
+    )
+    .unwrap()
+});
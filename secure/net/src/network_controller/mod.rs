@@ -2,7 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::network_controller::{
-    inbound_handler::InboundHandler, outbound_handler::OutboundHandler,
+    error::Error, inbound_handler::InboundHandler, outbound_handler::OutboundHandler,
+    security::NetworkSecurity,
 };
 use aptos_logger::{info, warn};
 use crossbeam_channel::{unbounded, Receiver, Sender};
@@ -10,13 +11,18 @@ use serde::{Deserialize, Serialize};
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{runtime::Runtime, sync::oneshot};
 
-mod error;
+/// How long `NetworkController::shutdown()` waits for the tasks of each runtime to finish.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub mod error;
 mod inbound_handler;
 pub(crate) mod metrics;
 mod outbound_handler;
+pub mod security;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[allow(dead_code)]
@@ -78,16 +84,18 @@ impl Message {
 /// 3. NetworkController, InboundHandler and OutboundHandler work as a bridge between the sync and
 ///    async worlds.
 /// 4. We need to shutdown all the async tasks spawned by the NetworkController runtimes, otherwise
-///    the program will hang, or have resource leaks.
+///    the program will hang, or have resource leaks. `shutdown()` does that, and is called on drop.
 #[allow(dead_code)]
 pub struct NetworkController {
     inbound_handler: Arc<Mutex<InboundHandler>>,
     outbound_handler: OutboundHandler,
-    inbound_rpc_runtime: Runtime,
-    outbound_rpc_runtime: Runtime,
+    // The runtimes are taken by `shutdown()`.
+    inbound_rpc_runtime: Option<Runtime>,
+    outbound_rpc_runtime: Option<Runtime>,
     inbound_server_shutdown_tx: Option<oneshot::Sender<()>>,
     outbound_task_shutdown_tx: Option<Sender<Message>>,
     listen_addr: SocketAddr,
+    security: NetworkSecurity,
 }
 
 impl NetworkController {
@@ -102,15 +110,27 @@ impl NetworkController {
         Self {
             inbound_handler,
             outbound_handler,
-            inbound_rpc_runtime: Runtime::new().unwrap(),
-            outbound_rpc_runtime: Runtime::new().unwrap(),
+            inbound_rpc_runtime: Some(Runtime::new().unwrap()),
+            outbound_rpc_runtime: Some(Runtime::new().unwrap()),
             // we initialize the shutdown handles when we start the network controller
             inbound_server_shutdown_tx: None,
             outbound_task_shutdown_tx: None,
             listen_addr,
+            security: NetworkSecurity::Plaintext,
         }
     }
 
+    /// Authenticate the connections with the other nodes as set by `security`. Has to be called
+    /// before `start()`, and the other nodes must be set up the same way.
+    pub fn set_security(&mut self, security: NetworkSecurity) {
+        assert!(
+            self.inbound_server_shutdown_tx.is_none() && self.outbound_task_shutdown_tx.is_none(),
+            "Network controller at {} is already started",
+            self.listen_addr
+        );
+        self.security = security;
+    }
+
     pub fn create_outbound_channel(
         &mut self,
         remote_peer_addr: SocketAddr,
@@ -124,6 +144,13 @@ impl NetworkController {
         outbound_sender
     }
 
+    /// How many messages `remote_addr` rejected as not authenticated, e.g. as it is set up with
+    /// another shared secret. These messages are dropped, the later ones still being sent.
+    pub fn num_unauthenticated_messages(&self, remote_addr: &SocketAddr) -> u64 {
+        self.outbound_handler
+            .num_unauthenticated_messages(remote_addr)
+    }
+
     pub fn create_inbound_channel(&mut self, message_type: String) -> Receiver<Message> {
         let (inbound_sender, inbound_receiver) = unbounded();
 
@@ -135,26 +162,48 @@ impl NetworkController {
         inbound_receiver
     }
 
-    pub fn start(&mut self) {
+    /// Start the server and the outbound task. Fails if the TLS config is invalid, e.g. its
+    /// certificate is not signed by its CA.
+    pub fn start(&mut self) -> Result<(), Error> {
         info!(
             "Starting network controller started for at {}",
             self.listen_addr
         );
+        let (Some(inbound_rpc_runtime), Some(outbound_rpc_runtime)) =
+            (&self.inbound_rpc_runtime, &self.outbound_rpc_runtime)
+        else {
+            panic!("Network controller at {} is shutdown", self.listen_addr);
+        };
         self.inbound_server_shutdown_tx = self
             .inbound_handler
             .lock()
             .unwrap()
-            .start(&self.inbound_rpc_runtime);
-        self.outbound_task_shutdown_tx = self.outbound_handler.start(&self.outbound_rpc_runtime);
+            .start(inbound_rpc_runtime, self.security.clone())?;
+        self.outbound_task_shutdown_tx = self
+            .outbound_handler
+            .start(outbound_rpc_runtime, &self.security)?;
+        Ok(())
     }
 
-    // TODO: This is still not a very clean shutdown. We don't wait for the full shutdown after
-    //       sending the signal. May not matter much for now because we shutdown before exiting the
-    //       process. Ideally, we want to fix this.
-    pub fn shutdown(&mut self) {
+    /// Stop the server and the outbound task, and wait (up to `SHUTDOWN_TIMEOUT` per runtime) for
+    /// all the tasks of the runtimes to finish. The inbound channels are closed, so the services
+    /// receiving from them (e.g. the executor shards) see a disconnected channel and stop as well.
+    ///
+    /// Returns false if some task had not finished in time. Calling it again does nothing.
+    pub fn shutdown(&mut self) -> bool {
+        let (Some(inbound_rpc_runtime), Some(outbound_rpc_runtime)) = (
+            self.inbound_rpc_runtime.take(),
+            self.outbound_rpc_runtime.take(),
+        ) else {
+            return true;
+        };
         info!("Shutting down network controller at {}", self.listen_addr);
         if let Some(shutdown_signal) = self.inbound_server_shutdown_tx.take() {
-            shutdown_signal.send(()).unwrap();
+            shutdown_signal.send(()).unwrap_or_else(|_| {
+                warn!(
+                    "Failed to send shutdown signal to inbound server; probably already shutdown"
+                );
+            })
         }
 
         if let Some(shutdown_signal) = self.outbound_task_shutdown_tx.take() {
@@ -162,18 +211,97 @@ impl NetworkController {
                 warn!("Failed to send shutdown signal to outbound task; probably already shutdown");
             })
         }
+        self.inbound_handler.lock().unwrap().close();
+
+        let outbound_stopped = Self::shutdown_runtime(outbound_rpc_runtime);
+        let inbound_stopped = Self::shutdown_runtime(inbound_rpc_runtime);
+        if !outbound_stopped || !inbound_stopped {
+            warn!(
+                "Network controller at {} did not shutdown within {:?} (outbound stopped: {}, inbound stopped: {})",
+                self.listen_addr, SHUTDOWN_TIMEOUT, outbound_stopped, inbound_stopped
+            );
+        }
+        outbound_stopped && inbound_stopped
+    }
+
+    fn shutdown_runtime(runtime: Runtime) -> bool {
+        let started_at = Instant::now();
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        // `shutdown_timeout()` only gives up on the remaining tasks once the timeout has elapsed.
+        started_at.elapsed() < SHUTDOWN_TIMEOUT
+    }
+}
+
+impl Drop for NetworkController {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::network_controller::{Message, NetworkController};
+    use crate::network_controller::{
+        error::Error,
+        security::{NetworkSecurity, SharedSecret, TlsConfig},
+        Message, NetworkController,
+    };
     use aptos_config::utils;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
     use std::{
         net::{IpAddr, Ipv4Addr, SocketAddr},
         thread,
+        time::{Duration, Instant},
     };
 
+    const TEST_SERVER_NAME: &str = "executor-service";
+
+    fn test_ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Certificate::from_params(params).unwrap()
+    }
+
+    // A certificate signed by `ca`, and `ca` as the CA of the other nodes.
+    fn test_tls(ca: &Certificate) -> NetworkSecurity {
+        let node =
+            Certificate::from_params(CertificateParams::new(vec![TEST_SERVER_NAME.to_string()]))
+                .unwrap();
+        NetworkSecurity::Tls(TlsConfig {
+            cert: node.serialize_pem_with_signer(ca).unwrap().into_bytes(),
+            key: node.serialize_private_key_pem().into_bytes(),
+            ca_cert: ca.serialize_pem().unwrap().into_bytes(),
+            server_name: TEST_SERVER_NAME.to_string(),
+        })
+    }
+
+    // Send a message from a node set up with `client_security` to a node set up with
+    // `server_security`, and return whether the message got through.
+    fn send_with_security(
+        server_security: NetworkSecurity,
+        client_security: NetworkSecurity,
+    ) -> bool {
+        let server_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let client_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let mut server = NetworkController::new("server".to_string(), server_addr, 1000);
+        server.set_security(server_security);
+        let mut client = NetworkController::new("client".to_string(), client_addr, 1000);
+        client.set_security(client_security);
+        let sender = client.create_outbound_channel(server_addr, "test".to_string());
+        let receiver = server.create_inbound_channel("test".to_string());
+        server.start().unwrap();
+        client.start().unwrap();
+
+        sender.send(Message::new(vec![1, 2, 3])).unwrap();
+        // The client retries while it cannot connect, so a rejected message never arrives.
+        let received = receiver.recv_timeout(Duration::from_secs(2));
+        client.shutdown();
+        server.shutdown();
+        received.map_or(false, |message| message.data == vec![1, 2, 3])
+    }
+
     #[test]
     fn test_basic_send_receive() {
         let server_port1 = utils::get_available_port();
@@ -195,8 +323,8 @@ mod tests {
             network_controller1.create_outbound_channel(server_addr2, "test2".to_string());
         let test2_receiver = network_controller2.create_inbound_channel("test2".to_string());
 
-        network_controller1.start();
-        network_controller2.start();
+        network_controller1.start().unwrap();
+        network_controller2.start().unwrap();
 
         // wait for the server to be ready to serve
         // TODO: We need to pass this test without this sleep
@@ -218,8 +346,121 @@ mod tests {
         let received_test2_message = test2_receiver.recv().unwrap();
         assert_eq!(received_test2_message.data, test2_message);
 
-        network_controller1.shutdown();
-        network_controller2.shutdown();
-        thread::sleep(std::time::Duration::from_millis(100));
+        assert!(network_controller1.shutdown());
+        assert!(network_controller2.shutdown());
+    }
+
+    #[test]
+    fn test_shutdown_closes_inbound_channels_and_releases_port() {
+        let server_port = utils::get_available_port();
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server_port);
+        let client_port = utils::get_available_port();
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), client_port);
+
+        // The same addresses every time: the server can only receive if the port was released.
+        for i in 0..3 {
+            let mut server = NetworkController::new("server".to_string(), server_addr, 1000);
+            let mut client = NetworkController::new("client".to_string(), client_addr, 1000);
+            let sender = client.create_outbound_channel(server_addr, "test".to_string());
+            let receiver = server.create_inbound_channel("test".to_string());
+            server.start().unwrap();
+            client.start().unwrap();
+            thread::sleep(std::time::Duration::from_millis(100));
+
+            sender.send(Message::new(vec![i])).unwrap();
+            assert_eq!(receiver.recv().unwrap().data, vec![i]);
+
+            assert!(client.shutdown());
+            assert!(server.shutdown());
+            assert!(receiver.recv().is_err());
+            // A second shutdown, e.g. by `drop()`, does nothing.
+            assert!(server.shutdown());
+        }
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let ca = test_ca("test ca");
+        assert!(send_with_security(test_tls(&ca), test_tls(&ca)));
+    }
+
+    #[test]
+    fn test_tls_rejects_other_certificates() {
+        let ca = test_ca("test ca");
+        let other_ca = test_ca("other ca");
+        assert!(!send_with_security(test_tls(&ca), test_tls(&other_ca)));
+        assert!(!send_with_security(
+            test_tls(&ca),
+            NetworkSecurity::Plaintext
+        ));
+    }
+
+    #[test]
+    fn test_tls_rejects_mismatched_ca_at_startup() {
+        let ca = test_ca("test ca");
+        let other_ca = test_ca("other ca");
+        let NetworkSecurity::Tls(mut tls_config) = test_tls(&ca) else {
+            unreachable!()
+        };
+        tls_config.ca_cert = other_ca.serialize_pem().unwrap().into_bytes();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let other_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+
+        // As a server.
+        let mut server = NetworkController::new("server".to_string(), addr, 1000);
+        server.set_security(NetworkSecurity::Tls(tls_config.clone()));
+        let _receiver = server.create_inbound_channel("test".to_string());
+        assert!(matches!(server.start(), Err(Error::InvalidTlsConfig(_))));
+        assert!(server.shutdown());
+
+        // As a client.
+        let mut client = NetworkController::new("client".to_string(), addr, 1000);
+        client.set_security(NetworkSecurity::Tls(tls_config));
+        let _sender = client.create_outbound_channel(other_addr, "test".to_string());
+        assert!(matches!(client.start(), Err(Error::InvalidTlsConfig(_))));
+        assert!(client.shutdown());
+    }
+
+    #[test]
+    fn test_sender_survives_mismatched_shared_secret() {
+        let server_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let client_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), utils::get_available_port());
+        let mut server = NetworkController::new("server".to_string(), server_addr, 1000);
+        server.set_security(NetworkSecurity::SharedSecret(SharedSecret::new(b"secret")));
+        let mut client = NetworkController::new("client".to_string(), client_addr, 1000);
+        client.set_security(NetworkSecurity::SharedSecret(SharedSecret::new(
+            b"other secret",
+        )));
+        let sender = client.create_outbound_channel(server_addr, "test".to_string());
+        let receiver = server.create_inbound_channel("test".to_string());
+        server.start().unwrap();
+        client.start().unwrap();
+
+        // Every message is rejected, and the outbound task keeps sending the next ones.
+        for num_sent in 1..=2 {
+            sender.send(Message::new(vec![num_sent as u8])).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while client.num_unauthenticated_messages(&server_addr) < num_sent {
+                assert!(Instant::now() < deadline, "The rejection is not reported");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert!(receiver.try_recv().is_err());
+        assert!(client.shutdown());
+        assert!(server.shutdown());
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let secret = || NetworkSecurity::SharedSecret(SharedSecret::new(b"secret"));
+        assert!(send_with_security(secret(), secret()));
+        assert!(!send_with_security(
+            secret(),
+            NetworkSecurity::SharedSecret(SharedSecret::new(b"other secret"))
+        ));
+        assert!(!send_with_security(secret(), NetworkSecurity::Plaintext));
     }
 }
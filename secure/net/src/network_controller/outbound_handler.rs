@@ -4,10 +4,14 @@
 use crate::{
     grpc_network_service::GRPCNetworkMessageServiceClientWrapper,
     network_controller::{
-        inbound_handler::InboundHandler, metrics::NETWORK_HANDLER_TIMER, Message, MessageType,
+        error::Error,
+        inbound_handler::InboundHandler,
+        metrics::{NETWORK_HANDLER_TIMER, NETWORK_UNAUTHENTICATED_MESSAGES},
+        security::NetworkSecurity,
+        Message, MessageType,
     },
 };
-use aptos_logger::{info, warn};
+use aptos_logger::{error, info, warn};
 use crossbeam_channel::{unbounded, Receiver, Select, Sender};
 use std::{
     collections::{HashMap, HashSet},
@@ -24,6 +28,8 @@ pub struct OutboundHandler {
     // Used to route outgoing messages to correct network client with the correct message type
     handlers: Vec<(Receiver<Message>, SocketAddr, MessageType)>,
     inbound_handler: Arc<Mutex<InboundHandler>>,
+    // The number of messages each remote node rejected as not authenticated.
+    unauthenticated_messages: Arc<Mutex<HashMap<SocketAddr, u64>>>,
 }
 
 impl OutboundHandler {
@@ -38,9 +44,20 @@ impl OutboundHandler {
             address: listen_addr,
             handlers: Vec::new(),
             inbound_handler,
+            unauthenticated_messages: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// How many messages `remote_addr` rejected as not authenticated, which are dropped.
+    pub fn num_unauthenticated_messages(&self, remote_addr: &SocketAddr) -> u64 {
+        self.unauthenticated_messages
+            .lock()
+            .unwrap()
+            .get(remote_addr)
+            .copied()
+            .unwrap_or(0)
+    }
+
     pub fn register_handler(
         &mut self,
         message_type: String,
@@ -52,9 +69,23 @@ impl OutboundHandler {
             .push((receiver, remote_addr, MessageType::new(message_type)));
     }
 
-    pub fn start(&mut self, rt: &Runtime) -> Option<Sender<Message>> {
+    pub fn start(
+        &mut self,
+        rt: &Runtime,
+        security: &NetworkSecurity,
+    ) -> Result<Option<Sender<Message>>, Error> {
         if self.handlers.is_empty() {
-            return None;
+            return Ok(None);
+        }
+
+        // Create a grpc client for each remote address
+        let mut grpc_clients: HashMap<SocketAddr, GRPCNetworkMessageServiceClientWrapper> =
+            HashMap::new();
+        for remote_addr in self.remote_addresses.iter() {
+            grpc_clients.insert(
+                *remote_addr,
+                GRPCNetworkMessageServiceClientWrapper::new(rt, *remote_addr, security.clone())?,
+            );
         }
 
         // Register a signal handler to stop the outbound task
@@ -65,20 +96,11 @@ impl OutboundHandler {
             MessageType::new("stop_task".to_string()),
         ));
 
-        // Create a grpc client for each remote address
-        let mut grpc_clients: HashMap<SocketAddr, GRPCNetworkMessageServiceClientWrapper> =
-            HashMap::new();
-        self.remote_addresses.iter().for_each(|remote_addr| {
-            grpc_clients.insert(
-                *remote_addr,
-                GRPCNetworkMessageServiceClientWrapper::new(rt, *remote_addr),
-            );
-        });
-
         // Prepare for objects to be moved into the async block (&mut self cannot be moved into the
         // async block)
         let address = self.address;
         let inbound_handler = self.inbound_handler.clone();
+        let unauthenticated_messages = self.unauthenticated_messages.clone();
         // Moving the handlers out of self is fine because once 'start()' is called we do not intend
         // to register any more handlers. A reference count like Arc<Mutex> has issues of being
         // used across sync and async boundaries, and also not the most efficient because we pay
@@ -93,11 +115,12 @@ impl OutboundHandler {
                 &address,
                 inbound_handler.clone(),
                 &mut grpc_clients,
+                &unauthenticated_messages,
             )
             .await;
             info!("Stopping outbound handler at {}", address.to_string());
         });
-        Some(stop_signal_tx)
+        Ok(Some(stop_signal_tx))
     }
 
     async fn process_one_outgoing_message(
@@ -105,6 +128,7 @@ impl OutboundHandler {
         socket_addr: &SocketAddr,
         inbound_handler: Arc<Mutex<InboundHandler>>,
         grpc_clients: &mut HashMap<SocketAddr, GRPCNetworkMessageServiceClientWrapper>,
+        unauthenticated_messages: &Mutex<HashMap<SocketAddr, u64>>,
     ) {
         loop {
             let mut select = Select::new();
@@ -152,11 +176,35 @@ impl OutboundHandler {
                     .unwrap()
                     .send_incoming_message_to_handler(message_type, msg);
             } else {
-                grpc_clients
+                match grpc_clients
                     .get_mut(remote_addr)
                     .unwrap()
                     .send_message(*socket_addr, msg, message_type)
-                    .await;
+                    .await
+                {
+                    Ok(()) => {},
+                    // The message is dropped, but the next ones are still sent, which the remote
+                    // node accepts once it is set up like this one.
+                    Err(e @ Error::Unauthenticated { .. }) => {
+                        NETWORK_UNAUTHENTICATED_MESSAGES
+                            .with_label_values(&[
+                                &socket_addr.to_string(),
+                                &remote_addr.to_string(),
+                            ])
+                            .inc();
+                        *unauthenticated_messages
+                            .lock()
+                            .unwrap()
+                            .entry(*remote_addr)
+                            .or_insert(0) += 1;
+                        error!(
+                            "Dropping message of type {:?}: {}",
+                            message_type.get_type(),
+                            e
+                        );
+                    },
+                    Err(e) => panic!("{}", e),
+                }
             }
         }
     }
@@ -0,0 +1,158 @@
+// Copyright © Aptos Foundation
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::network_controller::error::Error;
+use ring::hmac;
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The gRPC metadata carrying the tag of a message authenticated with a `SharedSecret`.
+pub(crate) const MESSAGE_TAG_METADATA_KEY: &str = "x-aptos-message-tag-bin";
+
+/// How the nodes authenticate each other. All the nodes talking to each other must be set up the
+/// same way, a node rejecting the messages of the nodes that are not.
+#[derive(Clone, Debug, Default)]
+pub enum NetworkSecurity {
+    /// Neither authenticated nor encrypted, for nodes on a single trusted host.
+    #[default]
+    Plaintext,
+    /// Every message is authenticated with a secret all the nodes share, but not encrypted, for
+    /// nodes on a trusted network. This only guarantees the integrity of the messages: nothing
+    /// ties a tag to a connection or a point in time, so a recorded message can be replayed and
+    /// is accepted again.
+    SharedSecret(SharedSecret),
+    /// Mutual TLS: the connections are encrypted, and both ends must present a certificate signed
+    /// by the same CA.
+    Tls(TlsConfig),
+}
+
+/// A secret the nodes sign their messages with, which the receiving node checks the signature of
+/// before passing the message on. The signature only covers the type and data of the message, so
+/// it does not protect against replay.
+#[derive(Clone)]
+pub struct SharedSecret {
+    key: hmac::Key,
+}
+
+impl SharedSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub(crate) fn sign(&self, message_type: &str, message: &[u8]) -> Vec<u8> {
+        let mut context = hmac::Context::with_key(&self.key);
+        Self::update(&mut context, message_type, message);
+        context.sign().as_ref().to_vec()
+    }
+
+    pub(crate) fn verify(&self, message_type: &str, message: &[u8], tag: &[u8]) -> bool {
+        let mut context = hmac::Context::with_key(&self.key);
+        Self::update(&mut context, message_type, message);
+        // Compared in constant time, not to leak how much of the tag is right.
+        let expected_tag = context.sign();
+        ring::constant_time::verify_slices_are_equal(expected_tag.as_ref(), tag).is_ok()
+    }
+
+    fn update(context: &mut hmac::Context, message_type: &str, message: &[u8]) {
+        // The type is prefixed with its length, so that a message cannot pass for one of another
+        // type by moving bytes between the type and the data.
+        context.update(&(message_type.len() as u64).to_le_bytes());
+        context.update(message_type.as_bytes());
+        context.update(message);
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedSecret(<redacted>)")
+    }
+}
+
+/// The PEM-encoded certificates and key of a node for mutual TLS.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// The certificate the node presents to the other nodes.
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    /// The certificate of the CA the certificates of the other nodes, and `cert`, must be signed by.
+    pub ca_cert: Vec<u8>,
+    /// The name the certificates of the other nodes must be issued for.
+    pub server_name: String,
+}
+
+/// The signature algorithms the certificates may be signed with.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+impl TlsConfig {
+    /// Check that `cert` is signed by `ca_cert`. As all the nodes trust the same CA, a node whose
+    /// certificate is not would be rejected by every other node, which the TLS handshake only
+    /// reports once the first message is sent.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        let cert = Self::parse_cert(&self.cert, "cert")?;
+        let ca_cert = Self::parse_cert(&self.ca_cert, "ca_cert")?;
+        let trust_anchor = webpki::TrustAnchor::try_from_cert_der(&ca_cert)
+            .map_err(|e| Error::InvalidTlsConfig(format!("ca_cert: {}", e)))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The system time is before the UNIX epoch");
+        webpki::EndEntityCert::try_from(cert.as_slice())
+            .and_then(|cert| {
+                cert.verify_is_valid_tls_server_cert(
+                    SIGNATURE_ALGORITHMS,
+                    &webpki::TlsServerTrustAnchors(&[trust_anchor]),
+                    &[],
+                    webpki::Time::from_seconds_since_unix_epoch(now.as_secs()),
+                )
+            })
+            .map_err(|e| Error::InvalidTlsConfig(format!("cert is not signed by ca_cert: {}", e)))
+    }
+
+    /// The DER encoding of the first certificate of `pem`.
+    fn parse_cert(pem: &[u8], name: &str) -> Result<Vec<u8>, Error> {
+        rustls_pemfile::certs(&mut &pem[..])
+            .map_err(|e| Error::InvalidTlsConfig(format!("{}: {}", name, e)))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::InvalidTlsConfig(format!("{}: no certificate", name)))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_secret_tags() {
+        let secret = SharedSecret::new(b"secret");
+        let tag = secret.sign("type", b"message");
+        assert!(secret.verify("type", b"message", &tag));
+        assert!(!secret.verify("type", b"other message", &tag));
+        assert!(!secret.verify("typem", b"essage", &tag));
+        assert!(!secret.verify("type", b"message", &tag[1..]));
+        assert!(!SharedSecret::new(b"other secret").verify("type", b"message", &tag));
+    }
+}
@@ -1,16 +1,22 @@
 // Copyright © Aptos Foundation
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::transaction::{
-    analyzed_transaction::{AnalyzedTransaction, StorageLocation},
-    signature_verified_transaction::{into_signature_verified_block, SignatureVerifiedTransaction},
-    Transaction,
+use crate::{
+    state_store::state_key::StateKey,
+    transaction::{
+        analyzed_transaction::{AnalyzedTransaction, StorageLocation},
+        signature_verified_transaction::{
+            into_signature_verified_block, SignatureVerifiedTransaction,
+        },
+        Transaction,
+    },
 };
 use aptos_crypto::HashValue;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
+    ops::AddAssign,
 };
 
 pub type ShardId = usize;
@@ -430,6 +436,132 @@ impl<T: Clone> TransactionWithDependencies<T> {
     }
 }
 
+/// The written values are only known at execution time,
+/// so a cross-shard message is assumed to carry this many bytes besides its state key.
+pub const ESTIMATED_CROSS_SHARD_WRITE_BYTES: usize = 128;
+
+/// The traffic expected on a cross-shard channel during a block.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MessageVolume {
+    pub num_messages: usize,
+    /// The size of the state keys of the messages, plus `ESTIMATED_CROSS_SHARD_WRITE_BYTES` per message.
+    pub num_bytes: usize,
+}
+
+impl MessageVolume {
+    fn add_message(&mut self, state_key: &StateKey) {
+        self.num_messages += 1;
+        self.num_bytes += state_key.size() + ESTIMATED_CROSS_SHARD_WRITE_BYTES;
+    }
+}
+
+impl AddAssign for MessageVolume {
+    fn add_assign(&mut self, other: Self) {
+        self.num_messages += other.num_messages;
+        self.num_bytes += other.num_bytes;
+    }
+}
+
+/// The cross-shard messages a partitioned block sends, by (source shard, target shard, round),
+/// derived from the dependent edges of its txns.
+///
+/// Like `CrossShardCommitSender`, a txn sends one message per key and per sub-block depending on it.
+/// The counts are an upper bound, as a key in a dependent edge may end up not being written.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CrossShardMessageVolume {
+    num_shards: usize,
+    // Indexed by `(source_shard_id * num_shards + target_shard_id) * MAX_ALLOWED_PARTITIONING_ROUNDS + round_id`.
+    volumes: Vec<MessageVolume>,
+    // The messages to the global txns, by source shard.
+    global_volumes: Vec<MessageVolume>,
+}
+
+impl CrossShardMessageVolume {
+    pub fn new(sharded_txns: &[SubBlocksForShard<AnalyzedTransaction>]) -> Self {
+        let num_shards = sharded_txns.len();
+        let mut ret = Self {
+            num_shards,
+            volumes: vec![
+                MessageVolume::default();
+                num_shards * num_shards * MAX_ALLOWED_PARTITIONING_ROUNDS
+            ],
+            global_volumes: vec![MessageVolume::default(); num_shards],
+        };
+        for (source_shard_id, sub_blocks) in sharded_txns.iter().enumerate() {
+            for txn_with_deps in sub_blocks.iter() {
+                let mut targets: HashSet<(&StateKey, ShardId, RoundId)> = HashSet::new();
+                for (target, storage_locations) in txn_with_deps
+                    .cross_shard_dependencies
+                    .dependent_edges()
+                    .iter()
+                {
+                    for storage_location in storage_locations {
+                        let state_key = storage_location.state_key();
+                        if !targets.insert((state_key, target.shard_id, target.round_id)) {
+                            continue;
+                        }
+                        if target.round_id == GLOBAL_ROUND_ID {
+                            ret.global_volumes[source_shard_id].add_message(state_key);
+                        } else {
+                            let idx = ret.idx(source_shard_id, target.shard_id, target.round_id);
+                            ret.volumes[idx].add_message(state_key);
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn idx(&self, source_shard_id: ShardId, target_shard_id: ShardId, round_id: RoundId) -> usize {
+        // A larger round would land in the stride of the next pair of shards.
+        assert!(
+            round_id < MAX_ALLOWED_PARTITIONING_ROUNDS,
+            "Round {} is beyond the {} rounds allowed",
+            round_id,
+            MAX_ALLOWED_PARTITIONING_ROUNDS
+        );
+        (source_shard_id * self.num_shards + target_shard_id) * MAX_ALLOWED_PARTITIONING_ROUNDS
+            + round_id
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.num_shards
+    }
+
+    /// The messages from the sub-blocks of `source_shard_id` to the sub-block of `target_shard_id` in `round_id`.
+    pub fn get(
+        &self,
+        source_shard_id: ShardId,
+        target_shard_id: ShardId,
+        round_id: RoundId,
+    ) -> MessageVolume {
+        self.volumes[self.idx(source_shard_id, target_shard_id, round_id)]
+    }
+
+    /// The messages from all the shards to the sub-block of `target_shard_id` in `round_id`.
+    pub fn inbound(&self, target_shard_id: ShardId, round_id: RoundId) -> MessageVolume {
+        let mut ret = MessageVolume::default();
+        for source_shard_id in 0..self.num_shards {
+            ret += self.get(source_shard_id, target_shard_id, round_id);
+        }
+        ret
+    }
+
+    /// The messages from the sub-blocks of `source_shard_id` to the global txns.
+    pub fn to_global(&self, source_shard_id: ShardId) -> MessageVolume {
+        self.global_volumes[source_shard_id]
+    }
+
+    pub fn total(&self) -> MessageVolume {
+        let mut ret = MessageVolume::default();
+        for volume in self.volumes.iter().chain(self.global_volumes.iter()) {
+            ret += *volume;
+        }
+        ret
+    }
+}
+
 pub struct ExecutableBlock {
     pub block_id: HashValue,
     pub transactions: ExecutableTransactions,
@@ -463,6 +595,9 @@ impl From<(HashValue, Vec<Transaction>)> for ExecutableBlock {
 pub struct PartitionedTransactions {
     pub sharded_txns: Vec<SubBlocksForShard<AnalyzedTransaction>>,
     pub global_txns: Vec<TransactionWithDependencies<AnalyzedTransaction>>,
+    /// Computed from the dependencies when the partitioner creates the output,
+    /// so the executor can prepare its cross-shard channels for the block.
+    pub cross_shard_message_volume: CrossShardMessageVolume,
 }
 
 impl PartitionedTransactions {
@@ -470,9 +605,11 @@ impl PartitionedTransactions {
         sharded_txns: Vec<SubBlocksForShard<AnalyzedTransaction>>,
         global_txns: Vec<TransactionWithDependencies<AnalyzedTransaction>>,
     ) -> Self {
+        let cross_shard_message_volume = CrossShardMessageVolume::new(&sharded_txns);
         Self {
             sharded_txns,
             global_txns,
+            cross_shard_message_volume,
         }
     }
 
@@ -480,6 +617,7 @@ impl PartitionedTransactions {
         Self {
             sharded_txns: Vec::new(),
             global_txns: Vec::new(),
+            cross_shard_message_volume: CrossShardMessageVolume::default(),
         }
     }
 
@@ -500,6 +638,10 @@ impl PartitionedTransactions {
         &self.sharded_txns
     }
 
+    pub fn cross_shard_message_volume(&self) -> &CrossShardMessageVolume {
+        &self.cross_shard_message_volume
+    }
+
     pub fn num_sharded_txns(&self) -> usize {
         self.sharded_txns
             .iter()